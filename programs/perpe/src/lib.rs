@@ -13,16 +13,82 @@ const WSOL_MINT: Pubkey = pubkey!("So11111111111111111111111111111111111111112")
 
 const MAX_LEVERAGE: u64 = 10;
 const LIQUIDATION_THRESHOLD_BPS: u64 = 7000;
+// Collateral loss at which `warn_position` flags a position as at risk
+const SOFT_LIQUIDATION_THRESHOLD_BPS: u64 = 5000;
 const LIQUIDATOR_REWARD_BPS: u64 = 500;
 const PROTOCOL_FEE_BPS: u64 = 30;
+// LP + protocol + coin creator fee charged by pumpswap on every swap
+const PUMPSWAP_FEE_BPS: u64 = 30;
 const BPS_DENOMINATOR: u64 = 10_000;
+const MAX_ACTIVE_POSITIONS: usize = 8;
+const SECONDS_PER_YEAR: u64 = 31_536_000;
+// Trading volume decays linearly to zero over this window, approximating a
+// rolling 30-day total without storing per-trade history
+const VOLUME_DECAY_WINDOW: i64 = 30 * 86_400;
+// (minimum decayed volume in lamports, protocol fee bps), highest tier first;
+// below every tier the fee is PROTOCOL_FEE_BPS
+const FEE_TIERS: [(u64, u64); 2] = [(10_000_000_000_000, 20), (1_000_000_000_000, 25)];
+// Largest slice of a single position auto_deleverage may close per call
+const ADL_MAX_CLOSE_BPS: u64 = 5000;
+// Keeper closes may loosen their slippage limit in-instruction by this step,
+// up to the maximum, when the pool has moved past it
+const SLIPPAGE_WIDEN_STEP_BPS: u64 = 25;
+const MAX_SLIPPAGE_WIDEN_BPS: u64 = 1000;
 const PRECISION: u128 = 1_000_000_000_000;
+const WSOL_DECIMALS: u8 = 9;
 
 const POOL_BASE_MINT_OFFSET: usize = 43;
+const POOL_QUOTE_MINT_OFFSET: usize = 75;
+const POOL_BASE_VAULT_OFFSET: usize = 139;
+const POOL_QUOTE_VAULT_OFFSET: usize = 171;
+// Time for the mark price to fully catch up with a new pool price; a sample
+// after `elapsed` seconds moves the mark elapsed / MARK_PRICE_WINDOW of the way
+const MARK_PRICE_WINDOW: i64 = 300;
+// Most elapsed time a single sample is weighted by, so one sample moves the
+// mark at most MARK_PRICE_MAX_ELAPSED / MARK_PRICE_WINDOW (20%) of the way.
+// A mark left unsampled for a while can't be dragged to a manipulated price
+// by the next sample; it converges over several.
+const MARK_PRICE_MAX_ELAPSED: i64 = 60;
+// Longest close_delay a market may set for two-phase closes, and how long
+// after the delay the snapshot tolerance still applies. Past that window a
+// requested close goes through at any price, bounded by its slippage_limit,
+// so a price that runs away from the snapshot can't trap a position.
+const MAX_CLOSE_DELAY: i64 = 3600;
+const CLOSE_TOLERANCE_WINDOW: i64 = 600;
+// Max price divergence between old and new pool when migrating a market with open positions
+const POOL_MIGRATION_TOLERANCE_BPS: u64 = 100;
+// A pool whose WSOL reserve drops below this share of its reserve at open counts as rugged
+const POOL_COLLAPSE_BPS: u64 = 2000;
+// WSOL reserve (10 SOL) a pool needs for `create_market` until the admin sets its own floor
+const DEFAULT_MIN_POOL_LIQUIDITY: u64 = 10_000_000_000;
 const TOKEN_AMOUNT_OFFSET: usize = 64;
+// Accounts in one pumpswap group within remaining_accounts
+const PUMPSWAP_ACCOUNTS_LEN: usize = 14;
+// close_portfolio group: market, lending_pool, position, token_mint,
+// token_vault, base_token_program, then a pumpswap group
+const PORTFOLIO_GROUP_LEN: usize = 6 + PUMPSWAP_ACCOUNTS_LEN;
+// Positions close_portfolio takes per call; three groups already come close
+// to the transaction account-lock limit
+const MAX_PORTFOLIO_CLOSE: usize = 3;
 
 const BUY_DISCRIMINATOR: [u8; 8] = [102, 6, 61, 18, 1, 218, 235, 234];
 const SELL_DISCRIMINATOR: [u8; 8] = [51, 230, 133, 164, 1, 127, 131, 173];
+// Pyth `PriceUpdateV2`: discriminator, write authority, then a verification
+// level whose `Partial` variant carries one extra byte before the price message
+const PRICE_UPDATE_DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+// Pyth Solana Receiver, which owns every verified `PriceUpdateV2` account
+const PYTH_RECEIVER_PROGRAM_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+// Pyth SOL/USD feed id, 0xef0d8b6f...c280b56d
+const SOL_USD_FEED_ID: [u8; 32] = [
+    239, 13, 139, 111, 218, 44, 235, 164, 29, 161, 93, 64, 149, 209, 218, 57,
+    42, 13, 47, 142, 208, 198, 199, 188, 15, 76, 250, 200, 194, 128, 181, 109,
+];
+const PRICE_UPDATE_VERIFICATION_OFFSET: usize = 40;
+// Price, confidence, exponent and publish time after the 32-byte feed id
+const PRICE_MESSAGE_LEN: usize = 32 + 8 + 8 + 4 + 8;
+const MAX_ORACLE_AGE: i64 = 60;
+// USD-denominated limits are in micro-dollars
+const USD_UNIT: u64 = 1_000_000;
 
 #[program]
 pub mod perpe {
@@ -33,15 +99,58 @@ pub mod perpe {
         protocol.admin = ctx.accounts.admin.key();
         protocol.bump = ctx.bumps.protocol;
         protocol.vault_bump = ctx.bumps.protocol_vault;
-        
-        emit!(ProtocolInitialized { admin: protocol.admin });
+        protocol.fee_insurance_split_bps = 0;
+        protocol.insurance_fund = 0;
+        protocol.accumulated_fees = 0;
+        protocol.adl_threshold_bps = 0;
+        protocol.fee_discount_mint = Pubkey::default();
+        protocol.fee_collector = Pubkey::default();
+        protocol.fee_discount_bps = 0;
+        protocol.fee_tokens_per_lamport = 0;
+        protocol.market_count = 0;
+        protocol.total_open_positions = 0;
+        protocol.total_user_balances = 0;
+        protocol.total_short_collateral = 0;
+        protocol.crank_reward = 0;
+        protocol.crank_interval = 0;
+        protocol.min_liquidator_reward = 0;
+        protocol.liquidation_dust_threshold = 0;
+        protocol.migrating = false;
+        protocol.liquidation_buffer_bps = 0;
+        protocol.max_leverage_ceiling = MAX_LEVERAGE;
+        protocol.max_total_positions = 0;
+        protocol.total_open_size = 0;
+        protocol.insurance_coverage_multiple = 0;
+        protocol.sol_usd_oracle = Pubkey::default();
+        protocol.open_fee_rebate_bps = 0;
+        protocol.min_pool_liquidity = DEFAULT_MIN_POOL_LIQUIDITY;
+        protocol.open_whitelist_enabled = false;
+
+        // Seed the vault with its rent-exempt minimum so the reserve that
+        // withdraw leaves behind never comes out of user balances.
+        let rent_min = Rent::get()?.minimum_balance(ctx.accounts.protocol_vault.data_len());
+        let top_up = rent_min.saturating_sub(ctx.accounts.protocol_vault.lamports());
+        if top_up > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.admin.to_account_info(),
+                        to: ctx.accounts.protocol_vault.to_account_info(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+
+        emit!(ProtocolInitialized { admin: ctx.accounts.protocol.admin });
         Ok(())
     }
 
     pub fn create_market(ctx: Context<CreateMarket>, max_position_size: u64) -> Result<()> {
         require!(
             ctx.accounts.admin.key() == ctx.accounts.protocol.admin,
-            ErrorCode::Unauthorized
+            ErrorCode::NotAdmin
         );
     
         require!(
@@ -53,30 +162,100 @@ pub mod perpe {
         let base_mint = Pubkey::try_from(&pool_data[POOL_BASE_MINT_OFFSET..POOL_BASE_MINT_OFFSET + 32])
             .map_err(|_| ErrorCode::InvalidPool)?;
         require!(base_mint == ctx.accounts.token_mint.key(), ErrorCode::PoolMintMismatch);
+        let quote_mint = Pubkey::try_from(&pool_data[POOL_QUOTE_MINT_OFFSET..POOL_QUOTE_MINT_OFFSET + 32])
+            .map_err(|_| ErrorCode::InvalidPool)?;
+        // Balances, the protocol vault and the swap wrapping are all native
+        // SOL, so only WSOL-quoted pools can settle today. The quote mint is
+        // still recorded per market so trading accounts bind to it.
+        require!(quote_mint == WSOL_MINT, ErrorCode::UnsupportedQuoteMint);
         drop(pool_data);
+
+        let (_, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(quote_vault == ctx.accounts.pool_quote_vault.key(), ErrorCode::InvalidPool);
+        require!(
+            read_token_amount(&ctx.accounts.pool_quote_vault)? >= ctx.accounts.protocol.min_pool_liquidity,
+            ErrorCode::InsufficientPoolLiquidity
+        );
     
         let market = &mut ctx.accounts.market;
         market.token_mint = ctx.accounts.token_mint.key();
         market.pumpswap_pool = ctx.accounts.pumpswap_pool.key();
+        market.quote_mint = quote_mint;
         market.total_long_collateral = 0;
         market.total_short_collateral = 0;
         market.total_positions = 0;
         market.max_position_size = max_position_size;
         market.token_decimals = ctx.accounts.token_mint.decimals;
+        market.base_unit = 10u64
+            .checked_pow(ctx.accounts.token_mint.decimals as u32)
+            .ok_or(ErrorCode::Overflow)?;
+        market.quote_unit = 10u64.pow(WSOL_DECIMALS as u32);
+        market.cumulative_price = 0;
+        market.mark_price = 0;
+        market.last_price_update = 0;
+        market.last_crank_ts = 0;
+        market.enabled = true;
+        market.liquidations_paused = false;
+        market.min_position_size = 0;
+        market.mock_price = 0;
+        market.two_phase_close_size = 0;
+        market.close_delay = 0;
+        market.close_price_tolerance_bps = 0;
+        market.max_leverage = ctx.accounts.protocol.max_leverage_ceiling;
+        market.max_close_slippage_bps = 0;
+        market.open_cooldown_seconds = 0;
+        market.close_fee_decay_seconds = 0;
+        market.close_fee_floor_bps = 0;
+        market.max_liquidation_chunk = 0;
+        market.lender_fee_share_bps = 0;
+        market.largest_position_size = 0;
+        market.max_price_jump_bps = 0;
+        market.price_jump_guard_size = 0;
+        market.last_observed_price = 0;
+        market.last_observed_slot = 0;
+        market.prior_slot_price = 0;
+        market.unsold_long_tokens = 0;
         market.bump = ctx.bumps.market;
 
+        // Indices are never reused, so closed markets leave gaps.
+        let protocol = &mut ctx.accounts.protocol;
+        market.market_index = protocol.market_count;
+        protocol.market_count = protocol.market_count.checked_add(1).ok_or(ErrorCode::Overflow)?;
+
         let lending = &mut ctx.accounts.lending_pool;
         lending.market = market.key();
         lending.token_mint = ctx.accounts.token_mint.key();
         lending.total_deposits = 0;
         lending.total_borrowed = 0;
         lending.total_shares = 0;
+        lending.borrow_rate_bps = 0;
+        lending.cumulative_interest_collected = 0;
+        lending.max_borrow_per_position = 0;
+        lending.reserve_factor_bps = 0;
+        lending.protocol_reserves = 0;
+        lending.deposit_cap = 0;
+        lending.max_interest_bps = 0;
+        lending.fee_per_share = 0;
+        lending.withdrawal_fee_bps = 0;
+        lending.withdrawal_fee_decay_seconds = 0;
         lending.bump = ctx.bumps.lending_pool;
 
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MarketCreated,
+            0,
+            max_position_size,
+        );
+
         emit!(MarketCreated {
             token_mint: market.token_mint,
             pumpswap_pool: market.pumpswap_pool,
+            quote_mint: market.quote_mint,
             max_position_size,
+            market_index: market.market_index,
+            base_decimals: market.token_decimals,
+            price_precision: PRECISION as u64,
         });
     
         Ok(())
@@ -85,10 +264,18 @@ pub mod perpe {
     pub fn update_market(ctx: Context<UpdateMarket>, new_max_position_size: u64) -> Result<()> {
         require!(
             ctx.accounts.admin.key() == ctx.accounts.protocol.admin,
-            ErrorCode::Unauthorized
+            ErrorCode::NotAdmin
         );
     
-        ctx.accounts.market.max_position_size = new_max_position_size;
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MaxPositionSize,
+            market.max_position_size,
+            new_max_position_size,
+        );
+        market.max_position_size = new_max_position_size;
     
         emit!(MarketUpdated {
             token_mint: ctx.accounts.market.token_mint,
@@ -98,1622 +285,7339 @@ pub mod perpe {
         Ok(())
     }
 
-    pub fn create_wsol_vault(_ctx: Context<CreateWsolVault>) -> Result<()> {
+    /// Stops new positions on a market. Only `open_position` checks the flag:
+    /// closes, liquidations and ADL keep working so disabling a market never
+    /// traps collateral, and shorts on a dead pool still settle in SOL in
+    /// `close_position`.
+    pub fn set_market_enabled(ctx: Context<UpdateMarket>, enabled: bool) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MarketEnabled,
+            market.enabled as u64,
+            enabled as u64,
+        );
+        market.enabled = enabled;
+
+        emit!(MarketEnabledUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            enabled,
+        });
+
         Ok(())
     }
 
-    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
-        require!(
-            ctx.accounts.admin.key() == ctx.accounts.protocol.admin,
-            ErrorCode::Unauthorized
+    /// Circuit breaker for a known bad price feed: halts `liquidate` and
+    /// `liquidate_amount` on this market so traders aren't liquidated at bad
+    /// prices. Opens, closes and ADL are unaffected.
+    pub fn set_liquidations_paused(ctx: Context<UpdateMarket>, paused: bool) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::LiquidationsPaused,
+            market.liquidations_paused as u64,
+            paused as u64,
         );
-        
-        let market = &ctx.accounts.market;
-        let lending = &ctx.accounts.lending_pool;
-        
-        require!(market.total_positions == 0, ErrorCode::MarketHasPositions);
-        require!(lending.total_borrowed == 0, ErrorCode::MarketHasBorrows);
-        
-        emit!(MarketClosed {
-            token_mint: market.token_mint,
+        market.liquidations_paused = paused;
+
+        emit!(LiquidationsPausedUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            paused,
         });
-        
+
         Ok(())
     }
 
-    pub fn unwrap_wsol(ctx: Context<UnwrapWsol>) -> Result<()> {
-        let vault_bump = ctx.accounts.protocol.vault_bump;
-        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
-        let signer_seeds = &[seeds];
-    
-        token::close_account(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                token::CloseAccount {
-                    account: ctx.accounts.wsol_vault.to_account_info(),
-                    destination: ctx.accounts.protocol_vault.to_account_info(),
-                    authority: ctx.accounts.protocol_vault.to_account_info(),
-                },
-                signer_seeds,
-            ),
-        )?;
-    
+    /// Floor on the notional `position_size_sol` of new positions, independent
+    /// of collateral. Zero means no floor.
+    pub fn set_min_position_size(ctx: Context<UpdateMarket>, min_position_size: u64) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MinPositionSize,
+            market.min_position_size,
+            min_position_size,
+        );
+        market.min_position_size = min_position_size;
+
+        emit!(MinPositionSizeUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            min_position_size,
+        });
+
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-        require!(amount > 0, ErrorCode::ZeroAmount);
+    /// Sets how far below its estimated sale, valued at the mark price, a long
+    /// close may fill, whatever `slippage_limit` the trader passes (see
+    /// `min_close_fill`). Zero disables the floor.
+    pub fn set_max_close_slippage(ctx: Context<UpdateMarket>, max_close_slippage_bps: u64) -> Result<()> {
+        require!(max_close_slippage_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-        anchor_lang::system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.user.to_account_info(),
-                    to: ctx.accounts.protocol_vault.to_account_info(),
-                },
-            ),
-            amount,
-        )?;
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MaxCloseSlippage,
+            market.max_close_slippage_bps,
+            max_close_slippage_bps,
+        );
+        market.max_close_slippage_bps = max_close_slippage_bps;
 
-        let user_account = &mut ctx.accounts.user_account;
-        user_account.owner = ctx.accounts.user.key();
-        user_account.balance = user_account.balance.checked_add(amount).ok_or(ErrorCode::Overflow)?;
-        user_account.bump = ctx.bumps.user_account;
+        emit!(MaxCloseSlippageUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            max_close_slippage_bps,
+        });
 
-        emit!(Deposited {
-            user: ctx.accounts.user.key(),
-            amount,
-            new_balance: user_account.balance,
+        Ok(())
+    }
+
+    /// Sets the minimum time between a user's opens on this market. Zero
+    /// disables the cooldown.
+    pub fn set_open_cooldown(ctx: Context<UpdateMarket>, open_cooldown_seconds: i64) -> Result<()> {
+        require!(open_cooldown_seconds >= 0, ErrorCode::InvalidOpenCooldown);
+
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::OpenCooldown,
+            market.open_cooldown_seconds as u64,
+            open_cooldown_seconds as u64,
+        );
+        market.open_cooldown_seconds = open_cooldown_seconds;
+
+        emit!(OpenCooldownUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            open_cooldown_seconds,
         });
 
         Ok(())
     }
 
-    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
-        require!(ctx.accounts.user_account.balance >= amount, ErrorCode::InsufficientBalance);
+    /// Makes the close fee fall linearly from the rate locked at open down to
+    /// `close_fee_floor_bps` over `close_fee_decay_seconds` of holding time.
+    /// Liquidations keep their own fee. Zero seconds disables the decay.
+    pub fn set_close_fee_decay(
+        ctx: Context<UpdateMarket>,
+        close_fee_decay_seconds: i64,
+        close_fee_floor_bps: u64,
+    ) -> Result<()> {
+        require!(close_fee_decay_seconds >= 0, ErrorCode::InvalidCloseFeeDecay);
+        require!(close_fee_floor_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-        let new_balance = ctx.accounts.user_account.balance.checked_sub(amount).ok_or(ErrorCode::Overflow)?;
-        ctx.accounts.user_account.balance = new_balance;
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::CloseFeeDecay,
+            market.close_fee_decay_seconds as u64,
+            close_fee_decay_seconds as u64,
+        );
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::CloseFeeFloor,
+            market.close_fee_floor_bps,
+            close_fee_floor_bps,
+        );
+        market.close_fee_decay_seconds = close_fee_decay_seconds;
+        market.close_fee_floor_bps = close_fee_floor_bps;
 
-        let vault_bump = ctx.accounts.protocol.vault_bump;
-        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
-        let signer_seeds = &[seeds];
+        emit!(CloseFeeDecayUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            close_fee_decay_seconds,
+            close_fee_floor_bps,
+        });
 
-        anchor_lang::system_program::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.system_program.to_account_info(),
-                anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.protocol_vault.to_account_info(),
-                    to: ctx.accounts.user.to_account_info(),
-                },
-                signer_seeds,
-            ),
-            amount,
-        )?;
+        Ok(())
+    }
 
-        emit!(Withdrawn {
-            user: ctx.accounts.user.key(),
-            amount,
-            new_balance,
+    /// Caps how much position size, in lamports, one liquidation transaction
+    /// may close. Larger unhealthy positions must be worked down through
+    /// `liquidate_amount`, so several keepers each take a slice and a reward
+    /// sized to it. Zero lets `liquidate` close any position whole.
+    pub fn set_max_liquidation_chunk(ctx: Context<UpdateMarket>, max_liquidation_chunk: u64) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MaxLiquidationChunk,
+            market.max_liquidation_chunk,
+            max_liquidation_chunk,
+        );
+        market.max_liquidation_chunk = max_liquidation_chunk;
+
+        emit!(MaxLiquidationChunkUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            max_liquidation_chunk,
         });
 
         Ok(())
     }
 
-    pub fn deposit_to_lending(ctx: Context<DepositToLending>, amount: u64) -> Result<()> {
-        require!(amount > 0, ErrorCode::ZeroAmount);
+    /// Routes a share of this market's SOL open and close fees to its lenders.
+    /// The insurance split applies to what is left, so the two never exceed
+    /// the whole fee.
+    pub fn set_lender_fee_share(ctx: Context<UpdateMarket>, lender_fee_share_bps: u64) -> Result<()> {
+        require!(lender_fee_share_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-        let lending = &mut ctx.accounts.lending_pool;
-        let decimals = ctx.accounts.token_mint.decimals;
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::LenderFeeShare,
+            market.lender_fee_share_bps,
+            lender_fee_share_bps,
+        );
+        market.lender_fee_share_bps = lender_fee_share_bps;
 
-        let shares = if lending.total_deposits == 0 {
-            amount
-        } else {
-            (amount as u128)
-                .checked_mul(lending.total_shares as u128)
-                .ok_or(ErrorCode::Overflow)?
-                .checked_div(lending.total_deposits as u128)
-                .ok_or(ErrorCode::Overflow)? as u64
-        };
+        emit!(LenderFeeShareUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            lender_fee_share_bps,
+        });
 
-        token_interface::transfer_checked(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                TransferChecked {
-                    from: ctx.accounts.user_token_account.to_account_info(),
-                    mint: ctx.accounts.token_mint.to_account_info(),
-                    to: ctx.accounts.token_vault.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(),
-                },
-            ),
-            amount,
-            decimals,
-        )?;
+        Ok(())
+    }
 
-        lending.total_deposits = lending.total_deposits.checked_add(amount).ok_or(ErrorCode::Overflow)?;
-        lending.total_shares = lending.total_shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    /// Test builds only: overrides the price `get_pool_price` returns for this
+    /// market so liquidation and PnL paths can be driven deterministically.
+    /// Zero restores the pool price.
+    ///
+    /// The instruction itself cannot be compiled out: anchor-syn 0.30.1's
+    /// dispatch (`codegen/program/dispatch.rs`) emits a match arm calling
+    /// `perpe::set_mock_price` for every instruction without carrying its
+    /// attributes over, so a `#[cfg(feature = "test")]` on this fn fails the
+    /// default build with E0425. Without the feature the body is only an
+    /// error and the override in `get_pool_price` is compiled out, so
+    /// `mock_price` can never be non-zero in a production build.
+    pub fn set_mock_price(ctx: Context<UpdateMarket>, mock_price: u64) -> Result<()> {
+        #[cfg(feature = "test")]
+        {
+            let market = &mut ctx.accounts.market;
+            log_admin_action(
+                ctx.accounts.admin.key(),
+                market.key(),
+                AdminParam::MockPrice,
+                market.mock_price,
+                mock_price,
+            );
+            market.mock_price = mock_price;
+            Ok(())
+        }
 
-        let lender = &mut ctx.accounts.lender_position;
-        lender.owner = ctx.accounts.user.key();
-        lender.lending_pool = lending.key();
-        lender.shares = lender.shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
-        lender.bump = ctx.bumps.lender_position;
+        #[cfg(not(feature = "test"))]
+        {
+            let _ = (ctx, mock_price);
+            err!(ErrorCode::MockPriceDisabled)
+        }
+    }
 
-        emit!(LendingDeposited {
-            user: ctx.accounts.user.key(),
-            amount,
-            shares,
+    /// Positions with `position_size_sol` at or above `size` must close in two
+    /// phases: `request_close`, then `close_position` at least `delay` seconds
+    /// later with the pool price within `tolerance_bps` of the snapshot, or at
+    /// any price once `CLOSE_TOLERANCE_WINDOW` more has passed. `delay` is
+    /// capped at `MAX_CLOSE_DELAY`. A zero size disables two-phase closes.
+    pub fn set_two_phase_close(
+        ctx: Context<UpdateMarket>,
+        size: u64,
+        delay: i64,
+        tolerance_bps: u64,
+    ) -> Result<()> {
+        require!((0..=MAX_CLOSE_DELAY).contains(&delay), ErrorCode::InvalidCloseDelay);
+        require!(tolerance_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+
+        let admin = ctx.accounts.admin.key();
+        let market = &mut ctx.accounts.market;
+        let target = market.key();
+        log_admin_action(admin, target, AdminParam::TwoPhaseCloseSize, market.two_phase_close_size, size);
+        log_admin_action(admin, target, AdminParam::CloseDelay, market.close_delay as u64, delay as u64);
+        log_admin_action(
+            admin,
+            target,
+            AdminParam::ClosePriceTolerance,
+            market.close_price_tolerance_bps,
+            tolerance_bps,
+        );
+        market.two_phase_close_size = size;
+        market.close_delay = delay;
+        market.close_price_tolerance_bps = tolerance_bps;
+
+        emit!(TwoPhaseCloseUpdated {
+            token_mint: market.token_mint,
+            size,
+            delay,
+            tolerance_bps,
         });
 
         Ok(())
     }
 
-    pub fn withdraw_from_lending(ctx: Context<WithdrawFromLending>, shares: u64) -> Result<()> {
-        let lender = &mut ctx.accounts.lender_position;
-        require!(lender.shares >= shares, ErrorCode::InsufficientShares);
+    /// Guards opens of at least `guard_size` lamports against sudden pool
+    /// price jumps. A price more than `max_jump_bps` from the last sample must
+    /// first be observed by `poke_price` in an earlier slot and still hold.
+    /// `max_jump_bps` of zero disables the guard; a `guard_size` of zero
+    /// applies it to every open.
+    pub fn set_price_jump_guard(ctx: Context<UpdateMarket>, max_jump_bps: u64, guard_size: u64) -> Result<()> {
+        let admin = ctx.accounts.admin.key();
+        let market = &mut ctx.accounts.market;
+        let target = market.key();
+        log_admin_action(admin, target, AdminParam::MaxPriceJump, market.max_price_jump_bps, max_jump_bps);
+        log_admin_action(admin, target, AdminParam::PriceJumpGuardSize, market.price_jump_guard_size, guard_size);
+        market.max_price_jump_bps = max_jump_bps;
+        market.price_jump_guard_size = guard_size;
 
-        let lending = &mut ctx.accounts.lending_pool;
-        let decimals = ctx.accounts.token_mint.decimals;
+        emit!(PriceJumpGuardUpdated {
+            token_mint: market.token_mint,
+            max_jump_bps,
+            guard_size,
+        });
 
-        let tokens = (shares as u128)
-            .checked_mul(lending.total_deposits as u128)
-            .ok_or(ErrorCode::Overflow)?
-            .checked_div(lending.total_shares as u128)
-            .ok_or(ErrorCode::Overflow)? as u64;
+        Ok(())
+    }
 
-        let available = lending.total_deposits.saturating_sub(lending.total_borrowed);
-        require!(tokens <= available, ErrorCode::InsufficientLiquidity);
+    /// Sets a market's maximum leverage, bounded by the protocol's
+    /// `max_leverage_ceiling`.
+    pub fn set_market_leverage(ctx: Context<UpdateMarket>, max_leverage: u64) -> Result<()> {
+        let ceiling = ctx.accounts.protocol.max_leverage_ceiling;
+        require!(max_leverage >= 1, ErrorCode::InvalidLeverage);
+        require!(max_leverage <= ceiling, ErrorCode::LeverageAboveCeiling);
 
-        let vault_bump = ctx.accounts.protocol.vault_bump;
-        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
-        let signer_seeds = &[seeds];
+        let market = &mut ctx.accounts.market;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::MarketMaxLeverage,
+            market.max_leverage,
+            max_leverage,
+        );
+        market.max_leverage = max_leverage;
 
-        token_interface::transfer_checked(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                TransferChecked {
-                    from: ctx.accounts.token_vault.to_account_info(),
-                    mint: ctx.accounts.token_mint.to_account_info(),
-                    to: ctx.accounts.user_token_account.to_account_info(),
-                    authority: ctx.accounts.protocol_vault.to_account_info(),
-                },
-                signer_seeds,
-            ),
-            tokens,
-            decimals,
-        )?;
+        emit!(MarketLeverageUpdated {
+            token_mint: ctx.accounts.market.token_mint,
+            max_leverage,
+            max_leverage_ceiling: ceiling,
+        });
 
-        lending.total_deposits = lending.total_deposits.saturating_sub(tokens);
-        lending.total_shares = lending.total_shares.saturating_sub(shares);
-        lender.shares = lender.shares.saturating_sub(shares);
+        Ok(())
+    }
 
-        emit!(LendingWithdrawn {
-            user: ctx.accounts.user.key(),
-            tokens,
-            shares,
+    /// Points a market at a new pumpswap pool for the same token. With open
+    /// positions the new pool's price must be within
+    /// `POOL_MIGRATION_TOLERANCE_BPS` of the current pool's so existing entry
+    /// and liquidation prices stay meaningful.
+    pub fn migrate_market_pool(ctx: Context<MigrateMarketPool>) -> Result<()> {
+        require!(
+            ctx.accounts.new_pool.owner == &PUMPSWAP_PROGRAM_ID,
+            ErrorCode::InvalidPool
+        );
+        require!(
+            ctx.accounts.new_pool.key() != ctx.accounts.market.pumpswap_pool,
+            ErrorCode::InvalidPool
+        );
+
+        let new_pool_data = ctx.accounts.new_pool.try_borrow_data()?;
+        require!(new_pool_data.len() >= POOL_QUOTE_VAULT_OFFSET + 32, ErrorCode::InvalidPool);
+        let base_mint = Pubkey::try_from(&new_pool_data[POOL_BASE_MINT_OFFSET..POOL_BASE_MINT_OFFSET + 32])
+            .map_err(|_| ErrorCode::InvalidPool)?;
+        require!(base_mint == ctx.accounts.market.token_mint, ErrorCode::PoolMintMismatch);
+        let quote_mint = Pubkey::try_from(&new_pool_data[POOL_QUOTE_MINT_OFFSET..POOL_QUOTE_MINT_OFFSET + 32])
+            .map_err(|_| ErrorCode::InvalidPool)?;
+        require!(quote_mint == ctx.accounts.market.quote_mint, ErrorCode::PoolMintMismatch);
+        drop(new_pool_data);
+
+        let market = &ctx.accounts.market;
+        if market.total_positions > 0 {
+            let (old_base, old_quote) = read_pool_vaults(&ctx.accounts.current_pool)?;
+            let (new_base, new_quote) = read_pool_vaults(&ctx.accounts.new_pool)?;
+            require!(
+                old_base == ctx.accounts.current_base_vault.key()
+                    && old_quote == ctx.accounts.current_quote_vault.key()
+                    && new_base == ctx.accounts.new_base_vault.key()
+                    && new_quote == ctx.accounts.new_quote_vault.key(),
+                ErrorCode::InvalidPool
+            );
+
+            let old_price = get_pool_price(
+                &ctx.accounts.current_base_vault,
+                &ctx.accounts.current_quote_vault,
+                market,
+            )?;
+            let new_price = get_pool_price(
+                &ctx.accounts.new_base_vault,
+                &ctx.accounts.new_quote_vault,
+                market,
+            )?;
+            let max_diff = mul_div(old_price, POOL_MIGRATION_TOLERANCE_BPS, BPS_DENOMINATOR)?;
+            require!(old_price.abs_diff(new_price) <= max_diff, ErrorCode::PoolPriceMismatch);
+        }
+
+        let old_pool = market.pumpswap_pool;
+        let market = &mut ctx.accounts.market;
+        market.pumpswap_pool = ctx.accounts.new_pool.key();
+
+        log_admin_key_action(
+            ctx.accounts.admin.key(),
+            market.key(),
+            AdminParam::PumpswapPool,
+            old_pool,
+            market.pumpswap_pool,
+        );
+
+        emit!(MarketPoolMigrated {
+            token_mint: market.token_mint,
+            old_pool,
+            new_pool: market.pumpswap_pool,
+            open_positions: market.total_positions,
         });
 
         Ok(())
     }
 
-    pub fn open_position<'info>(
-        ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
-        is_long: bool,
-        collateral: u64,
-        leverage: u64,
-        slippage_limit: u64,
-    ) -> Result<()> {
-        require!(leverage >= 1 && leverage <= MAX_LEVERAGE, ErrorCode::InvalidLeverage);
-        require!(collateral > 0, ErrorCode::ZeroCollateral);
-    
-        let user_account = &mut ctx.accounts.user_account;
-        require!(user_account.balance >= collateral, ErrorCode::InsufficientBalance);
-    
-        let fee = collateral * PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
-        let collateral_after_fee = collateral - fee;
-        let position_size_sol = collateral_after_fee.checked_mul(leverage).ok_or(ErrorCode::Overflow)?;
-    
-        require!(
-            position_size_sol <= ctx.accounts.market.max_position_size,
-            ErrorCode::PositionTooLarge
+    pub fn set_fee_insurance_split(ctx: Context<UpdateProtocol>, split_bps: u64) -> Result<()> {
+        require!(split_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::FeeInsuranceSplit,
+            protocol.fee_insurance_split_bps,
+            split_bps,
         );
+        protocol.fee_insurance_split_bps = split_bps;
 
-        let pump = parse_pumpswap_accounts(ctx.remaining_accounts)?;
+        emit!(FeeSplitUpdated { fee_insurance_split_bps: split_bps });
 
-        user_account.balance = user_account.balance.checked_sub(collateral).ok_or(ErrorCode::Overflow)?;
+        Ok(())
+    }
 
-        let entry_price = get_pool_price(
-            pump.pool_base_vault,
-            pump.pool_quote_vault,
-        )?;
+    pub fn set_adl_threshold(ctx: Context<UpdateProtocol>, threshold_bps: u64) -> Result<()> {
+        require!(threshold_bps <= 2 * BPS_DENOMINATOR, ErrorCode::InvalidAdlThreshold);
 
-        let position = &mut ctx.accounts.position;
-        position.owner = ctx.accounts.user.key();
-        position.market = ctx.accounts.market.key();
-        position.is_long = is_long;
-        position.collateral = collateral_after_fee;
-        position.leverage = leverage;
-        position.entry_price = entry_price;
-        position.opened_at = Clock::get()?.unix_timestamp;
-        position.bump = ctx.bumps.position;
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::AdlThreshold,
+            protocol.adl_threshold_bps,
+            threshold_bps,
+        );
+        protocol.adl_threshold_bps = threshold_bps;
 
-        let vault_bump = ctx.accounts.protocol.vault_bump;
+        emit!(AdlThresholdUpdated { adl_threshold_bps: threshold_bps });
 
-        if is_long {
-            let (tokens, sol_spent) = execute_buy(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.global_volume_accumulator,
-                pump.user_volume_accumulator,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                position_size_sol,
-                slippage_limit,
-            )?;
+        Ok(())
+    }
 
-            let actual_entry_price = (sol_spent as u128)
-                .checked_mul(PRECISION)
-                .ok_or(ErrorCode::Overflow)?
-                .checked_div(tokens as u128)
-                .ok_or(ErrorCode::Overflow)? as u64;
-        
-            position.token_amount = tokens;
-            position.position_size_sol = sol_spent;
-            position.borrowed_tokens = 0;
-            position.entry_price = actual_entry_price;
-            position.liquidation_price = calc_liq_price_long(actual_entry_price, leverage)?;
+    /// Sets the lamports paid from accumulated fees to whoever runs
+    /// `refresh_position`. At most one reward is paid per market every
+    /// `crank_interval` seconds, which must be positive. A zero reward
+    /// disables it.
+    pub fn set_crank_reward(
+        ctx: Context<UpdateProtocol>,
+        crank_reward: u64,
+        crank_interval: i64,
+    ) -> Result<()> {
+        require!(crank_interval > 0, ErrorCode::InvalidCrankInterval);
 
-            let market = &mut ctx.accounts.market;
-            market.total_long_collateral = market.total_long_collateral
-                .checked_add(collateral_after_fee).ok_or(ErrorCode::Overflow)?;
+        let admin = ctx.accounts.admin.key();
+        let protocol = &mut ctx.accounts.protocol;
+        let target = protocol.key();
+        log_admin_action(admin, target, AdminParam::CrankReward, protocol.crank_reward, crank_reward);
+        log_admin_action(
+            admin,
+            target,
+            AdminParam::CrankInterval,
+            protocol.crank_interval as u64,
+            crank_interval as u64,
+        );
+        protocol.crank_reward = crank_reward;
+        protocol.crank_interval = crank_interval;
 
-        } else {
-            let tokens_to_borrow = (position_size_sol as u128)
-                .checked_mul(PRECISION)
-                .ok_or(ErrorCode::Overflow)?
-                .checked_div(entry_price as u128)
-                .ok_or(ErrorCode::Overflow)? as u64;
+        emit!(CrankRewardUpdated { crank_reward, crank_interval });
 
-            let lending = &mut ctx.accounts.lending_pool;
-            let available = lending.total_deposits.saturating_sub(lending.total_borrowed);
-            require!(tokens_to_borrow <= available, ErrorCode::InsufficientLiquidity);
+        Ok(())
+    }
 
-            lending.total_borrowed = lending.total_borrowed
-                .checked_add(tokens_to_borrow).ok_or(ErrorCode::Overflow)?;
+    /// Opens a maintenance window for account migrations. While it is open
+    /// deposits, withdrawals, new positions, lending deposits/withdrawals and
+    /// lender fee claims fail with `ProtocolMigrating`; closes and
+    /// liquidations keep working.
+    pub fn begin_migration(ctx: Context<UpdateProtocol>) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::Migrating,
+            protocol.migrating as u64,
+            1,
+        );
+        protocol.migrating = true;
 
-            let sol_received = execute_sell(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                tokens_to_borrow,
-                slippage_limit,
-            )?;
+        emit!(MigrationStarted { admin: ctx.accounts.admin.key() });
 
-            let actual_entry_price = (sol_received as u128)
-                .checked_mul(PRECISION)
-                .ok_or(ErrorCode::Overflow)?
-                .checked_div(tokens_to_borrow as u128)
-                .ok_or(ErrorCode::Overflow)? as u64;
-    
-            position.token_amount = 0;
-            position.position_size_sol = sol_received;
-            position.borrowed_tokens = tokens_to_borrow;
-            position.entry_price = actual_entry_price;
-            position.liquidation_price = calc_liq_price_short(actual_entry_price, leverage)?;
+        Ok(())
+    }
 
-            let market = &mut ctx.accounts.market;
-            market.total_short_collateral = market.total_short_collateral
-                .checked_add(collateral_after_fee).ok_or(ErrorCode::Overflow)?;
-        }
+    pub fn end_migration(ctx: Context<UpdateProtocol>) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::Migrating,
+            protocol.migrating as u64,
+            0,
+        );
+        protocol.migrating = false;
 
-        let market = &mut ctx.accounts.market;
-        market.total_positions += 1;
+        emit!(MigrationEnded { admin: ctx.accounts.admin.key() });
 
-        emit!(PositionOpened {
-            owner: position.owner,
-            market: position.market,
-            is_long,
-            collateral: collateral_after_fee,
-            leverage,
-            entry_price: position.entry_price,
-            liquidation_price: position.liquidation_price,
-        });
+        Ok(())
+    }
+
+    /// Lowers the protocol-wide leverage ceiling that every market's
+    /// `max_leverage` must respect. It starts at `MAX_LEVERAGE` and can only
+    /// ever be tightened, so a careless or compromised admin key cannot raise
+    /// it. Markets already above the new ceiling are capped at open.
+    pub fn lower_leverage_ceiling(ctx: Context<UpdateProtocol>, ceiling: u64) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        require!(
+            ceiling >= 1 && ceiling <= protocol.max_leverage_ceiling,
+            ErrorCode::InvalidLeverage
+        );
+
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::LeverageCeiling,
+            protocol.max_leverage_ceiling,
+            ceiling,
+        );
+        protocol.max_leverage_ceiling = ceiling;
+
+        emit!(LeverageCeilingUpdated { max_leverage_ceiling: ceiling });
 
         Ok(())
     }
 
-    pub fn close_position<'info>(
-        ctx: Context<'_, '_, '_, 'info, ClosePosition<'info>>,
-        slippage_limit: u64,
-    ) -> Result<()> {
-        let position = &ctx.accounts.position;
-        let pump = parse_pumpswap_accounts(ctx.remaining_accounts)?;
+    /// Points the protocol at a Pyth SOL/USD `PriceUpdateV2` account. While
+    /// set, market `max_position_size` and `min_position_size` are read as
+    /// micro-USD and converted to lamports at the oracle price on open, so
+    /// update them alongside. Opens reject the account unless the Pyth
+    /// receiver owns it and it carries the SOL/USD feed id.
+    /// `Pubkey::default()` returns to SOL limits.
+    pub fn set_sol_usd_oracle(ctx: Context<UpdateProtocol>, oracle: Pubkey) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_key_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::SolUsdOracle,
+            protocol.sol_usd_oracle,
+            oracle,
+        );
+        protocol.sol_usd_oracle = oracle;
 
-        let current_price = get_pool_price(
-            pump.pool_base_vault,
-            pump.pool_quote_vault,
-        )?;
+        emit!(SolUsdOracleUpdated { oracle });
 
-        let vault_bump = ctx.accounts.protocol.vault_bump;
-        let pnl: i64;
-        let payout: u64;
+        Ok(())
+    }
 
-        if position.is_long {
-            let sol_received = execute_sell(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                position.token_amount,
-                slippage_limit,
-            )?;
+    /// Caps total open position size across every market at `multiple` times
+    /// the insurance fund, so leverage grows only with the fund that backstops
+    /// it. Zero disables the cap.
+    pub fn set_insurance_coverage_multiple(ctx: Context<UpdateProtocol>, multiple: u64) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::InsuranceCoverageMultiple,
+            protocol.insurance_coverage_multiple,
+            multiple,
+        );
+        protocol.insurance_coverage_multiple = multiple;
 
-            pnl = (sol_received as i64) - (position.position_size_sol as i64);
-            
-            let close_fee = position.collateral * PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
-            let payout_i64 = position.collateral as i64 + pnl - close_fee as i64;
-            payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+        emit!(InsuranceCoverageMultipleUpdated { insurance_coverage_multiple: multiple });
 
-            let market = &mut ctx.accounts.market;
-            market.total_long_collateral = market.total_long_collateral
-                .saturating_sub(position.collateral);
+        Ok(())
+    }
 
-        } else {
-            let tokens_to_buy = position.borrowed_tokens;
+    /// Sets `total_open_size` to the summed `position_size_sol` of every open
+    /// position. Positions opened before the counter existed were never
+    /// added, so their closes only saturate it toward zero; the admin sums
+    /// open positions off-chain and backfills the counter. Only allowed in a
+    /// migration window, when no new position can open between the snapshot
+    /// and the write.
+    pub fn backfill_total_open_size(ctx: Context<UpdateProtocol>, total_open_size: u64) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        require!(protocol.migrating, ErrorCode::ProtocolNotMigrating);
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::TotalOpenSize,
+            protocol.total_open_size,
+            total_open_size,
+        );
+        let previous = protocol.total_open_size;
+        protocol.total_open_size = total_open_size;
 
-            let sol_spent = execute_buy_for_close(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.global_volume_accumulator,
-                pump.user_volume_accumulator,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                tokens_to_buy,
-                slippage_limit,
-            )?;
+        emit!(TotalOpenSizeBackfilled { previous, total_open_size });
 
-            let lending = &mut ctx.accounts.lending_pool;
-            lending.total_borrowed = lending.total_borrowed.saturating_sub(position.borrowed_tokens);
+        Ok(())
+    }
 
-            pnl = (position.position_size_sol as i64) - (sol_spent as i64);
-            
-            let close_fee = position.collateral * PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
-            let payout_i64 = position.collateral as i64 + pnl - close_fee as i64;
-            payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+    /// Refunds `rebate_bps` of a position's SOL open fee when it closes with
+    /// positive PnL, paid from `accumulated_fees` and never more than it
+    /// holds. Zero disables the rebate.
+    pub fn set_open_fee_rebate(ctx: Context<UpdateProtocol>, rebate_bps: u64) -> Result<()> {
+        require!(rebate_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-            let market = &mut ctx.accounts.market;
-            market.total_short_collateral = market.total_short_collateral
-                .saturating_sub(position.collateral);
-        }
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::OpenFeeRebate,
+            protocol.open_fee_rebate_bps,
+            rebate_bps,
+        );
+        protocol.open_fee_rebate_bps = rebate_bps;
 
-        let market = &mut ctx.accounts.market;
-        market.total_positions = market.total_positions.saturating_sub(1);
+        emit!(OpenFeeRebateUpdated { open_fee_rebate_bps: rebate_bps });
 
-        let user_account = &mut ctx.accounts.user_account;
-        user_account.balance = user_account.balance.checked_add(payout).ok_or(ErrorCode::Overflow)?;
+        Ok(())
+    }
 
-        emit!(PositionClosed {
-            owner: position.owner,
-            market: position.market,
-            is_long: position.is_long,
-            entry_price: position.entry_price,
-            exit_price: current_price,
-            pnl,
-            payout,
-        });
+    /// Sets the WSOL reserve, in lamports, a pool must hold for
+    /// `create_market` to list it. Existing markets are unaffected. Zero
+    /// disables the check.
+    pub fn set_min_pool_liquidity(ctx: Context<UpdateProtocol>, min_pool_liquidity: u64) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::MinPoolLiquidity,
+            protocol.min_pool_liquidity,
+            min_pool_liquidity,
+        );
+        protocol.min_pool_liquidity = min_pool_liquidity;
+
+        emit!(MinPoolLiquidityUpdated { min_pool_liquidity });
 
         Ok(())
     }
 
-    pub fn liquidate<'info>(
-        ctx: Context<'_, '_, '_, 'info, Liquidate<'info>>,
-        slippage_limit: u64,
+    /// Caps open positions across every market, on top of each market's own
+    /// size limits. Zero means unlimited.
+    pub fn set_max_total_positions(ctx: Context<UpdateProtocol>, max_total_positions: u64) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::MaxTotalPositions,
+            protocol.max_total_positions,
+            max_total_positions,
+        );
+        protocol.max_total_positions = max_total_positions;
+
+        emit!(MaxTotalPositionsUpdated { max_total_positions });
+
+        Ok(())
+    }
+
+    /// Sets how close to its liquidation price a position must be for
+    /// `quote_liquidation` to flag it as `near_liquidation`. Only the view
+    /// uses it; liquidation itself still requires the exact threshold.
+    pub fn set_liquidation_buffer(ctx: Context<UpdateProtocol>, buffer_bps: u64) -> Result<()> {
+        require!(buffer_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::LiquidationBuffer,
+            protocol.liquidation_buffer_bps,
+            buffer_bps,
+        );
+        protocol.liquidation_buffer_bps = buffer_bps;
+
+        emit!(LiquidationBufferUpdated { liquidation_buffer_bps: buffer_bps });
+
+        Ok(())
+    }
+
+    /// Sets the floor on a liquidator's reward. A position whose remaining
+    /// value is at or below `dust_threshold` lamports goes to the liquidator
+    /// whole; above it the reward is at least `min_reward`. Both in lamports,
+    /// zero disables.
+    pub fn set_liquidation_reward_floor(
+        ctx: Context<UpdateProtocol>,
+        min_reward: u64,
+        dust_threshold: u64,
     ) -> Result<()> {
-        let position = &ctx.accounts.position;
-        let pump = parse_pumpswap_accounts(ctx.remaining_accounts)?;
+        let admin = ctx.accounts.admin.key();
+        let protocol = &mut ctx.accounts.protocol;
+        let target = protocol.key();
+        log_admin_action(
+            admin,
+            target,
+            AdminParam::MinLiquidatorReward,
+            protocol.min_liquidator_reward,
+            min_reward,
+        );
+        log_admin_action(
+            admin,
+            target,
+            AdminParam::LiquidationDustThreshold,
+            protocol.liquidation_dust_threshold,
+            dust_threshold,
+        );
+        protocol.min_liquidator_reward = min_reward;
+        protocol.liquidation_dust_threshold = dust_threshold;
 
-        let current_price = get_pool_price(
-            pump.pool_base_vault,
-            pump.pool_quote_vault,
-        )?;
+        emit!(LiquidationRewardFloorUpdated { min_reward, dust_threshold });
 
-        if position.is_long {
-            require!(current_price <= position.liquidation_price, ErrorCode::NotLiquidatable);
-        } else {
-            require!(current_price >= position.liquidation_price, ErrorCode::NotLiquidatable);
-        }
+        Ok(())
+    }
 
-        let vault_bump = ctx.accounts.protocol.vault_bump;
-        let remaining: u64;
+    /// Enables paying the protocol fee in `fee_discount_mint` instead of SOL.
+    /// The fee token amount is `fee * discount_bps / 10_000` lamports
+    /// converted at `tokens_per_lamport` (scaled by `PRECISION`), which must be
+    /// non-zero. A fee that converts to zero tokens must be paid in SOL.
+    /// Passing the default pubkey as the mint disables the option.
+    pub fn set_fee_discount(
+        ctx: Context<UpdateProtocol>,
+        fee_discount_mint: Pubkey,
+        fee_collector: Pubkey,
+        discount_bps: u64,
+        tokens_per_lamport: u64,
+    ) -> Result<()> {
+        require!(discount_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+        require!(
+            fee_discount_mint == Pubkey::default() || tokens_per_lamport > 0,
+            ErrorCode::InvalidFeeTokenRate
+        );
 
-        if position.is_long {
-            let sol_received = execute_sell(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                position.token_amount,
-                slippage_limit,
-            )?;
+        let admin = ctx.accounts.admin.key();
+        let protocol = &mut ctx.accounts.protocol;
+        let target = protocol.key();
+        log_admin_key_action(
+            admin,
+            target,
+            AdminParam::FeeDiscountMint,
+            protocol.fee_discount_mint,
+            fee_discount_mint,
+        );
+        log_admin_key_action(admin, target, AdminParam::FeeCollector, protocol.fee_collector, fee_collector);
+        log_admin_action(admin, target, AdminParam::FeeDiscountBps, protocol.fee_discount_bps, discount_bps);
+        log_admin_action(
+            admin,
+            target,
+            AdminParam::FeeTokensPerLamport,
+            protocol.fee_tokens_per_lamport,
+            tokens_per_lamport,
+        );
+        protocol.fee_discount_mint = fee_discount_mint;
+        protocol.fee_collector = fee_collector;
+        protocol.fee_discount_bps = discount_bps;
+        protocol.fee_tokens_per_lamport = tokens_per_lamport;
+
+        emit!(FeeDiscountUpdated {
+            fee_discount_mint,
+            fee_collector,
+            discount_bps,
+            tokens_per_lamport,
+        });
 
-            remaining = sol_received;
+        Ok(())
+    }
 
-            let market = &mut ctx.accounts.market;
-            market.total_long_collateral = market.total_long_collateral
-                .saturating_sub(position.collateral);
+    pub fn set_borrow_rate(ctx: Context<UpdateLendingPool>, borrow_rate_bps: u64) -> Result<()> {
+        require!(borrow_rate_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-        } else {
-            let tokens_to_buy = position.borrowed_tokens;
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::BorrowRate,
+            lending.borrow_rate_bps,
+            borrow_rate_bps,
+        );
+        lending.borrow_rate_bps = borrow_rate_bps;
 
-            let sol_spent = execute_buy_for_close(
-                &ctx.accounts.protocol_vault,
-                &ctx.accounts.token_vault,
-                &ctx.accounts.wsol_vault,
-                pump.pumpswap_pool,
-                pump.pool_base_vault,
-                pump.pool_quote_vault,
-                pump.pumpswap_global,
-                &ctx.accounts.token_mint.to_account_info(),
-                &ctx.accounts.wsol_mint,
-                pump.protocol_fee_recipient,
-                pump.protocol_fee_recipient_ata,
-                pump.coin_creator_vault_ata,
-                pump.coin_creator_vault_authority,
-                pump.global_volume_accumulator,
-                pump.user_volume_accumulator,
-                pump.fee_config,
-                pump.fee_program,
-                &ctx.accounts.quote_token_program,
-                &ctx.accounts.base_token_program.to_account_info(),
-                &ctx.accounts.system_program,
-                &ctx.accounts.associated_token_program,
-                pump.event_authority,
-                pump.pumpswap_program,
-                vault_bump,
-                tokens_to_buy,
-                slippage_limit,
-            )?;
+        emit!(BorrowRateUpdated {
+            market: ctx.accounts.market.key(),
+            borrow_rate_bps,
+        });
 
-            let lending = &mut ctx.accounts.lending_pool;
-            lending.total_borrowed = lending.total_borrowed.saturating_sub(position.borrowed_tokens);
+        Ok(())
+    }
 
-            remaining = position.position_size_sol.saturating_sub(sol_spent);
+    pub fn set_fee_exempt(ctx: Context<SetFeeExempt>, user: Pubkey, exempt: bool) -> Result<()> {
+        let user_account = &mut ctx.accounts.user_account;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            user,
+            AdminParam::FeeExempt,
+            user_account.fee_exempt as u64,
+            exempt as u64,
+        );
+        user_account.fee_exempt = exempt;
 
-            let market = &mut ctx.accounts.market;
-            market.total_short_collateral = market.total_short_collateral
-                .saturating_sub(position.collateral);
-        }
+        emit!(FeeExemptionUpdated { user, exempt });
 
-        let market = &mut ctx.accounts.market;
-        market.total_positions = market.total_positions.saturating_sub(1);
+        Ok(())
+    }
 
-        let reward = remaining * LIQUIDATOR_REWARD_BPS / BPS_DENOMINATOR;
-        let to_owner = remaining.saturating_sub(reward);
+    /// Gates `open_position` on `UserAccount::whitelisted` for a private
+    /// launch. Deposits, lending and closes stay open to everyone.
+    pub fn set_open_whitelist_enabled(ctx: Context<UpdateProtocol>, enabled: bool) -> Result<()> {
+        let protocol = &mut ctx.accounts.protocol;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            protocol.key(),
+            AdminParam::OpenWhitelistEnabled,
+            protocol.open_whitelist_enabled as u64,
+            enabled as u64,
+        );
+        protocol.open_whitelist_enabled = enabled;
 
-        if reward > 0 {
-            let protocol_vault_info = ctx.accounts.protocol_vault.to_account_info();
-            let liquidator_info = ctx.accounts.liquidator.to_account_info();
-            **protocol_vault_info.try_borrow_mut_lamports()? -= reward;
-            **liquidator_info.try_borrow_mut_lamports()? += reward;
-        }
+        emit!(OpenWhitelistUpdated { enabled });
 
-        if to_owner > 0 {
-            let owner_account = &mut ctx.accounts.owner_account;
-            owner_account.balance = owner_account.balance.checked_add(to_owner).ok_or(ErrorCode::Overflow)?;
-        }
+        Ok(())
+    }
 
-        emit!(PositionLiquidated {
-            owner: position.owner,
-            market: position.market,
-            is_long: position.is_long,
-            liquidator: ctx.accounts.liquidator.key(),
-            reward,
-            exit_price: current_price,
+    pub fn set_whitelisted(ctx: Context<SetWhitelisted>, user: Pubkey, allowed: bool) -> Result<()> {
+        let user_account = &mut ctx.accounts.user_account;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            user,
+            AdminParam::Whitelisted,
+            user_account.whitelisted as u64,
+            allowed as u64,
+        );
+        user_account.whitelisted = allowed;
+
+        emit!(WhitelistUpdated { user, allowed });
+
+        Ok(())
+    }
+
+    /// Caps the tokens a single short may borrow from this pool. Zero means no
+    /// cap beyond available liquidity.
+    pub fn set_max_borrow_per_position(
+        ctx: Context<UpdateLendingPool>,
+        max_borrow_per_position: u64,
+    ) -> Result<()> {
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::MaxBorrowPerPosition,
+            lending.max_borrow_per_position,
+            max_borrow_per_position,
+        );
+        lending.max_borrow_per_position = max_borrow_per_position;
+
+        emit!(MaxBorrowUpdated {
+            market: ctx.accounts.market.key(),
+            max_borrow_per_position,
         });
 
         Ok(())
     }
-}
 
-// ========== Helper Functions ==========
+    /// Caps the interest a single short can accrue, in bps of its borrowed
+    /// tokens. Zero means uncapped.
+    pub fn set_max_interest(ctx: Context<UpdateLendingPool>, max_interest_bps: u64) -> Result<()> {
+        require!(max_interest_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-struct PumpswapAccounts<'a, 'info> {
-    pumpswap_pool: &'a AccountInfo<'info>,
-    pool_base_vault: &'a AccountInfo<'info>,
-    pool_quote_vault: &'a AccountInfo<'info>,
-    pumpswap_global: &'a AccountInfo<'info>,
-    protocol_fee_recipient: &'a AccountInfo<'info>,
-    protocol_fee_recipient_ata: &'a AccountInfo<'info>,
-    coin_creator_vault_ata: &'a AccountInfo<'info>,
-    coin_creator_vault_authority: &'a AccountInfo<'info>,
-    global_volume_accumulator: &'a AccountInfo<'info>,
-    user_volume_accumulator: &'a AccountInfo<'info>,
-    fee_config: &'a AccountInfo<'info>,
-    fee_program: &'a AccountInfo<'info>,
-    event_authority: &'a AccountInfo<'info>,
-    pumpswap_program: &'a AccountInfo<'info>,
-}
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::MaxInterest,
+            lending.max_interest_bps,
+            max_interest_bps,
+        );
+        lending.max_interest_bps = max_interest_bps;
 
-fn parse_pumpswap_accounts<'a, 'info>(
-    remaining: &'a [AccountInfo<'info>],
-) -> Result<PumpswapAccounts<'a, 'info>> {
-    require!(remaining.len() >= 14, ErrorCode::InvalidPumpswapAccounts);
-    Ok(PumpswapAccounts {
-        pumpswap_pool: &remaining[0],
-        pool_base_vault: &remaining[1],
-        pool_quote_vault: &remaining[2],
-        pumpswap_global: &remaining[3],
-        protocol_fee_recipient: &remaining[4],
-        protocol_fee_recipient_ata: &remaining[5],
-        coin_creator_vault_ata: &remaining[6],
-        coin_creator_vault_authority: &remaining[7],
-        global_volume_accumulator: &remaining[8],
-        user_volume_accumulator: &remaining[9],
-        fee_config: &remaining[10],
-        fee_program: &remaining[11],
-        event_authority: &remaining[12],
-        pumpswap_program: &remaining[13],
-    })
-}
+        emit!(MaxInterestUpdated {
+            market: ctx.accounts.market.key(),
+            max_interest_bps,
+        });
 
-fn get_pool_price(base_vault: &AccountInfo, quote_vault: &AccountInfo) -> Result<u64> {
-    let base_data = base_vault.try_borrow_data()?;
-    let quote_data = quote_vault.try_borrow_data()?;
+        Ok(())
+    }
 
-    let base_amount = u64::from_le_bytes(
-        base_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap()
-    );
-    let quote_amount = u64::from_le_bytes(
-        quote_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap()
-    );
+    /// Caps the pool's total deposits. Zero means unlimited.
+    pub fn set_deposit_cap(ctx: Context<UpdateLendingPool>, deposit_cap: u64) -> Result<()> {
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::DepositCap,
+            lending.deposit_cap,
+            deposit_cap,
+        );
+        lending.deposit_cap = deposit_cap;
 
-    require!(base_amount > 0, ErrorCode::EmptyPool);
+        emit!(DepositCapUpdated {
+            market: ctx.accounts.market.key(),
+            deposit_cap,
+        });
 
-    let price = (quote_amount as u128)
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::Overflow)?
-        .checked_div(base_amount as u128)
-        .ok_or(ErrorCode::Overflow)? as u64;
+        Ok(())
+    }
 
-    Ok(price)
-}
+    pub fn set_reserve_factor(ctx: Context<UpdateLendingPool>, reserve_factor_bps: u64) -> Result<()> {
+        require!(reserve_factor_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
 
-fn calc_liq_price_long(entry_price: u64, leverage: u64) -> Result<u64> {
-    let drop_bps = LIQUIDATION_THRESHOLD_BPS / leverage;
-    let liq = (entry_price as u128)
-        .checked_mul((BPS_DENOMINATOR - drop_bps) as u128)
-        .ok_or(ErrorCode::Overflow)?
-        .checked_div(BPS_DENOMINATOR as u128)
-        .ok_or(ErrorCode::Overflow)? as u64;
-    Ok(liq)
-}
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::ReserveFactor,
+            lending.reserve_factor_bps,
+            reserve_factor_bps,
+        );
+        lending.reserve_factor_bps = reserve_factor_bps;
 
-fn calc_liq_price_short(entry_price: u64, leverage: u64) -> Result<u64> {
-    let rise_bps = LIQUIDATION_THRESHOLD_BPS / leverage;
-    let liq = (entry_price as u128)
-        .checked_mul((BPS_DENOMINATOR + rise_bps) as u128)
-        .ok_or(ErrorCode::Overflow)?
-        .checked_div(BPS_DENOMINATOR as u128)
-        .ok_or(ErrorCode::Overflow)? as u64;
-    Ok(liq)
-}
+        emit!(ReserveFactorUpdated {
+            market: ctx.accounts.market.key(),
+            reserve_factor_bps,
+        });
 
-#[allow(clippy::too_many_arguments)]
-fn execute_buy<'info>(
-    protocol_vault: &AccountInfo<'info>,
-    token_vault: &InterfaceAccount<'info, TokenAccount>,
-    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
-    pumpswap_pool: &AccountInfo<'info>,
-    pool_base_vault: &AccountInfo<'info>,
-    pool_quote_vault: &AccountInfo<'info>,
-    pumpswap_global: &AccountInfo<'info>,
-    token_mint: &AccountInfo<'info>,
-    wsol_mint: &AccountInfo<'info>,
-    protocol_fee_recipient: &AccountInfo<'info>,
-    protocol_fee_recipient_ata: &AccountInfo<'info>,
-    coin_creator_vault_ata: &AccountInfo<'info>,
-    coin_creator_vault_authority: &AccountInfo<'info>,
-    global_volume_accumulator: &AccountInfo<'info>,
-    user_volume_accumulator: &AccountInfo<'info>,
-    fee_config: &AccountInfo<'info>,
-    fee_program: &AccountInfo<'info>,
-    quote_token_program: &Program<'info, Token>,
-    base_token_program: &AccountInfo<'info>,
-    system_program: &Program<'info, System>,
-    associated_token_program: &Program<'info, AssociatedToken>,
-    event_authority: &AccountInfo<'info>,
-    pumpswap_program: &AccountInfo<'info>,
-    vault_bump: u8,
-    sol_amount: u64,
-    min_tokens: u64,
-) -> Result<(u64, u64)> {
-    let vault_bump_slice = &[vault_bump];
-    let vault_seeds: &[&[u8]] = &[b"protocol_vault", vault_bump_slice];
-    let vault_signer_seeds = &[vault_seeds];
+        Ok(())
+    }
 
-    anchor_lang::system_program::transfer(
-        CpiContext::new_with_signer(
-            system_program.to_account_info(),
-            anchor_lang::system_program::Transfer {
-                from: protocol_vault.to_account_info(),
-                to: wsol_vault.to_account_info(),
-            },
-            vault_signer_seeds,
-        ),
-        sol_amount,
-    )?;
+    /// Charges lenders `withdrawal_fee_bps` of the tokens they withdraw,
+    /// decaying linearly to zero over `withdrawal_fee_decay_seconds` since
+    /// their last deposit. The fee stays in the pool for the remaining
+    /// lenders. A zero decay period disables the fee.
+    pub fn set_withdrawal_fee(
+        ctx: Context<UpdateLendingPool>,
+        withdrawal_fee_bps: u64,
+        withdrawal_fee_decay_seconds: i64,
+    ) -> Result<()> {
+        require!(withdrawal_fee_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+        require!(withdrawal_fee_decay_seconds >= 0, ErrorCode::InvalidWithdrawalFeeDecay);
 
-    token::sync_native(
-        CpiContext::new(
-            quote_token_program.to_account_info(),
-            SyncNative {
-                account: wsol_vault.to_account_info(),
-            },
-        ),
-    )?;
+        let lending = &mut ctx.accounts.lending_pool;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::WithdrawalFee,
+            lending.withdrawal_fee_bps,
+            withdrawal_fee_bps,
+        );
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            lending.market,
+            AdminParam::WithdrawalFeeDecay,
+            lending.withdrawal_fee_decay_seconds as u64,
+            withdrawal_fee_decay_seconds as u64,
+        );
+        lending.withdrawal_fee_bps = withdrawal_fee_bps;
+        lending.withdrawal_fee_decay_seconds = withdrawal_fee_decay_seconds;
 
-    let tokens_before = token_vault.amount;
-    let wsol_before = wsol_vault.amount;
+        emit!(WithdrawalFeeUpdated {
+            market: ctx.accounts.market.key(),
+            withdrawal_fee_bps,
+            withdrawal_fee_decay_seconds,
+        });
 
-    let mut ix_data = Vec::with_capacity(25);
-    ix_data.extend_from_slice(&BUY_DISCRIMINATOR);
-    ix_data.extend_from_slice(&min_tokens.to_le_bytes());
-    ix_data.extend_from_slice(&sol_amount.to_le_bytes());
-    ix_data.push(0);
+        Ok(())
+    }
 
-    let accounts = vec![
-        AccountMeta::new(pumpswap_pool.key(), false),
-        AccountMeta::new(protocol_vault.key(), true),
-        AccountMeta::new_readonly(pumpswap_global.key(), false),
-        AccountMeta::new_readonly(token_mint.key(), false),
-        AccountMeta::new_readonly(wsol_mint.key(), false),
-        AccountMeta::new(token_vault.key(), false),
-        AccountMeta::new(wsol_vault.key(), false),
-        AccountMeta::new(pool_base_vault.key(), false),
-        AccountMeta::new(pool_quote_vault.key(), false),
-        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
-        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
-        AccountMeta::new_readonly(base_token_program.key(), false),
-        AccountMeta::new_readonly(quote_token_program.key(), false),
-        AccountMeta::new_readonly(system_program.key(), false),
-        AccountMeta::new_readonly(associated_token_program.key(), false),
-        AccountMeta::new_readonly(event_authority.key(), false),
-        AccountMeta::new_readonly(pumpswap_program.key(), false),
-        AccountMeta::new(coin_creator_vault_ata.key(), false),
-        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
-        AccountMeta::new_readonly(global_volume_accumulator.key(), false),
-        AccountMeta::new(user_volume_accumulator.key(), false),
-        AccountMeta::new_readonly(fee_config.key(), false),
-        AccountMeta::new_readonly(fee_program.key(), false),
-    ];
+    /// Replaces every tunable lending pool parameter in one step, so the pool
+    /// never sits between two half-applied settings. Each field has its
+    /// single setter's meaning; on top of their checks, a per-position borrow
+    /// cap can't exceed the deposit cap and a withdrawal fee needs a decay
+    /// window to apply over.
+    pub fn set_lending_config(ctx: Context<UpdateLendingPool>, config: LendingConfig) -> Result<()> {
+        require!(
+            config.borrow_rate_bps <= BPS_DENOMINATOR
+                && config.max_interest_bps <= BPS_DENOMINATOR
+                && config.reserve_factor_bps <= BPS_DENOMINATOR
+                && config.withdrawal_fee_bps <= BPS_DENOMINATOR,
+            ErrorCode::InvalidBps
+        );
+        require!(config.withdrawal_fee_decay_seconds >= 0, ErrorCode::InvalidWithdrawalFeeDecay);
+        require!(
+            config.deposit_cap == 0 || config.max_borrow_per_position <= config.deposit_cap,
+            ErrorCode::InvalidLendingConfig
+        );
+        require!(
+            config.withdrawal_fee_bps == 0 || config.withdrawal_fee_decay_seconds > 0,
+            ErrorCode::InvalidLendingConfig
+        );
 
-    invoke_signed(
-        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
-        &[
-            pumpswap_pool.to_account_info(),
-            protocol_vault.to_account_info(),
-            pumpswap_global.to_account_info(),
-            token_mint.to_account_info(),
-            wsol_mint.to_account_info(),
-            token_vault.to_account_info(),
-            wsol_vault.to_account_info(),
-            pool_base_vault.to_account_info(),
-            pool_quote_vault.to_account_info(),
-            protocol_fee_recipient.to_account_info(),
-            protocol_fee_recipient_ata.to_account_info(),
-            base_token_program.to_account_info(),
-            quote_token_program.to_account_info(),
-            system_program.to_account_info(),
-            associated_token_program.to_account_info(),
-            event_authority.to_account_info(),
-            pumpswap_program.to_account_info(),
-            coin_creator_vault_ata.to_account_info(),
-            coin_creator_vault_authority.to_account_info(),
-            global_volume_accumulator.to_account_info(),
-            user_volume_accumulator.to_account_info(),
-            fee_config.to_account_info(),
-            fee_program.to_account_info(),
-        ],
-        vault_signer_seeds,
-    )?;
+        let admin = ctx.accounts.admin.key();
+        let lending = &mut ctx.accounts.lending_pool;
+        for (param, old_value, new_value) in [
+            (AdminParam::BorrowRate, lending.borrow_rate_bps, config.borrow_rate_bps),
+            (AdminParam::MaxBorrowPerPosition, lending.max_borrow_per_position, config.max_borrow_per_position),
+            (AdminParam::MaxInterest, lending.max_interest_bps, config.max_interest_bps),
+            (AdminParam::DepositCap, lending.deposit_cap, config.deposit_cap),
+            (AdminParam::ReserveFactor, lending.reserve_factor_bps, config.reserve_factor_bps),
+            (AdminParam::WithdrawalFee, lending.withdrawal_fee_bps, config.withdrawal_fee_bps),
+            (
+                AdminParam::WithdrawalFeeDecay,
+                lending.withdrawal_fee_decay_seconds as u64,
+                config.withdrawal_fee_decay_seconds as u64,
+            ),
+        ] {
+            log_admin_action(admin, lending.market, param, old_value, new_value);
+        }
 
-    let token_vault_info = token_vault.to_account_info();
-    let token_vault_data = token_vault_info.try_borrow_data()?;
-    let tokens_after = u64::from_le_bytes(token_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
-    drop(token_vault_data);
+        lending.borrow_rate_bps = config.borrow_rate_bps;
+        lending.max_borrow_per_position = config.max_borrow_per_position;
+        lending.max_interest_bps = config.max_interest_bps;
+        lending.deposit_cap = config.deposit_cap;
+        lending.reserve_factor_bps = config.reserve_factor_bps;
+        lending.withdrawal_fee_bps = config.withdrawal_fee_bps;
+        lending.withdrawal_fee_decay_seconds = config.withdrawal_fee_decay_seconds;
+
+        emit!(LendingConfigUpdated {
+            market: ctx.accounts.market.key(),
+            config,
+        });
 
-    let wsol_vault_info = wsol_vault.to_account_info();
-    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
-    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
-    drop(wsol_vault_data);
+        Ok(())
+    }
+
+    /// Pays `amount` lamports of `accumulated_fees` to the market's lenders
+    /// as a reward on top of interest. The fees are SOL while
+    /// `total_deposits` counts tokens, so the reward goes through
+    /// `fee_per_share` like the lenders' trading fee share and is collected
+    /// with `claim_lender_fees`.
+    pub fn distribute_to_lenders(ctx: Context<DistributeToLenders>, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::ZeroAmount);
+        let protocol = &mut ctx.accounts.protocol;
+        require!(amount <= protocol.accumulated_fees, ErrorCode::InsufficientFees);
+
+        let lending = &mut ctx.accounts.lending_pool;
+        crate::distribute_to_lenders(lending, amount)?;
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            ctx.accounts.market.key(),
+            AdminParam::LenderRewardDistributed,
+            protocol.accumulated_fees,
+            protocol.accumulated_fees - amount,
+        );
+        protocol.accumulated_fees -= amount;
+
+        emit!(LenderRewardDistributed {
+            market: ctx.accounts.market.key(),
+            amount,
+            fee_per_share: lending.fee_per_share,
+            accumulated_fees: protocol.accumulated_fees,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_wsol_vault(_ctx: Context<CreateWsolVault>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        require!(
+            ctx.accounts.admin.key() == ctx.accounts.protocol.admin,
+            ErrorCode::NotAdmin
+        );
+        
+        let market = &ctx.accounts.market;
+        let lending = &ctx.accounts.lending_pool;
+        
+        require!(market.total_positions == 0, ErrorCode::MarketHasPositions);
+        require!(lending.total_borrowed == 0, ErrorCode::MarketHasBorrows);
+
+        log_admin_action(ctx.accounts.admin.key(), market.key(), AdminParam::MarketClosed, 0, 0);
+        
+        emit!(MarketClosed {
+            token_mint: market.token_mint,
+        });
+        
+        Ok(())
+    }
+
+    /// Recovery tool for a position that can no longer be closed normally.
+    /// Settles it at the admin's `settle_price` without swapping and closes
+    /// the account, returning its rent to the owner. Only allowed when the
+    /// position's market account is already closed, or while the protocol
+    /// is migrating and the position is liquidatable at both `settle_price`
+    /// and the market's mark price. The payout is capped at the position's
+    /// collateral, so a force close never realizes a profit.
+    pub fn force_close_position(ctx: Context<ForceClosePosition>, settle_price: u64) -> Result<()> {
+        require!(settle_price > 0, ErrorCode::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        let position = &ctx.accounts.position;
+        let position_key = position.key();
+
+        // close_market drains both accounts together, so an empty market
+        // means the lending pool is gone as well
+        let market_closed = ctx.accounts.market.data_is_empty();
+        let (mut market, mut lending) = if market_closed {
+            (None, None)
+        } else {
+            let market = Market::try_deserialize(&mut &ctx.accounts.market.try_borrow_data()?[..])?;
+            let lending = LendingPool::try_deserialize(&mut &ctx.accounts.lending_pool.try_borrow_data()?[..])?;
+            (Some(market), Some(lending))
+        };
+
+        let (base_unit, quote_unit) = match (&market, &lending) {
+            (Some(market), Some(lending)) => {
+                require!(ctx.accounts.protocol.migrating, ErrorCode::ForceCloseNotAllowed);
+                let liquidation_price = effective_liquidation_price(position, lending, now)?;
+                require!(
+                    is_liquidatable(position, market, settle_price, liquidation_price),
+                    ErrorCode::ForceCloseNotAllowed
+                );
+                (market.base_unit, market.quote_unit)
+            }
+            _ => (
+                10u64
+                    .checked_pow(ctx.accounts.token_mint.decimals as u32)
+                    .ok_or(ErrorCode::Overflow)?,
+                10u64.pow(WSOL_DECIMALS as u32),
+            ),
+        };
+
+        // Longs' tokens stay in the vault, owed to the protocol until
+        // sweep_token_residual sells them; shorts' debt is settled in SOL
+        // when the lending pool still exists
+        let pnl = if position.is_long {
+            if let Some(market) = market.as_mut() {
+                market.total_long_collateral = market.total_long_collateral.saturating_sub(position.collateral);
+                keep_unsold_long_tokens(market, position.token_amount)?;
+            }
+            let value = quote_for_units(position.token_amount, settle_price, base_unit, quote_unit)?;
+            (value as i64) - (position.position_size_sol as i64)
+        } else {
+            let cost = match (market.as_ref(), lending.as_mut()) {
+                (Some(_), Some(lending)) => {
+                    let interest = capped_interest(
+                        lending,
+                        position_key,
+                        position.borrowed_tokens,
+                        position.opened_at,
+                        now,
+                    )?;
+                    let tokens_owed = position.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+                    let cost = quote_for_units(tokens_owed, settle_price, base_unit, quote_unit)?;
+                    settle_short_debt_in_sol(lending, position, position_key, interest, cost)?;
+                    cost
+                }
+                _ => quote_for_units(position.borrowed_tokens, settle_price, base_unit, quote_unit)?,
+            };
+            release_short_collateral(&mut ctx.accounts.protocol, market.as_mut(), position.collateral)?;
+            (position.position_size_sol as i64) - (cost as i64)
+        };
+
+        let gross = position.collateral as i64 + pnl;
+        let payout = if gross > 0 { (gross as u64).min(position.collateral) } else { 0 };
+
+        if let Some(market) = market.as_mut() {
+            market.total_positions = market.total_positions.saturating_sub(1);
+            market.try_serialize(&mut &mut ctx.accounts.market.try_borrow_mut_data()?[..])?;
+        }
+        if let Some(lending) = &lending {
+            lending.try_serialize(&mut &mut ctx.accounts.lending_pool.try_borrow_mut_data()?[..])?;
+        }
+
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.total_open_positions = protocol.total_open_positions.saturating_sub(1);
+        protocol.total_open_size = protocol.total_open_size.saturating_sub(position.position_size_sol);
+
+        let owner_account = &mut ctx.accounts.owner_account;
+        credit_balance(protocol, owner_account, payout)?;
+        unregister_active_market(owner_account, position.market);
+
+        log_admin_action(ctx.accounts.admin.key(), position_key, AdminParam::PositionForceClosed, 0, payout);
+
+        emit!(PositionForceClosed {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            admin: ctx.accounts.admin.key(),
+            settle_price,
+            market_closed,
+            pnl,
+            payout,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+
+        Ok(())
+    }
+
+    /// Sells the market's token vault residual through pumpswap and books
+    /// the SOL as `accumulated_fees`, the same way `sell_protocol_reserves`
+    /// does. The residual is what backs no lending deposit or protocol
+    /// reserve: the longs' tokens `force_close_position` or a collapsed-pool
+    /// settlement left behind, tracked in `unsold_long_tokens`, plus swap
+    /// rounding dust. Only allowed once
+    /// every position in the market has exited, so open longs' tokens can't
+    /// be mistaken for it.
+    pub fn sweep_token_residual<'info>(
+        ctx: Context<'_, '_, '_, 'info, SweepTokenResidual<'info>>,
+        min_sol: u64,
+    ) -> Result<()> {
+        require!(ctx.accounts.market.total_positions == 0, ErrorCode::MarketHasPositions);
+
+        let lending = &ctx.accounts.lending_pool;
+        let residual = protocol_owned_tokens(ctx.accounts.token_vault.amount, lending);
+        require!(residual > 0, ErrorCode::ZeroAmount);
+
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+        require!(pump.pumpswap_pool.key() == ctx.accounts.market.pumpswap_pool, ErrorCode::InvalidPool);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let sol_received = execute_sell(
+            &ctx.accounts.protocol_vault,
+            &ctx.accounts.token_vault,
+            &ctx.accounts.wsol_vault,
+            pump.pumpswap_pool,
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            pump.pumpswap_global,
+            &ctx.accounts.token_mint.to_account_info(),
+            &ctx.accounts.wsol_mint,
+            pump.protocol_fee_recipient,
+            pump.protocol_fee_recipient_ata,
+            pump.coin_creator_vault_ata,
+            pump.coin_creator_vault_authority,
+            pump.fee_config,
+            pump.fee_program,
+            &ctx.accounts.quote_token_program,
+            &ctx.accounts.base_token_program.to_account_info(),
+            &ctx.accounts.system_program,
+            &ctx.accounts.associated_token_program,
+            pump.event_authority,
+            pump.pumpswap_program,
+            vault_bump,
+            residual,
+            min_sol,
+        )?;
+
+        let market = &mut ctx.accounts.market;
+        let unsold_long_tokens = market.unsold_long_tokens;
+        market.unsold_long_tokens = 0;
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.accumulated_fees = protocol.accumulated_fees
+            .checked_add(sol_received).ok_or(ErrorCode::Overflow)?;
+
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            ctx.accounts.market.key(),
+            AdminParam::TokenResidualSwept,
+            residual,
+            0,
+        );
+
+        emit!(TokenResidualSwept {
+            market: ctx.accounts.market.key(),
+            amount: residual,
+            unsold_long_tokens,
+            sol_received,
+            accumulated_fees: protocol.accumulated_fees,
+        });
+
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Sells the pool's `protocol_reserves` through pumpswap and books the SOL
+    /// as `accumulated_fees`. The reserve factor's cut of interest is held in
+    /// the market token, so this is how it becomes protocol revenue.
+    pub fn sell_protocol_reserves<'info>(
+        ctx: Context<'_, '_, '_, 'info, SellProtocolReserves<'info>>,
+        min_sol: u64,
+    ) -> Result<()> {
+        let tokens = ctx.accounts.lending_pool.protocol_reserves;
+        require!(tokens > 0, ErrorCode::ZeroAmount);
+
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+        require!(pump.pumpswap_pool.key() == ctx.accounts.market.pumpswap_pool, ErrorCode::InvalidPool);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let sol_received = execute_sell(
+            &ctx.accounts.protocol_vault,
+            &ctx.accounts.token_vault,
+            &ctx.accounts.wsol_vault,
+            pump.pumpswap_pool,
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            pump.pumpswap_global,
+            &ctx.accounts.token_mint.to_account_info(),
+            &ctx.accounts.wsol_mint,
+            pump.protocol_fee_recipient,
+            pump.protocol_fee_recipient_ata,
+            pump.coin_creator_vault_ata,
+            pump.coin_creator_vault_authority,
+            pump.fee_config,
+            pump.fee_program,
+            &ctx.accounts.quote_token_program,
+            &ctx.accounts.base_token_program.to_account_info(),
+            &ctx.accounts.system_program,
+            &ctx.accounts.associated_token_program,
+            pump.event_authority,
+            pump.pumpswap_program,
+            vault_bump,
+            tokens,
+            min_sol,
+        )?;
+
+        ctx.accounts.lending_pool.protocol_reserves = 0;
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.accumulated_fees = protocol.accumulated_fees
+            .checked_add(sol_received).ok_or(ErrorCode::Overflow)?;
+
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            ctx.accounts.market.key(),
+            AdminParam::ProtocolReservesSold,
+            tokens,
+            0,
+        );
+
+        emit!(ProtocolReservesSold {
+            market: ctx.accounts.market.key(),
+            tokens,
+            sol_received,
+            accumulated_fees: protocol.accumulated_fees,
+        });
+
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Closes the WSOL vault back into the protocol vault. Every swap goes
+    /// through that vault, so this is refused while any position is open;
+    /// `create_wsol_vault` recreates it afterwards.
+    pub fn unwrap_wsol(ctx: Context<UnwrapWsol>) -> Result<()> {
+        require!(ctx.accounts.protocol.total_open_positions == 0, ErrorCode::OpenPositionsExist);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
+        let signer_seeds = &[seeds];
     
-    let tokens_received = tokens_after.checked_sub(tokens_before).ok_or(ErrorCode::SwapFailed)?;
-    let sol_spent = wsol_before.checked_add(sol_amount).ok_or(ErrorCode::Overflow)?
-        .checked_sub(wsol_after).ok_or(ErrorCode::SwapFailed)?;
+        token::close_account(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::CloseAccount {
+                    account: ctx.accounts.wsol_vault.to_account_info(),
+                    destination: ctx.accounts.protocol_vault.to_account_info(),
+                    authority: ctx.accounts.protocol_vault.to_account_info(),
+                },
+                signer_seeds,
+            ),
+        )?;
+
+        log_admin_action(
+            ctx.accounts.admin.key(),
+            ctx.accounts.wsol_vault.key(),
+            AdminParam::WsolVaultClosed,
+            0,
+            0,
+        );
     
-    require!(tokens_received >= min_tokens, ErrorCode::SlippageExceeded);
+        Ok(())
+    }
 
-    Ok((tokens_received, sol_spent))
-}
+    pub fn create_user_account(ctx: Context<CreateUserAccount>) -> Result<()> {
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.owner = ctx.accounts.user.key();
+        user_account.balance = 0;
+        user_account.recent_volume = 0;
+        user_account.volume_updated_at = 0;
+        user_account.last_open_ts = 0;
+        user_account.bump = ctx.bumps.user_account;
 
-#[allow(clippy::too_many_arguments)]
-fn execute_sell<'info>(
-    protocol_vault: &AccountInfo<'info>,
-    token_vault: &InterfaceAccount<'info, TokenAccount>,
-    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
-    pumpswap_pool: &AccountInfo<'info>,
-    pool_base_vault: &AccountInfo<'info>,
-    pool_quote_vault: &AccountInfo<'info>,
-    pumpswap_global: &AccountInfo<'info>,
-    token_mint: &AccountInfo<'info>,
-    wsol_mint: &AccountInfo<'info>,
-    protocol_fee_recipient: &AccountInfo<'info>,
-    protocol_fee_recipient_ata: &AccountInfo<'info>,
-    coin_creator_vault_ata: &AccountInfo<'info>,
-    coin_creator_vault_authority: &AccountInfo<'info>,
-    fee_config: &AccountInfo<'info>,
-    fee_program: &AccountInfo<'info>,
-    quote_token_program: &Program<'info, Token>,
-    base_token_program: &AccountInfo<'info>,
-    system_program: &Program<'info, System>,
-    associated_token_program: &Program<'info, AssociatedToken>,
-    event_authority: &AccountInfo<'info>,
-    pumpswap_program: &AccountInfo<'info>,
-    vault_bump: u8,
-    token_amount: u64,
-    min_sol: u64,
-) -> Result<u64> {
-    let bump_slice = &[vault_bump];
-    let seeds: &[&[u8]] = &[b"protocol_vault", bump_slice];
-    let signer_seeds = &[seeds];
+        emit!(UserAccountCreated { user: user_account.owner });
+
+        Ok(())
+    }
+
+    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(amount > 0, ErrorCode::ZeroAmount);
+
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.user.to_account_info(),
+                    to: ctx.accounts.protocol_vault.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.owner = ctx.accounts.user.key();
+        credit_balance(&mut ctx.accounts.protocol, user_account, amount)?;
+        user_account.bump = ctx.bumps.user_account;
+
+        emit!(Deposited {
+            user: ctx.accounts.user.key(),
+            amount,
+            new_balance: user_account.balance,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(ctx.accounts.user_account.balance >= amount, ErrorCode::InsufficientBalance);
+        // Fail with a clear error instead of inside the system transfer, and
+        // keep the vault rent-exempt.
+        let rent_min = Rent::get()?.minimum_balance(ctx.accounts.protocol_vault.data_len());
+        require!(
+            ctx.accounts.protocol_vault.lamports()
+                >= amount.checked_add(rent_min).ok_or(ErrorCode::Overflow)?,
+            ErrorCode::InsufficientBalance
+        );
+
+        debit_balance(&mut ctx.accounts.protocol, &mut ctx.accounts.user_account, amount)?;
+        let new_balance = ctx.accounts.user_account.balance;
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
+        let signer_seeds = &[seeds];
+
+        anchor_lang::system_program::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.protocol_vault.to_account_info(),
+                    to: ctx.accounts.user.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            amount,
+        )?;
+
+        emit!(Withdrawn {
+            user: ctx.accounts.user.key(),
+            amount,
+            new_balance,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+
+        Ok(())
+    }
+
+    pub fn deposit_to_lending(ctx: Context<DepositToLending>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(amount > 0, ErrorCode::ZeroAmount);
+
+        let lending = &mut ctx.accounts.lending_pool;
+        let decimals = ctx.accounts.token_mint.decimals;
+
+        let new_total = lending.total_deposits.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+        require!(
+            lending.deposit_cap == 0 || new_total <= lending.deposit_cap,
+            ErrorCode::DepositCapReached
+        );
+
+        let shares = lending_shares_for_tokens(lending, amount)?;
+        // Truncation can round a small deposit into a large pool down to no
+        // shares, which would just gift the tokens to existing lenders
+        require!(shares > 0, ErrorCode::ZeroShares);
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    mint: ctx.accounts.token_mint.to_account_info(),
+                    to: ctx.accounts.token_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+            decimals,
+        )?;
+
+        let lending_key = lending.key();
+        let lender = &mut ctx.accounts.lender_position;
+        let now = Clock::get()?.unix_timestamp;
+        credit_lending_deposit(lending, lending_key, lender, ctx.accounts.user.key(), amount, shares, now)?;
+        lender.bump = ctx.bumps.lender_position;
+
+        emit!(LendingDeposited {
+            user: ctx.accounts.user.key(),
+            amount,
+            shares,
+            cumulative_interest_collected: lending.cumulative_interest_collected,
+        });
+
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    pub fn withdraw_from_lending(ctx: Context<WithdrawFromLending>, shares: u64) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        let lender = &mut ctx.accounts.lender_position;
+        require!(lender.shares >= shares, ErrorCode::InsufficientShares);
+
+        let lending = &mut ctx.accounts.lending_pool;
+        let decimals = ctx.accounts.token_mint.decimals;
+
+        let tokens = lending_tokens_for_shares(lending, shares)?;
+        require!(tokens > 0, ErrorCode::ZeroTokens);
+
+        // The fee stays in the pool, so the last lender out pays none: with
+        // no shares left it would strand tokens no one can redeem
+        let fee = if shares == lending.total_shares {
+            0
+        } else {
+            let fee_bps = withdrawal_fee_bps(lending, lender, Clock::get()?.unix_timestamp);
+            mul_div(tokens, fee_bps, BPS_DENOMINATOR)?
+        };
+        let tokens = tokens - fee;
+        require!(tokens > 0, ErrorCode::ZeroTokens);
+
+        let available = lending.total_deposits.saturating_sub(lending.total_borrowed);
+        require!(tokens <= available, ErrorCode::InsufficientLiquidity);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let seeds: &[&[u8]] = &[b"protocol_vault", &[vault_bump]];
+        let signer_seeds = &[seeds];
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.token_vault.to_account_info(),
+                    mint: ctx.accounts.token_mint.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.protocol_vault.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            tokens,
+            decimals,
+        )?;
+
+        lending.total_deposits = lending.total_deposits.saturating_sub(tokens);
+        lending.total_shares = lending.total_shares.saturating_sub(shares);
+        settle_lender_fees(lending, lender)?;
+        lender.shares = lender.shares.saturating_sub(shares);
+        lender.fee_debt = lender_fee_debt(lending, lender.shares);
+
+        emit!(LendingWithdrawn {
+            user: ctx.accounts.user.key(),
+            tokens,
+            shares,
+            fee,
+            cumulative_interest_collected: lending.cumulative_interest_collected,
+        });
+
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Lends from the internal SOL balance: buys `sol_amount` of the market
+    /// token through pumpswap into the protocol's token vault and books the
+    /// tokens as a lending deposit. SOL the swap doesn't spend goes back to
+    /// the balance. Lending is token-denominated, so this is the one-step
+    /// path for lenders who only hold SOL.
+    pub fn swap_balance_to_token_and_lend<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapBalanceAndLend<'info>>,
+        sol_amount: u64,
+        min_tokens: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(sol_amount > 0, ErrorCode::ZeroAmount);
+        require!(ctx.accounts.user_account.balance >= sol_amount, ErrorCode::InsufficientBalance);
+
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+        require!(pump.pumpswap_pool.key() == ctx.accounts.market.pumpswap_pool, ErrorCode::InvalidPool);
+
+        debit_balance(&mut ctx.accounts.protocol, &mut ctx.accounts.user_account, sol_amount)?;
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let (tokens, sol_spent) = execute_buy(
+            &ctx.accounts.protocol_vault,
+            &ctx.accounts.token_vault,
+            &ctx.accounts.wsol_vault,
+            pump.pumpswap_pool,
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            pump.pumpswap_global,
+            &ctx.accounts.token_mint.to_account_info(),
+            &ctx.accounts.wsol_mint,
+            pump.protocol_fee_recipient,
+            pump.protocol_fee_recipient_ata,
+            pump.coin_creator_vault_ata,
+            pump.coin_creator_vault_authority,
+            pump.global_volume_accumulator,
+            pump.user_volume_accumulator,
+            pump.fee_config,
+            pump.fee_program,
+            &ctx.accounts.quote_token_program,
+            &ctx.accounts.base_token_program.to_account_info(),
+            &ctx.accounts.system_program,
+            &ctx.accounts.associated_token_program,
+            pump.event_authority,
+            pump.pumpswap_program,
+            vault_bump,
+            sol_amount,
+            min_tokens,
+        )?;
+        credit_balance(&mut ctx.accounts.protocol, &mut ctx.accounts.user_account, sol_amount - sol_spent)?;
+
+        let lending = &mut ctx.accounts.lending_pool;
+        let new_total = lending.total_deposits.checked_add(tokens).ok_or(ErrorCode::Overflow)?;
+        require!(
+            lending.deposit_cap == 0 || new_total <= lending.deposit_cap,
+            ErrorCode::DepositCapReached
+        );
+        let shares = lending_shares_for_tokens(lending, tokens)?;
+        require!(shares > 0, ErrorCode::ZeroShares);
+
+        let lending_key = lending.key();
+        let lender = &mut ctx.accounts.lender_position;
+        let now = Clock::get()?.unix_timestamp;
+        credit_lending_deposit(lending, lending_key, lender, ctx.accounts.user.key(), tokens, shares, now)?;
+        lender.bump = ctx.bumps.lender_position;
+
+        emit!(LendingDeposited {
+            user: ctx.accounts.user.key(),
+            amount: tokens,
+            shares,
+            cumulative_interest_collected: lending.cumulative_interest_collected,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Credits the SOL fees a lender's shares have earned to their protocol
+    /// balance, from where `withdraw` pays them out.
+    pub fn claim_lender_fees(ctx: Context<ClaimLenderFees>) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        let lending = &ctx.accounts.lending_pool;
+        let lender = &mut ctx.accounts.lender_position;
+        settle_lender_fees(lending, lender)?;
+        lender.fee_debt = lender_fee_debt(lending, lender.shares);
+
+        let amount = lender.unclaimed_fees;
+        require!(amount > 0, ErrorCode::ZeroAmount);
+        lender.unclaimed_fees = 0;
+
+        credit_balance(&mut ctx.accounts.protocol, &mut ctx.accounts.user_account, amount)?;
+
+        emit!(LenderFeesClaimed {
+            user: ctx.accounts.user.key(),
+            lending_pool: lending.key(),
+            amount,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+
+        Ok(())
+    }
+
+    pub fn open_position<'info>(
+        ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
+        is_long: bool,
+        collateral: u64,
+        leverage: u64,
+        slippage_limit: u64,
+        entry_price_limit: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(ctx.accounts.market.enabled, ErrorCode::MarketDisabled);
+        let max_leverage = ctx.accounts.market.max_leverage.min(ctx.accounts.protocol.max_leverage_ceiling);
+        require!((1..=max_leverage).contains(&leverage), ErrorCode::InvalidLeverage);
+        require!(collateral > 0, ErrorCode::ZeroCollateral);
+        let max_total_positions = ctx.accounts.protocol.max_total_positions;
+        require!(
+            max_total_positions == 0 || ctx.accounts.protocol.total_open_positions < max_total_positions,
+            ErrorCode::ProtocolPositionLimit
+        );
+    
+        let whitelist_enabled = ctx.accounts.protocol.open_whitelist_enabled;
+        let user_account = &mut ctx.accounts.user_account;
+        require!(!whitelist_enabled || user_account.whitelisted, ErrorCode::NotWhitelisted);
+        require!(user_account.balance >= collateral, ErrorCode::InsufficientBalance);
+    
+        let now = Clock::get()?.unix_timestamp;
+        let cooldown = ctx.accounts.market.open_cooldown_seconds;
+        require!(
+            cooldown == 0 || now >= user_account.last_open_ts.saturating_add(cooldown),
+            ErrorCode::OpenCooldown
+        );
+        user_account.last_open_ts = now;
+        let fee_bps = fee_bps_for_volume(decayed_volume(user_account, now));
+        let fee_exempt = user_account.fee_exempt;
+        let pays_fee_in_token = !fee_exempt && ctx.accounts.fee_token_account.is_some();
+        let base_fee = if fee_exempt { 0 } else { protocol_fee(collateral, fee_bps)? };
+        let fee = if pays_fee_in_token { 0 } else { base_fee };
+        let collateral_after_fee = collateral - fee;
+        // The fee rounds up, so dust collateral can be eaten whole
+        require!(collateral_after_fee > 0, ErrorCode::CollateralTooSmall);
+        let position_size_sol = collateral_after_fee.checked_mul(leverage).ok_or(ErrorCode::Overflow)?;
+    
+        let (max_position_size, min_position_size) = position_size_limits(
+            &ctx.accounts.protocol,
+            &ctx.accounts.market,
+            ctx.accounts.sol_usd_oracle.as_ref(),
+            now,
+        )?;
+        require!(position_size_sol <= max_position_size, ErrorCode::PositionTooLarge);
+        require!(position_size_sol >= min_position_size, ErrorCode::PositionTooSmall);
+        let protocol = &ctx.accounts.protocol;
+        if protocol.insurance_coverage_multiple > 0 {
+            let exposure = protocol.total_open_size
+                .checked_add(position_size_sol).ok_or(ErrorCode::Overflow)?;
+            let covered = protocol.insurance_fund.saturating_mul(protocol.insurance_coverage_multiple);
+            require!(exposure <= covered, ErrorCode::InsuranceCoverageExceeded);
+        }
+
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+
+        debit_balance(&mut ctx.accounts.protocol, user_account, collateral)?;
+        register_active_market(user_account, ctx.accounts.market.key())?;
+        record_volume(user_account, position_size_sol, now)?;
+        if pays_fee_in_token {
+            pay_fee_in_discount_token(
+                &ctx.accounts.protocol,
+                ctx.accounts.market.key(),
+                &ctx.accounts.user,
+                ctx.accounts.fee_token_account.as_deref(),
+                ctx.accounts.fee_collector.as_deref(),
+                ctx.accounts.fee_token_mint.as_deref(),
+                &ctx.accounts.quote_token_program,
+                base_fee,
+            )?;
+        } else {
+            collect_fee(
+                &mut ctx.accounts.protocol,
+                &ctx.accounts.market,
+                &mut ctx.accounts.lending_pool,
+                fee,
+            )?;
+        }
+
+        let entry_price = get_pool_price(
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+        // Zero disables the limit; longs enter at or below it, shorts at or above
+        require!(
+            entry_price_limit == 0
+                || if is_long { entry_price <= entry_price_limit } else { entry_price >= entry_price_limit },
+            ErrorCode::EntryPriceLimitExceeded
+        );
+        check_price_jump(&ctx.accounts.market, entry_price, position_size_sol, Clock::get()?.slot)?;
+
+        // The PDA is reused by every reopen on this market and side, so write
+        // the whole struct: a field added later can't be left uninitialized
+        let position = &mut ctx.accounts.position;
+        position.set_inner(Position {
+            owner: ctx.accounts.user.key(),
+            market: ctx.accounts.market.key(),
+            is_long,
+            collateral: collateral_after_fee,
+            leverage,
+            entry_price: 0,
+            mark_entry_price: entry_price,
+            liquidation_price: 0,
+            token_amount: 0,
+            position_size_sol: 0,
+            borrowed_tokens: 0,
+            opened_at: now,
+            bump: ctx.bumps.position,
+            pool_liquidity_at_open: read_token_amount(pump.pool_quote_vault)?,
+            close_slippage_bps: 0,
+            close_requested_at: 0,
+            close_request_price: 0,
+            open_fee_bps: fee_bps,
+            realized_pnl: 0,
+            soft_liquidation_price: 0,
+            open_fee: fee,
+        });
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+
+        if is_long {
+            let (tokens, sol_spent) = execute_buy(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.global_volume_accumulator,
+                pump.user_volume_accumulator,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                position_size_sol,
+                slippage_limit,
+            )?;
+
+            let actual_entry_price = price_from_amounts(sol_spent, tokens, &ctx.accounts.market)?;
+        
+            position.token_amount = tokens;
+            position.position_size_sol = sol_spent;
+            // Longs are funded from the vault and never touch the lending pool
+            position.borrowed_tokens = 0;
+            position.entry_price = actual_entry_price;
+            position.liquidation_price = calc_liq_price_long(actual_entry_price, leverage, LIQUIDATION_THRESHOLD_BPS)?;
+            position.soft_liquidation_price =
+                calc_liq_price_long(actual_entry_price, leverage, SOFT_LIQUIDATION_THRESHOLD_BPS)?;
+
+            let market = &mut ctx.accounts.market;
+            market.total_long_collateral = market.total_long_collateral
+                .checked_add(collateral_after_fee).ok_or(ErrorCode::Overflow)?;
+
+        } else {
+            let tokens_to_borrow = tokens_for_quote(position_size_sol, entry_price, &ctx.accounts.market)?;
+
+            let lending = &mut ctx.accounts.lending_pool;
+            let available = lending.total_deposits.saturating_sub(lending.total_borrowed);
+            require!(tokens_to_borrow <= available, ErrorCode::InsufficientLiquidity);
+            // The sell draws straight from token_vault, so the tokens must
+            // physically be there, not just in the pool's accounting
+            ctx.accounts.token_vault.reload()?;
+            require!(ctx.accounts.token_vault.amount >= tokens_to_borrow, ErrorCode::InsufficientLiquidity);
+            require!(
+                lending.max_borrow_per_position == 0 || tokens_to_borrow <= lending.max_borrow_per_position,
+                ErrorCode::BorrowCapExceeded
+            );
+
+            lending.total_borrowed = lending.total_borrowed
+                .checked_add(tokens_to_borrow).ok_or(ErrorCode::Overflow)?;
+
+            emit!(LendingBorrowed {
+                market: lending.market,
+                position: position.key(),
+                amount: tokens_to_borrow,
+                total_borrowed: lending.total_borrowed,
+            });
+
+            let sol_received = execute_sell(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens_to_borrow,
+                slippage_limit,
+            )?;
+
+            // The sell moves the pool, so the basis is what the borrowed tokens
+            // actually fetched. PnL at close is measured against
+            // position_size_sol, and liquidation must use the same basis.
+            let actual_entry_price = price_from_amounts(sol_received, tokens_to_borrow, &ctx.accounts.market)?;
+
+            position.token_amount = 0;
+            position.position_size_sol = sol_received;
+            position.borrowed_tokens = tokens_to_borrow;
+            position.entry_price = actual_entry_price;
+            position.liquidation_price = calc_liq_price_short(actual_entry_price, leverage, LIQUIDATION_THRESHOLD_BPS)?;
+            position.soft_liquidation_price =
+                calc_liq_price_short(actual_entry_price, leverage, SOFT_LIQUIDATION_THRESHOLD_BPS)?;
+
+            add_short_collateral(&mut ctx.accounts.protocol, &mut ctx.accounts.market, collateral_after_fee)?;
+        }
+
+        validate_leverage(&ctx.accounts.market, position)?;
+
+        let market = &mut ctx.accounts.market;
+        market.total_positions += 1;
+        market.largest_position_size = market.largest_position_size.max(position.position_size_sol);
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.total_open_positions += 1;
+        protocol.total_open_size = protocol.total_open_size
+            .checked_add(position.position_size_sol).ok_or(ErrorCode::Overflow)?;
+
+        emit!(PositionOpened {
+            owner: position.owner,
+            market: position.market,
+            is_long,
+            collateral: collateral_after_fee,
+            leverage,
+            entry_price: position.entry_price,
+            mark_entry_price: position.mark_entry_price,
+            liquidation_price: position.liquidation_price,
+            fee_exempt,
+            base_decimals: ctx.accounts.market.token_decimals,
+            price_precision: PRECISION as u64,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Deposits `deposit_amount` and opens a position with `collateral` drawn
+    /// from the resulting balance. Whatever the position doesn't use stays in
+    /// `user_account.balance` for later trades.
+    pub fn open_position_with_deposit<'info>(
+        ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
+        is_long: bool,
+        deposit_amount: u64,
+        collateral: u64,
+        leverage: u64,
+        slippage_limit: u64,
+        entry_price_limit: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.protocol.migrating, ErrorCode::ProtocolMigrating);
+        require!(deposit_amount > 0, ErrorCode::ZeroAmount);
+        let available = ctx.accounts.user_account.balance
+            .checked_add(deposit_amount).ok_or(ErrorCode::Overflow)?;
+        require!(collateral <= available, ErrorCode::InsufficientBalance);
+
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.user.to_account_info(),
+                    to: ctx.accounts.protocol_vault.to_account_info(),
+                },
+            ),
+            deposit_amount,
+        )?;
+
+        let user_account = &mut ctx.accounts.user_account;
+        credit_balance(&mut ctx.accounts.protocol, user_account, deposit_amount)?;
+
+        emit!(Deposited {
+            user: ctx.accounts.user.key(),
+            amount: deposit_amount,
+            new_balance: user_account.balance,
+        });
+
+        open_position(ctx, is_long, collateral, leverage, slippage_limit, entry_price_limit)
+    }
+
+    pub fn close_position<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClosePosition<'info>>,
+        slippage_limit: u64,
+    ) -> Result<()> {
+        let position = &ctx.accounts.position;
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+
+        let current_price = get_pool_price(
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+
+        let quote_reserve = read_token_amount(pump.pool_quote_vault)?;
+        let pool_collapsed = is_pool_collapsed(position.pool_liquidity_at_open, quote_reserve);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let now = Clock::get()?.unix_timestamp;
+        check_two_phase_close(&ctx.accounts.market, position, current_price, now)?;
+        // Closes are charged the tier rate locked in at open, so a later fee
+        // change or a drop out of a volume tier can't raise the exit cost;
+        // holding time can only lower it
+        let fee_bps = close_fee_bps(&ctx.accounts.market, position, now);
+        let fee_exempt = ctx.accounts.user_account.fee_exempt;
+        let pays_fee_in_token = !fee_exempt && ctx.accounts.fee_token_account.is_some();
+        let base_close_fee = if fee_exempt { 0 } else { protocol_fee(position.collateral, fee_bps)? };
+        let close_fee = if pays_fee_in_token { 0 } else { base_close_fee };
+        let token_mint_info = ctx.accounts.token_mint.to_account_info();
+        let base_token_program_info = ctx.accounts.base_token_program.to_account_info();
+        let accounts = CloseAccounts {
+            protocol_vault: &ctx.accounts.protocol_vault,
+            token_vault: &ctx.accounts.token_vault,
+            wsol_vault: &ctx.accounts.wsol_vault,
+            token_mint: &token_mint_info,
+            wsol_mint: &ctx.accounts.wsol_mint,
+            quote_token_program: &ctx.accounts.quote_token_program,
+            base_token_program: &base_token_program_info,
+            system_program: &ctx.accounts.system_program,
+            associated_token_program: &ctx.accounts.associated_token_program,
+        };
+        let (pnl, payout, fee_collected) = settle_close(
+            &accounts,
+            &pump,
+            &mut ctx.accounts.protocol,
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.lending_pool,
+            position,
+            ctx.accounts.position.key(),
+            vault_bump,
+            slippage_limit,
+            close_fee,
+            pool_collapsed,
+            quote_reserve,
+            (0, 0),
+            now,
+        )?;
+
+        if pays_fee_in_token {
+            pay_fee_in_discount_token(
+                &ctx.accounts.protocol,
+                ctx.accounts.market.key(),
+                &ctx.accounts.user,
+                ctx.accounts.fee_token_account.as_deref(),
+                ctx.accounts.fee_collector.as_deref(),
+                ctx.accounts.fee_token_mint.as_deref(),
+                &ctx.accounts.quote_token_program,
+                base_close_fee,
+            )?;
+        } else {
+            collect_fee(
+                &mut ctx.accounts.protocol,
+                &ctx.accounts.market,
+                &mut ctx.accounts.lending_pool,
+                fee_collected,
+            )?;
+        }
+
+        let (price_pnl, execution_pnl) = attribute_pnl(position, &ctx.accounts.market, current_price, pnl)?;
+        let payout_account = ctx.accounts.payout_account.as_ref().map_or(ctx.accounts.user_account.key(), |a| a.key());
+        finish_close(
+            &mut ctx.accounts.protocol,
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.user_account,
+            ctx.accounts.payout_account.as_deref_mut().map(|a| &mut **a),
+            position,
+            pnl,
+            payout,
+            fee_collected,
+            now,
+        )?;
+
+        emit!(PositionClosed {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            entry_price: position.entry_price,
+            exit_price: current_price,
+            pnl,
+            payout,
+            pool_liquidity_collapsed: pool_collapsed,
+            fee_exempt,
+            close_fee_bps: fee_bps,
+            realized_pnl: add_realized_pnl(position.realized_pnl, payout, position.collateral),
+            base_decimals: ctx.accounts.market.token_decimals,
+            price_precision: PRECISION as u64,
+            payout_account,
+            price_pnl,
+            execution_pnl,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Closes up to `MAX_PORTFOLIO_CLOSE` of the caller's positions, across
+    /// markets, in one transaction. remaining_accounts holds one
+    /// `PORTFOLIO_GROUP_LEN` group per position and `slippage_limits` one
+    /// limit per group, with `close_position`'s meaning. A position whose
+    /// estimated fill misses its limit is skipped and reported with
+    /// `PortfolioCloseSkipped`; every other failure aborts the batch. Fees are
+    /// charged in SOL. A long and a short on the same market are netted in the
+    /// vault first (see `portfolio_netting`) and reported with
+    /// `PortfolioSwapNetted`; a netted leg that misses its limit aborts too.
+    pub fn close_portfolio<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClosePortfolio<'info>>,
+        slippage_limits: Vec<u64>,
+    ) -> Result<()> {
+        let remaining = ctx.remaining_accounts;
+        #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+        let whole_groups = remaining.len() % PORTFOLIO_GROUP_LEN == 0;
+        require!(
+            !remaining.is_empty() && whole_groups,
+            ErrorCode::InvalidPumpswapAccounts
+        );
+        let count = remaining.len() / PORTFOLIO_GROUP_LEN;
+        require!(count <= MAX_PORTFOLIO_CLOSE, ErrorCode::PortfolioTooLarge);
+        require!(slippage_limits.len() == count, ErrorCode::InvalidPumpswapAccounts);
+
+        let user = ctx.accounts.user.key();
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let now = Clock::get()?.unix_timestamp;
+        let mut closed: u64 = 0;
+        let mut skipped: u64 = 0;
+        let netting = portfolio_netting(remaining, now)?;
+
+        for ((group, slippage_limit), (netted_tokens, netted_sol)) in
+            remaining.chunks(PORTFOLIO_GROUP_LEN).zip(slippage_limits).zip(netting)
+        {
+            require!(
+                group[0].is_writable && group[1].is_writable && group[2].is_writable,
+                ErrorCode::InvalidMarketAccount
+            );
+            let mut market = Account::<Market>::try_from(&group[0])?;
+            let mut lending = Account::<LendingPool>::try_from(&group[1])?;
+            let position = Account::<Position>::try_from(&group[2])?;
+            let token_mint = &group[3];
+            let token_vault = InterfaceAccount::<TokenAccount>::try_from(&group[4])?;
+            let base_token_program = &group[5];
+            let pump = parse_pumpswap_accounts(group, 6)?;
+
+            let market_key = market.key();
+            require!(lending.market == market_key, ErrorCode::InvalidMarketAccount);
+            require!(position.owner == user, ErrorCode::NotPositionOwner);
+            require!(position.market == market_key, ErrorCode::InvalidMarketAccount);
+            require!(
+                token_mint.key() == market.token_mint
+                    && base_token_program.key() == *token_mint.owner
+                    && token_vault.mint == market.token_mint
+                    && token_vault.owner == ctx.accounts.protocol_vault.key(),
+                ErrorCode::InvalidMarketAccount
+            );
+            require!(pump.pumpswap_pool.key() == market.pumpswap_pool, ErrorCode::InvalidPool);
+            let (base_vault, quote_vault) = read_pool_vaults(pump.pumpswap_pool)?;
+            require!(
+                base_vault == pump.pool_base_vault.key() && quote_vault == pump.pool_quote_vault.key(),
+                ErrorCode::InvalidPool
+            );
+
+            let current_price = get_pool_price(pump.pool_base_vault, pump.pool_quote_vault, &market)?;
+            let (base_reserve, quote_reserve) = get_pool_reserves(pump.pool_base_vault, pump.pool_quote_vault)?;
+            let pool_collapsed = is_pool_collapsed(position.pool_liquidity_at_open, quote_reserve);
+            check_two_phase_close(&market, &position, current_price, now)?;
+
+            // A failed swap can't be caught once its CPI runs, so misses are
+            // detected up front from the constant-product estimate. Netted
+            // tokens never touch the pool, so only the rest is estimated.
+            let (fill_ok, pool_tokens) = if position.is_long && pool_collapsed {
+                let value = long_value_at_mark(&market, position.token_amount, base_reserve, quote_reserve)?;
+                (value >= slippage_limit, 0)
+            } else if position.is_long {
+                let min_sol = slippage_limit.max(min_close_fill(
+                    &market,
+                    base_reserve,
+                    quote_reserve,
+                    position.token_amount,
+                )?);
+                let pool_tokens = position.token_amount.checked_sub(netted_tokens).ok_or(ErrorCode::Overflow)?;
+                let pool_sol = if pool_tokens == 0 {
+                    0
+                } else {
+                    estimate_sell_output(base_reserve, quote_reserve, pool_tokens)?
+                };
+                (pool_sol.saturating_add(netted_sol) >= min_sol, pool_tokens)
+            } else {
+                let interest = cap_interest(
+                    accrued_interest(position.borrowed_tokens, lending.borrow_rate_bps, position.opened_at, now)?,
+                    position.borrowed_tokens,
+                    lending.max_interest_bps,
+                )?;
+                let tokens_owed = position.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+                let pool_tokens = tokens_owed.checked_sub(netted_tokens).ok_or(ErrorCode::Overflow)?;
+                let pool_sol = if pool_tokens == 0 {
+                    0
+                } else {
+                    estimate_buy_input(base_reserve, quote_reserve, pool_tokens)?
+                };
+                let fill_ok = short_settles_in_sol(pool_collapsed, tokens_owed, base_reserve)
+                    || pool_sol.saturating_add(netted_sol) <= slippage_limit;
+                (fill_ok, pool_tokens)
+            };
+            // The other leg of a netted pair may already have settled against
+            // this one, so it can't be skipped on its own
+            require!(fill_ok || netted_tokens == 0, ErrorCode::SlippageExceeded);
+            if !fill_ok {
+                emit!(PortfolioCloseSkipped {
+                    owner: user,
+                    market: market_key,
+                    is_long: position.is_long,
+                    slippage_limit,
+                });
+                skipped += 1;
+                continue;
+            }
+
+            let fee_exempt = ctx.accounts.user_account.fee_exempt;
+            let fee_bps = close_fee_bps(&market, &position, now);
+            let close_fee = if fee_exempt { 0 } else { protocol_fee(position.collateral, fee_bps)? };
+            let accounts = CloseAccounts {
+                protocol_vault: &ctx.accounts.protocol_vault,
+                token_vault: &token_vault,
+                wsol_vault: &ctx.accounts.wsol_vault,
+                token_mint,
+                wsol_mint: &ctx.accounts.wsol_mint,
+                quote_token_program: &ctx.accounts.quote_token_program,
+                base_token_program,
+                system_program: &ctx.accounts.system_program,
+                associated_token_program: &ctx.accounts.associated_token_program,
+            };
+            let (pnl, payout, fee_collected) = settle_close(
+                &accounts,
+                &pump,
+                &mut ctx.accounts.protocol,
+                &mut market,
+                &mut lending,
+                &position,
+                position.key(),
+                vault_bump,
+                slippage_limit,
+                close_fee,
+                pool_collapsed,
+                quote_reserve,
+                (netted_tokens, netted_sol),
+                now,
+            )?;
+            if netted_tokens > 0 {
+                emit!(PortfolioSwapNetted {
+                    owner: user,
+                    market: market_key,
+                    is_long: position.is_long,
+                    netted_tokens,
+                    netted_sol,
+                    pool_tokens,
+                });
+            }
+            collect_fee(&mut ctx.accounts.protocol, &market, &mut lending, fee_collected)?;
+            let (price_pnl, execution_pnl) = attribute_pnl(&position, &market, current_price, pnl)?;
+            finish_close(
+                &mut ctx.accounts.protocol,
+                &mut market,
+                &mut ctx.accounts.user_account,
+                None,
+                &position,
+                pnl,
+                payout,
+                fee_collected,
+                now,
+            )?;
+
+            emit!(PositionClosed {
+                owner: position.owner,
+                market: market_key,
+                is_long: position.is_long,
+                entry_price: position.entry_price,
+                exit_price: current_price,
+                pnl,
+                payout,
+                pool_liquidity_collapsed: pool_collapsed,
+                fee_exempt,
+                close_fee_bps: fee_bps,
+                realized_pnl: add_realized_pnl(position.realized_pnl, payout, position.collateral),
+                base_decimals: market.token_decimals,
+                price_precision: PRECISION as u64,
+                payout_account: ctx.accounts.user_account.key(),
+                price_pnl,
+                execution_pnl,
+            });
+
+            market.exit(&crate::ID)?;
+            lending.exit(&crate::ID)?;
+            position.close(ctx.accounts.user.to_account_info())?;
+            closed += 1;
+        }
+
+        emit!(PortfolioClosed {
+            owner: user,
+            closed,
+            skipped,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+
+        Ok(())
+    }
+
+    pub fn liquidate<'info>(
+        ctx: Context<'_, '_, '_, 'info, Liquidate<'info>>,
+        slippage_limit: u64,
+        max_widen_bps: u64,
+    ) -> Result<()> {
+        require!(max_widen_bps <= MAX_SLIPPAGE_WIDEN_BPS, ErrorCode::InvalidBps);
+        require!(!ctx.accounts.market.liquidations_paused, ErrorCode::LiquidationsPaused);
+        let position = &ctx.accounts.position;
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+
+        let current_price = get_pool_price(
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+
+        let liquidation_price = effective_liquidation_price(
+            position,
+            &ctx.accounts.lending_pool,
+            Clock::get()?.unix_timestamp,
+        )?;
+        require!(
+            is_liquidatable(position, &ctx.accounts.market, current_price, liquidation_price),
+            ErrorCode::NotLiquidatable
+        );
+        let (decision_price, mark_governed) =
+            liquidation_decision_price(position, &ctx.accounts.market, current_price);
+        let max_chunk = ctx.accounts.market.max_liquidation_chunk;
+        require!(
+            max_chunk == 0 || position.position_size_sol <= max_chunk,
+            ErrorCode::LiquidationChunkRequired
+        );
+
+        let (base_reserve, quote_reserve) = get_pool_reserves(pump.pool_base_vault, pump.pool_quote_vault)?;
+        let pool_collapsed = is_pool_collapsed(position.pool_liquidity_at_open, quote_reserve);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let remaining: u64;
+
+        if position.is_long && pool_collapsed {
+            require_long_borrow_free(position)?;
+            // Settled like a collapsed-pool close; the keeper's limit only
+            // guards a swap, and none runs
+            let token_amount = position.token_amount;
+            let collateral = position.collateral;
+            let market = &mut ctx.accounts.market;
+            remaining = long_value_at_mark(market, token_amount, base_reserve, quote_reserve)?;
+            keep_unsold_long_tokens(market, token_amount)?;
+            market.total_long_collateral = market.total_long_collateral.saturating_sub(collateral);
+        } else if position.is_long {
+            require_long_borrow_free(position)?;
+            let slippage_limit = keeper_sell_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                position.token_amount,
+            )?;
+            let sol_received = execute_sell(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                position.token_amount,
+                slippage_limit,
+            )?;
+
+            remaining = sol_received;
+
+            let market = &mut ctx.accounts.market;
+            market.total_long_collateral = market.total_long_collateral
+                .saturating_sub(position.collateral);
+
+        } else {
+            let interest = capped_interest(
+                &ctx.accounts.lending_pool,
+                ctx.accounts.position.key(),
+                position.borrowed_tokens,
+                position.opened_at,
+                Clock::get()?.unix_timestamp,
+            )?;
+            let tokens_to_buy = position.borrowed_tokens
+                .checked_add(interest).ok_or(ErrorCode::Overflow)?;
+            let slippage_limit = keeper_buy_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens_to_buy,
+            )?;
+
+            let sol_spent = execute_buy_for_close(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.global_volume_accumulator,
+                pump.user_volume_accumulator,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens_to_buy,
+                slippage_limit,
+            )?;
+
+            let lending = &mut ctx.accounts.lending_pool;
+            lending.total_borrowed = lending.total_borrowed.saturating_sub(position.borrowed_tokens);
+            emit!(LendingRepaid {
+                market: lending.market,
+                position: position.key(),
+                amount: position.borrowed_tokens,
+                interest,
+                total_borrowed: lending.total_borrowed,
+            });
+            settle_interest(lending, interest)?;
+
+            remaining = position.position_size_sol.saturating_sub(sol_spent);
+
+            release_short_collateral(&mut ctx.accounts.protocol, Some(&mut ctx.accounts.market), position.collateral)?;
+        }
+
+        let market = &mut ctx.accounts.market;
+        market.total_positions = market.total_positions.saturating_sub(1);
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.total_open_positions = protocol.total_open_positions.saturating_sub(1);
+        protocol.total_open_size = protocol.total_open_size.saturating_sub(position.position_size_sol);
+
+        let (reward, to_owner) = calc_liquidation_split(&ctx.accounts.protocol, remaining);
+
+        emit!(LiquidationDistributed {
+            position: ctx.accounts.position.key(),
+            remaining,
+            reward,
+            to_owner,
+        });
+
+        if reward > 0 {
+            transfer_vault_lamports(
+                &ctx.accounts.protocol_vault.to_account_info(),
+                &ctx.accounts.liquidator.to_account_info(),
+                reward,
+            )?;
+
+            emit!(LiquidatorRewardPaid {
+                liquidator: ctx.accounts.liquidator.key(),
+                position: ctx.accounts.position.key(),
+                reward,
+            });
+        }
+
+        let owner_account = &mut ctx.accounts.owner_account;
+        credit_balance(&mut ctx.accounts.protocol, owner_account, to_owner)?;
+        unregister_active_market(owner_account, position.market);
+
+        emit!(PositionLiquidated {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            liquidator: ctx.accounts.liquidator.key(),
+            reward,
+            exit_price: current_price,
+            mark_price: ctx.accounts.market.mark_price,
+            pool_liquidity_collapsed: pool_collapsed,
+            realized_pnl: add_realized_pnl(position.realized_pnl, to_owner, position.collateral),
+            decision_price,
+            mark_governed,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Liquidates up to `max_tokens` of an unhealthy position: tokens held for a
+    /// long, borrowed tokens bought back for a short. Collateral and size are
+    /// reduced pro rata and the slice's proceeds are split between liquidator
+    /// and owner exactly like `liquidate`. Lets keepers chunk large positions
+    /// across transactions; the final chunk must go through `liquidate`,
+    /// which closes the position account. A slice is capped at the market's
+    /// `max_liquidation_chunk` of position size.
+    pub fn liquidate_amount<'info>(
+        ctx: Context<'_, '_, '_, 'info, LiquidateAmount<'info>>,
+        max_tokens: u64,
+        slippage_limit: u64,
+        max_widen_bps: u64,
+    ) -> Result<()> {
+        require!(max_widen_bps <= MAX_SLIPPAGE_WIDEN_BPS, ErrorCode::InvalidBps);
+        require!(!ctx.accounts.market.liquidations_paused, ErrorCode::LiquidationsPaused);
+        let position = &ctx.accounts.position;
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+
+        let current_price = get_pool_price(
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+
+        let liquidation_price = effective_liquidation_price(
+            position,
+            &ctx.accounts.lending_pool,
+            Clock::get()?.unix_timestamp,
+        )?;
+        require!(
+            is_liquidatable(position, &ctx.accounts.market, current_price, liquidation_price),
+            ErrorCode::NotLiquidatable
+        );
+
+        let is_long = position.is_long;
+        let total_tokens = if is_long { position.token_amount } else { position.borrowed_tokens };
+        let max_chunk = ctx.accounts.market.max_liquidation_chunk;
+        let chunk_tokens = if max_chunk == 0 || position.position_size_sol <= max_chunk {
+            total_tokens
+        } else {
+            mul_div(total_tokens, max_chunk, position.position_size_sol)?
+        };
+        let tokens = max_tokens.min(total_tokens).min(chunk_tokens);
+        require!(tokens > 0 && tokens < total_tokens, ErrorCode::InvalidLiquidationAmount);
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let collateral_part = mul_div(position.collateral, tokens, total_tokens)?;
+        let size_part = mul_div(position.position_size_sol, tokens, total_tokens)?;
+        let remaining: u64;
+
+        if is_long {
+            require_long_borrow_free(position)?;
+            let slippage_limit = keeper_sell_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens,
+            )?;
+            let sol_received = execute_sell(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens,
+                slippage_limit,
+            )?;
+
+            remaining = sol_received;
+
+            let position = &mut ctx.accounts.position;
+            position.token_amount = position.token_amount.saturating_sub(tokens);
+
+            let market = &mut ctx.accounts.market;
+            market.total_long_collateral = market.total_long_collateral
+                .saturating_sub(collateral_part);
+
+        } else {
+            let interest = capped_interest(
+                &ctx.accounts.lending_pool,
+                ctx.accounts.position.key(),
+                tokens,
+                position.opened_at,
+                Clock::get()?.unix_timestamp,
+            )?;
+            let tokens_to_buy = tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+            let slippage_limit = keeper_buy_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens_to_buy,
+            )?;
+
+            let sol_spent = execute_buy_for_close(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.global_volume_accumulator,
+                pump.user_volume_accumulator,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens_to_buy,
+                slippage_limit,
+            )?;
+
+            let lending = &mut ctx.accounts.lending_pool;
+            lending.total_borrowed = lending.total_borrowed.saturating_sub(tokens);
+            emit!(LendingRepaid {
+                market: lending.market,
+                position: ctx.accounts.position.key(),
+                amount: tokens,
+                interest,
+                total_borrowed: lending.total_borrowed,
+            });
+            settle_interest(lending, interest)?;
+
+            remaining = size_part.saturating_sub(sol_spent);
+
+            let position = &mut ctx.accounts.position;
+            position.borrowed_tokens = position.borrowed_tokens.saturating_sub(tokens);
+
+            release_short_collateral(&mut ctx.accounts.protocol, Some(&mut ctx.accounts.market), collateral_part)?;
+        }
+
+        let position = &mut ctx.accounts.position;
+        position.collateral = position.collateral.saturating_sub(collateral_part);
+        position.position_size_sol = position.position_size_sol.saturating_sub(size_part);
+        reprice_after_partial_fill(position)?;
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.total_open_size = protocol.total_open_size.saturating_sub(size_part);
+
+        let (reward, to_owner) = calc_liquidation_split(&ctx.accounts.protocol, remaining);
+
+        emit!(LiquidationDistributed {
+            position: ctx.accounts.position.key(),
+            remaining,
+            reward,
+            to_owner,
+        });
+
+        if reward > 0 {
+            transfer_vault_lamports(
+                &ctx.accounts.protocol_vault.to_account_info(),
+                &ctx.accounts.liquidator.to_account_info(),
+                reward,
+            )?;
+
+            emit!(LiquidatorRewardPaid {
+                liquidator: ctx.accounts.liquidator.key(),
+                position: ctx.accounts.position.key(),
+                reward,
+            });
+        }
+
+        let owner_account = &mut ctx.accounts.owner_account;
+        credit_balance(&mut ctx.accounts.protocol, owner_account, to_owner)?;
+        let position = &mut ctx.accounts.position;
+        position.realized_pnl = add_realized_pnl(position.realized_pnl, to_owner, collateral_part);
+
+        // The swap itself moved the pool and the fill repriced the
+        // position, so health is re-read for the keeper against both.
+        let price_after = get_pool_price(
+            pump.pool_base_vault,
+            pump.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+        let position = &ctx.accounts.position;
+        let liquidation_price = effective_liquidation_price(
+            position,
+            &ctx.accounts.lending_pool,
+            Clock::get()?.unix_timestamp,
+        )?;
+        let still_liquidatable = is_liquidatable(position, &ctx.accounts.market, price_after, liquidation_price);
+
+        emit!(PositionPartiallyLiquidated {
+            owner: position.owner,
+            market: position.market,
+            is_long,
+            liquidator: ctx.accounts.liquidator.key(),
+            tokens_liquidated: tokens,
+            size_liquidated: size_part,
+            remaining_tokens: total_tokens - tokens,
+            reward,
+            exit_price: current_price,
+            mark_price: ctx.accounts.market.mark_price,
+            still_liquidatable,
+            realized_pnl: position.realized_pnl,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Permissionless auto-deleveraging of a profitable position on the crowded
+    /// side of a market.
+    ///
+    /// Triggers only while `protocol.adl_threshold_bps` is non-zero and
+    ///   side_share_bps + utilization_bps >= adl_threshold_bps
+    /// where `side_share_bps` is the position side's share of the market's
+    /// total collateral (must be above 50%, i.e. the crowded side) and
+    /// `utilization_bps` is `total_borrowed / total_deposits` of the lending
+    /// pool. The position must be in profit at the current pool price. At most
+    /// `ADL_MAX_CLOSE_BPS` of the position is closed per call; the closed
+    /// slice's collateral plus realized profit is credited to the owner with
+    /// no close fee. Keepers are expected to pick the most profitable
+    /// positions off-chain. The position's stored `close_slippage_bps` bounds
+    /// whatever `slippage_limit` the keeper passes.
+    pub fn auto_deleverage<'info>(
+        ctx: Context<'_, '_, '_, 'info, AutoDeleverage<'info>>,
+        close_bps: u64,
+        slippage_limit: u64,
+        max_widen_bps: u64,
+    ) -> Result<()> {
+        let threshold_bps = ctx.accounts.protocol.adl_threshold_bps;
+        require!(threshold_bps > 0, ErrorCode::AdlNotTriggered);
+        require!(close_bps > 0 && close_bps <= ADL_MAX_CLOSE_BPS, ErrorCode::InvalidBps);
+        require!(max_widen_bps <= MAX_SLIPPAGE_WIDEN_BPS, ErrorCode::InvalidBps);
+
+        let is_long = ctx.accounts.position.is_long;
+        let market = &ctx.accounts.market;
+        let lending = &ctx.accounts.lending_pool;
+
+        let total_collateral = market.total_long_collateral
+            .checked_add(market.total_short_collateral).ok_or(ErrorCode::Overflow)?;
+        require!(total_collateral > 0, ErrorCode::AdlNotTriggered);
+        let side_collateral = if is_long { market.total_long_collateral } else { market.total_short_collateral };
+        let side_share_bps = mul_div(side_collateral, BPS_DENOMINATOR, total_collateral)?;
+        let utilization_bps = lending_utilization_bps(lending)?;
+        require!(side_share_bps > BPS_DENOMINATOR / 2, ErrorCode::AdlNotTriggered);
+        require!(side_share_bps + utilization_bps >= threshold_bps, ErrorCode::AdlNotTriggered);
+
+        let pump = parse_pumpswap_accounts(ctx.remaining_accounts, 0)?;
+        let current_price = get_pool_price(pump.pool_base_vault, pump.pool_quote_vault, &ctx.accounts.market)?;
+        let position = &ctx.accounts.position;
+        if is_long {
+            require!(current_price > position.entry_price, ErrorCode::AdlNotTriggered);
+        } else {
+            require!(current_price < position.entry_price, ErrorCode::AdlNotTriggered);
+        }
+
+        let vault_bump = ctx.accounts.protocol.vault_bump;
+        let collateral_part = mul_div(position.collateral, close_bps, BPS_DENOMINATOR)?;
+        let size_part = mul_div(position.position_size_sol, close_bps, BPS_DENOMINATOR)?;
+        let tokens: u64;
+        let pnl: i64;
+        let slippage_bound: u64;
+
+        if is_long {
+            require_long_borrow_free(position)?;
+            tokens = mul_div(position.token_amount, close_bps, BPS_DENOMINATOR)?;
+            let slippage_limit = keeper_sell_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens,
+            )?;
+            let min_sol = close_sell_limit(
+                slippage_limit,
+                position.close_slippage_bps,
+                &ctx.accounts.market,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens,
+            )?;
+            slippage_bound = min_sol;
+
+            let sol_received = execute_sell(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens,
+                min_sol,
+            )?;
+
+            pnl = (sol_received as i64) - (size_part as i64);
+
+            let position = &mut ctx.accounts.position;
+            position.token_amount = position.token_amount.saturating_sub(tokens);
+
+            let market = &mut ctx.accounts.market;
+            market.total_long_collateral = market.total_long_collateral
+                .saturating_sub(collateral_part);
+
+        } else {
+            tokens = mul_div(position.borrowed_tokens, close_bps, BPS_DENOMINATOR)?;
+            let interest = capped_interest(
+                &ctx.accounts.lending_pool,
+                ctx.accounts.position.key(),
+                tokens,
+                position.opened_at,
+                Clock::get()?.unix_timestamp,
+            )?;
+            let tokens_to_buy = tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+            let slippage_limit = keeper_buy_limit(
+                ctx.accounts.position.key(),
+                slippage_limit,
+                max_widen_bps,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens_to_buy,
+            )?;
+            let max_sol = close_buy_limit(
+                slippage_limit,
+                position.close_slippage_bps,
+                &ctx.accounts.market,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                tokens_to_buy,
+            )?;
+            slippage_bound = max_sol;
+
+            let sol_spent = execute_buy_for_close(
+                &ctx.accounts.protocol_vault,
+                &ctx.accounts.token_vault,
+                &ctx.accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                &ctx.accounts.token_mint.to_account_info(),
+                &ctx.accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.global_volume_accumulator,
+                pump.user_volume_accumulator,
+                pump.fee_config,
+                pump.fee_program,
+                &ctx.accounts.quote_token_program,
+                &ctx.accounts.base_token_program.to_account_info(),
+                &ctx.accounts.system_program,
+                &ctx.accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                tokens_to_buy,
+                max_sol,
+            )?;
+
+            pnl = (size_part as i64) - (sol_spent as i64);
+
+            let lending = &mut ctx.accounts.lending_pool;
+            lending.total_borrowed = lending.total_borrowed.saturating_sub(tokens);
+            emit!(LendingRepaid {
+                market: lending.market,
+                position: ctx.accounts.position.key(),
+                amount: tokens,
+                interest,
+                total_borrowed: lending.total_borrowed,
+            });
+            settle_interest(lending, interest)?;
+
+            let position = &mut ctx.accounts.position;
+            position.borrowed_tokens = position.borrowed_tokens.saturating_sub(tokens);
+
+            release_short_collateral(&mut ctx.accounts.protocol, Some(&mut ctx.accounts.market), collateral_part)?;
+        }
+
+        let payout_i64 = collateral_part as i64 + pnl;
+        let payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+
+        let position = &mut ctx.accounts.position;
+        position.collateral = position.collateral.saturating_sub(collateral_part);
+        position.position_size_sol = position.position_size_sol.saturating_sub(size_part);
+        reprice_after_partial_fill(position)?;
+        position.realized_pnl = add_realized_pnl(position.realized_pnl, payout, collateral_part);
+        let protocol = &mut ctx.accounts.protocol;
+        protocol.total_open_size = protocol.total_open_size.saturating_sub(size_part);
+
+        credit_balance(&mut ctx.accounts.protocol, &mut ctx.accounts.owner_account, payout)?;
+        let position = &ctx.accounts.position;
+
+        emit!(AutoDeleveraged {
+            owner: position.owner,
+            market: position.market,
+            is_long,
+            keeper: ctx.accounts.keeper.key(),
+            closed_bps: close_bps,
+            tokens,
+            pnl,
+            payout,
+            exit_price: current_price,
+            slippage_bound,
+            realized_pnl: position.realized_pnl,
+        });
+
+        check_vault_backing(&ctx.accounts.protocol_vault, &ctx.accounts.protocol)?;
+        check_lending_backing(&ctx.accounts.token_vault.to_account_info(), &ctx.accounts.lending_pool)?;
+
+        Ok(())
+    }
+
+    /// Phase one of a two-phase close: snapshots the pool price that the
+    /// eventual `close_position` is measured against. Calling it again
+    /// replaces the snapshot and restarts the delay.
+    pub fn request_close(ctx: Context<RequestClose>) -> Result<()> {
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let price = get_pool_price(
+            &ctx.accounts.pool_base_vault,
+            &ctx.accounts.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+
+        let position = &mut ctx.accounts.position;
+        position.close_requested_at = now;
+        position.close_request_price = price;
+
+        emit!(CloseRequested {
+            owner: position.owner,
+            market: position.market,
+            snapshot_price: price,
+            executable_at: now.saturating_add(ctx.accounts.market.close_delay),
+        });
+
+        Ok(())
+    }
+
+    /// Stores the worst slippage the owner accepts when a keeper closes the
+    /// position on their behalf. Auto-deleveraging enforces it whatever limit
+    /// the keeper passes. Liquidations ignore it, since an owner could
+    /// otherwise make their own position unliquidatable.
+    pub fn set_close_slippage(ctx: Context<SetCloseSlippage>, close_slippage_bps: u64) -> Result<()> {
+        require!(close_slippage_bps <= BPS_DENOMINATOR, ErrorCode::InvalidBps);
+
+        let position = &mut ctx.accounts.position;
+        position.close_slippage_bps = close_slippage_bps;
+
+        emit!(CloseSlippageUpdated {
+            owner: position.owner,
+            market: position.market,
+            close_slippage_bps,
+        });
+
+        Ok(())
+    }
+
+    /// Permissionless: emits `PositionAtRisk` once the pool price is past the
+    /// position's soft liquidation price, giving the owner a window to add
+    /// collateral or reduce before the hard `liquidation_price` is reached.
+    /// Changes no state.
+    pub fn warn_position(ctx: Context<WarnPosition>) -> Result<()> {
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let position = &ctx.accounts.position;
+        let soft_price = position.soft_liquidation_price;
+        let current_price = get_pool_price(
+            &ctx.accounts.pool_base_vault,
+            &ctx.accounts.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+        let at_risk = soft_price > 0
+            && if position.is_long { current_price <= soft_price } else { current_price >= soft_price };
+        require!(at_risk, ErrorCode::PositionNotAtRisk);
+
+        emit!(PositionAtRisk {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            current_price,
+            soft_liquidation_price: soft_price,
+            liquidation_price: position.liquidation_price,
+        });
+
+        Ok(())
+    }
+
+    pub fn quote_liquidation(ctx: Context<QuoteLiquidation>) -> Result<()> {
+        let position = &ctx.accounts.position;
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let (base_reserve, quote_reserve) =
+            get_pool_reserves(&ctx.accounts.pool_base_vault, &ctx.accounts.pool_quote_vault)?;
+
+        let current_price = get_pool_price(
+            &ctx.accounts.pool_base_vault,
+            &ctx.accounts.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+
+        let liquidation_price = effective_liquidation_price(
+            position,
+            &ctx.accounts.lending_pool,
+            Clock::get()?.unix_timestamp,
+        )?;
+        let liquidatable = is_liquidatable(position, &ctx.accounts.market, current_price, liquidation_price);
+        // Warning band for keepers: within the buffer of the threshold but
+        // not necessarily past it, so they can get ready without reverting.
+        let buffer = mul_div(liquidation_price, ctx.accounts.protocol.liquidation_buffer_bps, BPS_DENOMINATOR)?;
+        let near_liquidation = if position.is_long {
+            current_price <= liquidation_price.saturating_add(buffer)
+        } else {
+            current_price >= liquidation_price.saturating_sub(buffer)
+        };
+
+        let expected_remaining = if position.is_long {
+            estimate_sell_output(base_reserve, quote_reserve, position.token_amount)?
+        } else {
+            let lending = &ctx.accounts.lending_pool;
+            let interest = cap_interest(
+                accrued_interest(
+                    position.borrowed_tokens,
+                    lending.borrow_rate_bps,
+                    position.opened_at,
+                    Clock::get()?.unix_timestamp,
+                )?,
+                position.borrowed_tokens,
+                lending.max_interest_bps,
+            )?;
+            let tokens_owed = position.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+            let buyback_cost = estimate_buy_input(base_reserve, quote_reserve, tokens_owed)?;
+            position.position_size_sol.saturating_sub(buyback_cost)
+        };
+
+        let (expected_reward, _) = calc_liquidation_split(&ctx.accounts.protocol, expected_remaining);
+
+        emit!(LiquidationQuote {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            current_price,
+            mark_price: ctx.accounts.market.mark_price,
+            liquidation_price,
+            liquidatable,
+            near_liquidation,
+            expected_remaining,
+            expected_reward,
+        });
+
+        Ok(())
+    }
+
+    /// Read-only: returns the position and its live state as return data, so
+    /// other programs can read it over CPI with `get_return_data`.
+    pub fn get_position_snapshot(ctx: Context<GetPositionSnapshot>) -> Result<PositionSnapshot> {
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let position = &ctx.accounts.position;
+        let lending = &ctx.accounts.lending_pool;
+        let market = &ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        let current_price = get_pool_price(&ctx.accounts.pool_base_vault, &ctx.accounts.pool_quote_vault, market)?;
+        let liquidation_price = effective_liquidation_price(position, lending, now)?;
+        let is_liquidatable = is_liquidatable(position, market, current_price, liquidation_price);
+
+        // Marked at the spot price; an actual close also pays price impact
+        // and fees.
+        let accrued_interest = if position.is_long {
+            0
+        } else {
+            cap_interest(
+                accrued_interest(position.borrowed_tokens, lending.borrow_rate_bps, position.opened_at, now)?,
+                position.borrowed_tokens,
+                lending.max_interest_bps,
+            )?
+        };
+        let unrealized_pnl = if position.is_long {
+            quote_for_tokens(position.token_amount, current_price, market)? as i64 - position.position_size_sol as i64
+        } else {
+            let tokens_owed = position.borrowed_tokens.checked_add(accrued_interest).ok_or(ErrorCode::Overflow)?;
+            position.position_size_sol as i64 - quote_for_tokens(tokens_owed, current_price, market)? as i64
+        };
+
+        Ok(PositionSnapshot {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            collateral: position.collateral,
+            leverage: position.leverage,
+            entry_price: position.entry_price,
+            liquidation_price,
+            token_amount: position.token_amount,
+            position_size_sol: position.position_size_sol,
+            borrowed_tokens: position.borrowed_tokens,
+            accrued_interest,
+            opened_at: position.opened_at,
+            current_price,
+            unrealized_pnl,
+            is_liquidatable,
+            mark_price: market.mark_price,
+            realized_pnl: position.realized_pnl,
+        })
+    }
+
+    /// Permissionless: tightens a short's stored `liquidation_price` for the
+    /// borrow interest it has accrued. A no-op for longs, and the price only
+    /// ever moves toward entry.
+    pub fn refresh_position(ctx: Context<RefreshPosition>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let liquidation_price = effective_liquidation_price(
+            &ctx.accounts.position,
+            &ctx.accounts.lending_pool,
+            now,
+        )?;
+
+        let position = &mut ctx.accounts.position;
+        if liquidation_price == position.liquidation_price {
+            return Ok(());
+        }
+
+        let previous = position.liquidation_price;
+        position.liquidation_price = liquidation_price;
+
+        emit!(LiquidationPriceUpdated {
+            owner: position.owner,
+            market: position.market,
+            previous,
+            liquidation_price,
+        });
+
+        // Only refreshes that move a price are rewarded, and at most once
+        // per market per interval, so spamming the crank earns nothing. A
+        // protocol that never set an interval pays nothing.
+        let protocol = &mut ctx.accounts.protocol;
+        let market = &mut ctx.accounts.market;
+        let reward = protocol.crank_reward.min(protocol.accumulated_fees);
+        if reward > 0
+            && protocol.crank_interval > 0
+            && now.saturating_sub(market.last_crank_ts) >= protocol.crank_interval
+        {
+            market.last_crank_ts = now;
+            protocol.accumulated_fees -= reward;
+
+            transfer_vault_lamports(
+                &ctx.accounts.protocol_vault.to_account_info(),
+                &ctx.accounts.keeper.to_account_info(),
+                reward,
+            )?;
+
+            emit!(CrankRewardPaid {
+                keeper: ctx.accounts.keeper.key(),
+                market: market.key(),
+                reward,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Read-only: emits a market's main risk metrics in one `RiskSnapshot`
+    /// for monitoring.
+    pub fn get_risk_snapshot(ctx: Context<GetRiskSnapshot>) -> Result<()> {
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let protocol = &ctx.accounts.protocol;
+        let market = &ctx.accounts.market;
+        let lending = &ctx.accounts.lending_pool;
+        let now = Clock::get()?.unix_timestamp;
+
+        let current_price = get_pool_price(&ctx.accounts.pool_base_vault, &ctx.accounts.pool_quote_vault, market)?;
+        let total_collateral = market.total_long_collateral
+            .checked_add(market.total_short_collateral).ok_or(ErrorCode::Overflow)?;
+        // With nothing open there is no skew and nothing to cover
+        let (long_share_bps, insurance_coverage_bps) = if total_collateral == 0 {
+            (0, u64::MAX)
+        } else {
+            (
+                mul_div(market.total_long_collateral, BPS_DENOMINATOR, total_collateral)?,
+                mul_div(protocol.insurance_fund, BPS_DENOMINATOR, total_collateral)?,
+            )
+        };
+
+        // Insurance fund over open size across every market
+        let exposure_coverage_bps = if protocol.total_open_size == 0 {
+            u64::MAX
+        } else {
+            mul_div(protocol.insurance_fund, BPS_DENOMINATOR, protocol.total_open_size)?
+        };
+
+        emit!(RiskSnapshot {
+            market: market.key(),
+            current_price,
+            mark_price: market.mark_price,
+            total_long_collateral: market.total_long_collateral,
+            total_short_collateral: market.total_short_collateral,
+            long_share_bps,
+            utilization_bps: lending_utilization_bps(lending)?,
+            total_positions: market.total_positions,
+            protocol_open_positions: protocol.total_open_positions,
+            max_total_positions: protocol.max_total_positions,
+            largest_position_size: market.largest_position_size,
+            seconds_since_price_update: now.saturating_sub(market.last_price_update),
+            seconds_since_crank: now.saturating_sub(market.last_crank_ts),
+            insurance_fund: protocol.insurance_fund,
+            insurance_coverage_bps,
+            total_open_size: protocol.total_open_size,
+            exposure_coverage_bps,
+            insurance_coverage_multiple: protocol.insurance_coverage_multiple,
+            adl_threshold_bps: protocol.adl_threshold_bps,
+        });
+
+        Ok(())
+    }
+
+    /// Read-only: emits every protocol-level parameter plus the insurance
+    /// fund, fee and user balance totals in one `ProtocolConfig`, so clients
+    /// don't have to decode the `Protocol` account layout.
+    pub fn get_protocol_config(ctx: Context<GetProtocolConfig>) -> Result<()> {
+        let protocol = &ctx.accounts.protocol;
+
+        emit!(ProtocolConfig {
+            admin: protocol.admin,
+            migrating: protocol.migrating,
+            fee_insurance_split_bps: protocol.fee_insurance_split_bps,
+            adl_threshold_bps: protocol.adl_threshold_bps,
+            fee_discount_mint: protocol.fee_discount_mint,
+            fee_collector: protocol.fee_collector,
+            fee_discount_bps: protocol.fee_discount_bps,
+            fee_tokens_per_lamport: protocol.fee_tokens_per_lamport,
+            crank_reward: protocol.crank_reward,
+            crank_interval: protocol.crank_interval,
+            min_liquidator_reward: protocol.min_liquidator_reward,
+            liquidation_dust_threshold: protocol.liquidation_dust_threshold,
+            liquidation_buffer_bps: protocol.liquidation_buffer_bps,
+            max_leverage_ceiling: protocol.max_leverage_ceiling,
+            max_total_positions: protocol.max_total_positions,
+            insurance_coverage_multiple: protocol.insurance_coverage_multiple,
+            sol_usd_oracle: protocol.sol_usd_oracle,
+            open_fee_rebate_bps: protocol.open_fee_rebate_bps,
+            min_pool_liquidity: protocol.min_pool_liquidity,
+            open_whitelist_enabled: protocol.open_whitelist_enabled,
+            market_count: protocol.market_count,
+            total_open_positions: protocol.total_open_positions,
+            total_open_size: protocol.total_open_size,
+            insurance_fund: protocol.insurance_fund,
+            accumulated_fees: protocol.accumulated_fees,
+            total_user_balances: protocol.total_user_balances,
+        });
+
+        Ok(())
+    }
+
+    pub fn get_lending_stats(ctx: Context<GetLendingStats>) -> Result<()> {
+        let lending = &ctx.accounts.lending_pool;
+
+        emit!(LendingStats {
+            market: lending.market,
+            total_deposits: lending.total_deposits,
+            total_borrowed: lending.total_borrowed,
+            total_shares: lending.total_shares,
+            borrow_rate_bps: lending.borrow_rate_bps,
+            cumulative_interest_collected: lending.cumulative_interest_collected,
+            max_borrow_per_position: lending.max_borrow_per_position,
+            reserve_factor_bps: lending.reserve_factor_bps,
+            protocol_reserves: lending.protocol_reserves,
+            deposit_cap: lending.deposit_cap,
+            max_interest_bps: lending.max_interest_bps,
+        });
+
+        Ok(())
+    }
+
+    /// Read-only: emits the tokens `shares` would redeem for right now, using
+    /// the same rounding as `withdraw_from_lending`.
+    pub fn quote_shares_to_tokens(ctx: Context<GetLendingStats>, shares: u64) -> Result<()> {
+        let lending = &ctx.accounts.lending_pool;
+        let tokens = lending_tokens_for_shares(lending, shares)?;
+
+        emit!(LendingConversionQuoted {
+            market: lending.market,
+            shares,
+            tokens,
+        });
+
+        Ok(())
+    }
+
+    /// Read-only: emits the shares a deposit of `tokens` would mint right now,
+    /// using the same rounding as `deposit_to_lending`.
+    pub fn quote_tokens_to_shares(ctx: Context<GetLendingStats>, tokens: u64) -> Result<()> {
+        let lending = &ctx.accounts.lending_pool;
+        let shares = lending_shares_for_tokens(lending, tokens)?;
+
+        emit!(LendingConversionQuoted {
+            market: lending.market,
+            shares,
+            tokens,
+        });
+
+        Ok(())
+    }
+
+    /// Permissionless: samples the market's pool price into its cumulative
+    /// price so a TWAP can be read between any two samples.
+    pub fn poke_price(ctx: Context<PokePrice>) -> Result<()> {
+        let (base_vault, quote_vault) = read_pool_vaults(&ctx.accounts.pumpswap_pool)?;
+        require!(
+            base_vault == ctx.accounts.pool_base_vault.key()
+                && quote_vault == ctx.accounts.pool_quote_vault.key(),
+            ErrorCode::InvalidPool
+        );
+
+        let price = get_pool_price(
+            &ctx.accounts.pool_base_vault,
+            &ctx.accounts.pool_quote_vault,
+            &ctx.accounts.market,
+        )?;
+        let market_key = ctx.accounts.market.key();
+        let clock = Clock::get()?;
+        record_price_sample(&mut ctx.accounts.market, market_key, price, clock.unix_timestamp, clock.slot)
+    }
+
+    /// Batch form of `poke_price`. remaining_accounts holds groups of
+    /// `[market (mut), pumpswap_pool, pool_base_vault, pool_quote_vault]`.
+    /// Groups whose pool or vaults don't match the market are skipped so one
+    /// stale entry doesn't fail the whole batch.
+    pub fn poke_prices<'info>(ctx: Context<'_, '_, 'info, 'info, PokePrices>) -> Result<()> {
+        let remaining = ctx.remaining_accounts;
+        // Not `is_multiple_of`: the Solana platform tools' rustc predates it
+        #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+        let whole_groups = remaining.len() % 4 == 0;
+        require!(
+            !remaining.is_empty() && whole_groups,
+            ErrorCode::InvalidPumpswapAccounts
+        );
+
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        for group in remaining.chunks(4) {
+            require!(group[0].is_writable, ErrorCode::InvalidMarketAccount);
+            let mut market = Account::<Market>::try_from(&group[0])?;
+            let (pool, base_vault, quote_vault) = (&group[1], &group[2], &group[3]);
+
+            if pool.key() != market.pumpswap_pool {
+                continue;
+            }
+            match read_pool_vaults(pool) {
+                Ok((base, quote)) if base == base_vault.key() && quote == quote_vault.key() => {}
+                _ => continue,
+            }
+            let Ok(price) = get_pool_price(base_vault, quote_vault, &market) else {
+                continue;
+            };
+
+            record_price_sample(&mut market, group[0].key(), price, now, clock.slot)?;
+            market.exit(&crate::ID)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_user_positions(ctx: Context<GetUserPositions>) -> Result<()> {
+        let user_account = &ctx.accounts.user_account;
+
+        emit!(UserPositions {
+            owner: user_account.owner,
+            markets: user_account.active_markets.clone(),
+        });
+
+        Ok(())
+    }
+}
+
+// ========== Helper Functions ==========
+
+struct PumpswapAccounts<'a, 'info> {
+    pumpswap_pool: &'a AccountInfo<'info>,
+    pool_base_vault: &'a AccountInfo<'info>,
+    pool_quote_vault: &'a AccountInfo<'info>,
+    pumpswap_global: &'a AccountInfo<'info>,
+    protocol_fee_recipient: &'a AccountInfo<'info>,
+    protocol_fee_recipient_ata: &'a AccountInfo<'info>,
+    coin_creator_vault_ata: &'a AccountInfo<'info>,
+    coin_creator_vault_authority: &'a AccountInfo<'info>,
+    global_volume_accumulator: &'a AccountInfo<'info>,
+    user_volume_accumulator: &'a AccountInfo<'info>,
+    fee_config: &'a AccountInfo<'info>,
+    fee_program: &'a AccountInfo<'info>,
+    event_authority: &'a AccountInfo<'info>,
+    pumpswap_program: &'a AccountInfo<'info>,
+}
+
+/// Vault, mint and program accounts a close swaps through.
+struct CloseAccounts<'a, 'info> {
+    protocol_vault: &'a AccountInfo<'info>,
+    token_vault: &'a InterfaceAccount<'info, TokenAccount>,
+    wsol_vault: &'a InterfaceAccount<'info, TokenAccount>,
+    token_mint: &'a AccountInfo<'info>,
+    wsol_mint: &'a AccountInfo<'info>,
+    quote_token_program: &'a Program<'info, Token>,
+    base_token_program: &'a AccountInfo<'info>,
+    system_program: &'a Program<'info, System>,
+    associated_token_program: &'a Program<'info, AssociatedToken>,
+}
+
+/// Swaps a position out for `close_position` and `close_portfolio`: sells a
+/// long's tokens, or buys back and repays a short's borrow plus interest. `netted` is the
+/// `(tokens, sol)` share settled against an offsetting leg inside the vault
+/// (see `portfolio_netting`); only the remainder is swapped. Updates the
+/// side's collateral and returns `(pnl, payout, fee_collected)`.
+#[allow(clippy::too_many_arguments)]
+fn settle_close<'info>(
+    accounts: &CloseAccounts<'_, 'info>,
+    pump: &PumpswapAccounts<'_, 'info>,
+    protocol: &mut Protocol,
+    market: &mut Market,
+    lending: &mut LendingPool,
+    position: &Position,
+    position_key: Pubkey,
+    vault_bump: u8,
+    slippage_limit: u64,
+    close_fee: u64,
+    pool_collapsed: bool,
+    quote_reserve: u64,
+    netted: (u64, u64),
+    now: i64,
+) -> Result<(i64, u64, u64)> {
+    let (netted_tokens, netted_sol) = netted;
+    if position.is_long {
+        // A position that holds or owes nothing can only come from a bug;
+        // refuse it instead of swapping zero
+        require!(position.token_amount > 0, ErrorCode::EmptyPosition);
+        require_long_borrow_free(position)?;
+        let borrowed_before = lending.total_borrowed;
+
+        // Dumping into a rugged pool would net close to nothing, so a
+        // collapsed pool settles at the mark and keeps the tokens for
+        // sweep_token_residual. portfolio_netting never nets such a leg.
+        let base_reserve = read_token_amount(pump.pool_base_vault)?;
+        let pool_tokens = position.token_amount.checked_sub(netted_tokens).ok_or(ErrorCode::Overflow)?;
+        let sol_received = if pool_collapsed {
+            let value = long_value_at_mark(market, position.token_amount, base_reserve, quote_reserve)?;
+            require!(value >= slippage_limit, ErrorCode::SlippageExceeded);
+            keep_unsold_long_tokens(market, position.token_amount)?;
+            value
+        } else if pool_tokens == 0 {
+            netted_sol
+        } else {
+            let min_sol = slippage_limit.max(min_close_fill(market, base_reserve, quote_reserve, position.token_amount)?);
+            netted_sol + execute_sell(
+                accounts.protocol_vault,
+                accounts.token_vault,
+                accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                accounts.token_mint,
+                accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.fee_config,
+                pump.fee_program,
+                accounts.quote_token_program,
+                accounts.base_token_program,
+                accounts.system_program,
+                accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                pool_tokens,
+                min_sol.saturating_sub(netted_sol),
+            )?
+        };
+
+        let pnl = (sol_received as i64) - (position.position_size_sol as i64);
+        
+        let payout_i64 = position.collateral as i64 + pnl - close_fee as i64;
+        let payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+        let fee_collected = realized_close_fee(position.collateral, pnl, close_fee);
+
+        market.total_long_collateral = market.total_long_collateral
+            .saturating_sub(position.collateral);
+
+        debug_assert_eq!(
+            lending.total_borrowed,
+            borrowed_before,
+            "long close touched the lending pool"
+        );
+
+        Ok((pnl, payout, fee_collected))
+    } else {
+        require!(position.borrowed_tokens > 0, ErrorCode::EmptyPosition);
+        let interest = capped_interest(
+            lending,
+            position_key,
+            position.borrowed_tokens,
+            position.opened_at,
+            now,
+        )?;
+        let tokens_to_buy = position.borrowed_tokens
+            .checked_add(interest).ok_or(ErrorCode::Overflow)?;
+
+        let base_reserve = read_token_amount(pump.pool_base_vault)?;
+        if short_settles_in_sol(pool_collapsed, tokens_to_buy, base_reserve) {
+            let sol_spent = mul_div(tokens_to_buy, quote_reserve, base_reserve)?;
+            settle_short_debt_in_sol(lending, position, position_key, interest, sol_spent)?;
+
+            let pnl = (position.position_size_sol as i64) - (sol_spent as i64);
+            let payout_i64 = position.collateral as i64 + pnl - close_fee as i64;
+            let payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+            let fee_collected = realized_close_fee(position.collateral, pnl, close_fee);
+
+            release_short_collateral(protocol, Some(market), position.collateral)?;
+
+            return Ok((pnl, payout, fee_collected));
+        }
+
+        let pool_tokens = tokens_to_buy.checked_sub(netted_tokens).ok_or(ErrorCode::Overflow)?;
+        let sol_spent = if pool_tokens == 0 {
+            netted_sol
+        } else {
+            netted_sol + execute_buy_for_close(
+                accounts.protocol_vault,
+                accounts.token_vault,
+                accounts.wsol_vault,
+                pump.pumpswap_pool,
+                pump.pool_base_vault,
+                pump.pool_quote_vault,
+                pump.pumpswap_global,
+                accounts.token_mint,
+                accounts.wsol_mint,
+                pump.protocol_fee_recipient,
+                pump.protocol_fee_recipient_ata,
+                pump.coin_creator_vault_ata,
+                pump.coin_creator_vault_authority,
+                pump.global_volume_accumulator,
+                pump.user_volume_accumulator,
+                pump.fee_config,
+                pump.fee_program,
+                accounts.quote_token_program,
+                accounts.base_token_program,
+                accounts.system_program,
+                accounts.associated_token_program,
+                pump.event_authority,
+                pump.pumpswap_program,
+                vault_bump,
+                pool_tokens,
+                slippage_limit.saturating_sub(netted_sol),
+            )?
+        };
+
+        lending.total_borrowed = lending.total_borrowed.saturating_sub(position.borrowed_tokens);
+        emit!(LendingRepaid {
+            market: lending.market,
+            position: position_key,
+            amount: position.borrowed_tokens,
+            interest,
+            total_borrowed: lending.total_borrowed,
+        });
+        settle_interest(lending, interest)?;
+        emit!(ShortCloseSettled {
+            market: lending.market,
+            position: position_key,
+            settled_in_sol: false,
+            tokens_owed: tokens_to_buy,
+            sol_spent,
+        });
+
+        let pnl = (position.position_size_sol as i64) - (sol_spent as i64);
+        
+        let payout_i64 = position.collateral as i64 + pnl - close_fee as i64;
+        let payout = if payout_i64 > 0 { payout_i64 as u64 } else { 0 };
+        let fee_collected = realized_close_fee(position.collateral, pnl, close_fee);
+
+        release_short_collateral(protocol, Some(market), position.collateral)?;
+
+        Ok((pnl, payout, fee_collected))
+    }
+}
+
+/// Splits a close's `pnl` into the price move from `mark_entry_price` (the
+/// spot before the open's swap) to `exit_price` (the spot before the close's
+/// swap) on the position's tokens, and the rest: both swaps' price impact
+/// versus spot, rounding and, for shorts, borrow interest. The two always
+/// sum to `pnl`.
+fn attribute_pnl(position: &Position, market: &Market, exit_price: u64, pnl: i64) -> Result<(i64, i64)> {
+    let tokens = if position.is_long { position.token_amount } else { position.borrowed_tokens };
+    let entry_value = quote_for_tokens(tokens, position.mark_entry_price, market)? as i64;
+    let exit_value = quote_for_tokens(tokens, exit_price, market)? as i64;
+    let price_pnl = if position.is_long { exit_value - entry_value } else { entry_value - exit_value };
+    let execution_pnl = pnl.checked_sub(price_pnl).ok_or(ErrorCode::Overflow)?;
+    Ok((price_pnl, execution_pnl))
+}
+
+/// Pairs the long and short a `close_portfolio` call closes on the same
+/// market. The long's tokens stay in the vault to repay the short's debt at
+/// the pre-swap spot price, so only the larger leg's excess goes through the
+/// pool. Returns `(netted_tokens, netted_sol)` per group, zero for legs
+/// without a partner or whose pool has collapsed. Accounts are only read
+/// here; the main loop validates every group before settling it.
+fn portfolio_netting<'info>(remaining: &'info [AccountInfo<'info>], now: i64) -> Result<Vec<(u64, u64)>> {
+    let groups: Vec<&'info [AccountInfo<'info>]> = remaining.chunks(PORTFOLIO_GROUP_LEN).collect();
+    let mut netting = vec![(0, 0); groups.len()];
+
+    for i in 0..groups.len() {
+        for j in (i + 1)..groups.len() {
+            if groups[i][0].key() != groups[j][0].key() {
+                continue;
+            }
+            let first = Account::<Position>::try_from(&groups[i][2])?;
+            let second = Account::<Position>::try_from(&groups[j][2])?;
+            if first.is_long == second.is_long {
+                continue;
+            }
+            let (long, short, short_group) = if first.is_long {
+                (first, second, groups[j])
+            } else {
+                (second, first, groups[i])
+            };
+
+            let pump = parse_pumpswap_accounts(groups[i], 6)?;
+            let (base_reserve, quote_reserve) = get_pool_reserves(pump.pool_base_vault, pump.pool_quote_vault)?;
+            if is_pool_collapsed(long.pool_liquidity_at_open, quote_reserve)
+                || is_pool_collapsed(short.pool_liquidity_at_open, quote_reserve)
+            {
+                continue;
+            }
+
+            let lending = Account::<LendingPool>::try_from(&short_group[1])?;
+            let interest = cap_interest(
+                accrued_interest(short.borrowed_tokens, lending.borrow_rate_bps, short.opened_at, now)?,
+                short.borrowed_tokens,
+                lending.max_interest_bps,
+            )?;
+            let tokens_owed = short.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+            if short_settles_in_sol(false, tokens_owed, base_reserve) {
+                continue;
+            }
+
+            let tokens = long.token_amount.min(tokens_owed);
+            let sol = mul_div(tokens, quote_reserve, base_reserve)?;
+            netting[i] = (tokens, sol);
+            netting[j] = (tokens, sol);
+        }
+    }
+
+    Ok(netting)
+}
+
+/// A short's token debt is settled in SOL when buying it back is impossible:
+/// the pool has collapsed since open, or the debt is at least the pool's
+/// whole token reserve. A tight slippage limit alone never qualifies, so
+/// traders can't use the fallback to skip price impact.
+fn short_settles_in_sol(pool_collapsed: bool, tokens_owed: u64, base_reserve: u64) -> bool {
+    pool_collapsed || tokens_owed >= base_reserve
+}
+
+/// Retires a short's borrow without returning tokens. The debt is paid to
+/// lenders as `sol_value` lamports through `fee_per_share`, which stay in the
+/// vault; the borrowed tokens are gone, so they come off `total_deposits`.
+fn settle_short_debt_in_sol(
+    lending: &mut LendingPool,
+    position: &Position,
+    position_key: Pubkey,
+    interest: u64,
+    sol_value: u64,
+) -> Result<()> {
+    lending.total_borrowed = lending.total_borrowed.saturating_sub(position.borrowed_tokens);
+    lending.total_deposits = lending.total_deposits.saturating_sub(position.borrowed_tokens);
+    distribute_to_lenders(lending, sol_value)?;
+
+    emit!(ShortCloseSettled {
+        market: lending.market,
+        position: position_key,
+        settled_in_sol: true,
+        tokens_owed: position.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?,
+        sol_spent: sol_value,
+    });
+
+    Ok(())
+}
+
+/// Bookkeeping shared by every full close once the swap has settled and the
+/// fee is booked: releases the position's slot and credits the payout plus
+/// any open fee rebate, to `payout_account` when the owner routed it there.
+#[allow(clippy::too_many_arguments)]
+fn finish_close(
+    protocol: &mut Protocol,
+    market: &mut Market,
+    user_account: &mut UserAccount,
+    payout_account: Option<&mut UserAccount>,
+    position: &Position,
+    pnl: i64,
+    payout: u64,
+    fee_collected: u64,
+    now: i64,
+) -> Result<()> {
+    market.total_positions = market.total_positions.saturating_sub(1);
+    protocol.total_open_positions = protocol.total_open_positions.saturating_sub(1);
+    protocol.total_open_size = protocol.total_open_size.saturating_sub(position.position_size_sol);
+
+    // A floored payout means the position's remaining value was consumed.
+    // Whatever it still covered was booked as fee; anything below zero is a
+    // loss the vault absorbed.
+    if payout == 0 {
+        let gross = position.collateral as i64 + pnl;
+        emit!(UnderwaterClose {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            retained: fee_collected,
+            shortfall: if gross < 0 { gross.unsigned_abs() } else { 0 },
+        });
+    } else if pnl == 0 {
+        // Break-even exits are rare; flag them so analytics can tell them
+        // apart from unset data
+        emit!(FlatClose {
+            owner: position.owner,
+            market: position.market,
+            is_long: position.is_long,
+            payout,
+        });
+    }
+
+    let rebate = open_fee_rebate(protocol, position, pnl)?;
+    let credit = payout.checked_add(rebate).ok_or(ErrorCode::Overflow)?;
+    match payout_account {
+        Some(destination) => credit_balance(protocol, destination, credit)?,
+        None => credit_balance(protocol, user_account, credit)?,
+    }
+    unregister_active_market(user_account, position.market);
+    record_volume(user_account, position.position_size_sol, now)
+}
+
+/// Takes `open_fee_rebate_bps` of a profitable position's open fee out of
+/// `accumulated_fees`, capped at what it holds so a rebate never draws on
+/// the insurance fund or user balances.
+fn open_fee_rebate(protocol: &mut Protocol, position: &Position, pnl: i64) -> Result<u64> {
+    if pnl <= 0 || protocol.open_fee_rebate_bps == 0 {
+        return Ok(0);
+    }
+    let rebate = mul_div(position.open_fee, protocol.open_fee_rebate_bps, BPS_DENOMINATOR)?
+        .min(protocol.accumulated_fees);
+    if rebate == 0 {
+        return Ok(0);
+    }
+    protocol.accumulated_fees -= rebate;
+
+    emit!(OpenFeeRebated {
+        owner: position.owner,
+        market: position.market,
+        open_fee: position.open_fee,
+        rebate,
+    });
+
+    Ok(rebate)
+}
+
+/// Parses the pumpswap account group starting at `offset` in
+/// remaining_accounts, so instructions can carry several groups back to back.
+/// The swap CPIs invoke `PUMPSWAP_PROGRAM_ID`, so the group's program account
+/// must be that same program.
+fn parse_pumpswap_accounts<'a, 'info>(
+    remaining: &'a [AccountInfo<'info>],
+    offset: usize,
+) -> Result<PumpswapAccounts<'a, 'info>> {
+    let end = offset.checked_add(PUMPSWAP_ACCOUNTS_LEN).ok_or(ErrorCode::InvalidPumpswapAccounts)?;
+    let group = remaining.get(offset..end).ok_or(ErrorCode::InvalidPumpswapAccounts)?;
+    require!(group[13].key() == PUMPSWAP_PROGRAM_ID, ErrorCode::InvalidPumpswapAccounts);
+    Ok(PumpswapAccounts {
+        pumpswap_pool: &group[0],
+        pool_base_vault: &group[1],
+        pool_quote_vault: &group[2],
+        pumpswap_global: &group[3],
+        protocol_fee_recipient: &group[4],
+        protocol_fee_recipient_ata: &group[5],
+        coin_creator_vault_ata: &group[6],
+        coin_creator_vault_authority: &group[7],
+        global_volume_accumulator: &group[8],
+        user_volume_accumulator: &group[9],
+        fee_config: &group[10],
+        fee_program: &group[11],
+        event_authority: &group[12],
+        pumpswap_program: &group[13],
+    })
+}
+
+fn get_pool_price(base_vault: &AccountInfo, quote_vault: &AccountInfo, market: &Market) -> Result<u64> {
+    #[cfg(feature = "test")]
+    if market.mock_price > 0 {
+        return Ok(market.mock_price);
+    }
+
+    let (base_amount, quote_amount) = get_pool_reserves(base_vault, quote_vault)?;
+
+    require!(base_amount > 0, ErrorCode::EmptyPool);
+
+    price_from_amounts(quote_amount, base_amount, market)
+}
+
+/// Reads a pool's `(base, quote)` reserves together, checking both vaults are
+/// SPL or Token-2022 token accounts first so a look-alike account can't feed
+/// prices, size caps or swap estimates.
+fn get_pool_reserves(base_vault: &AccountInfo, quote_vault: &AccountInfo) -> Result<(u64, u64)> {
+    for owner in [base_vault.owner, quote_vault.owner] {
+        require!(*owner == token::ID || *owner == anchor_spl::token_2022::ID, ErrorCode::InvalidPool);
+    }
+    Ok((read_token_amount(base_vault)?, read_token_amount(quote_vault)?))
+}
+
+/// Reads the base and quote vault addresses recorded in a pumpswap pool.
+fn read_pool_vaults(pool: &AccountInfo) -> Result<(Pubkey, Pubkey)> {
+    let data = pool.try_borrow_data()?;
+    require!(data.len() >= POOL_QUOTE_VAULT_OFFSET + 32, ErrorCode::InvalidPool);
+    let base_vault = Pubkey::try_from(&data[POOL_BASE_VAULT_OFFSET..POOL_BASE_VAULT_OFFSET + 32])
+        .map_err(|_| ErrorCode::InvalidPool)?;
+    let quote_vault = Pubkey::try_from(&data[POOL_QUOTE_VAULT_OFFSET..POOL_QUOTE_VAULT_OFFSET + 32])
+        .map_err(|_| ErrorCode::InvalidPool)?;
+    Ok((base_vault, quote_vault))
+}
+
+/// Price of one whole base token in whole quote tokens, scaled by
+/// `PRECISION`. Normalizing by both mints' decimals keeps prices comparable
+/// across tokens with different decimal counts.
+fn price_from_amounts(quote_amount: u64, base_amount: u64, market: &Market) -> Result<u64> {
+    let price = (quote_amount as u128)
+        .checked_mul(PRECISION)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_mul(market.base_unit as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div((base_amount as u128).checked_mul(market.quote_unit as u128).ok_or(ErrorCode::Overflow)?)
+        .ok_or(ErrorCode::Overflow)?;
+
+    u64::try_from(price).map_err(|_| ErrorCode::PriceTruncation.into())
+}
+
+/// Inverse of `price_from_amounts`: base token units worth `quote_amount` at
+/// `price`.
+fn tokens_for_quote(quote_amount: u64, price: u64, market: &Market) -> Result<u64> {
+    let tokens = (quote_amount as u128)
+        .checked_mul(PRECISION)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_mul(market.base_unit as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div((price as u128).checked_mul(market.quote_unit as u128).ok_or(ErrorCode::Overflow)?)
+        .ok_or(ErrorCode::Overflow)?;
+
+    u64::try_from(tokens).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Quote units `token_amount` base units are worth at `price`.
+fn quote_for_tokens(token_amount: u64, price: u64, market: &Market) -> Result<u64> {
+    quote_for_units(token_amount, price, market.base_unit, market.quote_unit)
+}
+
+/// `quote_for_tokens` with explicit units, for when the market account is
+/// gone.
+fn quote_for_units(token_amount: u64, price: u64, base_unit: u64, quote_unit: u64) -> Result<u64> {
+    let quote = (token_amount as u128)
+        .checked_mul(price as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_mul(quote_unit as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(PRECISION.checked_mul(base_unit as u128).ok_or(ErrorCode::Overflow)?)
+        .ok_or(ErrorCode::Overflow)?;
+
+    u64::try_from(quote).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Books a collected fee (already sitting in the vault). The market's
+/// `lender_fee_share_bps` goes to the lending pool's lenders first, while it
+/// has any; the rest is split between the insurance fund and the treasury
+/// according to `fee_insurance_split_bps`.
+fn collect_fee(protocol: &mut Protocol, market: &Market, lending: &mut LendingPool, fee: u64) -> Result<()> {
+    if fee == 0 {
+        return Ok(());
+    }
+
+    let to_lenders = if lending.total_shares == 0 {
+        0
+    } else {
+        mul_div(fee, market.lender_fee_share_bps, BPS_DENOMINATOR)?
+    };
+    distribute_to_lenders(lending, to_lenders)?;
+    let remainder = fee.checked_sub(to_lenders).ok_or(ErrorCode::Overflow)?;
+
+    let to_insurance = (remainder as u128)
+        .checked_mul(protocol.fee_insurance_split_bps as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::Overflow)? as u64;
+    let to_treasury = remainder.checked_sub(to_insurance).ok_or(ErrorCode::Overflow)?;
+
+    protocol.insurance_fund = protocol.insurance_fund.checked_add(to_insurance).ok_or(ErrorCode::Overflow)?;
+    protocol.accumulated_fees = protocol.accumulated_fees.checked_add(to_treasury).ok_or(ErrorCode::Overflow)?;
+
+    emit!(FeeCollected { market: lending.market, fee, to_lenders, to_insurance, to_treasury });
+
+    Ok(())
+}
+
+/// Charges a protocol fee of `fee` lamports in the fee-discount token instead
+/// of SOL, at the configured discount and conversion rate.
+#[allow(clippy::too_many_arguments)]
+fn pay_fee_in_discount_token<'info>(
+    protocol: &Protocol,
+    market: Pubkey,
+    user: &Signer<'info>,
+    fee_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    fee_collector: Option<&InterfaceAccount<'info, TokenAccount>>,
+    fee_token_mint: Option<&InterfaceAccount<'info, Mint>>,
+    token_program: &Program<'info, Token>,
+    fee: u64,
+) -> Result<()> {
+    require!(protocol.fee_discount_mint != Pubkey::default(), ErrorCode::FeeDiscountDisabled);
+
+    let fee_token_account = fee_token_account.ok_or(ErrorCode::FeeTokenAccountMissing)?;
+    let fee_collector = fee_collector.ok_or(ErrorCode::FeeTokenAccountMissing)?;
+    let fee_token_mint = fee_token_mint.ok_or(ErrorCode::FeeTokenAccountMissing)?;
+
+    require!(fee_token_mint.key() == protocol.fee_discount_mint, ErrorCode::FeeTokenMintMismatch);
+    require!(fee_token_account.mint == protocol.fee_discount_mint, ErrorCode::FeeTokenMintMismatch);
+    require!(fee_collector.key() == protocol.fee_collector, ErrorCode::FeeTokenMintMismatch);
+
+    let discounted_fee = mul_div(fee, protocol.fee_discount_bps, BPS_DENOMINATOR)?;
+    let token_amount = (discounted_fee as u128)
+        .checked_mul(protocol.fee_tokens_per_lamport as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(PRECISION)
+        .ok_or(ErrorCode::Overflow)?;
+    let token_amount = u64::try_from(token_amount).map_err(|_| ErrorCode::CastOverflow)?;
+    // Waiving a SOL fee for zero tokens would make small trades free
+    require!(fee == 0 || token_amount > 0, ErrorCode::FeeTokenAmountTooSmall);
+
+    if token_amount > 0 {
+        token_interface::transfer_checked(
+            CpiContext::new(
+                token_program.to_account_info(),
+                TransferChecked {
+                    from: fee_token_account.to_account_info(),
+                    mint: fee_token_mint.to_account_info(),
+                    to: fee_collector.to_account_info(),
+                    authority: user.to_account_info(),
+                },
+            ),
+            token_amount,
+            fee_token_mint.decimals,
+        )?;
+    }
+
+    emit!(FeePaidInToken {
+        market,
+        user: user.key(),
+        sol_fee_waived: fee,
+        token_amount,
+    });
+
+    Ok(())
+}
+
+/// Close fee rate after holding-time decay: the open rate, falling linearly
+/// to the market's floor once the position has been held
+/// `close_fee_decay_seconds`. A floor above the open rate never raises it.
+fn close_fee_bps(market: &Market, position: &Position, now: i64) -> u64 {
+    let open_bps = position.open_fee_bps;
+    let decay = market.close_fee_decay_seconds;
+    if decay <= 0 {
+        return open_bps;
+    }
+    let floor = market.close_fee_floor_bps.min(open_bps);
+    let held = now.saturating_sub(position.opened_at).clamp(0, decay);
+    let reduction = (open_bps - floor) as u128 * held as u128 / decay as u128;
+    open_bps - reduction as u64
+}
+
+/// The close fee only counts as collected to the extent the position's
+/// collateral plus PnL could cover it; a wiped-out position pays nothing.
+fn realized_close_fee(collateral: u64, pnl: i64, close_fee: u64) -> u64 {
+    let gross = collateral as i64 + pnl;
+    if gross <= 0 {
+        0
+    } else {
+        (gross as u64).min(close_fee)
+    }
+}
+
+/// Running realized PnL after settling part or all of a position: the payout
+/// less the collateral that part released, so fees and liquidation rewards
+/// are already netted out.
+fn add_realized_pnl(realized: i64, payout: u64, collateral: u64) -> i64 {
+    realized.saturating_add(payout as i64 - collateral as i64)
+}
+
+fn mul_div(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    let result = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(denominator as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(result).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Protocol fee of `fee_bps` on `amount`, rounded up.
+///
+/// Rounding convention for settlement: fees round up and everything paid to
+/// a trader rounds down (`payout` is built from exact swap fills minus the
+/// rounded-up fee), so truncation never moves lamports from the vault to
+/// users. Splits between two users, such as the liquidator reward against the
+/// owner's remainder, are protocol-neutral and simply truncate.
+fn protocol_fee(amount: u64, fee_bps: u64) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .div_ceil(BPS_DENOMINATOR as u128);
+    u64::try_from(fee).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Market size limits in lamports. Without a SOL/USD oracle they are stored
+/// in lamports already; with one they are micro-USD converted at its price.
+fn position_size_limits(
+    protocol: &Protocol,
+    market: &Market,
+    oracle: Option<&AccountInfo>,
+    now: i64,
+) -> Result<(u64, u64)> {
+    if protocol.sol_usd_oracle == Pubkey::default() {
+        return Ok((market.max_position_size, market.min_position_size));
+    }
+    let oracle = oracle.ok_or(ErrorCode::OracleRequired)?;
+    let (price, expo) = read_sol_usd_price(oracle, now)?;
+    Ok((
+        usd_to_lamports(market.max_position_size, price, expo)?,
+        usd_to_lamports(market.min_position_size, price, expo)?,
+    ))
+}
+
+/// SOL/USD price and exponent from a Pyth `PriceUpdateV2` account, rejecting
+/// accounts the Pyth receiver doesn't own, other feeds, non-positive prices
+/// and updates older than `MAX_ORACLE_AGE`.
+fn read_sol_usd_price(oracle: &AccountInfo, now: i64) -> Result<(u64, i32)> {
+    require!(oracle.owner == &PYTH_RECEIVER_PROGRAM_ID, ErrorCode::InvalidOracle);
+    let data = oracle.try_borrow_data()?;
+    require!(
+        data.len() > PRICE_UPDATE_VERIFICATION_OFFSET && data[..8] == PRICE_UPDATE_DISCRIMINATOR,
+        ErrorCode::InvalidOracle
+    );
+    let message = match data[PRICE_UPDATE_VERIFICATION_OFFSET] {
+        0 => PRICE_UPDATE_VERIFICATION_OFFSET + 2,
+        1 => PRICE_UPDATE_VERIFICATION_OFFSET + 1,
+        _ => return err!(ErrorCode::InvalidOracle),
+    };
+    require!(data.len() >= message + PRICE_MESSAGE_LEN, ErrorCode::InvalidOracle);
+    require!(data[message..message + 32] == SOL_USD_FEED_ID, ErrorCode::InvalidOracle);
+
+    let price_at = message + 32;
+    let price = i64::from_le_bytes(data[price_at..price_at + 8].try_into().unwrap());
+    let expo = i32::from_le_bytes(data[price_at + 16..price_at + 20].try_into().unwrap());
+    let publish_time = i64::from_le_bytes(data[price_at + 20..price_at + 28].try_into().unwrap());
+
+    require!(price > 0 && (-12..=0).contains(&expo), ErrorCode::InvalidOracle);
+    require!(now.saturating_sub(publish_time) <= MAX_ORACLE_AGE, ErrorCode::StaleOracle);
+    Ok((price as u64, expo))
+}
+
+/// Lamports worth `usd` micro-dollars at a SOL/USD price of `price * 10^expo`.
+fn usd_to_lamports(usd: u64, price: u64, expo: i32) -> Result<u64> {
+    // A limit past u64 is as good as none
+    let Some(scaled) = (usd as u128).checked_mul(10u128.pow(WSOL_DECIMALS as u32 + expo.unsigned_abs())) else {
+        return Ok(u64::MAX);
+    };
+    let lamports = scaled
+        .checked_div((price as u128) * USD_UNIT as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    Ok(u64::try_from(lamports).unwrap_or(u64::MAX))
+}
+
+fn read_token_amount(token_account: &AccountInfo) -> Result<u64> {
+    let data = token_account.try_borrow_data()?;
+    require!(data.len() >= TOKEN_AMOUNT_OFFSET + 8, ErrorCode::InvalidPool);
+    Ok(u64::from_le_bytes(
+        data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap()
+    ))
+}
+
+/// Constant-product estimate of the SOL received for selling `tokens_in`,
+/// net of the pumpswap fee.
+fn estimate_sell_output(base_reserve: u64, quote_reserve: u64, tokens_in: u64) -> Result<u64> {
+    let gross = (quote_reserve as u128)
+        .checked_mul(tokens_in as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div((base_reserve as u128).checked_add(tokens_in as u128).ok_or(ErrorCode::Overflow)?)
+        .ok_or(ErrorCode::EmptyPool)?;
+    let net = gross
+        .checked_mul((BPS_DENOMINATOR - PUMPSWAP_FEE_BPS) as u128)
+        .ok_or(ErrorCode::Overflow)?
+        / BPS_DENOMINATOR as u128;
+    Ok(net as u64)
+}
+
+/// Constant-product estimate of the SOL needed to buy exactly `tokens_out`,
+/// including the pumpswap fee. Rounds up.
+fn estimate_buy_input(base_reserve: u64, quote_reserve: u64, tokens_out: u64) -> Result<u64> {
+    require!(tokens_out < base_reserve, ErrorCode::InsufficientLiquidity);
+
+    let remaining_base = (base_reserve - tokens_out) as u128;
+    let net = (quote_reserve as u128)
+        .checked_mul(tokens_out as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .div_ceil(remaining_base);
+    let gross = net
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .div_ceil((BPS_DENOMINATOR - PUMPSWAP_FEE_BPS) as u128);
+    u64::try_from(gross).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Protocol floor on what a long close may receive: the constant-product
+/// estimate for `token_amount`, rescaled from the pool's spot price to the
+/// market's `mark_price`, less `max_close_slippage_bps`. The estimate keeps
+/// the sale's honest price impact, while the mark keeps a sandwich that drags
+/// the spot down inside the transaction from dragging the floor with it.
+/// Before the first mark sample the spot estimate is used as is; zero when
+/// the market sets no floor.
+fn min_close_fill(market: &Market, base_reserve: u64, quote_reserve: u64, token_amount: u64) -> Result<u64> {
+    if market.max_close_slippage_bps == 0 {
+        return Ok(0);
+    }
+    let expected = estimate_sell_output(base_reserve, quote_reserve, token_amount)?;
+    let expected = at_mark_price(market, base_reserve, quote_reserve, expected)?;
+    mul_div(expected, BPS_DENOMINATOR - market.max_close_slippage_bps, BPS_DENOMINATOR)
+}
+
+/// Rescales a constant-product estimate `amount` from the pool's spot price
+/// to the market's `mark_price`, keeping its price impact but not whatever
+/// moved the spot inside the transaction. Unchanged before the first mark
+/// sample.
+fn at_mark_price(market: &Market, base_reserve: u64, quote_reserve: u64, amount: u64) -> Result<u64> {
+    if market.mark_price == 0 {
+        return Ok(amount);
+    }
+    let spot = price_from_amounts(quote_reserve, base_reserve, market)?;
+    if spot == 0 {
+        return Ok(amount);
+    }
+    mul_div(amount, market.mark_price, spot)
+}
+
+/// Lowers a keeper's `min_sol_out` in `SLIPPAGE_WIDEN_STEP_BPS` steps, up to
+/// `max_widen_bps`, until the pool's estimated output for `tokens` meets it.
+/// Returns the bound and how far it was widened; if even the loosest bound
+/// misses, the swap fails on it as it would have on the original.
+fn widen_sell_limit(limit: u64, max_widen_bps: u64, expected: u64) -> Result<(u64, u64)> {
+    let mut bound = limit;
+    let mut widened_bps = 0;
+    while bound > expected && widened_bps < max_widen_bps {
+        widened_bps = (widened_bps + SLIPPAGE_WIDEN_STEP_BPS).min(max_widen_bps);
+        bound = mul_div(limit, BPS_DENOMINATOR - widened_bps, BPS_DENOMINATOR)?;
+    }
+    Ok((bound, widened_bps))
+}
+
+/// Buy-side `widen_sell_limit`: raises a keeper's `max_sol_in` until it
+/// covers the estimated input. A zero limit is already uncapped.
+fn widen_buy_limit(limit: u64, max_widen_bps: u64, expected: u64) -> Result<(u64, u64)> {
+    let mut bound = limit;
+    let mut widened_bps = 0;
+    while limit > 0 && bound < expected && widened_bps < max_widen_bps {
+        widened_bps = (widened_bps + SLIPPAGE_WIDEN_STEP_BPS).min(max_widen_bps);
+        bound = mul_div(limit, BPS_DENOMINATOR + widened_bps, BPS_DENOMINATOR)?;
+    }
+    Ok((bound, widened_bps))
+}
+
+/// Slippage limit a keeper's sell runs with. Zero `max_widen_bps` leaves the
+/// limit untouched; otherwise it is widened against current reserves and
+/// the result emitted.
+fn keeper_sell_limit(
+    position: Pubkey,
+    slippage_limit: u64,
+    max_widen_bps: u64,
+    base_vault: &AccountInfo,
+    quote_vault: &AccountInfo,
+    tokens: u64,
+) -> Result<u64> {
+    if max_widen_bps == 0 {
+        return Ok(slippage_limit);
+    }
+    let expected = estimate_sell_output(read_token_amount(base_vault)?, read_token_amount(quote_vault)?, tokens)?;
+    let (bound, widened_bps) = widen_sell_limit(slippage_limit, max_widen_bps, expected)?;
+    emit!(KeeperSlippageUsed {
+        position,
+        requested_limit: slippage_limit,
+        slippage_limit: bound,
+        widened_bps,
+    });
+    Ok(bound)
+}
+
+/// Buy-side `keeper_sell_limit`.
+fn keeper_buy_limit(
+    position: Pubkey,
+    slippage_limit: u64,
+    max_widen_bps: u64,
+    base_vault: &AccountInfo,
+    quote_vault: &AccountInfo,
+    tokens: u64,
+) -> Result<u64> {
+    if max_widen_bps == 0 || slippage_limit == 0 {
+        return Ok(slippage_limit);
+    }
+    let expected = estimate_buy_input(read_token_amount(base_vault)?, read_token_amount(quote_vault)?, tokens)?;
+    let (bound, widened_bps) = widen_buy_limit(slippage_limit, max_widen_bps, expected)?;
+    emit!(KeeperSlippageUsed {
+        position,
+        requested_limit: slippage_limit,
+        slippage_limit: bound,
+        widened_bps,
+    });
+    Ok(bound)
+}
+
+/// Minimum SOL for a keeper-driven sell of `tokens`. The owner's stored
+/// `close_slippage_bps`, applied to the constant-product estimate valued at
+/// the mark price, is a floor the keeper can tighten but not loosen; a keeper
+/// that drags the pool down first doesn't drag the floor with it. Without one
+/// the keeper's `slippage_limit` is used as given.
+fn close_sell_limit(
+    slippage_limit: u64,
+    close_slippage_bps: u64,
+    market: &Market,
+    base_vault: &AccountInfo,
+    quote_vault: &AccountInfo,
+    tokens: u64,
+) -> Result<u64> {
+    if close_slippage_bps == 0 {
+        return Ok(slippage_limit);
+    }
+    let (base_reserve, quote_reserve) = (read_token_amount(base_vault)?, read_token_amount(quote_vault)?);
+    let expected = estimate_sell_output(base_reserve, quote_reserve, tokens)?;
+    let expected = at_mark_price(market, base_reserve, quote_reserve, expected)?;
+    let owner_floor = mul_div(expected, BPS_DENOMINATOR - close_slippage_bps, BPS_DENOMINATOR)?;
+    Ok(slippage_limit.max(owner_floor))
+}
+
+/// Maximum SOL for a keeper-driven buy of `tokens`, mirroring
+/// `close_sell_limit`: the owner's bound caps whatever the keeper passes.
+fn close_buy_limit(
+    slippage_limit: u64,
+    close_slippage_bps: u64,
+    market: &Market,
+    base_vault: &AccountInfo,
+    quote_vault: &AccountInfo,
+    tokens: u64,
+) -> Result<u64> {
+    if close_slippage_bps == 0 {
+        return Ok(slippage_limit);
+    }
+    let (base_reserve, quote_reserve) = (read_token_amount(base_vault)?, read_token_amount(quote_vault)?);
+    let expected = estimate_buy_input(base_reserve, quote_reserve, tokens)?;
+    let expected = at_mark_price(market, base_reserve, quote_reserve, expected)?;
+    let owner_cap = mul_div(expected, BPS_DENOMINATOR + close_slippage_bps, BPS_DENOMINATOR)?;
+    Ok(if slippage_limit == 0 { owner_cap } else { slippage_limit.min(owner_cap) })
+}
+
+/// Credits a user's withdrawable balance and the protocol-wide total that
+/// `check_vault_backing` compares against the vault.
+fn credit_balance(protocol: &mut Protocol, user_account: &mut UserAccount, amount: u64) -> Result<()> {
+    user_account.balance = user_account.balance.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+    protocol.total_user_balances = protocol.total_user_balances.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+fn debit_balance(protocol: &mut Protocol, user_account: &mut UserAccount, amount: u64) -> Result<()> {
+    user_account.balance = user_account.balance.checked_sub(amount).ok_or(ErrorCode::Overflow)?;
+    protocol.total_user_balances = sub_tracked_total(protocol.total_user_balances, amount)?;
+    Ok(())
+}
+
+/// Adds a short's collateral to its market's and the protocol's totals.
+fn add_short_collateral(protocol: &mut Protocol, market: &mut Market, amount: u64) -> Result<()> {
+    market.total_short_collateral = market.total_short_collateral.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+    protocol.total_short_collateral = protocol.total_short_collateral.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Releases a short's collateral from the protocol's total and, while the
+/// market still exists, from the market's.
+fn release_short_collateral(protocol: &mut Protocol, market: Option<&mut Market>, amount: u64) -> Result<()> {
+    if let Some(market) = market {
+        market.total_short_collateral = market.total_short_collateral.saturating_sub(amount);
+    }
+    protocol.total_short_collateral = sub_tracked_total(protocol.total_short_collateral, amount)?;
+    Ok(())
+}
+
+/// Subtracts from a protocol-wide total that `check_vault_backing` reads.
+/// With `invariant-checks` an underflow means the total drifted from the
+/// accounts it sums and fails the instruction; otherwise it saturates.
+fn sub_tracked_total(total: u64, amount: u64) -> Result<u64> {
+    #[cfg(feature = "invariant-checks")]
+    let total = total.checked_sub(amount).ok_or(ErrorCode::Overflow)?;
+    #[cfg(not(feature = "invariant-checks"))]
+    let total = total.saturating_sub(amount);
+    Ok(total)
+}
+
+/// With the `invariant-checks` feature, fails the instruction if the vault
+/// can no longer cover every user's withdrawable balance plus open shorts'
+/// collateral, which stays in the vault as SOL next to the sale proceeds.
+/// Longs' collateral is left out: it is spent on the open's buy and sits in
+/// the token vault as tokens, not lamports. Leveraged longs also spend vault
+/// SOL beyond their collateral, so this only holds while the vault carries
+/// enough liquidity; it is meant to catch accounting drift in tests rather
+/// than guard production.
+#[cfg(feature = "invariant-checks")]
+fn check_vault_backing(protocol_vault: &AccountInfo, protocol: &Protocol) -> Result<()> {
+    let owed = protocol.total_user_balances
+        .checked_add(protocol.total_short_collateral)
+        .ok_or(ErrorCode::Overflow)?;
+    require!(protocol_vault.lamports() >= owed, ErrorCode::VaultBackingViolated);
+    Ok(())
+}
+
+#[cfg(not(feature = "invariant-checks"))]
+fn check_vault_backing(_protocol_vault: &AccountInfo, _protocol: &Protocol) -> Result<()> {
+    Ok(())
+}
+
+/// With the `invariant-checks` feature, fails the instruction if the token
+/// vault holds less than the lenders' unborrowed deposits plus the protocol
+/// reserves. Open longs keep their tokens in the same vault, so the vault
+/// may hold more, never less.
+#[cfg(feature = "invariant-checks")]
+fn check_lending_backing(token_vault: &AccountInfo, lending: &LendingPool) -> Result<()> {
+    let owed = lending.total_deposits
+        .saturating_sub(lending.total_borrowed)
+        .checked_add(lending.protocol_reserves)
+        .ok_or(ErrorCode::Overflow)?;
+    require!(read_token_amount(token_vault)? >= owed, ErrorCode::LendingBackingViolated);
+    Ok(())
+}
+
+#[cfg(not(feature = "invariant-checks"))]
+fn check_lending_backing(_token_vault: &AccountInfo, _lending: &LendingPool) -> Result<()> {
+    Ok(())
+}
+
+/// Whether `key` is `position`'s PDA under `owner` and `market`. Positions
+/// are keyed by side, `["position", owner, market, is_long]`; ones opened
+/// before that live at `["position", owner, market]` and are still accepted
+/// where a position is closed, liquidated or force-closed, so they can be
+/// wound down without a migration. `position.bump` is whichever bump the
+/// account was created with.
+fn is_position_pda(key: &Pubkey, owner: &Pubkey, market: &Pubkey, position: &Position) -> bool {
+    let side = [position.is_long as u8];
+    let bump = [position.bump];
+    let keyed_by_side: &[&[u8]] = &[b"position", owner.as_ref(), market.as_ref(), &side, &bump];
+    let legacy: &[&[u8]] = &[b"position", owner.as_ref(), market.as_ref(), &bump];
+    [keyed_by_side, legacy]
+        .iter()
+        .any(|seeds| Pubkey::create_program_address(seeds, &crate::ID).is_ok_and(|pda| pda == *key))
+}
+
+/// Records that the user holds a position in `market`. Positions are keyed by
+/// `["position", user, market, is_long]`, so the list is enough to derive
+/// every open position PDA without scanning program accounts; a market held
+/// on both sides as a hedge is listed once per side.
+fn register_active_market(user_account: &mut UserAccount, market: Pubkey) -> Result<()> {
+    require!(
+        user_account.active_markets.len() < MAX_ACTIVE_POSITIONS,
+        ErrorCode::TooManyPositions
+    );
+    user_account.active_markets.push(market);
+    Ok(())
+}
+
+/// Drops one entry for `market`, leaving the other side of a hedge listed.
+fn unregister_active_market(user_account: &mut UserAccount, market: Pubkey) {
+    if let Some(index) = user_account.active_markets.iter().position(|m| *m == market) {
+        user_account.active_markets.remove(index);
+    }
+}
+
+/// Moves lamports out of the protocol vault by direct lamport mutation,
+/// refusing to dip below the vault's balance and checking that the two sides
+/// of the move conserve the total.
+fn transfer_vault_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> Result<()> {
+    let from_before = from.lamports();
+    let to_before = to.lamports();
+    require!(from_before >= amount, ErrorCode::InsufficientVaultLamports);
+
+    let from_after = from_before.checked_sub(amount).ok_or(ErrorCode::Overflow)?;
+    let to_after = to_before.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+
+    **from.try_borrow_mut_lamports()? = from_after;
+    **to.try_borrow_mut_lamports()? = to_after;
+
+    require!(
+        (from_after as u128) + (to_after as u128) == (from_before as u128) + (to_before as u128),
+        ErrorCode::Overflow
+    );
+
+    Ok(())
+}
+
+/// Adds `amount` lamports, already in the vault, to what lenders can claim,
+/// pro rata to shares.
+fn distribute_to_lenders(lending: &mut LendingPool, amount: u64) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    require!(lending.total_shares > 0, ErrorCode::InsufficientLiquidity);
+    let per_share = (amount as u128)
+        .checked_mul(PRECISION)
+        .ok_or(ErrorCode::Overflow)?
+        / lending.total_shares as u128;
+    lending.fee_per_share = lending.fee_per_share.checked_add(per_share).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Fees `shares` have accrued since `fee_per_share` started, in lamports.
+fn lender_fee_debt(lending: &LendingPool, shares: u64) -> u128 {
+    (shares as u128) * lending.fee_per_share / PRECISION
+}
+
+/// Moves the fees a lender's current shares earned since their last
+/// checkpoint into `unclaimed_fees`. Callers reset `fee_debt` once the share
+/// count is final.
+fn settle_lender_fees(lending: &LendingPool, lender: &mut LenderPosition) -> Result<()> {
+    let pending = lender_fee_debt(lending, lender.shares).saturating_sub(lender.fee_debt);
+    let pending = u64::try_from(pending).map_err(|_| ErrorCode::CastOverflow)?;
+    lender.unclaimed_fees = lender.unclaimed_fees.checked_add(pending).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Books `amount` tokens, already in the token vault, as a deposit minting
+/// `shares` to `lender`, settling the lender's fees at the old share count.
+/// Restarts the lender's withdrawal fee decay.
+fn credit_lending_deposit(
+    lending: &mut LendingPool,
+    lending_key: Pubkey,
+    lender: &mut LenderPosition,
+    owner: Pubkey,
+    amount: u64,
+    shares: u64,
+    now: i64,
+) -> Result<()> {
+    lending.total_deposits = lending.total_deposits.checked_add(amount).ok_or(ErrorCode::Overflow)?;
+    lending.total_shares = lending.total_shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
+
+    lender.owner = owner;
+    lender.lending_pool = lending_key;
+    settle_lender_fees(lending, lender)?;
+    lender.shares = lender.shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    lender.fee_debt = lender_fee_debt(lending, lender.shares);
+    lender.last_deposit_ts = now;
+    Ok(())
+}
+
+/// Withdrawal fee in bps for `lender` at `now`: the pool's full rate right
+/// after a deposit, decaying linearly to zero over the decay period.
+fn withdrawal_fee_bps(lending: &LendingPool, lender: &LenderPosition, now: i64) -> u64 {
+    let decay = lending.withdrawal_fee_decay_seconds;
+    if decay <= 0 {
+        return 0;
+    }
+    let held = now.saturating_sub(lender.last_deposit_ts).clamp(0, decay);
+    let remaining = (decay - held) as u128;
+    (lending.withdrawal_fee_bps as u128 * remaining / decay as u128) as u64
+}
+
+/// Token vault balance not owed to lenders or held as protocol reserves.
+/// With no positions open nothing else has a claim on the vault, so this is
+/// the swap rounding residual.
+fn protocol_owned_tokens(vault_amount: u64, lending: &LendingPool) -> u64 {
+    let lender_tokens = lending.total_deposits.saturating_sub(lending.total_borrowed);
+    vault_amount
+        .saturating_sub(lender_tokens)
+        .saturating_sub(lending.protocol_reserves)
+}
+
+/// Shares minted for depositing `amount` tokens. The first deposit mints 1:1.
+fn lending_shares_for_tokens(lending: &LendingPool, amount: u64) -> Result<u64> {
+    if lending.total_deposits == 0 {
+        return Ok(amount);
+    }
+    let shares = (amount as u128)
+        .checked_mul(lending.total_shares as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(lending.total_deposits as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(shares).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Tokens redeemed for burning `shares`, rounded down.
+fn lending_tokens_for_shares(lending: &LendingPool, shares: u64) -> Result<u64> {
+    let tokens = (shares as u128)
+        .checked_mul(lending.total_deposits as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(lending.total_shares as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(tokens).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Simple (non-compounding) borrow interest, in tokens, owed on `borrowed`
+/// since `since` at an annualized `rate_bps`.
+fn accrued_interest(borrowed: u64, rate_bps: u64, since: i64, now: i64) -> Result<u64> {
+    let elapsed = now.saturating_sub(since).max(0) as u128;
+    let interest = (borrowed as u128)
+        .checked_mul(rate_bps as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_mul(elapsed)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div((BPS_DENOMINATOR as u128) * (SECONDS_PER_YEAR as u128))
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(interest).map_err(|_| ErrorCode::CastOverflow.into())
+}
+
+/// Limits `interest` to `max_interest_bps` of `borrowed`. Zero means uncapped.
+fn cap_interest(interest: u64, borrowed: u64, max_interest_bps: u64) -> Result<u64> {
+    if max_interest_bps == 0 {
+        return Ok(interest);
+    }
+    Ok(interest.min(mul_div(borrowed, max_interest_bps, BPS_DENOMINATOR)?))
+}
+
+/// Interest a short owes on settlement, bounded by the pool's
+/// `max_interest_bps`. Emits `InterestCapped` when the bound bites so the
+/// forgone interest is visible to lenders.
+fn capped_interest(
+    lending: &LendingPool,
+    position: Pubkey,
+    borrowed: u64,
+    since: i64,
+    now: i64,
+) -> Result<u64> {
+    let accrued = accrued_interest(borrowed, lending.borrow_rate_bps, since, now)?;
+    let interest = cap_interest(accrued, borrowed, lending.max_interest_bps)?;
+
+    if interest < accrued {
+        emit!(InterestCapped {
+            market: lending.market,
+            position,
+            accrued,
+            charged: interest,
+        });
+    }
+
+    Ok(interest)
+}
+
+/// Folds interest bought back on a short close into the pool's deposits,
+/// raising the value of every lender share. The `reserve_factor_bps` cut is
+/// kept as protocol reserves instead; it is denominated in the pool's token,
+/// so it is tracked on the pool rather than in the protocol's SOL counters.
+fn settle_interest(lending: &mut LendingPool, interest: u64) -> Result<()> {
+    if interest == 0 {
+        return Ok(());
+    }
+
+    let reserve = mul_div(interest, lending.reserve_factor_bps, BPS_DENOMINATOR)?;
+    let to_lenders = interest - reserve;
+
+    lending.total_deposits = lending.total_deposits.checked_add(to_lenders).ok_or(ErrorCode::Overflow)?;
+    lending.protocol_reserves = lending.protocol_reserves.checked_add(reserve).ok_or(ErrorCode::Overflow)?;
+    lending.cumulative_interest_collected = lending.cumulative_interest_collected
+        .checked_add(interest).ok_or(ErrorCode::Overflow)?;
+
+    emit!(InterestCollected {
+        market: lending.market,
+        interest,
+        reserve,
+        cumulative_interest_collected: lending.cumulative_interest_collected,
+    });
+
+    Ok(())
+}
+
+/// Splits what is left of a liquidated position into the liquidator's reward
+/// and the owner's share. Dust remainders go entirely to the liquidator, and
+/// otherwise the bps reward is lifted to `min_liquidator_reward` so small
+/// positions stay worth liquidating.
+fn calc_liquidation_split(protocol: &Protocol, remaining: u64) -> (u64, u64) {
+    let reward = if remaining <= protocol.liquidation_dust_threshold {
+        remaining
+    } else {
+        (remaining * LIQUIDATOR_REWARD_BPS / BPS_DENOMINATOR)
+            .max(protocol.min_liquidator_reward.min(remaining))
+    };
+    (reward, remaining.saturating_sub(reward))
+}
+
+/// True once the pool's WSOL reserve has fallen below `POOL_COLLAPSE_BPS` of
+/// the reserve recorded when the position opened. Positions without a
+/// snapshot never count as collapsed.
+fn is_pool_collapsed(liquidity_at_open: u64, current_liquidity: u64) -> bool {
+    (current_liquidity as u128) * (BPS_DENOMINATOR as u128)
+        < (liquidity_at_open as u128) * (POOL_COLLAPSE_BPS as u128)
+}
+
+/// A long's tokens valued at the market's `mark_price`, or at spot before the
+/// first mark sample, for settling without a swap once its pool has
+/// collapsed. The mark lags a rug, so the trader isn't paid the dumped price.
+fn long_value_at_mark(market: &Market, token_amount: u64, base_reserve: u64, quote_reserve: u64) -> Result<u64> {
+    let price = if market.mark_price > 0 {
+        market.mark_price
+    } else {
+        price_from_amounts(quote_reserve, base_reserve, market)?
+    };
+    quote_for_tokens(token_amount, price, market)
+}
+
+/// Leaves a settled long's tokens in the token vault, owed to the protocol
+/// until `sweep_token_residual` sells them.
+fn keep_unsold_long_tokens(market: &mut Market, token_amount: u64) -> Result<()> {
+    market.unsold_long_tokens = market.unsold_long_tokens
+        .checked_add(token_amount).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Adds `price` weighted by the seconds since the previous sample to the
+/// market's cumulative price and moves the mark price toward it. The first
+/// sample only starts the clock and seeds the mark.
+fn record_price_sample(market: &mut Market, market_key: Pubkey, price: u64, now: i64, slot: u64) -> Result<()> {
+    if market.max_price_jump_bps > 0 && price_jumped(market.last_observed_price, price, market.max_price_jump_bps) {
+        emit!(PriceJumpDetected {
+            market: market_key,
+            previous_price: market.last_observed_price,
+            price,
+            previous_slot: market.last_observed_slot,
+            slot,
+        });
+    }
+    if slot != market.last_observed_slot {
+        market.prior_slot_price = market.last_observed_price;
+    }
+    market.last_observed_price = price;
+    market.last_observed_slot = slot;
+
+    if market.last_price_update > 0 {
+        let elapsed = now.saturating_sub(market.last_price_update).max(0);
+        let weighted = (price as u128).checked_mul(elapsed as u128).ok_or(ErrorCode::Overflow)?;
+        market.cumulative_price = market.cumulative_price
+            .checked_add(weighted).ok_or(ErrorCode::Overflow)?;
+        market.mark_price = next_mark_price(market.mark_price, price, elapsed)?;
+    } else {
+        market.mark_price = price;
+    }
+    market.last_price_update = now;
+
+    emit!(PriceSampled {
+        market: market_key,
+        price,
+        mark_price: market.mark_price,
+        cumulative_price: market.cumulative_price,
+        timestamp: now,
+    });
+
+    Ok(())
+}
+
+/// True when `price` is more than `max_jump_bps` away from `previous`. A
+/// market that has never been sampled has nothing to jump from.
+fn price_jumped(previous: u64, price: u64, max_jump_bps: u64) -> bool {
+    previous > 0
+        && (price.abs_diff(previous) as u128) * (BPS_DENOMINATOR as u128)
+            > (previous as u128) * (max_jump_bps as u128)
+}
+
+/// Refuses a guarded open while the pool price has jumped past the market's
+/// `max_price_jump_bps` from the last sample taken in an earlier slot.
+/// Samples from the current slot don't count, so a price can't be moved and
+/// confirmed in one transaction; calling `poke_price` and retrying in a later
+/// slot confirms a move that holds.
+fn check_price_jump(market: &Market, price: u64, position_size: u64, slot: u64) -> Result<()> {
+    if market.max_price_jump_bps == 0 || position_size < market.price_jump_guard_size {
+        return Ok(());
+    }
+    let reference = if market.last_observed_slot < slot {
+        market.last_observed_price
+    } else {
+        market.prior_slot_price
+    };
+    require!(
+        !price_jumped(reference, price, market.max_price_jump_bps),
+        ErrorCode::PriceJumpUnconfirmed
+    );
+    Ok(())
+}
+
+/// Moves `mark` toward `price` by `elapsed / MARK_PRICE_WINDOW`, with elapsed
+/// capped at `MARK_PRICE_MAX_ELAPSED` so the previous mark always keeps most
+/// of its weight. A price held for a single block barely moves it.
+fn next_mark_price(mark: u64, price: u64, elapsed: i64) -> Result<u64> {
+    let weight = elapsed.clamp(0, MARK_PRICE_MAX_ELAPSED) as u64;
+    if price >= mark {
+        mark.checked_add(mul_div(price - mark, weight, MARK_PRICE_WINDOW as u64)?).ok_or(ErrorCode::Overflow.into())
+    } else {
+        Ok(mark - mul_div(mark - price, weight, MARK_PRICE_WINDOW as u64)?)
+    }
+}
+
+/// Whether a position is past `liquidation_price` at both the live pool price
+/// and the market's mark price, so moving the pool for one block can't by
+/// itself trigger a liquidation. Markets that were never sampled have no mark
+/// and use the live price alone.
+fn is_liquidatable(position: &Position, market: &Market, current_price: u64, liquidation_price: u64) -> bool {
+    let (price, _) = liquidation_decision_price(position, market, current_price);
+    if position.is_long {
+        price <= liquidation_price
+    } else {
+        price >= liquidation_price
+    }
+}
+
+/// The less aggressive of the live price and the mark for `position`: the
+/// higher for a long, the lower for a short. Being past `liquidation_price`
+/// at this price is the same as being past it at both. Returns whether the
+/// mark was the one that governed.
+fn liquidation_decision_price(position: &Position, market: &Market, current_price: u64) -> (u64, bool) {
+    let mark = market.mark_price;
+    let mark_governs = mark > 0 && if position.is_long { mark > current_price } else { mark < current_price };
+    if mark_governs {
+        (mark, true)
+    } else {
+        (current_price, false)
+    }
+}
+
+/// `recent_volume` decayed linearly by the time since it was last updated,
+/// reaching zero after `VOLUME_DECAY_WINDOW`.
+fn decayed_volume(user_account: &UserAccount, now: i64) -> u64 {
+    let elapsed = now.saturating_sub(user_account.volume_updated_at).max(0);
+    if elapsed >= VOLUME_DECAY_WINDOW {
+        return 0;
+    }
+    ((user_account.recent_volume as u128) * ((VOLUME_DECAY_WINDOW - elapsed) as u128)
+        / VOLUME_DECAY_WINDOW as u128) as u64
+}
+
+/// Adds a trade's notional to the user's decayed volume.
+fn record_volume(user_account: &mut UserAccount, notional: u64, now: i64) -> Result<()> {
+    user_account.recent_volume = decayed_volume(user_account, now)
+        .checked_add(notional).ok_or(ErrorCode::Overflow)?;
+    user_account.volume_updated_at = now;
+    Ok(())
+}
+
+fn fee_bps_for_volume(volume: u64) -> u64 {
+    FEE_TIERS
+        .iter()
+        .find(|(min_volume, _)| volume >= *min_volume)
+        .map_or(PROTOCOL_FEE_BPS, |(_, fee_bps)| *fee_bps)
+}
+
+/// Phase two of a two-phase close. Positions under the market's
+/// `two_phase_close_size` pass straight through. Larger ones need a
+/// `request_close` at least `close_delay` seconds old. For the next
+/// `CLOSE_TOLERANCE_WINDOW` seconds the pool price must not have moved against
+/// the owner by more than `close_price_tolerance_bps` since the snapshot: down
+/// for a long's sell, up for a short's buyback. After that the close is no
+/// longer held to the snapshot.
+fn check_two_phase_close(market: &Market, position: &Position, current_price: u64, now: i64) -> Result<()> {
+    if market.two_phase_close_size == 0 || position.position_size_sol < market.two_phase_close_size {
+        return Ok(());
+    }
+
+    require!(position.close_requested_at > 0, ErrorCode::CloseNotRequested);
+    let executable_at = position.close_requested_at.saturating_add(market.close_delay);
+    require!(now >= executable_at, ErrorCode::CloseDelayPending);
+    if now >= executable_at.saturating_add(CLOSE_TOLERANCE_WINDOW) {
+        return Ok(());
+    }
+
+    let tolerance = mul_div(position.close_request_price, market.close_price_tolerance_bps, BPS_DENOMINATOR)?;
+    let within = if position.is_long {
+        current_price >= position.close_request_price.saturating_sub(tolerance)
+    } else {
+        current_price <= position.close_request_price.saturating_add(tolerance)
+    };
+    require!(within, ErrorCode::PriceMovedSinceCloseRequest);
+
+    Ok(())
+}
+
+/// Audit record for an admin instruction. Every admin-only instruction logs
+/// one per parameter it touches, alongside its own specific event.
+fn log_admin_action(admin: Pubkey, target: Pubkey, param: AdminParam, old_value: u64, new_value: u64) {
+    emit!(AdminAction {
+        admin,
+        target,
+        param,
+        old_value,
+        new_value,
+    });
+}
+
+/// `log_admin_action` for parameters that are keys rather than amounts.
+fn log_admin_key_action(admin: Pubkey, target: Pubkey, param: AdminParam, old_key: Pubkey, new_key: Pubkey) {
+    emit!(AdminKeyAction {
+        admin,
+        target,
+        param,
+        old_key,
+        new_key,
+    });
+}
+
+/// Share of the lending pool's deposits currently borrowed, in bps.
+fn lending_utilization_bps(lending: &LendingPool) -> Result<u64> {
+    if lending.total_deposits == 0 {
+        return Ok(0);
+    }
+    mul_div(lending.total_borrowed, BPS_DENOMINATOR, lending.total_deposits)
+}
+
+/// Longs buy with vault SOL and never borrow from the lending pool; only
+/// shorts carry `borrowed_tokens`. Checked on every long exit so a change to
+/// the long path that starts borrowing can't settle without repaying.
+fn require_long_borrow_free(position: &Position) -> Result<()> {
+    require!(position.borrowed_tokens == 0, ErrorCode::LongHasBorrow);
+    Ok(())
+}
+
+/// Live leverage of a position, `position_size_sol / collateral` rounded to
+/// the nearest whole multiple. Swap slippage and partial closes make the
+/// exact ratio drift from what was requested at open, so a plain floor would
+/// turn a 10x position into 9x.
+fn effective_leverage(position: &Position) -> Result<u64> {
+    require!(position.collateral > 0, ErrorCode::ZeroCollateral);
+    let half = position.collateral / 2;
+    let rounded = position.position_size_sol
+        .checked_add(half).ok_or(ErrorCode::Overflow)?
+        / position.collateral;
+    Ok(rounded)
+}
+
+/// Effective leverage, rejected with `InvalidLeverage` unless it is within
+/// `1..=market.max_leverage`. Every instruction through which the owner sets
+/// a position's size or collateral checks it before returning, so the bound
+/// enforced on the requested leverage can't be slipped by the fill.
+fn validate_leverage(market: &Market, position: &Position) -> Result<u64> {
+    let leverage = effective_leverage(position)?;
+    require!((1..=market.max_leverage).contains(&leverage), ErrorCode::InvalidLeverage);
+    Ok(leverage)
+}
+
+/// Stores the leverage a keeper's partial fill (`liquidate_amount`,
+/// `auto_deleverage`) leaves and recomputes both liquidation prices for it.
+/// The fill only shrinks exposure, so unlike the owner's paths it skips
+/// `validate_leverage`: rounding can nudge it past the cap, and refusing
+/// that would block the de-risking itself.
+fn reprice_after_partial_fill(position: &mut Position) -> Result<()> {
+    let leverage = effective_leverage(position)?.max(1);
+    position.leverage = leverage;
+    if position.is_long {
+        position.liquidation_price = calc_liq_price_long(position.entry_price, leverage, LIQUIDATION_THRESHOLD_BPS)?;
+        position.soft_liquidation_price =
+            calc_liq_price_long(position.entry_price, leverage, SOFT_LIQUIDATION_THRESHOLD_BPS)?;
+    } else {
+        position.liquidation_price = calc_liq_price_short(position.entry_price, leverage, LIQUIDATION_THRESHOLD_BPS)?;
+        position.soft_liquidation_price =
+            calc_liq_price_short(position.entry_price, leverage, SOFT_LIQUIDATION_THRESHOLD_BPS)?;
+    }
+    Ok(())
+}
+
+/// Price at which a long has lost `threshold_bps` of its collateral.
+fn calc_liq_price_long(entry_price: u64, leverage: u64, threshold_bps: u64) -> Result<u64> {
+    let drop_bps = threshold_bps / leverage;
+    let liq = (entry_price as u128)
+        .checked_mul((BPS_DENOMINATOR - drop_bps) as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(liq).map_err(|_| ErrorCode::PriceTruncation.into())
+}
+
+/// Price at which a short has lost `threshold_bps` of its collateral.
+fn calc_liq_price_short(entry_price: u64, leverage: u64, threshold_bps: u64) -> Result<u64> {
+    let rise_bps = threshold_bps / leverage;
+    let liq = (entry_price as u128)
+        .checked_mul((BPS_DENOMINATOR + rise_bps) as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::Overflow)?;
+    u64::try_from(liq).map_err(|_| ErrorCode::PriceTruncation.into())
+}
+
+/// The price a position is liquidatable at right now. Longs use the stored
+/// price. A short owes `borrowed + interest` tokens, so the buyback that
+/// exhausts its collateral happens at
+///   liquidation_price * borrowed / (borrowed + interest)
+/// and the stored price is tightened to that. It never loosens.
+fn effective_liquidation_price(position: &Position, lending: &LendingPool, now: i64) -> Result<u64> {
+    if position.is_long || position.borrowed_tokens == 0 {
+        return Ok(position.liquidation_price);
+    }
+
+    let interest = cap_interest(
+        accrued_interest(position.borrowed_tokens, lending.borrow_rate_bps, position.opened_at, now)?,
+        position.borrowed_tokens,
+        lending.max_interest_bps,
+    )?;
+    let owed = position.borrowed_tokens.checked_add(interest).ok_or(ErrorCode::Overflow)?;
+    let adjusted = mul_div(position.liquidation_price, position.borrowed_tokens, owed)?;
+
+    Ok(adjusted.min(position.liquidation_price))
+}
+
+/// Moves `sol_amount` lamports into the WSOL vault and syncs it, returning the
+/// synced WSOL balance to measure the swap against.
+///
+/// `sync_native` credits every native lamport above rent, so any residual
+/// lamports left in the vault by an earlier operation get wrapped here too.
+/// Those are folded into the returned baseline rather than being counted as
+/// part of this swap, and callers cap the spend at `sol_amount`.
+fn wrap_sol_into_vault<'info>(
+    protocol_vault: &AccountInfo<'info>,
+    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
+    quote_token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    signer_seeds: &[&[&[u8]]],
+    sol_amount: u64,
+) -> Result<u64> {
+    let wsol_vault_info = wsol_vault.to_account_info();
+    let wsol_pre = read_token_amount(&wsol_vault_info)?;
+
+    anchor_lang::system_program::transfer(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            anchor_lang::system_program::Transfer {
+                from: protocol_vault.to_account_info(),
+                to: wsol_vault_info.clone(),
+            },
+            signer_seeds,
+        ),
+        sol_amount,
+    )?;
+
+    token::sync_native(
+        CpiContext::new(
+            quote_token_program.to_account_info(),
+            SyncNative {
+                account: wsol_vault_info.clone(),
+            },
+        ),
+    )?;
+
+    let wsol_synced = read_token_amount(&wsol_vault_info)?;
+    let synced_delta = wsol_synced.checked_sub(wsol_pre).ok_or(ErrorCode::WsolSyncMismatch)?;
+    require!(synced_delta >= sol_amount, ErrorCode::WsolSyncMismatch);
+
+    Ok(wsol_synced)
+}
+
+/// Pumpswap pays its protocol and coin-creator fees into two quote-mint ATAs
+/// the caller passes in remaining_accounts. The program never creates them,
+/// so a missing one would otherwise surface as an opaque CPI failure.
+fn require_pumpswap_atas<'info>(
+    protocol_fee_recipient_ata: &AccountInfo<'info>,
+    coin_creator_vault_ata: &AccountInfo<'info>,
+    quote_mint: &AccountInfo<'info>,
+    quote_token_program: &AccountInfo<'info>,
+) -> Result<()> {
+    for ata in [protocol_fee_recipient_ata, coin_creator_vault_ata] {
+        require!(ata.owner == quote_token_program.key, ErrorCode::PumpswapAtaUninitialized);
+        let data = ata.try_borrow_data()?;
+        require!(data.len() >= TOKEN_AMOUNT_OFFSET + 8, ErrorCode::PumpswapAtaUninitialized);
+        require!(data[..32] == quote_mint.key.to_bytes(), ErrorCode::PumpswapAtaUninitialized);
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_buy<'info>(
+    protocol_vault: &AccountInfo<'info>,
+    token_vault: &InterfaceAccount<'info, TokenAccount>,
+    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
+    pumpswap_pool: &AccountInfo<'info>,
+    pool_base_vault: &AccountInfo<'info>,
+    pool_quote_vault: &AccountInfo<'info>,
+    pumpswap_global: &AccountInfo<'info>,
+    token_mint: &AccountInfo<'info>,
+    wsol_mint: &AccountInfo<'info>,
+    protocol_fee_recipient: &AccountInfo<'info>,
+    protocol_fee_recipient_ata: &AccountInfo<'info>,
+    coin_creator_vault_ata: &AccountInfo<'info>,
+    coin_creator_vault_authority: &AccountInfo<'info>,
+    global_volume_accumulator: &AccountInfo<'info>,
+    user_volume_accumulator: &AccountInfo<'info>,
+    fee_config: &AccountInfo<'info>,
+    fee_program: &AccountInfo<'info>,
+    quote_token_program: &Program<'info, Token>,
+    base_token_program: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    associated_token_program: &Program<'info, AssociatedToken>,
+    event_authority: &AccountInfo<'info>,
+    pumpswap_program: &AccountInfo<'info>,
+    vault_bump: u8,
+    sol_amount: u64,
+    min_tokens: u64,
+) -> Result<(u64, u64)> {
+    require_pumpswap_atas(
+        protocol_fee_recipient_ata,
+        coin_creator_vault_ata,
+        wsol_mint,
+        quote_token_program,
+    )?;
+    let vault_bump_slice = &[vault_bump];
+    let vault_seeds: &[&[u8]] = &[b"protocol_vault", vault_bump_slice];
+    let vault_signer_seeds = &[vault_seeds];
+
+    let wsol_before = wrap_sol_into_vault(
+        protocol_vault,
+        wsol_vault,
+        quote_token_program,
+        system_program,
+        vault_signer_seeds,
+        sol_amount,
+    )?;
+
+    let tokens_before = token_vault.amount;
+
+    let mut ix_data = Vec::with_capacity(25);
+    ix_data.extend_from_slice(&BUY_DISCRIMINATOR);
+    ix_data.extend_from_slice(&min_tokens.to_le_bytes());
+    ix_data.extend_from_slice(&sol_amount.to_le_bytes());
+    ix_data.push(0);
+
+    let accounts = vec![
+        AccountMeta::new(pumpswap_pool.key(), false),
+        AccountMeta::new(protocol_vault.key(), true),
+        AccountMeta::new_readonly(pumpswap_global.key(), false),
+        AccountMeta::new_readonly(token_mint.key(), false),
+        AccountMeta::new_readonly(wsol_mint.key(), false),
+        AccountMeta::new(token_vault.key(), false),
+        AccountMeta::new(wsol_vault.key(), false),
+        AccountMeta::new(pool_base_vault.key(), false),
+        AccountMeta::new(pool_quote_vault.key(), false),
+        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
+        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
+        AccountMeta::new_readonly(base_token_program.key(), false),
+        AccountMeta::new_readonly(quote_token_program.key(), false),
+        AccountMeta::new_readonly(system_program.key(), false),
+        AccountMeta::new_readonly(associated_token_program.key(), false),
+        AccountMeta::new_readonly(event_authority.key(), false),
+        AccountMeta::new_readonly(pumpswap_program.key(), false),
+        AccountMeta::new(coin_creator_vault_ata.key(), false),
+        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
+        AccountMeta::new_readonly(global_volume_accumulator.key(), false),
+        AccountMeta::new(user_volume_accumulator.key(), false),
+        AccountMeta::new_readonly(fee_config.key(), false),
+        AccountMeta::new_readonly(fee_program.key(), false),
+    ];
+
+    invoke_signed(
+        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
+        &[
+            pumpswap_pool.to_account_info(),
+            protocol_vault.to_account_info(),
+            pumpswap_global.to_account_info(),
+            token_mint.to_account_info(),
+            wsol_mint.to_account_info(),
+            token_vault.to_account_info(),
+            wsol_vault.to_account_info(),
+            pool_base_vault.to_account_info(),
+            pool_quote_vault.to_account_info(),
+            protocol_fee_recipient.to_account_info(),
+            protocol_fee_recipient_ata.to_account_info(),
+            base_token_program.to_account_info(),
+            quote_token_program.to_account_info(),
+            system_program.to_account_info(),
+            associated_token_program.to_account_info(),
+            event_authority.to_account_info(),
+            pumpswap_program.to_account_info(),
+            coin_creator_vault_ata.to_account_info(),
+            coin_creator_vault_authority.to_account_info(),
+            global_volume_accumulator.to_account_info(),
+            user_volume_accumulator.to_account_info(),
+            fee_config.to_account_info(),
+            fee_program.to_account_info(),
+        ],
+        vault_signer_seeds,
+    )?;
+
+    let token_vault_info = token_vault.to_account_info();
+    let token_vault_data = token_vault_info.try_borrow_data()?;
+    let tokens_after = u64::from_le_bytes(token_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
+    drop(token_vault_data);
+
+    let wsol_vault_info = wsol_vault.to_account_info();
+    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
+    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
+    drop(wsol_vault_data);
+    
+    let tokens_received = tokens_after.checked_sub(tokens_before).ok_or(ErrorCode::SwapFailed)?;
+    let sol_spent = wsol_before.checked_sub(wsol_after).ok_or(ErrorCode::SwapFailed)?;
+    
+    require!(sol_spent <= sol_amount, ErrorCode::SlippageExceeded);
+    require!(tokens_received >= min_tokens, ErrorCode::SlippageExceeded);
+
+    emit!(SwapExecuted {
+        direction: SwapDirection::Buy,
+        pool: pumpswap_pool.key(),
+        amount_in: sol_spent,
+        amount_out: tokens_received,
+    });
+
+    Ok((tokens_received, sol_spent))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_sell<'info>(
+    protocol_vault: &AccountInfo<'info>,
+    token_vault: &InterfaceAccount<'info, TokenAccount>,
+    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
+    pumpswap_pool: &AccountInfo<'info>,
+    pool_base_vault: &AccountInfo<'info>,
+    pool_quote_vault: &AccountInfo<'info>,
+    pumpswap_global: &AccountInfo<'info>,
+    token_mint: &AccountInfo<'info>,
+    wsol_mint: &AccountInfo<'info>,
+    protocol_fee_recipient: &AccountInfo<'info>,
+    protocol_fee_recipient_ata: &AccountInfo<'info>,
+    coin_creator_vault_ata: &AccountInfo<'info>,
+    coin_creator_vault_authority: &AccountInfo<'info>,
+    fee_config: &AccountInfo<'info>,
+    fee_program: &AccountInfo<'info>,
+    quote_token_program: &Program<'info, Token>,
+    base_token_program: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    associated_token_program: &Program<'info, AssociatedToken>,
+    event_authority: &AccountInfo<'info>,
+    pumpswap_program: &AccountInfo<'info>,
+    vault_bump: u8,
+    token_amount: u64,
+    min_sol: u64,
+) -> Result<u64> {
+    require_pumpswap_atas(
+        protocol_fee_recipient_ata,
+        coin_creator_vault_ata,
+        wsol_mint,
+        quote_token_program,
+    )?;
+    let bump_slice = &[vault_bump];
+    let seeds: &[&[u8]] = &[b"protocol_vault", bump_slice];
+    let signer_seeds = &[seeds];
+
+    let wsol_before = wsol_vault.amount;
+
+    let mut ix_data = Vec::with_capacity(24);
+    ix_data.extend_from_slice(&SELL_DISCRIMINATOR);
+    ix_data.extend_from_slice(&token_amount.to_le_bytes());
+    ix_data.extend_from_slice(&min_sol.to_le_bytes());
+
+    let accounts = vec![
+        AccountMeta::new(pumpswap_pool.key(), false),
+        AccountMeta::new(protocol_vault.key(), true),
+        AccountMeta::new_readonly(pumpswap_global.key(), false),
+        AccountMeta::new_readonly(token_mint.key(), false),
+        AccountMeta::new_readonly(wsol_mint.key(), false),
+        AccountMeta::new(token_vault.key(), false),
+        AccountMeta::new(wsol_vault.key(), false),
+        AccountMeta::new(pool_base_vault.key(), false),
+        AccountMeta::new(pool_quote_vault.key(), false),
+        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
+        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
+        AccountMeta::new_readonly(base_token_program.key(), false),
+        AccountMeta::new_readonly(quote_token_program.key(), false),
+        AccountMeta::new_readonly(system_program.key(), false),
+        AccountMeta::new_readonly(associated_token_program.key(), false),
+        AccountMeta::new_readonly(event_authority.key(), false),
+        AccountMeta::new_readonly(pumpswap_program.key(), false),
+        AccountMeta::new(coin_creator_vault_ata.key(), false),
+        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
+        AccountMeta::new_readonly(fee_config.key(), false),
+        AccountMeta::new_readonly(fee_program.key(), false),
+    ];
+
+    invoke_signed(
+        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
+        &[
+            pumpswap_pool.to_account_info(),
+            protocol_vault.to_account_info(),
+            pumpswap_global.to_account_info(),
+            token_mint.to_account_info(),
+            wsol_mint.to_account_info(),
+            token_vault.to_account_info(),
+            wsol_vault.to_account_info(),
+            pool_base_vault.to_account_info(),
+            pool_quote_vault.to_account_info(),
+            protocol_fee_recipient.to_account_info(),
+            protocol_fee_recipient_ata.to_account_info(),
+            base_token_program.to_account_info(),
+            quote_token_program.to_account_info(),
+            system_program.to_account_info(),
+            associated_token_program.to_account_info(),
+            event_authority.to_account_info(),
+            pumpswap_program.to_account_info(),
+            coin_creator_vault_ata.to_account_info(),
+            coin_creator_vault_authority.to_account_info(),
+            fee_config.to_account_info(),
+            fee_program.to_account_info(),
+        ],
+        signer_seeds,
+    )?;
+
+    let wsol_vault_info = wsol_vault.to_account_info();
+    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
+    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
+    drop(wsol_vault_data);
+    
+    let received = wsol_after.checked_sub(wsol_before).ok_or(ErrorCode::SwapFailed)?;
+    require!(received >= min_sol, ErrorCode::SlippageExceeded);
+
+    emit!(SwapExecuted {
+        direction: SwapDirection::Sell,
+        pool: pumpswap_pool.key(),
+        amount_in: token_amount,
+        amount_out: received,
+    });
+
+    Ok(received)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_buy_for_close<'info>(
+    protocol_vault: &AccountInfo<'info>,
+    token_vault: &InterfaceAccount<'info, TokenAccount>,
+    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
+    pumpswap_pool: &AccountInfo<'info>,
+    pool_base_vault: &AccountInfo<'info>,
+    pool_quote_vault: &AccountInfo<'info>,
+    pumpswap_global: &AccountInfo<'info>,
+    token_mint: &AccountInfo<'info>,
+    wsol_mint: &AccountInfo<'info>,
+    protocol_fee_recipient: &AccountInfo<'info>,
+    protocol_fee_recipient_ata: &AccountInfo<'info>,
+    coin_creator_vault_ata: &AccountInfo<'info>,
+    coin_creator_vault_authority: &AccountInfo<'info>,
+    global_volume_accumulator: &AccountInfo<'info>,
+    user_volume_accumulator: &AccountInfo<'info>,
+    fee_config: &AccountInfo<'info>,
+    fee_program: &AccountInfo<'info>,
+    quote_token_program: &Program<'info, Token>,
+    base_token_program: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    associated_token_program: &Program<'info, AssociatedToken>,
+    event_authority: &AccountInfo<'info>,
+    pumpswap_program: &AccountInfo<'info>,
+    vault_bump: u8,
+    tokens_to_buy: u64,
+    max_sol: u64,
+) -> Result<u64> {
+    require_pumpswap_atas(
+        protocol_fee_recipient_ata,
+        coin_creator_vault_ata,
+        wsol_mint,
+        quote_token_program,
+    )?;
+    let bump_slice = &[vault_bump];
+    let seeds: &[&[u8]] = &[b"protocol_vault", bump_slice];
+    let signer_seeds = &[seeds];
+
+    let wsol_before = wrap_sol_into_vault(
+        protocol_vault,
+        wsol_vault,
+        quote_token_program,
+        system_program,
+        signer_seeds,
+        max_sol,
+    )?;
+
+    let mut ix_data = Vec::with_capacity(25);
+    ix_data.extend_from_slice(&BUY_DISCRIMINATOR);
+    ix_data.extend_from_slice(&tokens_to_buy.to_le_bytes());
+    ix_data.extend_from_slice(&max_sol.to_le_bytes());
+    ix_data.push(0);
+
+    let accounts = vec![
+        AccountMeta::new(pumpswap_pool.key(), false),
+        AccountMeta::new(protocol_vault.key(), true),
+        AccountMeta::new_readonly(pumpswap_global.key(), false),
+        AccountMeta::new_readonly(token_mint.key(), false),
+        AccountMeta::new_readonly(wsol_mint.key(), false),
+        AccountMeta::new(token_vault.key(), false),
+        AccountMeta::new(wsol_vault.key(), false),
+        AccountMeta::new(pool_base_vault.key(), false),
+        AccountMeta::new(pool_quote_vault.key(), false),
+        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
+        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
+        AccountMeta::new_readonly(base_token_program.key(), false),
+        AccountMeta::new_readonly(quote_token_program.key(), false),
+        AccountMeta::new_readonly(system_program.key(), false),
+        AccountMeta::new_readonly(associated_token_program.key(), false),
+        AccountMeta::new_readonly(event_authority.key(), false),
+        AccountMeta::new_readonly(pumpswap_program.key(), false),
+        AccountMeta::new(coin_creator_vault_ata.key(), false),
+        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
+        AccountMeta::new_readonly(global_volume_accumulator.key(), false),
+        AccountMeta::new(user_volume_accumulator.key(), false),
+        AccountMeta::new_readonly(fee_config.key(), false),
+        AccountMeta::new_readonly(fee_program.key(), false),
+    ];
+
+    invoke_signed(
+        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
+        &[
+            pumpswap_pool.to_account_info(),
+            protocol_vault.to_account_info(),
+            pumpswap_global.to_account_info(),
+            token_mint.to_account_info(),
+            wsol_mint.to_account_info(),
+            token_vault.to_account_info(),
+            wsol_vault.to_account_info(),
+            pool_base_vault.to_account_info(),
+            pool_quote_vault.to_account_info(),
+            protocol_fee_recipient.to_account_info(),
+            protocol_fee_recipient_ata.to_account_info(),
+            base_token_program.to_account_info(),
+            quote_token_program.to_account_info(),
+            system_program.to_account_info(),
+            associated_token_program.to_account_info(),
+            event_authority.to_account_info(),
+            pumpswap_program.to_account_info(),
+            coin_creator_vault_ata.to_account_info(),
+            coin_creator_vault_authority.to_account_info(),
+            global_volume_accumulator.to_account_info(),
+            user_volume_accumulator.to_account_info(),
+            fee_config.to_account_info(),
+            fee_program.to_account_info(),
+        ],
+        signer_seeds,
+    )?;
+
+    let wsol_vault_info = wsol_vault.to_account_info();
+    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
+    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
+    drop(wsol_vault_data);
+    
+    let spent = wsol_before.checked_sub(wsol_after).ok_or(ErrorCode::SwapFailed)?;
+    require!(spent <= max_sol, ErrorCode::SlippageExceeded);
+
+    emit!(SwapExecuted {
+        direction: SwapDirection::BuyForClose,
+        pool: pumpswap_pool.key(),
+        amount_in: spent,
+        amount_out: tokens_to_buy,
+    });
+
+    Ok(spent)
+}
+
+// ========== Account Contexts ==========
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Protocol::INIT_SPACE,
+        seeds = [b"protocol"],
+        bump,
+    )]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Global vault PDA
+    #[account(mut, seeds = [b"protocol_vault"], bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = protocol_vault,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: WSOL mint
+    #[account(address = WSOL_MINT)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnwrapWsol<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: WSOL mint
+    #[account(address = WSOL_MINT)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarket<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarketPool<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: Current pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub current_pool: AccountInfo<'info>,
+
+    /// CHECK: Current pool base vault, checked against the pool when positions are open
+    pub current_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Current pool quote vault, checked against the pool when positions are open
+    pub current_quote_vault: AccountInfo<'info>,
+
+    /// CHECK: New pumpswap pool, validated in the handler
+    pub new_pool: AccountInfo<'info>,
+
+    /// CHECK: New pool base vault, checked against the pool when positions are open
+    pub new_base_vault: AccountInfo<'info>,
+
+    /// CHECK: New pool quote vault, checked against the pool when positions are open
+    pub new_quote_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocol<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLendingPool<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Account<'info, LendingPool>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeToLenders<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Account<'info, LendingPool>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct SetFeeExempt<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(mut, seeds = [b"user_account", user.as_ref()], bump = user_account.bump)]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct SetWhitelisted<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    #[account(mut, seeds = [b"user_account", user.as_ref()], bump = user_account.bump)]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[derive(Accounts)]
+pub struct CreateWsolVault<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Account<'info, Protocol>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = protocol_vault,
+    )]
+    pub wsol_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: WSOL mint
+    #[account(address = WSOL_MINT)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Account<'info, Protocol>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, close = admin, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, close = admin, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Account<'info, LendingPool>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub token_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ForceClosePosition<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    /// CHECK: Position owner
+    #[account(mut)]
+    pub position_owner: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"user_account", position_owner.key().as_ref()], bump = owner_account.bump)]
+    pub owner_account: Box<Account<'info, UserAccount>>,
+
+    /// CHECK: The position's market; empty once closed, otherwise read and
+    /// written back by the handler
+    #[account(mut, seeds = [b"market", token_mint.key().as_ref()], bump, address = position.market)]
+    pub market: AccountInfo<'info>,
+
+    /// CHECK: The market's lending pool, closed together with it
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump)]
+    pub lending_pool: AccountInfo<'info>,
+
+    #[account(
+        mut, close = position_owner,
+        constraint = is_position_pda(&position.key(), &position_owner.key(), &market.key(), &position)
+            @ ErrorCode::InvalidPositionAddress,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+}
+
+#[derive(Accounts)]
+pub struct SweepTokenResidual<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = market.token_mint)]
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SellProtocolReserves<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump, has_one = admin @ ErrorCode::NotAdmin)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = market.token_mint)]
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    /// Classic SPL or Token-2022; must be owned by `token_program`.
+    #[account(mint::token_program = token_program)]
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init, payer = admin, space = 8 + Market::INIT_SPACE,
+        seeds = [b"market", token_mint.key().as_ref()], bump,
+    )]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(
+        init, payer = admin, space = 8 + LendingPool::INIT_SPACE,
+        seeds = [b"lending_pool", market.key().as_ref()], bump,
+    )]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: Pumpswap pool
+    pub pumpswap_pool: AccountInfo<'info>,
+
+    /// CHECK: Matched against the quote vault recorded in `pumpswap_pool`
+    pub pool_quote_vault: AccountInfo<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateUserAccount<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        init, payer = user, space = 8 + UserAccount::INIT_SPACE,
+        seeds = [b"user_account", user.key().as_ref()], bump,
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Account<'info, Protocol>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(
+        init_if_needed, payer = user, space = 8 + UserAccount::INIT_SPACE,
+        seeds = [b"user_account", user.key().as_ref()], bump,
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Account<'info, Protocol>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_account", user.key().as_ref()],
+        bump = user_account.bump,
+        constraint = user_account.owner == user.key() @ ErrorCode::Unauthorized,
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositToLending<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        init_if_needed, payer = user, space = 8 + LenderPosition::INIT_SPACE,
+        seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()], bump,
+    )]
+    pub lender_position: Box<Account<'info, LenderPosition>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFromLending<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut, seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()],
+        bump = lender_position.bump,
+        constraint = lender_position.owner == user.key() @ ErrorCode::Unauthorized,
+    )]
+    pub lender_position: Box<Account<'info, LenderPosition>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SwapBalanceAndLend<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_account", user.key().as_ref()],
+        bump = user_account.bump,
+        constraint = user_account.owner == user.key() @ ErrorCode::Unauthorized,
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        init_if_needed, payer = user, space = 8 + LenderPosition::INIT_SPACE,
+        seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()], bump,
+    )]
+    pub lender_position: Box<Account<'info, LenderPosition>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = market.token_mint)]
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimLenderFees<'info> {
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut, seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()],
+        bump = lender_position.bump,
+        constraint = lender_position.owner == user.key() @ ErrorCode::Unauthorized,
+    )]
+    pub lender_position: Box<Account<'info, LenderPosition>>,
+
+    #[account(
+        mut,
+        seeds = [b"user_account", user.key().as_ref()],
+        bump = user_account.bump,
+        constraint = user_account.owner == user.key() @ ErrorCode::Unauthorized,
+    )]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(is_long: bool)]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump = user_account.bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Recreated if `unwrap_wsol` closed it, so swaps never depend on a
+    /// separate `create_wsol_vault` call.
+    #[account(
+        init_if_needed, payer = user,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = quote_token_program,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init, payer = user, space = 8 + Position::INIT_SPACE,
+        seeds = [b"position", user.key().as_ref(), market.key().as_ref(), &[is_long as u8]], bump,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    #[account(mut, token::authority = user)]
+    pub fee_token_account: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(mut)]
+    pub fee_collector: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    pub fee_token_mint: Option<Box<InterfaceAccount<'info, Mint>>>,
+
+    /// CHECK: Pyth SOL/USD price update, parsed by `read_sol_usd_price`;
+    /// required while the protocol has one set
+    #[account(address = protocol.sol_usd_oracle @ ErrorCode::InvalidOracle)]
+    pub sol_usd_oracle: Option<AccountInfo<'info>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// CHECK: Position owner
+    #[account(mut)]
+    pub position_owner: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump = user_account.bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed, payer = user,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = quote_token_program,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut, close = position_owner,
+        constraint = is_position_pda(&position.key(), &position_owner.key(), &market.key(), &position)
+            @ ErrorCode::InvalidPositionAddress,
+        constraint = position.owner == user.key() @ ErrorCode::NotPositionOwner,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    #[account(mut, token::authority = user)]
+    pub fee_token_account: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(mut)]
+    pub fee_collector: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    pub fee_token_mint: Option<Box<InterfaceAccount<'info, Mint>>>,
+
+    /// Another user's account to credit the payout to instead of
+    /// `user_account`, for settlement or recovery.
+    #[account(mut, constraint = payout_account.key() != user_account.key() @ ErrorCode::InvalidPayoutAccount)]
+    pub payout_account: Option<Box<Account<'info, UserAccount>>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePortfolio<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump = user_account.bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: WSOL mint
+    #[account(address = WSOL_MINT)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    /// CHECK: Position owner
+    #[account(mut)]
+    pub position_owner: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"user_account", position_owner.key().as_ref()], bump = owner_account.bump)]
+    pub owner_account: Box<Account<'info, UserAccount>>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed, payer = liquidator,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = quote_token_program,
+    )]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut, close = position_owner,
+        constraint = is_position_pda(&position.key(), &position_owner.key(), &market.key(), &position)
+            @ ErrorCode::InvalidPositionAddress,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateAmount<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"user_account", position_owner.key().as_ref()], bump = owner_account.bump)]
+    pub owner_account: Box<Account<'info, UserAccount>>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
+
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
 
-    let wsol_before = wsol_vault.amount;
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    let mut ix_data = Vec::with_capacity(24);
-    ix_data.extend_from_slice(&SELL_DISCRIMINATOR);
-    ix_data.extend_from_slice(&token_amount.to_le_bytes());
-    ix_data.extend_from_slice(&min_sol.to_le_bytes());
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    let accounts = vec![
-        AccountMeta::new(pumpswap_pool.key(), false),
-        AccountMeta::new(protocol_vault.key(), true),
-        AccountMeta::new_readonly(pumpswap_global.key(), false),
-        AccountMeta::new_readonly(token_mint.key(), false),
-        AccountMeta::new_readonly(wsol_mint.key(), false),
-        AccountMeta::new(token_vault.key(), false),
-        AccountMeta::new(wsol_vault.key(), false),
-        AccountMeta::new(pool_base_vault.key(), false),
-        AccountMeta::new(pool_quote_vault.key(), false),
-        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
-        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
-        AccountMeta::new_readonly(base_token_program.key(), false),
-        AccountMeta::new_readonly(quote_token_program.key(), false),
-        AccountMeta::new_readonly(system_program.key(), false),
-        AccountMeta::new_readonly(associated_token_program.key(), false),
-        AccountMeta::new_readonly(event_authority.key(), false),
-        AccountMeta::new_readonly(pumpswap_program.key(), false),
-        AccountMeta::new(coin_creator_vault_ata.key(), false),
-        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
-        AccountMeta::new_readonly(fee_config.key(), false),
-        AccountMeta::new_readonly(fee_program.key(), false),
-    ];
+    #[account(
+        mut,
+        constraint = is_position_pda(&position.key(), &position_owner.key(), &market.key(), &position)
+            @ ErrorCode::InvalidPositionAddress,
+    )]
+    pub position: Box<Account<'info, Position>>,
 
-    invoke_signed(
-        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
-        &[
-            pumpswap_pool.to_account_info(),
-            protocol_vault.to_account_info(),
-            pumpswap_global.to_account_info(),
-            token_mint.to_account_info(),
-            wsol_mint.to_account_info(),
-            token_vault.to_account_info(),
-            wsol_vault.to_account_info(),
-            pool_base_vault.to_account_info(),
-            pool_quote_vault.to_account_info(),
-            protocol_fee_recipient.to_account_info(),
-            protocol_fee_recipient_ata.to_account_info(),
-            base_token_program.to_account_info(),
-            quote_token_program.to_account_info(),
-            system_program.to_account_info(),
-            associated_token_program.to_account_info(),
-            event_authority.to_account_info(),
-            pumpswap_program.to_account_info(),
-            coin_creator_vault_ata.to_account_info(),
-            coin_creator_vault_authority.to_account_info(),
-            fee_config.to_account_info(),
-            fee_program.to_account_info(),
-        ],
-        signer_seeds,
-    )?;
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
 
-    let wsol_vault_info = wsol_vault.to_account_info();
-    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
-    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
-    drop(wsol_vault_data);
-    
-    let received = wsol_after.checked_sub(wsol_before).ok_or(ErrorCode::SwapFailed)?;
-    require!(received >= min_sol, ErrorCode::SlippageExceeded);
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
 
-    Ok(received)
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
 }
 
-#[allow(clippy::too_many_arguments)]
-fn execute_buy_for_close<'info>(
-    protocol_vault: &AccountInfo<'info>,
-    token_vault: &InterfaceAccount<'info, TokenAccount>,
-    wsol_vault: &InterfaceAccount<'info, TokenAccount>,
-    pumpswap_pool: &AccountInfo<'info>,
-    pool_base_vault: &AccountInfo<'info>,
-    pool_quote_vault: &AccountInfo<'info>,
-    pumpswap_global: &AccountInfo<'info>,
-    token_mint: &AccountInfo<'info>,
-    wsol_mint: &AccountInfo<'info>,
-    protocol_fee_recipient: &AccountInfo<'info>,
-    protocol_fee_recipient_ata: &AccountInfo<'info>,
-    coin_creator_vault_ata: &AccountInfo<'info>,
-    coin_creator_vault_authority: &AccountInfo<'info>,
-    global_volume_accumulator: &AccountInfo<'info>,
-    user_volume_accumulator: &AccountInfo<'info>,
-    fee_config: &AccountInfo<'info>,
-    fee_program: &AccountInfo<'info>,
-    quote_token_program: &Program<'info, Token>,
-    base_token_program: &AccountInfo<'info>,
-    system_program: &Program<'info, System>,
-    associated_token_program: &Program<'info, AssociatedToken>,
-    event_authority: &AccountInfo<'info>,
-    pumpswap_program: &AccountInfo<'info>,
-    vault_bump: u8,
-    tokens_to_buy: u64,
-    max_sol: u64,
-) -> Result<u64> {
-    let bump_slice = &[vault_bump];
-    let seeds: &[&[u8]] = &[b"protocol_vault", bump_slice];
-    let signer_seeds = &[seeds];
+#[derive(Accounts)]
+pub struct AutoDeleverage<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
 
-    let wsol_before = wsol_vault.amount;
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
 
-    anchor_lang::system_program::transfer(
-        CpiContext::new_with_signer(
-            system_program.to_account_info(),
-            anchor_lang::system_program::Transfer {
-                from: protocol_vault.to_account_info(),
-                to: wsol_vault.to_account_info(),
-            },
-            signer_seeds,
-        ),
-        max_sol,
-    )?;
+    #[account(mut, seeds = [b"user_account", position_owner.key().as_ref()], bump = owner_account.bump)]
+    pub owner_account: Box<Account<'info, UserAccount>>,
 
-    token::sync_native(
-        CpiContext::new(
-            quote_token_program.to_account_info(),
-            SyncNative {
-                account: wsol_vault.to_account_info(),
-            },
-        ),
-    )?;
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
 
-    let mut ix_data = Vec::with_capacity(25);
-    ix_data.extend_from_slice(&BUY_DISCRIMINATOR);
-    ix_data.extend_from_slice(&tokens_to_buy.to_le_bytes());
-    ix_data.extend_from_slice(&max_sol.to_le_bytes());
-    ix_data.push(0);
+    /// CHECK: Protocol vault
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    pub protocol_vault: AccountInfo<'info>,
 
-    let accounts = vec![
-        AccountMeta::new(pumpswap_pool.key(), false),
-        AccountMeta::new(protocol_vault.key(), true),
-        AccountMeta::new_readonly(pumpswap_global.key(), false),
-        AccountMeta::new_readonly(token_mint.key(), false),
-        AccountMeta::new_readonly(wsol_mint.key(), false),
-        AccountMeta::new(token_vault.key(), false),
-        AccountMeta::new(wsol_vault.key(), false),
-        AccountMeta::new(pool_base_vault.key(), false),
-        AccountMeta::new(pool_quote_vault.key(), false),
-        AccountMeta::new_readonly(protocol_fee_recipient.key(), false),
-        AccountMeta::new(protocol_fee_recipient_ata.key(), false),
-        AccountMeta::new_readonly(base_token_program.key(), false),
-        AccountMeta::new_readonly(quote_token_program.key(), false),
-        AccountMeta::new_readonly(system_program.key(), false),
-        AccountMeta::new_readonly(associated_token_program.key(), false),
-        AccountMeta::new_readonly(event_authority.key(), false),
-        AccountMeta::new_readonly(pumpswap_program.key(), false),
-        AccountMeta::new(coin_creator_vault_ata.key(), false),
-        AccountMeta::new_readonly(coin_creator_vault_authority.key(), false),
-        AccountMeta::new_readonly(global_volume_accumulator.key(), false),
-        AccountMeta::new(user_volume_accumulator.key(), false),
-        AccountMeta::new_readonly(fee_config.key(), false),
-        AccountMeta::new_readonly(fee_program.key(), false),
-    ];
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
 
-    invoke_signed(
-        &Instruction { program_id: PUMPSWAP_PROGRAM_ID, accounts, data: ix_data },
-        &[
-            pumpswap_pool.to_account_info(),
-            protocol_vault.to_account_info(),
-            pumpswap_global.to_account_info(),
-            token_mint.to_account_info(),
-            wsol_mint.to_account_info(),
-            token_vault.to_account_info(),
-            wsol_vault.to_account_info(),
-            pool_base_vault.to_account_info(),
-            pool_quote_vault.to_account_info(),
-            protocol_fee_recipient.to_account_info(),
-            protocol_fee_recipient_ata.to_account_info(),
-            base_token_program.to_account_info(),
-            quote_token_program.to_account_info(),
-            system_program.to_account_info(),
-            associated_token_program.to_account_info(),
-            event_authority.to_account_info(),
-            pumpswap_program.to_account_info(),
-            coin_creator_vault_ata.to_account_info(),
-            coin_creator_vault_authority.to_account_info(),
-            global_volume_accumulator.to_account_info(),
-            user_volume_accumulator.to_account_info(),
-            fee_config.to_account_info(),
-            fee_program.to_account_info(),
-        ],
-        signer_seeds,
-    )?;
+    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
 
-    let wsol_vault_info = wsol_vault.to_account_info();
-    let wsol_vault_data = wsol_vault_info.try_borrow_data()?;
-    let wsol_after = u64::from_le_bytes(wsol_vault_data[TOKEN_AMOUNT_OFFSET..TOKEN_AMOUNT_OFFSET + 8].try_into().unwrap());
-    drop(wsol_vault_data);
-    
-    let spent = wsol_before.checked_add(max_sol).ok_or(ErrorCode::Overflow)?
-        .checked_sub(wsol_after).ok_or(ErrorCode::SwapFailed)?;
-    require!(spent <= max_sol, ErrorCode::SlippageExceeded);
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = protocol_vault,
+        associated_token::token_program = base_token_program,
+    )]
+    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    Ok(spent)
-}
+    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
+    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
 
-// ========== Account Contexts ==========
+    #[account(
+        mut,
+        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    /// CHECK: Market quote mint (WSOL)
+    #[account(address = market.quote_mint)]
+    pub wsol_mint: AccountInfo<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
 
 #[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
+pub struct SetCloseSlippage<'info> {
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
 
     #[account(
-        init,
-        payer = admin,
-        space = 8 + Protocol::INIT_SPACE,
-        seeds = [b"protocol"],
-        bump,
+        mut,
+        seeds = [b"position", user.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
+        constraint = position.owner == user.key() @ ErrorCode::NotPositionOwner,
     )]
-    pub protocol: Box<Account<'info, Protocol>>,
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+pub struct RequestClose<'info> {
+    pub user: Signer<'info>,
 
-    /// CHECK: Global vault PDA
-    #[account(mut, seeds = [b"protocol_vault"], bump)]
-    pub protocol_vault: AccountInfo<'info>,
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
 
     #[account(
-        init,
-        payer = admin,
-        associated_token::mint = wsol_mint,
-        associated_token::authority = protocol_vault,
+        mut,
+        seeds = [b"position", user.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
+        constraint = position.owner == user.key() @ ErrorCode::NotPositionOwner,
     )]
-    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub position: Account<'info, Position>,
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+    /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub pumpswap_pool: AccountInfo<'info>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct UnwrapWsol<'info> {
-    pub admin: Signer<'info>,
+pub struct WarnPosition<'info> {
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin)]
-    pub protocol: Account<'info, Protocol>,
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+    #[account(
+        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
+    )]
+    pub position: Box<Account<'info, Position>>,
 
-    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
-    pub wsol_vault: InterfaceAccount<'info, TokenAccount>,
+    /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub pumpswap_pool: AccountInfo<'info>,
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
 
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateMarket<'info> {
-    pub admin: Signer<'info>,
+pub struct QuoteLiquidation<'info> {
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin)]
-    pub protocol: Account<'info, Protocol>,
+    #[account(seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
 
-    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Account<'info, Market>,
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
+
+    #[account(
+        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub pumpswap_pool: AccountInfo<'info>,
+
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CreateWsolVault<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+pub struct GetPositionSnapshot<'info> {
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Account<'info, Protocol>,
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
 
-    /// CHECK: Protocol vault
-    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
 
     #[account(
-        init,
-        payer = payer,
-        associated_token::mint = wsol_mint,
-        associated_token::authority = protocol_vault,
+        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
     )]
-    pub wsol_vault: InterfaceAccount<'info, TokenAccount>,
+    pub position: Box<Account<'info, Position>>,
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+    /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub pumpswap_pool: AccountInfo<'info>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CloseMarket<'info> {
+pub struct RefreshPosition<'info> {
     #[account(mut)]
-    pub admin: Signer<'info>,
+    pub keeper: Signer<'info>,
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump, has_one = admin)]
-    pub protocol: Account<'info, Protocol>,
+    /// CHECK: Position owner
+    pub position_owner: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"protocol"], bump = protocol.bump)]
+    pub protocol: Box<Account<'info, Protocol>>,
 
     /// CHECK: Protocol vault
-    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
+    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
     pub protocol_vault: AccountInfo<'info>,
 
-    #[account(mut, close = admin, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Account<'info, Market>,
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Box<Account<'info, Market>>,
 
-    #[account(mut, close = admin, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Account<'info, LendingPool>,
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Box<Account<'info, LendingPool>>,
 
     #[account(
         mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = token_program,
+        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref(), &[position.is_long as u8]],
+        bump = position.bump,
     )]
-    pub token_vault: InterfaceAccount<'info, TokenAccount>,
-
-    pub token_mint: InterfaceAccount<'info, Mint>,
-    pub token_program: Interface<'info, TokenInterface>,
+    pub position: Box<Account<'info, Position>>,
 }
 
 #[derive(Accounts)]
-pub struct CreateMarket<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
-
+pub struct GetRiskSnapshot<'info> {
     #[account(seeds = [b"protocol"], bump = protocol.bump)]
     pub protocol: Box<Account<'info, Protocol>>,
 
-    /// CHECK: Protocol vault
-    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
-
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
-
-    #[account(
-        init, payer = admin, space = 8 + Market::INIT_SPACE,
-        seeds = [b"market", token_mint.key().as_ref()], bump,
-    )]
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
     pub market: Box<Account<'info, Market>>,
 
-    #[account(
-        init, payer = admin, space = 8 + LendingPool::INIT_SPACE,
-        seeds = [b"lending_pool", market.key().as_ref()], bump,
-    )]
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
     pub lending_pool: Box<Account<'info, LendingPool>>,
 
-    #[account(
-        init_if_needed,
-        payer = admin,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
-
     /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
     pub pumpswap_pool: AccountInfo<'info>,
 
-    pub token_program: Interface<'info, TokenInterface>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Deposit<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-
+pub struct GetProtocolConfig<'info> {
     #[account(seeds = [b"protocol"], bump = protocol.bump)]
     pub protocol: Account<'info, Protocol>,
+}
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+#[derive(Accounts)]
+pub struct GetLendingStats<'info> {
+    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
 
-    #[account(
-        init_if_needed, payer = user, space = 8 + UserAccount::INIT_SPACE,
-        seeds = [b"user_account", user.key().as_ref()], bump,
-    )]
+    #[account(seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
+    pub lending_pool: Account<'info, LendingPool>,
+}
+
+#[derive(Accounts)]
+pub struct PokePrice<'info> {
+    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: Pumpswap pool
+    #[account(address = market.pumpswap_pool)]
+    pub pumpswap_pool: AccountInfo<'info>,
+
+    /// CHECK: Pool base vault, checked against the pool
+    pub pool_base_vault: AccountInfo<'info>,
+
+    /// CHECK: Pool quote vault, checked against the pool
+    pub pool_quote_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PokePrices<'info> {
+    pub keeper: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetUserPositions<'info> {
+    /// CHECK: Account owner
+    pub owner: AccountInfo<'info>,
+
+    #[account(seeds = [b"user_account", owner.key().as_ref()], bump = user_account.bump)]
     pub user_account: Account<'info, UserAccount>,
+}
+
+// ========== State ==========
+
+#[account]
+#[derive(InitSpace)]
+pub struct Protocol {
+    pub admin: Pubkey,
+    pub bump: u8,
+    pub vault_bump: u8,
+    pub fee_insurance_split_bps: u64,
+    pub insurance_fund: u64,
+    pub accumulated_fees: u64,
+    pub adl_threshold_bps: u64,
+    pub fee_discount_mint: Pubkey,
+    pub fee_collector: Pubkey,
+    pub fee_discount_bps: u64,
+    pub fee_tokens_per_lamport: u64,
+    pub market_count: u64,
+    pub total_open_positions: u64,
+    pub total_user_balances: u64,
+    pub crank_reward: u64,
+    pub crank_interval: i64,
+    pub min_liquidator_reward: u64,
+    pub liquidation_dust_threshold: u64,
+    pub migrating: bool,
+    pub liquidation_buffer_bps: u64,
+    pub max_leverage_ceiling: u64,
+    pub max_total_positions: u64,
+    // Sum of position_size_sol over open positions; positions opened before
+    // it was tracked are added by backfill_total_open_size
+    pub total_open_size: u64,
+    pub insurance_coverage_multiple: u64,
+    // Pubkey::default() while market size limits are in lamports
+    pub sol_usd_oracle: Pubkey,
+    pub open_fee_rebate_bps: u64,
+    // WSOL reserve a pool needs for create_market; 0 disables
+    pub min_pool_liquidity: u64,
+    // Only whitelisted users may open positions while set
+    pub open_whitelist_enabled: bool,
+    // Sum of Market::total_short_collateral across markets: shorts' collateral
+    // stays in the vault as SOL, so check_vault_backing counts it
+    pub total_short_collateral: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Market {
+    pub token_mint: Pubkey,
+    pub pumpswap_pool: Pubkey,
+    pub quote_mint: Pubkey,
+    pub total_long_collateral: u64,
+    pub total_short_collateral: u64,
+    pub total_positions: u64,
+    pub max_position_size: u64,
+    pub token_decimals: u8,
+    pub bump: u8,
+    pub base_unit: u64,
+    pub quote_unit: u64,
+    pub market_index: u64,
+    pub cumulative_price: u128,
+    pub last_price_update: i64,
+    pub last_crank_ts: i64,
+    pub enabled: bool,
+    pub min_position_size: u64,
+    // Only read or written with the `test` feature; always zero otherwise
+    pub mock_price: u64,
+    pub two_phase_close_size: u64,
+    pub close_delay: i64,
+    pub close_price_tolerance_bps: u64,
+    pub max_leverage: u64,
+    pub max_close_slippage_bps: u64,
+    pub open_cooldown_seconds: i64,
+    pub close_fee_decay_seconds: i64,
+    pub close_fee_floor_bps: u64,
+    pub max_liquidation_chunk: u64,
+    pub lender_fee_share_bps: u64,
+    // High-water mark of position_size_sol at open; not lowered on close
+    pub largest_position_size: u64,
+    // Time-smoothed pool price used alongside the live price for liquidations
+    pub mark_price: u64,
+    pub liquidations_paused: bool,
+    pub max_price_jump_bps: u64,
+    pub price_jump_guard_size: u64,
+    // Raw pool price and slot of the latest poke_price sample
+    pub last_observed_price: u64,
+    pub last_observed_slot: u64,
+    // Latest sample taken before last_observed_slot
+    pub prior_slot_price: u64,
+    // Tokens of longs force-closed or settled against a collapsed pool, still
+    // in the token vault and owed to the protocol; sweep_token_residual sells them
+    pub unsold_long_tokens: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct LendingPool {
+    pub market: Pubkey,
+    pub token_mint: Pubkey,
+    pub total_deposits: u64,
+    pub total_borrowed: u64,
+    pub total_shares: u64,
+    pub bump: u8,
+    pub borrow_rate_bps: u64,
+    pub cumulative_interest_collected: u64,
+    pub max_borrow_per_position: u64,
+    pub reserve_factor_bps: u64,
+    pub protocol_reserves: u64,
+    pub deposit_cap: u64,
+    pub max_interest_bps: u64,
+    // Lender fee lamports per share, scaled by PRECISION
+    pub fee_per_share: u128,
+    pub withdrawal_fee_bps: u64,
+    pub withdrawal_fee_decay_seconds: i64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct LenderPosition {
+    pub owner: Pubkey,
+    pub lending_pool: Pubkey,
+    pub shares: u64,
+    pub bump: u8,
+    // shares * fee_per_share / PRECISION at the last settlement
+    pub fee_debt: u128,
+    pub unclaimed_fees: u64,
+    pub last_deposit_ts: i64,
+}
+
+/// Never closed: close and liquidation payouts for open positions are
+/// credited here, so the account must outlive them. `withdraw` can empty the
+/// balance but leaves the account in place.
+#[account]
+#[derive(InitSpace)]
+pub struct UserAccount {
+    pub owner: Pubkey,
+    pub balance: u64,
+    pub bump: u8,
+    pub fee_exempt: bool,
+    pub recent_volume: u64,
+    pub volume_updated_at: i64,
+    pub last_open_ts: i64,
+    #[max_len(MAX_ACTIVE_POSITIONS)]
+    pub active_markets: Vec<Pubkey>,
+    pub whitelisted: bool,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Position {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub collateral: u64,
+    pub leverage: u64,
+    pub entry_price: u64,
+    pub mark_entry_price: u64,
+    pub liquidation_price: u64,
+    pub token_amount: u64,
+    pub position_size_sol: u64,
+    pub borrowed_tokens: u64,
+    pub opened_at: i64,
+    pub bump: u8,
+    pub pool_liquidity_at_open: u64,
+    pub close_slippage_bps: u64,
+    pub close_requested_at: i64,
+    pub close_request_price: u64,
+    /// Fee rate charged at open; the close fee uses the same rate.
+    pub open_fee_bps: u64,
+    /// Realized PnL of the portions already closed by ADL or partial
+    /// liquidation, net of fees.
+    pub realized_pnl: i64,
+    /// Warning tier: price at `SOFT_LIQUIDATION_THRESHOLD_BPS` of collateral
+    /// lost. Zero for positions opened before the tier existed.
+    pub soft_liquidation_price: u64,
+    /// Open fee charged in SOL; zero when exempt or paid in the discount token.
+    pub open_fee: u64,
+}
 
-    pub system_program: Program<'info, System>,
+/// Input of `set_lending_config`: every admin-tunable `LendingPool` field.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LendingConfig {
+    pub borrow_rate_bps: u64,
+    pub max_borrow_per_position: u64,
+    pub max_interest_bps: u64,
+    pub deposit_cap: u64,
+    pub reserve_factor_bps: u64,
+    pub withdrawal_fee_bps: u64,
+    pub withdrawal_fee_decay_seconds: i64,
 }
 
-#[derive(Accounts)]
-pub struct Withdraw<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+/// Return data of `get_position_snapshot`. Fields are only ever appended so
+/// CPI callers can keep deserializing older layouts' prefix.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PositionSnapshot {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub collateral: u64,
+    pub leverage: u64,
+    pub entry_price: u64,
+    /// Interest-adjusted, as used by `liquidate`.
+    pub liquidation_price: u64,
+    pub token_amount: u64,
+    pub position_size_sol: u64,
+    pub borrowed_tokens: u64,
+    /// Capped borrow interest owed so far, in tokens. Zero for longs.
+    pub accrued_interest: u64,
+    pub opened_at: i64,
+    pub current_price: u64,
+    /// Lamports, marked at `current_price`.
+    pub unrealized_pnl: i64,
+    /// Live and mark price both past `liquidation_price`.
+    pub is_liquidatable: bool,
+    pub mark_price: u64,
+    pub realized_pnl: i64,
+}
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Account<'info, Protocol>,
+// ========== Events ==========
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+/// Parameter an `AdminAction` or `AdminKeyAction` changed. Flags are logged
+/// as 0/1 and signed durations as their non-negative value.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminParam {
+    MarketCreated,
+    MarketClosed,
+    MaxPositionSize,
+    MarketEnabled,
+    MinPositionSize,
+    MockPrice,
+    TwoPhaseCloseSize,
+    CloseDelay,
+    ClosePriceTolerance,
+    MarketMaxLeverage,
+    PumpswapPool,
+    FeeInsuranceSplit,
+    AdlThreshold,
+    CrankReward,
+    CrankInterval,
+    Migrating,
+    LeverageCeiling,
+    LiquidationBuffer,
+    MinLiquidatorReward,
+    LiquidationDustThreshold,
+    FeeDiscountMint,
+    FeeCollector,
+    FeeDiscountBps,
+    FeeTokensPerLamport,
+    BorrowRate,
+    FeeExempt,
+    MaxBorrowPerPosition,
+    MaxInterest,
+    DepositCap,
+    ReserveFactor,
+    WsolVaultClosed,
+    MaxCloseSlippage,
+    OpenCooldown,
+    MaxTotalPositions,
+    CloseFeeDecay,
+    CloseFeeFloor,
+    MaxLiquidationChunk,
+    LenderFeeShare,
+    InsuranceCoverageMultiple,
+    WithdrawalFee,
+    WithdrawalFeeDecay,
+    TokenResidualSwept,
+    LiquidationsPaused,
+    SolUsdOracle,
+    OpenFeeRebate,
+    MinPoolLiquidity,
+    OpenWhitelistEnabled,
+    Whitelisted,
+    MaxPriceJump,
+    PriceJumpGuardSize,
+    PositionForceClosed,
+    ProtocolReservesSold,
+    TotalOpenSize,
+    LenderRewardDistributed,
+}
 
-    #[account(
-        mut,
-        seeds = [b"user_account", user.key().as_ref()],
-        bump = user_account.bump,
-        constraint = user_account.owner == user.key() @ ErrorCode::Unauthorized,
-    )]
-    pub user_account: Account<'info, UserAccount>,
+#[event]
+pub struct AdminAction {
+    pub admin: Pubkey,
+    pub target: Pubkey,
+    pub param: AdminParam,
+    pub old_value: u64,
+    pub new_value: u64,
+}
 
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct AdminKeyAction {
+    pub admin: Pubkey,
+    pub target: Pubkey,
+    pub param: AdminParam,
+    pub old_key: Pubkey,
+    pub new_key: Pubkey,
 }
 
-#[derive(Accounts)]
-pub struct DepositToLending<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+/// Which swap helper ran: `Buy` spends SOL for tokens at open, `Sell` sells
+/// tokens for SOL, `BuyForClose` buys back an exact token amount.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapDirection {
+    Buy,
+    Sell,
+    BuyForClose,
+}
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Box<Account<'info, Protocol>>,
+#[event]
+pub struct SwapExecuted {
+    pub direction: SwapDirection,
+    pub pool: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
 
-    /// CHECK: Protocol vault
-    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+#[event]
+pub struct ProtocolInitialized { pub admin: Pubkey }
 
-    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Box<Account<'info, Market>>,
+#[event]
+pub struct FeeSplitUpdated { pub fee_insurance_split_bps: u64 }
 
-    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Box<Account<'info, LendingPool>>,
+#[event]
+pub struct AdlThresholdUpdated { pub adl_threshold_bps: u64 }
 
-    #[account(
-        init_if_needed, payer = user, space = 8 + LenderPosition::INIT_SPACE,
-        seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()], bump,
-    )]
-    pub lender_position: Box<Account<'info, LenderPosition>>,
+#[event]
+pub struct CrankRewardUpdated { pub crank_reward: u64, pub crank_interval: i64 }
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct LeverageCeilingUpdated { pub max_leverage_ceiling: u64 }
 
-    #[account(mut)]
-    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct InsuranceCoverageMultipleUpdated { pub insurance_coverage_multiple: u64 }
 
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
-    pub token_program: Interface<'info, TokenInterface>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct TotalOpenSizeBackfilled {
+    pub previous: u64,
+    pub total_open_size: u64,
 }
 
-#[derive(Accounts)]
-pub struct WithdrawFromLending<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Box<Account<'info, Protocol>>,
-
-    /// CHECK: Protocol vault
-    #[account(seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
-
-    #[account(seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Box<Account<'info, Market>>,
+#[event]
+pub struct MaxTotalPositionsUpdated { pub max_total_positions: u64 }
 
-    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Box<Account<'info, LendingPool>>,
+#[event]
+pub struct LiquidationBufferUpdated { pub liquidation_buffer_bps: u64 }
 
-    #[account(
-        mut, seeds = [b"lender", user.key().as_ref(), lending_pool.key().as_ref()],
-        bump = lender_position.bump,
-        constraint = lender_position.owner == user.key() @ ErrorCode::Unauthorized,
-    )]
-    pub lender_position: Box<Account<'info, LenderPosition>>,
+#[event]
+pub struct MigrationStarted { pub admin: Pubkey }
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct MigrationEnded { pub admin: Pubkey }
 
-    #[account(mut)]
-    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct LiquidationRewardFloorUpdated { pub min_reward: u64, pub dust_threshold: u64 }
 
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
-    pub token_program: Interface<'info, TokenInterface>,
+#[event]
+pub struct LiquidationDistributed {
+    pub position: Pubkey,
+    pub remaining: u64,
+    pub reward: u64,
+    pub to_owner: u64,
 }
 
-#[derive(Accounts)]
-pub struct OpenPosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[event]
+pub struct CrankRewardPaid {
+    pub keeper: Pubkey,
+    pub market: Pubkey,
+    pub reward: u64,
+}
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Box<Account<'info, Protocol>>,
+#[event]
+pub struct FeeDiscountUpdated {
+    pub fee_discount_mint: Pubkey,
+    pub fee_collector: Pubkey,
+    pub discount_bps: u64,
+    pub tokens_per_lamport: u64,
+}
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+#[event]
+pub struct FeePaidInToken {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub sol_fee_waived: u64,
+    pub token_amount: u64,
+}
 
-    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump = user_account.bump)]
-    pub user_account: Box<Account<'info, UserAccount>>,
+#[event]
+pub struct FeeCollected {
+    pub market: Pubkey,
+    pub fee: u64,
+    pub to_lenders: u64,
+    pub to_insurance: u64,
+    pub to_treasury: u64,
+}
 
-    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Box<Account<'info, Market>>,
+#[event]
+pub struct LenderFeesClaimed {
+    pub user: Pubkey,
+    pub lending_pool: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Box<Account<'info, LendingPool>>,
+#[event]
+pub struct MarketCreated { 
+    pub token_mint: Pubkey, 
+    pub pumpswap_pool: Pubkey,
+    pub quote_mint: Pubkey,
+    pub max_position_size: u64,
+    pub market_index: u64,
+    /// Prices are quote per whole base token scaled by `price_precision`;
+    /// `base_decimals` converts raw token amounts.
+    pub base_decimals: u8,
+    pub price_precision: u64,
+}
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = base_token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct MarketUpdated {
+    pub token_mint: Pubkey,
+    pub max_position_size: u64,
+}
 
-    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
-    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct PriceJumpGuardUpdated {
+    pub token_mint: Pubkey,
+    pub max_jump_bps: u64,
+    pub guard_size: u64,
+}
 
-    #[account(
-        init, payer = user, space = 8 + Position::INIT_SPACE,
-        seeds = [b"position", user.key().as_ref(), market.key().as_ref()], bump,
-    )]
-    pub position: Box<Account<'info, Position>>,
+#[event]
+pub struct PriceJumpDetected {
+    pub market: Pubkey,
+    pub previous_price: u64,
+    pub price: u64,
+    pub previous_slot: u64,
+    pub slot: u64,
+}
 
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+#[event]
+pub struct TwoPhaseCloseUpdated {
+    pub token_mint: Pubkey,
+    pub size: u64,
+    pub delay: i64,
+    pub tolerance_bps: u64,
+}
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+#[event]
+pub struct CloseRequested {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub snapshot_price: u64,
+    pub executable_at: i64,
+}
 
-    pub base_token_program: Interface<'info, TokenInterface>,
-    pub quote_token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct MarketLeverageUpdated {
+    pub token_mint: Pubkey,
+    pub max_leverage: u64,
+    pub max_leverage_ceiling: u64,
 }
 
-#[derive(Accounts)]
-pub struct ClosePosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[event]
+pub struct MinPositionSizeUpdated { pub token_mint: Pubkey, pub min_position_size: u64 }
 
-    /// CHECK: Position owner
-    #[account(mut)]
-    pub position_owner: AccountInfo<'info>,
+#[event]
+pub struct MaxCloseSlippageUpdated { pub token_mint: Pubkey, pub max_close_slippage_bps: u64 }
 
-    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump = user_account.bump)]
-    pub user_account: Box<Account<'info, UserAccount>>,
+#[event]
+pub struct OpenCooldownUpdated { pub token_mint: Pubkey, pub open_cooldown_seconds: i64 }
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Box<Account<'info, Protocol>>,
+#[event]
+pub struct LenderFeeShareUpdated { pub token_mint: Pubkey, pub lender_fee_share_bps: u64 }
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+#[event]
+pub struct MaxLiquidationChunkUpdated { pub token_mint: Pubkey, pub max_liquidation_chunk: u64 }
 
-    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Box<Account<'info, Market>>,
+#[event]
+pub struct CloseFeeDecayUpdated {
+    pub token_mint: Pubkey,
+    pub close_fee_decay_seconds: i64,
+    pub close_fee_floor_bps: u64,
+}
 
-    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Box<Account<'info, LendingPool>>,
+#[event]
+pub struct MarketEnabledUpdated { pub token_mint: Pubkey, pub enabled: bool }
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = base_token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct LiquidationsPausedUpdated { pub token_mint: Pubkey, pub paused: bool }
 
-    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
-    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct SolUsdOracleUpdated { pub oracle: Pubkey }
 
-    #[account(
-        mut, close = position_owner,
-        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref()],
-        bump = position.bump,
-        constraint = position.owner == user.key() @ ErrorCode::Unauthorized,
-    )]
-    pub position: Box<Account<'info, Position>>,
+#[event]
+pub struct OpenFeeRebateUpdated { pub open_fee_rebate_bps: u64 }
 
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+#[event]
+pub struct MinPoolLiquidityUpdated { pub min_pool_liquidity: u64 }
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+#[event]
+pub struct OpenFeeRebated {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub open_fee: u64,
+    pub rebate: u64,
+}
 
-    pub base_token_program: Interface<'info, TokenInterface>,
-    pub quote_token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct MarketPoolMigrated {
+    pub token_mint: Pubkey,
+    pub old_pool: Pubkey,
+    pub new_pool: Pubkey,
+    pub open_positions: u64,
 }
 
-#[derive(Accounts)]
-pub struct Liquidate<'info> {
-    #[account(mut)]
-    pub liquidator: Signer<'info>,
+#[event]
+pub struct UserAccountCreated { pub user: Pubkey }
 
-    /// CHECK: Position owner
-    #[account(mut)]
-    pub position_owner: AccountInfo<'info>,
+#[event]
+pub struct Deposited { pub user: Pubkey, pub amount: u64, pub new_balance: u64 }
 
-    #[account(mut, seeds = [b"user_account", position_owner.key().as_ref()], bump = owner_account.bump)]
-    pub owner_account: Box<Account<'info, UserAccount>>,
+#[event]
+pub struct Withdrawn { pub user: Pubkey, pub amount: u64, pub new_balance: u64 }
 
-    #[account(seeds = [b"protocol"], bump = protocol.bump)]
-    pub protocol: Box<Account<'info, Protocol>>,
+#[event]
+pub struct LendingDeposited {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub shares: u64,
+    /// Lifetime borrow interest folded into the pool's deposits.
+    pub cumulative_interest_collected: u64,
+}
 
-    /// CHECK: Protocol vault
-    #[account(mut, seeds = [b"protocol_vault"], bump = protocol.vault_bump)]
-    pub protocol_vault: AccountInfo<'info>,
+#[event]
+pub struct LendingWithdrawn {
+    pub user: Pubkey,
+    pub tokens: u64,
+    pub shares: u64,
+    pub fee: u64,
+    pub cumulative_interest_collected: u64,
+}
 
-    #[account(mut, seeds = [b"market", market.token_mint.as_ref()], bump = market.bump)]
-    pub market: Box<Account<'info, Market>>,
+#[event]
+pub struct MarketClosed { pub token_mint: Pubkey }
 
-    #[account(mut, seeds = [b"lending_pool", market.key().as_ref()], bump = lending_pool.bump)]
-    pub lending_pool: Box<Account<'info, LendingPool>>,
+#[event]
+pub struct PositionForceClosed {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub admin: Pubkey,
+    pub settle_price: u64,
+    pub market_closed: bool,
+    pub pnl: i64,
+    pub payout: u64,
+}
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = protocol_vault,
-        associated_token::token_program = base_token_program,
-    )]
-    pub token_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct FeeExemptionUpdated { pub user: Pubkey, pub exempt: bool }
 
-    #[account(mut, associated_token::mint = wsol_mint, associated_token::authority = protocol_vault)]
-    pub wsol_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+#[event]
+pub struct OpenWhitelistUpdated { pub enabled: bool }
 
-    #[account(
-        mut, close = position_owner,
-        seeds = [b"position", position_owner.key().as_ref(), market.key().as_ref()],
-        bump = position.bump,
-    )]
-    pub position: Box<Account<'info, Position>>,
+#[event]
+pub struct WhitelistUpdated { pub user: Pubkey, pub allowed: bool }
 
-    pub token_mint: Box<InterfaceAccount<'info, Mint>>,
+#[event]
+pub struct BorrowRateUpdated { pub market: Pubkey, pub borrow_rate_bps: u64 }
 
-    /// CHECK: WSOL mint
-    #[account(address = WSOL_MINT)]
-    pub wsol_mint: AccountInfo<'info>,
+#[event]
+pub struct MaxBorrowUpdated { pub market: Pubkey, pub max_borrow_per_position: u64 }
 
-    pub base_token_program: Interface<'info, TokenInterface>,
-    pub quote_token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-}
+#[event]
+pub struct ReserveFactorUpdated { pub market: Pubkey, pub reserve_factor_bps: u64 }
 
-// ========== State ==========
+#[event]
+pub struct DepositCapUpdated { pub market: Pubkey, pub deposit_cap: u64 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Protocol {
-    pub admin: Pubkey,
-    pub bump: u8,
-    pub vault_bump: u8,
+#[event]
+pub struct MaxInterestUpdated { pub market: Pubkey, pub max_interest_bps: u64 }
+
+#[event]
+pub struct TokenResidualSwept {
+    pub market: Pubkey,
+    pub amount: u64,
+    // Part of `amount` left behind by force-closed longs
+    pub unsold_long_tokens: u64,
+    pub sol_received: u64,
+    pub accumulated_fees: u64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Market {
-    pub token_mint: Pubkey,
-    pub pumpswap_pool: Pubkey,
-    pub total_long_collateral: u64,
-    pub total_short_collateral: u64,
-    pub total_positions: u64,
-    pub max_position_size: u64,
-    pub token_decimals: u8,
-    pub bump: u8,
+#[event]
+pub struct ProtocolReservesSold {
+    pub market: Pubkey,
+    pub tokens: u64,
+    pub sol_received: u64,
+    pub accumulated_fees: u64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct LendingPool {
+#[event]
+pub struct WithdrawalFeeUpdated {
     pub market: Pubkey,
-    pub token_mint: Pubkey,
-    pub total_deposits: u64,
-    pub total_borrowed: u64,
-    pub total_shares: u64,
-    pub bump: u8,
+    pub withdrawal_fee_bps: u64,
+    pub withdrawal_fee_decay_seconds: i64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct LenderPosition {
-    pub owner: Pubkey,
-    pub lending_pool: Pubkey,
-    pub shares: u64,
-    pub bump: u8,
+#[event]
+pub struct LenderRewardDistributed {
+    pub market: Pubkey,
+    pub amount: u64,
+    pub fee_per_share: u128,
+    pub accumulated_fees: u64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct UserAccount {
-    pub owner: Pubkey,
-    pub balance: u64,
-    pub bump: u8,
+#[event]
+pub struct LendingConfigUpdated {
+    pub market: Pubkey,
+    pub config: LendingConfig,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Position {
-    pub owner: Pubkey,
+#[event]
+pub struct InterestCapped {
     pub market: Pubkey,
-    pub is_long: bool,
-    pub collateral: u64,
-    pub leverage: u64,
-    pub entry_price: u64,
-    pub liquidation_price: u64,
-    pub token_amount: u64,
-    pub position_size_sol: u64,
-    pub borrowed_tokens: u64,
-    pub opened_at: i64,
-    pub bump: u8,
+    pub position: Pubkey,
+    pub accrued: u64,
+    pub charged: u64,
 }
 
-// ========== Events ==========
+#[event]
+pub struct LendingBorrowed {
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub amount: u64,
+    pub total_borrowed: u64,
+}
 
 #[event]
-pub struct ProtocolInitialized { pub admin: Pubkey }
+pub struct ShortCloseSettled {
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub settled_in_sol: bool,
+    pub tokens_owed: u64,
+    pub sol_spent: u64,
+}
 
 #[event]
-pub struct MarketCreated { 
-    pub token_mint: Pubkey, 
-    pub pumpswap_pool: Pubkey,
-    pub max_position_size: u64,
+pub struct LendingRepaid {
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub amount: u64,
+    pub interest: u64,
+    pub total_borrowed: u64,
 }
 
 #[event]
-pub struct MarketUpdated {
-    pub token_mint: Pubkey,
-    pub max_position_size: u64,
+pub struct InterestCollected {
+    pub market: Pubkey,
+    pub interest: u64,
+    pub reserve: u64,
+    pub cumulative_interest_collected: u64,
 }
 
 #[event]
-pub struct Deposited { pub user: Pubkey, pub amount: u64, pub new_balance: u64 }
+pub struct LiquidationPriceUpdated {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub previous: u64,
+    pub liquidation_price: u64,
+}
 
 #[event]
-pub struct Withdrawn { pub user: Pubkey, pub amount: u64, pub new_balance: u64 }
+pub struct LendingConversionQuoted {
+    pub market: Pubkey,
+    pub shares: u64,
+    pub tokens: u64,
+}
 
 #[event]
-pub struct LendingDeposited { pub user: Pubkey, pub amount: u64, pub shares: u64 }
+pub struct RiskSnapshot {
+    pub market: Pubkey,
+    pub current_price: u64,
+    pub mark_price: u64,
+    pub total_long_collateral: u64,
+    pub total_short_collateral: u64,
+    /// Long side's share of open collateral; 5000 is balanced.
+    pub long_share_bps: u64,
+    pub utilization_bps: u64,
+    pub total_positions: u64,
+    /// Open positions across every market, against the protocol-wide cap.
+    pub protocol_open_positions: u64,
+    pub max_total_positions: u64,
+    pub largest_position_size: u64,
+    pub seconds_since_price_update: i64,
+    /// Time since `refresh_position` last paid a crank reward on this market.
+    pub seconds_since_crank: i64,
+    pub insurance_fund: u64,
+    /// Protocol insurance fund over this market's open collateral;
+    /// `u64::MAX` when nothing is open.
+    pub insurance_coverage_bps: u64,
+    /// Open position size across every market.
+    pub total_open_size: u64,
+    /// Insurance fund over `total_open_size`; `u64::MAX` when nothing is open.
+    pub exposure_coverage_bps: u64,
+    pub insurance_coverage_multiple: u64,
+    pub adl_threshold_bps: u64,
+}
 
 #[event]
-pub struct LendingWithdrawn { pub user: Pubkey, pub tokens: u64, pub shares: u64 }
+pub struct ProtocolConfig {
+    pub admin: Pubkey,
+    pub migrating: bool,
+    pub fee_insurance_split_bps: u64,
+    pub adl_threshold_bps: u64,
+    pub fee_discount_mint: Pubkey,
+    pub fee_collector: Pubkey,
+    pub fee_discount_bps: u64,
+    pub fee_tokens_per_lamport: u64,
+    pub crank_reward: u64,
+    pub crank_interval: i64,
+    pub min_liquidator_reward: u64,
+    pub liquidation_dust_threshold: u64,
+    pub liquidation_buffer_bps: u64,
+    pub max_leverage_ceiling: u64,
+    pub max_total_positions: u64,
+    pub insurance_coverage_multiple: u64,
+    pub sol_usd_oracle: Pubkey,
+    pub open_fee_rebate_bps: u64,
+    pub min_pool_liquidity: u64,
+    pub open_whitelist_enabled: bool,
+    pub market_count: u64,
+    pub total_open_positions: u64,
+    pub total_open_size: u64,
+    pub insurance_fund: u64,
+    pub accumulated_fees: u64,
+    pub total_user_balances: u64,
+}
 
 #[event]
-pub struct MarketClosed { pub token_mint: Pubkey }
+pub struct LendingStats {
+    pub market: Pubkey,
+    pub total_deposits: u64,
+    pub total_borrowed: u64,
+    pub total_shares: u64,
+    pub borrow_rate_bps: u64,
+    pub cumulative_interest_collected: u64,
+    pub max_borrow_per_position: u64,
+    pub reserve_factor_bps: u64,
+    pub protocol_reserves: u64,
+    pub deposit_cap: u64,
+    pub max_interest_bps: u64,
+}
 
 #[event]
 pub struct PositionOpened {
@@ -1723,7 +7627,13 @@ pub struct PositionOpened {
     pub collateral: u64,
     pub leverage: u64,
     pub entry_price: u64,
+    pub mark_entry_price: u64,
     pub liquidation_price: u64,
+    pub fee_exempt: bool,
+    /// Prices are quote per whole base token scaled by `price_precision`;
+    /// `base_decimals` converts raw token amounts.
+    pub base_decimals: u8,
+    pub price_precision: u64,
 }
 
 #[event]
@@ -1735,6 +7645,66 @@ pub struct PositionClosed {
     pub exit_price: u64,
     pub pnl: i64,
     pub payout: u64,
+    pub pool_liquidity_collapsed: bool,
+    pub fee_exempt: bool,
+    /// Close fee rate charged after holding-time decay.
+    pub close_fee_bps: u64,
+    /// Final realized PnL over every partial and the full close.
+    pub realized_pnl: i64,
+    /// Prices are quote per whole base token scaled by `price_precision`;
+    /// `base_decimals` converts raw token amounts.
+    pub base_decimals: u8,
+    pub price_precision: u64,
+    /// `UserAccount` credited with the payout.
+    pub payout_account: Pubkey,
+    /// Part of `pnl` explained by the move from `entry_price` to
+    /// `exit_price`; `execution_pnl` is the rest, so the two sum to `pnl`.
+    pub price_pnl: i64,
+    pub execution_pnl: i64,
+}
+
+#[event]
+pub struct PortfolioCloseSkipped {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub slippage_limit: u64,
+}
+
+#[event]
+pub struct PortfolioSwapNetted {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    /// Tokens settled against the offsetting leg, and their SOL value at spot.
+    pub netted_tokens: u64,
+    pub netted_sol: u64,
+    /// Tokens still swapped through the pool for this leg.
+    pub pool_tokens: u64,
+}
+
+#[event]
+pub struct PortfolioClosed {
+    pub owner: Pubkey,
+    pub closed: u64,
+    pub skipped: u64,
+}
+
+#[event]
+pub struct FlatClose {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub payout: u64,
+}
+
+#[event]
+pub struct UnderwaterClose {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub retained: u64,
+    pub shortfall: u64,
 }
 
 #[event]
@@ -1745,6 +7715,110 @@ pub struct PositionLiquidated {
     pub liquidator: Pubkey,
     pub reward: u64,
     pub exit_price: u64,
+    pub mark_price: u64,
+    pub pool_liquidity_collapsed: bool,
+    pub realized_pnl: i64,
+    /// Price the liquidation was decided at: the less aggressive of
+    /// `exit_price` and `mark_price`. The swap itself runs at the live price.
+    pub decision_price: u64,
+    /// Whether `mark_price` rather than the live price governed the decision.
+    pub mark_governed: bool,
+}
+
+#[event]
+pub struct PositionAtRisk {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub current_price: u64,
+    pub soft_liquidation_price: u64,
+    pub liquidation_price: u64,
+}
+
+#[event]
+pub struct PositionPartiallyLiquidated {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub liquidator: Pubkey,
+    pub tokens_liquidated: u64,
+    /// Position size this keeper closed; its reward is sized to this slice.
+    pub size_liquidated: u64,
+    pub remaining_tokens: u64,
+    pub reward: u64,
+    pub exit_price: u64,
+    pub mark_price: u64,
+    pub still_liquidatable: bool,
+    pub realized_pnl: i64,
+}
+
+#[event]
+pub struct KeeperSlippageUsed {
+    pub position: Pubkey,
+    pub requested_limit: u64,
+    /// Limit the swap ran with after widening.
+    pub slippage_limit: u64,
+    pub widened_bps: u64,
+}
+
+#[event]
+pub struct AutoDeleveraged {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub keeper: Pubkey,
+    pub closed_bps: u64,
+    pub tokens: u64,
+    pub pnl: i64,
+    pub payout: u64,
+    pub exit_price: u64,
+    /// Minimum SOL received (long) or maximum SOL spent (short) after
+    /// applying the owner's `close_slippage_bps`.
+    pub slippage_bound: u64,
+    pub realized_pnl: i64,
+}
+
+#[event]
+pub struct LiquidatorRewardPaid {
+    pub liquidator: Pubkey,
+    pub position: Pubkey,
+    pub reward: u64,
+}
+
+#[event]
+pub struct CloseSlippageUpdated {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub close_slippage_bps: u64,
+}
+
+#[event]
+pub struct LiquidationQuote {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub current_price: u64,
+    pub mark_price: u64,
+    pub liquidation_price: u64,
+    pub liquidatable: bool,
+    pub near_liquidation: bool,
+    pub expected_remaining: u64,
+    pub expected_reward: u64,
+}
+
+#[event]
+pub struct PriceSampled {
+    pub market: Pubkey,
+    pub price: u64,
+    pub mark_price: u64,
+    pub cumulative_price: u128,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UserPositions {
+    pub owner: Pubkey,
+    pub markets: Vec<Pubkey>,
 }
 
 // ========== Errors ==========
@@ -1787,4 +7861,130 @@ pub enum ErrorCode {
     MarketHasPositions,
     #[msg("Market has borrowed tokens")]
     MarketHasBorrows,
+    #[msg("Basis points value exceeds 10000")]
+    InvalidBps,
+    #[msg("WSOL vault balance did not sync as expected")]
+    WsolSyncMismatch,
+    #[msg("Too many active positions")]
+    TooManyPositions,
+    #[msg("Auto-deleverage conditions not met")]
+    AdlNotTriggered,
+    #[msg("Protocol vault has insufficient lamports")]
+    InsufficientVaultLamports,
+    #[msg("Fee discount token is not enabled")]
+    FeeDiscountDisabled,
+    #[msg("Fee token account, collector and mint must all be provided")]
+    FeeTokenAccountMissing,
+    #[msg("Fee token account does not match the configured fee discount mint")]
+    FeeTokenMintMismatch,
+    #[msg("New pool price diverges too far from the current pool")]
+    PoolPriceMismatch,
+    #[msg("Liquidation amount must be non-zero and leave part of the position")]
+    InvalidLiquidationAmount,
+    #[msg("Market account must be writable")]
+    InvalidMarketAccount,
+    #[msg("Borrow exceeds the per-position cap")]
+    BorrowCapExceeded,
+    #[msg("Positions are still open")]
+    OpenPositionsExist,
+    #[msg("Vault lamports no longer cover user balances")]
+    VaultBackingViolated,
+    #[msg("Lending pool deposit cap reached")]
+    DepositCapReached,
+    #[msg("Crank interval must be positive")]
+    InvalidCrankInterval,
+    #[msg("Market is disabled for new positions")]
+    MarketDisabled,
+    #[msg("Only WSOL-quoted pools are supported")]
+    UnsupportedQuoteMint,
+    #[msg("Protocol is in a migration window")]
+    ProtocolMigrating,
+    #[msg("Position size below market minimum")]
+    PositionTooSmall,
+    #[msg("Mock prices are only available in test builds")]
+    MockPriceDisabled,
+    #[msg("Close delay must be between 0 and 3600 seconds")]
+    InvalidCloseDelay,
+    #[msg("Position requires request_close before closing")]
+    CloseNotRequested,
+    #[msg("Close delay has not elapsed")]
+    CloseDelayPending,
+    #[msg("Pool price moved past tolerance since the close request")]
+    PriceMovedSinceCloseRequest,
+    #[msg("Market leverage exceeds the protocol ceiling")]
+    LeverageAboveCeiling,
+    #[msg("Deposit too small to mint any lending shares")]
+    ZeroShares,
+    #[msg("Shares redeem for zero tokens")]
+    ZeroTokens,
+    #[msg("Open cooldown cannot be negative")]
+    InvalidOpenCooldown,
+    #[msg("Open cooldown has not elapsed")]
+    OpenCooldown,
+    #[msg("Long position carries borrowed tokens")]
+    LongHasBorrow,
+    #[msg("Too many positions for one close_portfolio call")]
+    PortfolioTooLarge,
+    #[msg("Protocol-wide open position limit reached")]
+    ProtocolPositionLimit,
+    #[msg("Collateral is fully consumed by the open fee")]
+    CollateralTooSmall,
+    #[msg("Close fee decay period cannot be negative")]
+    InvalidCloseFeeDecay,
+    #[msg("Position exceeds the liquidation chunk; use liquidate_amount")]
+    LiquidationChunkRequired,
+    #[msg("Pumpswap fee recipient or coin creator ATA is not an initialized quote-mint token account")]
+    PumpswapAtaUninitialized,
+    #[msg("Open would exceed the insurance fund's coverage multiple")]
+    InsuranceCoverageExceeded,
+    #[msg("Withdrawal fee decay period cannot be negative")]
+    InvalidWithdrawalFeeDecay,
+    #[msg("Pool price is past the entry price limit")]
+    EntryPriceLimitExceeded,
+    #[msg("Liquidations are paused on this market")]
+    LiquidationsPaused,
+    #[msg("SOL/USD oracle account missing while USD limits are enabled")]
+    OracleRequired,
+    #[msg("SOL/USD oracle account is not a valid price update")]
+    InvalidOracle,
+    #[msg("SOL/USD oracle price is too old")]
+    StaleOracle,
+    #[msg("Position is not past its soft liquidation price")]
+    PositionNotAtRisk,
+    #[msg("Price does not fit in u64")]
+    PriceTruncation,
+    #[msg("Amount does not fit in u64")]
+    CastOverflow,
+    #[msg("Signer is not the position owner")]
+    NotPositionOwner,
+    #[msg("Signer is not the protocol admin")]
+    NotAdmin,
+    #[msg("Pool WSOL reserve is below the minimum for a new market")]
+    InsufficientPoolLiquidity,
+    #[msg("Payout account must be another user's account")]
+    InvalidPayoutAccount,
+    #[msg("Lending config fields are inconsistent")]
+    InvalidLendingConfig,
+    #[msg("Position holds no tokens and owes none")]
+    EmptyPosition,
+    #[msg("Amount exceeds accumulated fees")]
+    InsufficientFees,
+    #[msg("Pool price jumped since the last sample; poke the price and retry in a later slot")]
+    PriceJumpUnconfirmed,
+    #[msg("Force close needs a closed market, or a migrating protocol and an unhealthy position")]
+    ForceCloseNotAllowed,
+    #[msg("ADL threshold exceeds 20000 bps (side share plus utilization)")]
+    InvalidAdlThreshold,
+    #[msg("Fee rounds down to zero discount tokens; pay it in SOL")]
+    FeeTokenAmountTooSmall,
+    #[msg("Discount token rate must be non-zero while the discount is enabled")]
+    InvalidFeeTokenRate,
+    #[msg("Token vault no longer covers lending deposits and reserves")]
+    LendingBackingViolated,
+    #[msg("Position account is not the owner's position for this market")]
+    InvalidPositionAddress,
+    #[msg("Protocol is not in a migration window")]
+    ProtocolNotMigrating,
+    #[msg("User is not whitelisted to open positions")]
+    NotWhitelisted,
 }
\ No newline at end of file
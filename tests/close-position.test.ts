@@ -18,8 +18,28 @@ import {
   findLendingPoolPDA,
   findUserAccountPDA,
   findPositionPDA,
+  findLegacyPositionPDA,
   PROTOCOL_FEE_BPS,
   BPS_DENOMINATOR,
+  isPoolCollapsed,
+  longValueAtMark,
+  checkTwoPhaseClose,
+  MAX_CLOSE_DELAY,
+  CLOSE_TOLERANCE_WINDOW,
+  calcRealizedCloseFee,
+  feeBpsForVolume,
+  minCloseFill,
+  estimateSellOutput,
+  estimateBuyInput,
+  closeFeeBps,
+  shortSettlesInSol,
+  addRealizedPnl,
+  calcPortfolioNetting,
+  calcOpenFeeRebate,
+  calcFee,
+  calcPoolPrice,
+  snapshotUnrealizedPnl,
+  attributePnl,
 } from "./setup";
 
 describe("close_position", () => {
@@ -75,6 +95,26 @@ describe("close_position", () => {
       expect(payout).to.equal(0);
     });
 
+    it("books what an underwater close still covers as retained fee", async () => {
+      // collateral + pnl = 0.004 SOL < close fee of 0.006 SOL
+      const collateral = new BN(2 * LAMPORTS_PER_SOL);
+      const pnl = new BN(-1.996 * LAMPORTS_PER_SOL);
+      const closeFee = collateral
+        .mul(new BN(PROTOCOL_FEE_BPS))
+        .div(new BN(BPS_DENOMINATOR));
+      const retained = calcRealizedCloseFee(collateral, pnl, closeFee);
+      expect(retained.toNumber()).to.equal(4_000_000);
+    });
+
+    it("reports the loss beyond collateral as shortfall", async () => {
+      const collateral = new BN(2 * LAMPORTS_PER_SOL);
+      const pnl = new BN(-3 * LAMPORTS_PER_SOL);
+      const gross = collateral.add(pnl);
+      const shortfall = gross.isNeg() ? gross.neg() : new BN(0);
+      expect(shortfall.toNumber()).to.equal(LAMPORTS_PER_SOL);
+      expect(calcRealizedCloseFee(collateral, pnl, new BN(6_000_000)).toNumber()).to.equal(0);
+    });
+
     it("decrements market total_long_collateral", async () => {
       // market.total_long_collateral -= position.collateral
       // Placeholder for integration test
@@ -84,6 +124,48 @@ describe("close_position", () => {
       // For long close, execute_sell with position.token_amount
       // Placeholder for integration test
     });
+
+    describe("close fill floor", () => {
+      // 10,000,000 tokens (6 decimals) against 300 SOL: 0.00003 SOL each
+      const baseReserve = new BN("10000000000000");
+      const quoteReserve = new BN(300 * LAMPORTS_PER_SOL);
+      const mark = new BN(30_000_000);
+      // A tenth of the pool's tokens, 30 SOL at spot
+      const tokens = new BN("1000000000000");
+
+      it("floors the fill at max_close_slippage_bps below the estimated sale", () => {
+        const floor = minCloseFill(baseReserve, quoteReserve, tokens, mark, 500, 6);
+        expect(floor.toString()).to.equal("25831363635");
+
+        // A trader passing min_sol = 0 still gets the floor; a tighter limit wins
+        expect(BN.max(new BN(0), floor).toString()).to.equal(floor.toString());
+        const tight = new BN(26 * LAMPORTS_PER_SOL);
+        expect(BN.max(tight, floor).toString()).to.equal(tight.toString());
+      });
+
+      it("lets a large long close through its honest price impact", () => {
+        // The sale only fetches ~27.19 SOL, under 95% of its 30 SOL spot value
+        const fill = estimateSellOutput(baseReserve, quoteReserve, tokens);
+        expect(fill.lt(new BN(28_500_000_000))).to.be.true;
+        expect(fill.gte(minCloseFill(baseReserve, quoteReserve, tokens, mark, 500, 6))).to.be.true;
+      });
+
+      it("keeps the floor at the mark when a sandwich drags the spot down", () => {
+        // The attacker sells 2,500,000 tokens first; k is unchanged
+        const frontRun = new BN("2500000000000");
+        const base = baseReserve.add(frontRun);
+        const quote = baseReserve.mul(quoteReserve).div(base);
+        const fill = estimateSellOutput(base, quote, tokens);
+
+        expect(fill.lt(minCloseFill(base, quote, tokens, mark, 500, 6))).to.be.true;
+        // Measured at the manipulated spot instead, the floor would follow
+        expect(fill.gte(minCloseFill(base, quote, tokens, new BN(0), 500, 6))).to.be.true;
+      });
+
+      it("applies no floor when max_close_slippage_bps is zero", () => {
+        expect(minCloseFill(baseReserve, quoteReserve, tokens, mark, 0, 6).isZero()).to.be.true;
+      });
+    });
   });
 
   describe("short position close", () => {
@@ -104,6 +186,24 @@ describe("close_position", () => {
       expect(pnl.toNumber()).to.equal(-3 * LAMPORTS_PER_SOL);
     });
 
+    it("nets flat PnL on a round trip at an unchanged price, less fees", () => {
+      // The short sold 3_333_333 tokens (6 decimals) for 9.87654321 SOL;
+      // entry_price is that fill, so buying back at the same price is flat
+      const collateral = new BN(2 * LAMPORTS_PER_SOL);
+      const borrowed = new BN(3_333_333);
+      const solReceived = new BN(9_876_543_210);
+      const entryPrice = calcPoolPrice(solReceived, borrowed, 6);
+
+      const pnl = snapshotUnrealizedPnl(false, solReceived, borrowed, entryPrice, 6);
+      // Only price truncation is left, and it rounds in the short's favour
+      expect(pnl.gten(0)).to.be.true;
+      expect(pnl.lten(1)).to.be.true;
+
+      const closeFee = calcFee(collateral);
+      const payout = collateral.add(pnl).sub(closeFee);
+      expect(collateral.sub(closeFee).sub(payout).abs().lten(1)).to.be.true;
+    });
+
     it("repays borrowed tokens to lending pool", async () => {
       // lending.total_borrowed -= position.borrowed_tokens
       // Placeholder for integration test
@@ -121,6 +221,14 @@ describe("close_position", () => {
   });
 
   describe("common close behavior", () => {
+    it("emits FlatClose when a position exits at exactly break-even", () => {
+      // pnl == 0 leaves payout = collateral - close_fee
+      const collateral = new BN(LAMPORTS_PER_SOL);
+      const closeFee = calcFee(collateral);
+      const pnl = new BN(0);
+      expect(collateral.add(pnl).sub(closeFee).toNumber()).to.equal(LAMPORTS_PER_SOL - 3_000_000);
+    });
+
     it("decrements market total_positions", async () => {
       // market.total_positions -= 1
       // Using saturating_sub to prevent underflow
@@ -138,7 +246,7 @@ describe("close_position", () => {
     });
 
     it("only position owner can close their position", async () => {
-      // constraint = position.owner == user.key() @ Unauthorized
+      // constraint = position.owner == user.key() @ NotPositionOwner
       // Another user trying to close should fail
       // Placeholder for integration test
     });
@@ -154,4 +262,322 @@ describe("close_position", () => {
       // Placeholder for integration test
     });
   });
+
+  describe("legacy position address", () => {
+    const owner = Keypair.generate().publicKey;
+    const market = Keypair.generate().publicKey;
+
+    it("derives the legacy address without the side byte", async () => {
+      const [legacy] = findLegacyPositionPDA(owner, market);
+      const [long] = findPositionPDA(owner, market, true);
+      const [short] = findPositionPDA(owner, market, false);
+      expect(legacy.equals(long)).to.be.false;
+      expect(legacy.equals(short)).to.be.false;
+    });
+
+    it("matches the legacy address from the stored bump", async () => {
+      // The program re-derives with create_program_address and position.bump,
+      // so the bump recorded at open must reproduce the legacy address
+      const [legacy, bump] = findLegacyPositionPDA(owner, market);
+      const derived = PublicKey.createProgramAddressSync(
+        [Buffer.from("position"), owner.toBuffer(), market.toBuffer(), Buffer.from([bump])],
+        program.programId
+      );
+      expect(derived.equals(legacy)).to.be.true;
+    });
+  });
+
+  describe("close_portfolio", () => {
+    const baseReserve = new BN("1000000000000"); // 1M tokens (6 decimals)
+    const quoteReserve = new BN(100 * LAMPORTS_PER_SOL);
+    const tokens = new BN("10000000000"); // 1% of the pool
+
+    it("skips a long whose estimated fill is below its limit", () => {
+      const estimate = estimateSellOutput(baseReserve, quoteReserve, tokens);
+      expect(estimate.toNumber()).to.equal(987_128_711);
+      // Limit above the estimate: reported via PortfolioCloseSkipped
+      expect(estimate.gte(new BN(990_000_000))).to.be.false;
+      expect(estimate.gte(new BN(980_000_000))).to.be.true;
+    });
+
+    it("skips a short whose estimated buyback exceeds its limit", () => {
+      const cost = estimateBuyInput(baseReserve, quoteReserve, tokens);
+      expect(cost.toNumber()).to.equal(1_013_140_433);
+      expect(cost.lte(new BN(LAMPORTS_PER_SOL))).to.be.false;
+      expect(cost.lte(new BN(1_020_000_000))).to.be.true;
+    });
+
+    it("nets a long and a short on the same market before swapping", () => {
+      const shortOwed = new BN("6000000000");
+      const netting = calcPortfolioNetting(tokens, shortOwed, baseReserve, quoteReserve);
+      expect(netting.nettedTokens.eq(shortOwed)).to.be.true;
+      expect(netting.nettedSol.toNumber()).to.equal(600_000_000);
+      // Only the long's excess is sold; the short buys nothing
+      expect(netting.longPoolTokens.toString()).to.equal("4000000000");
+      expect(netting.shortPoolTokens.isZero()).to.be.true;
+      expect(
+        estimateSellOutput(baseReserve, quoteReserve, netting.longPoolTokens).lt(
+          estimateSellOutput(baseReserve, quoteReserve, tokens)
+        )
+      ).to.be.true;
+    });
+  });
+
+  describe("fee rate locked at open", () => {
+    const sol = (n: number) => new BN(n).mul(new BN(LAMPORTS_PER_SOL));
+
+    it("charges the close fee at the rate stored on the position", () => {
+      // Opened below every tier, then the trader's volume (or the fee
+      // schedule) moved to 20 bps before the close
+      const openFeeBps = feeBpsForVolume(sol(0));
+      const currentFeeBps = feeBpsForVolume(sol(10_000));
+      expect(openFeeBps).to.equal(PROTOCOL_FEE_BPS);
+      expect(currentFeeBps).to.equal(20);
+
+      const collateral = new BN(LAMPORTS_PER_SOL);
+      const closeFee = collateral
+        .mul(new BN(openFeeBps))
+        .div(new BN(BPS_DENOMINATOR));
+      expect(closeFee.toNumber()).to.equal(3_000_000);
+      expect(
+        collateral.mul(new BN(currentFeeBps)).div(new BN(BPS_DENOMINATOR)).toNumber()
+      ).to.not.equal(closeFee.toNumber());
+    });
+  });
+
+  describe("close fee decay", () => {
+    const DAY = 86_400;
+
+    it("falls linearly from the open rate to the floor", () => {
+      expect(closeFeeBps(30, 0, 7 * DAY, 10)).to.equal(30);
+      expect(closeFeeBps(30, 3.5 * DAY, 7 * DAY, 10)).to.equal(20);
+      expect(closeFeeBps(30, 7 * DAY, 7 * DAY, 10)).to.equal(10);
+      expect(closeFeeBps(30, 30 * DAY, 7 * DAY, 10)).to.equal(10);
+    });
+
+    it("rounds the partial reduction in the trader's disfavor", () => {
+      // 20 bps of decay over 7 days; one day held removes floor(20/7) = 2
+      expect(closeFeeBps(30, DAY, 7 * DAY, 10)).to.equal(28);
+    });
+
+    it("never raises the fee above the open rate", () => {
+      expect(closeFeeBps(20, 7 * DAY, 7 * DAY, 50)).to.equal(20);
+    });
+
+    it("is disabled while close_fee_decay_seconds is zero", () => {
+      expect(closeFeeBps(30, 365 * DAY, 0, 0)).to.equal(30);
+    });
+  });
+
+  describe("realized pnl", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+
+    it("accumulates each partial close's payout less its collateral", () => {
+      // ADL closes half of a 2 SOL position for 1.3 SOL, then the rest
+      // closes for 0.8 SOL
+      let realized = addRealizedPnl(new BN(0), sol(1.3), sol(1));
+      expect(realized.toNumber()).to.equal(0.3 * LAMPORTS_PER_SOL);
+      realized = addRealizedPnl(realized, sol(0.8), sol(1));
+      expect(realized.toNumber()).to.equal(0.1 * LAMPORTS_PER_SOL);
+    });
+
+    it("books a wiped-out portion as the full collateral lost", () => {
+      expect(addRealizedPnl(new BN(0), new BN(0), sol(1)).toNumber()).to.equal(-LAMPORTS_PER_SOL);
+    });
+  });
+
+  describe("pnl attribution", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+    const tokens = new BN(4_500_000); // 4.5 tokens at 6 decimals
+    // Spot before the open's swap, position.mark_entry_price
+    const markEntryPrice = new BN(1_000_000_000_000); // 1 SOL per token
+
+    it("splits a long's pnl into price movement and execution", () => {
+      // Spot at 1.2 implies 5.4 SOL; the sell only fills 5.3 SOL
+      const pnl = sol(5.3).sub(sol(4.5));
+      const { pricePnl, executionPnl } = attributePnl(
+        true, tokens, markEntryPrice, new BN(1_200_000_000_000), pnl, 6
+      );
+      expect(pricePnl.toNumber()).to.equal(0.9 * LAMPORTS_PER_SOL);
+      expect(executionPnl.toNumber()).to.equal(-0.1 * LAMPORTS_PER_SOL);
+      expect(pricePnl.add(executionPnl).eq(pnl)).to.be.true;
+    });
+
+    it("splits a short's pnl into price movement and execution", () => {
+      // Spot at 0.8 implies a 3.6 SOL buyback; slippage and interest make it 3.7
+      const pnl = sol(4.5).sub(sol(3.7));
+      const { pricePnl, executionPnl } = attributePnl(
+        false, tokens, markEntryPrice, new BN(800_000_000_000), pnl, 6
+      );
+      expect(pricePnl.toNumber()).to.equal(0.9 * LAMPORTS_PER_SOL);
+      expect(executionPnl.toNumber()).to.equal(-0.1 * LAMPORTS_PER_SOL);
+      expect(pricePnl.add(executionPnl).eq(pnl)).to.be.true;
+    });
+
+    it("counts the open's price impact as execution, not price movement", () => {
+      // The open bought 4.5 tokens at spot 1.0 but paid 4.59 SOL (fill 1.02);
+      // spot reaches 1.2 and the sell fills 5.3 SOL
+      const pnl = sol(5.3).sub(sol(4.59));
+      const { pricePnl, executionPnl } = attributePnl(
+        true, tokens, markEntryPrice, new BN(1_200_000_000_000), pnl, 6
+      );
+      expect(pricePnl.toNumber()).to.equal(0.9 * LAMPORTS_PER_SOL);
+      expect(executionPnl.toNumber()).to.equal(-0.19 * LAMPORTS_PER_SOL);
+    });
+
+    it("sums to pnl even when rounding leaves dust", () => {
+      const pnl = new BN(-123_457);
+      const { pricePnl, executionPnl } = attributePnl(
+        true, new BN(3), new BN(333_333_333_333), new BN(1), pnl, 6
+      );
+      expect(pricePnl.add(executionPnl).eq(pnl)).to.be.true;
+    });
+  });
+
+  describe("open fee rebate", () => {
+    const openFee = new BN(3_000_000); // 0.3% of 1 SOL
+
+    it("refunds rebate_bps of the open fee on a profitable close", () => {
+      const rebate = calcOpenFeeRebate(openFee, new BN(1), 5_000, new BN(LAMPORTS_PER_SOL));
+      expect(rebate.toNumber()).to.equal(1_500_000);
+    });
+
+    it("pays nothing on a losing or flat close, or while disabled", () => {
+      const fees = new BN(LAMPORTS_PER_SOL);
+      expect(calcOpenFeeRebate(openFee, new BN(0), 5_000, fees).isZero()).to.be.true;
+      expect(calcOpenFeeRebate(openFee, new BN(-1), 5_000, fees).isZero()).to.be.true;
+      expect(calcOpenFeeRebate(openFee, new BN(1), 0, fees).isZero()).to.be.true;
+    });
+
+    it("never pays more than accumulated_fees holds", () => {
+      const rebate = calcOpenFeeRebate(openFee, new BN(1), 10_000, new BN(1_000));
+      expect(rebate.toNumber()).to.equal(1_000);
+    });
+  });
+
+  describe("two-phase close", () => {
+    const market = {
+      twoPhaseCloseSize: new BN(100 * LAMPORTS_PER_SOL),
+      closeDelay: new BN(30),
+      closePriceToleranceBps: new BN(200),
+    };
+    const whale = (isLong: boolean, requestedAt = 0) => ({
+      isLong,
+      positionSizeSol: new BN(150 * LAMPORTS_PER_SOL),
+      closeRequestedAt: new BN(requestedAt),
+      closeRequestPrice: new BN(1_000_000),
+    });
+
+    it("lets positions under the size threshold close in one step", () => {
+      const small = { ...whale(true), positionSizeSol: new BN(99 * LAMPORTS_PER_SOL) };
+      expect(checkTwoPhaseClose(market, small, new BN(1), new BN(0))).to.be.null;
+    });
+
+    it("requires request_close first for large positions", () => {
+      expect(checkTwoPhaseClose(market, whale(true), new BN(1_000_000), new BN(1_000))).to.equal(
+        "CloseNotRequested"
+      );
+    });
+
+    it("waits out close_delay after the request", () => {
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), new BN(1_000_000), new BN(1_029))).to.equal(
+        "CloseDelayPending"
+      );
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), new BN(1_000_000), new BN(1_030))).to.be.null;
+    });
+
+    it("measures a long's exit against the snapshot price", () => {
+      // 2% tolerance below the 1_000_000 snapshot
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), new BN(980_000), new BN(1_030))).to.be.null;
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), new BN(979_999), new BN(1_030))).to.equal(
+        "PriceMovedSinceCloseRequest"
+      );
+    });
+
+    it("measures a short's buyback against the snapshot price", () => {
+      expect(checkTwoPhaseClose(market, whale(false, 1_000), new BN(1_020_000), new BN(1_030))).to.be.null;
+      expect(checkTwoPhaseClose(market, whale(false, 1_000), new BN(1_020_001), new BN(1_030))).to.equal(
+        "PriceMovedSinceCloseRequest"
+      );
+    });
+
+    it("stops holding the close to the snapshot after the tolerance window", () => {
+      // Delay ends at 1_030; the 2% tolerance applies until 1_630
+      const crashed = new BN(500_000);
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), crashed, new BN(1_629))).to.equal(
+        "PriceMovedSinceCloseRequest"
+      );
+      expect(checkTwoPhaseClose(market, whale(true, 1_000), crashed, new BN(1_630))).to.be.null;
+      expect(
+        checkTwoPhaseClose(market, whale(false, 1_000), new BN(2_000_000), new BN(1_000 + 30 + CLOSE_TOLERANCE_WINDOW))
+      ).to.be.null;
+    });
+
+    it("lets a requested close through within the longest allowed wait", () => {
+      // set_two_phase_close fails with InvalidCloseDelay past MAX_CLOSE_DELAY
+      const slowest = { ...market, closeDelay: new BN(MAX_CLOSE_DELAY) };
+      const deadline = new BN(1_000 + MAX_CLOSE_DELAY + CLOSE_TOLERANCE_WINDOW);
+      expect(checkTwoPhaseClose(slowest, whale(true, 1_000), new BN(1), deadline)).to.be.null;
+    });
+  });
+
+  describe("disabled market", () => {
+    it("settles longs on a dead pool through the collapsed pool valuation", async () => {
+      // With the pool below POOL_COLLAPSE_BPS of its open snapshot, the long
+      // close values tokens at the mark rather than swapping
+      expect(isPoolCollapsed(new BN(LAMPORTS_PER_SOL * 100), new BN(LAMPORTS_PER_SOL))).to.be.true;
+    });
+  });
+
+  describe("rugged pool settlement", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+
+    it("flags a pool whose WSOL reserve fell below 20% of the open snapshot", async () => {
+      expect(isPoolCollapsed(sol(100), sol(19))).to.be.true;
+      expect(isPoolCollapsed(sol(100), sol(20))).to.be.false;
+    });
+
+    it("never flags positions opened without a snapshot", async () => {
+      expect(isPoolCollapsed(new BN(0), new BN(0))).to.be.false;
+    });
+
+    it("values a long at the mark instead of selling into the pool", () => {
+      // 50k tokens; the pool rugged from 100 SOL to 5 SOL of WSOL while the
+      // mark still reflects the pre-rug price
+      const tokenAmount = new BN("50000000000");
+      const quoteReserve = sol(5);
+      const baseReserve = new BN("5000000000000");
+      const markPrice = calcPoolPrice(sol(100), baseReserve, 6);
+
+      const value = longValueAtMark(markPrice, tokenAmount, baseReserve, quoteReserve, 6);
+      expect(value.toString()).to.equal(sol(1).toString());
+      expect(value.gt(estimateSellOutput(baseReserve, quoteReserve, tokenAmount))).to.be.true;
+    });
+
+    it("falls back to spot before the market's first mark sample", () => {
+      const tokenAmount = new BN("50000000000");
+      const value = longValueAtMark(new BN(0), tokenAmount, new BN("5000000000000"), sol(5), 6);
+      expect(value.toString()).to.equal(sol(0.05).toString());
+    });
+
+    it("buys back shorts through the pool while it can fill", () => {
+      const baseReserve = new BN("5000000000000");
+      expect(shortSettlesInSol(false, new BN("4999999999999"), baseReserve)).to.be.false;
+    });
+
+    it("settles shorts in SOL when the pool collapsed or can't supply the debt", () => {
+      const baseReserve = new BN("5000000000000");
+      expect(shortSettlesInSol(true, new BN(1), baseReserve)).to.be.true;
+      expect(shortSettlesInSol(false, baseReserve, baseReserve)).to.be.true;
+    });
+
+    it("values the token debt at the spot reserve ratio", () => {
+      // sol_spent = tokens_owed * quote_reserve / base_reserve; the short's
+      // PnL is position_size_sol - sol_spent as for a swapped close
+      const tokensOwed = new BN("6000000000000");
+      const solSpent = tokensOwed.mul(sol(5)).div(new BN("5000000000000"));
+      expect(solSpent.toString()).to.equal(sol(6).toString());
+      expect(sol(10).sub(solSpent).toString()).to.equal(sol(4).toString());
+    });
+  });
 });
@@ -33,6 +33,76 @@ describe("deposit / withdraw", () => {
     [userAccountPDA] = findUserAccountPDA(user.publicKey);
   });
 
+  describe("create_user_account", () => {
+    it("creates an empty user account without a deposit", async () => {
+      await program.methods
+        .createUserAccount()
+        .accounts({
+          user: user.publicKey,
+          userAccount: userAccountPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      const userAccount = (await program.account.userAccount.fetch(
+        userAccountPDA
+      )) as any;
+      expect(userAccount.owner.toBase58()).to.equal(user.publicKey.toBase58());
+      expect(userAccount.balance.toNumber()).to.equal(0);
+    });
+
+    it("deposit still works on a pre-created account", async () => {
+      await program.methods
+        .createUserAccount()
+        .accounts({
+          user: user.publicKey,
+          userAccount: userAccountPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      await program.methods
+        .deposit(new BN(LAMPORTS_PER_SOL))
+        .accounts({
+          user: user.publicKey,
+          protocol,
+          protocolVault,
+          userAccount: userAccountPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      const userAccount = (await program.account.userAccount.fetch(
+        userAccountPDA
+      )) as any;
+      expect(userAccount.balance.toNumber()).to.equal(LAMPORTS_PER_SOL);
+    });
+
+    it("cannot be created twice", async () => {
+      const create = () =>
+        program.methods
+          .createUserAccount()
+          .accounts({
+            user: user.publicKey,
+            userAccount: userAccountPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+
+      await create();
+      try {
+        await create();
+        expect.fail("Should have thrown an error");
+      } catch (err: any) {
+        expect(err.toString()).to.include("already in use");
+      }
+    });
+  });
+
   describe("deposit", () => {
     it("deposits SOL successfully and creates user account", async () => {
       const depositAmount = new BN(5 * LAMPORTS_PER_SOL);
@@ -176,6 +246,35 @@ describe("deposit / withdraw", () => {
       await new Promise((r) => setTimeout(r, 2000));
       await program.removeEventListener(listener);
     });
+
+    it("tracks deposits in protocol.total_user_balances", async () => {
+      const amount = new BN(2 * LAMPORTS_PER_SOL);
+      const before = (await program.account.protocol.fetch(protocol)) as any;
+
+      await program.methods
+        .deposit(amount)
+        .accounts({
+          user: user.publicKey,
+          protocol,
+          protocolVault,
+          userAccount: userAccountPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      const after = (await program.account.protocol.fetch(protocol)) as any;
+      expect(
+        after.totalUserBalances.sub(before.totalUserBalances).toString()
+      ).to.equal(amount.toString());
+
+      // The vault backing invariant: lamports cover every withdrawable balance
+      // plus open shorts' collateral
+      const vaultLamports = await provider.connection.getBalance(protocolVault);
+      expect(vaultLamports).to.be.at.least(
+        after.totalUserBalances.add(after.totalShortCollateral).toNumber()
+      );
+    });
   });
 
   describe("withdraw", () => {
@@ -267,6 +366,29 @@ describe("deposit / withdraw", () => {
       }
     });
 
+    it("leaves the vault rent-exempt after a full withdrawal", async () => {
+      const userAccount = (await program.account.userAccount.fetch(
+        userAccountPDA
+      )) as any;
+      await program.methods
+        .withdraw(userAccount.balance)
+        .accounts({
+          user: user.publicKey,
+          protocol,
+          protocolVault,
+          userAccount: userAccountPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      // initialize seeds the rent reserve and withdraw refuses to dip into it
+      const rentMin =
+        await provider.connection.getMinimumBalanceForRentExemption(0);
+      const vaultLamports = await provider.connection.getBalance(protocolVault);
+      expect(vaultLamports).to.be.at.least(rentMin);
+    });
+
     it("rejects unauthorized withdrawal by another user", async () => {
       const attacker = Keypair.generate();
       await airdrop(provider.connection, attacker.publicKey);
@@ -6,6 +6,7 @@ import {
   SystemProgram,
   LAMPORTS_PER_SOL,
 } from "@solana/web3.js";
+import { TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID } from "@solana/spl-token";
 import { expect } from "chai";
 import {
   findProtocolPDA,
@@ -13,7 +14,10 @@ import {
   findUserAccountPDA,
   calcLiqPriceLong,
   calcLiqPriceShort,
+  calcPoolPrice,
+  calcTokensForQuote,
   calcFee,
+  calcDiscountTokenFee,
   calcPositionSize,
   calcLendingShares,
   calcLendingTokens,
@@ -24,6 +28,8 @@ import {
   MAX_LEVERAGE,
   PRECISION,
   airdrop,
+  readPoolReserves,
+  TOKEN_AMOUNT_OFFSET,
 } from "./setup";
 
 describe("edge cases and math", () => {
@@ -78,6 +84,30 @@ describe("edge cases and math", () => {
       // On-chain: require!(base_amount > 0, ErrorCode::EmptyPool)
     });
 
+    it("get_pool_reserves reads both vault amounts in one call", () => {
+      const vault = (owner: PublicKey, amount: number, len = 165) => {
+        const data = Buffer.alloc(len);
+        new BN(amount).toArrayLike(Buffer, "le", 8).copy(data, TOKEN_AMOUNT_OFFSET);
+        return { owner, data };
+      };
+      const [base, quote] = readPoolReserves(
+        vault(TOKEN_2022_PROGRAM_ID, 1_000_000),
+        vault(TOKEN_PROGRAM_ID, 2_000_000_000)
+      );
+      expect(base.toNumber()).to.equal(1_000_000);
+      expect(quote.toNumber()).to.equal(2_000_000_000);
+    });
+
+    it("get_pool_reserves rejects foreign-owned or short vaults with InvalidPool", () => {
+      const data = Buffer.alloc(165);
+      const good = { owner: TOKEN_PROGRAM_ID, data };
+      const foreign = { owner: Keypair.generate().publicKey, data };
+      const short = { owner: TOKEN_PROGRAM_ID, data: Buffer.alloc(TOKEN_AMOUNT_OFFSET + 7) };
+      expect(() => readPoolReserves(foreign, good)).to.throw("InvalidPool");
+      expect(() => readPoolReserves(good, foreign)).to.throw("InvalidPool");
+      expect(() => readPoolReserves(good, short)).to.throw("InvalidPool");
+    });
+
     it("price uses PRECISION constant for accuracy", () => {
       // price = quote_amount * PRECISION / base_amount
       const quoteAmount = new BN(1_000_000_000); // 1 SOL in lamports
@@ -108,6 +138,50 @@ describe("edge cases and math", () => {
     });
   });
 
+  describe("decimals-aware price", () => {
+    it("prices a 6-decimal token per whole token", () => {
+      // 100 SOL against 1M whole tokens (6 decimals) = 0.0001 SOL/token
+      const price = calcPoolPrice(
+        new BN(100 * LAMPORTS_PER_SOL),
+        new BN("1000000000000"),
+        6
+      );
+      expect(price.toString()).to.equal("100000000"); // 1e-4 * 1e12
+    });
+
+    it("prices a 9-decimal token per whole token", () => {
+      // Same economic pool, base amount expressed with 9 decimals
+      const price = calcPoolPrice(
+        new BN(100 * LAMPORTS_PER_SOL),
+        new BN("1000000000000000"),
+        9
+      );
+      expect(price.toString()).to.equal("100000000");
+    });
+
+    it("6- and 9-decimal tokens at the same value share one price", () => {
+      const p6 = calcPoolPrice(new BN(5 * LAMPORTS_PER_SOL), new BN(2_000_000), 6);
+      const p9 = calcPoolPrice(
+        new BN(5 * LAMPORTS_PER_SOL),
+        new BN(2_000_000_000),
+        9
+      );
+      expect(p6.toString()).to.equal(p9.toString());
+    });
+
+    it("converts SOL back into base units consistently", () => {
+      const price = new BN("100000000"); // 0.0001 SOL per token
+      const sol = new BN(LAMPORTS_PER_SOL);
+      // 1 SOL buys 10k whole tokens
+      expect(calcTokensForQuote(sol, price, 6).toString()).to.equal(
+        "10000000000"
+      );
+      expect(calcTokensForQuote(sol, price, 9).toString()).to.equal(
+        "10000000000000"
+      );
+    });
+  });
+
   describe("liquidation price edge cases", () => {
     it("1x leverage long: liquidation at 30% drop", () => {
       const entryPrice = new BN(10000);
@@ -148,16 +222,58 @@ describe("edge cases and math", () => {
       // liq at 2000 should be exactly 2x liq at 1000
       expect(liq2000.toNumber()).to.equal(liq1000.toNumber() * 2);
     });
+
+    it("fits in u64 for entry prices near u64::MAX / BPS_DENOMINATOR", () => {
+      const maxU64 = new BN("18446744073709551615");
+      const entryPrice = maxU64.divn(BPS_DENOMINATOR);
+      expect(calcLiqPriceShort(entryPrice, new BN(1)).lte(maxU64)).to.be.true;
+      expect(calcLiqPriceLong(entryPrice, new BN(1)).lte(maxU64)).to.be.true;
+    });
+
+    it("rejects a short liquidation price past u64 with PriceTruncation", () => {
+      // The u128 result is range-checked before narrowing instead of
+      // truncating; a 1x short above u64::MAX * 10000 / 17000 overflows
+      const maxU64 = new BN("18446744073709551615");
+      expect(calcLiqPriceShort(maxU64, new BN(1)).gt(maxU64)).to.be.true;
+      // A long's liquidation price is below entry, so it always fits
+      expect(calcLiqPriceLong(maxU64, new BN(1)).lte(maxU64)).to.be.true;
+    });
   });
 
   describe("fee edge cases", () => {
-    it("fee on very small amount rounds down to zero", () => {
-      // fee = amount * 30 / 10000
-      // For amount < 334 lamports, fee rounds to 0
+    it("fee on very small amount rounds up to one lamport", () => {
+      // fee = ceil(amount * 30 / 10000), so dust never trades fee-free
       const smallAmount = new BN(100);
       const fee = calcFee(smallAmount);
-      // 100 * 30 / 10000 = 0 (integer division)
-      expect(fee.toNumber()).to.equal(0);
+      // 100 * 30 / 10000 = 0.3, rounded up
+      expect(fee.toNumber()).to.equal(1);
+    });
+
+    it("fee can consume dust collateral entirely", () => {
+      // open_position rejects collateral_after_fee == 0 with CollateralTooSmall
+      // instead of opening a zero-size position
+      const dust = new BN(1);
+      expect(dust.sub(calcFee(dust)).toNumber()).to.equal(0);
+      expect(new BN(2).sub(calcFee(new BN(2))).toNumber()).to.equal(1);
+      // At a full-collateral fee rate any amount is consumed
+      const collateral = new BN(1_000);
+      expect(collateral.sub(calcFee(collateral, 10_000)).toNumber()).to.equal(0);
+    });
+
+    it("flat round trip never pays out more than collateral minus fees", () => {
+      // Collateral sizes where floor and ceil differ; at a flat price with
+      // no swap loss the close pays collateral_after_fee - close_fee
+      for (const amount of [1, 333, 334, 3_333, 1_000_001, 123_456_789]) {
+        const collateral = new BN(amount);
+        const openFee = calcFee(collateral);
+        const afterFee = collateral.sub(openFee);
+        const closeFee = calcFee(afterFee);
+        const payout = BN.max(afterFee.sub(closeFee), new BN(0));
+
+        // Unrounded result of taking 0.3% twice
+        const keep = 1 - PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
+        expect(payout.toNumber()).to.be.at.most(amount * keep * keep);
+      }
     });
 
     it("fee on 1 SOL is 0.003 SOL (3000 lamports)", () => {
@@ -178,6 +294,113 @@ describe("edge cases and math", () => {
     });
   });
 
+  describe("fee exemption", () => {
+    it("exempt traders keep the full collateral in the position", () => {
+      // fee_exempt skips the protocol fee on open and close
+      const collateral = new BN(10 * LAMPORTS_PER_SOL);
+      const leverage = new BN(3);
+      const exemptSize = collateral.mul(leverage);
+      const regularSize = calcPositionSize(collateral, leverage);
+      expect(exemptSize.sub(regularSize).toString()).to.equal(
+        calcFee(collateral).mul(leverage).toString()
+      );
+    });
+
+    it("exemption takes precedence over paying in the discount token", () => {
+      // pays_fee_in_token = !fee_exempt && fee_token_account.is_some()
+      const feeExempt = true;
+      const hasFeeTokenAccount = true;
+      expect(!feeExempt && hasFeeTokenAccount).to.be.false;
+    });
+  });
+
+  describe("fee split between insurance and treasury", () => {
+    const admin = (provider.wallet as anchor.Wallet).payer;
+    const [protocol] = findProtocolPDA();
+
+    it("stores the split set by the admin", async () => {
+      await program.methods
+        .setFeeInsuranceSplit(new BN(2_500))
+        .accounts({ admin: admin.publicKey, protocol })
+        .rpc();
+
+      const protocolState = (await program.account.protocol.fetch(protocol)) as any;
+      expect(protocolState.feeInsuranceSplitBps.toNumber()).to.equal(2_500);
+    });
+
+    it("rejects a split above 10000 bps", async () => {
+      try {
+        await program.methods
+          .setFeeInsuranceSplit(new BN(BPS_DENOMINATOR + 1))
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        expect.fail("Should have thrown InvalidBps");
+      } catch (err: any) {
+        expect(err.toString()).to.include("InvalidBps");
+      }
+    });
+
+    it("rejects non-admin callers", async () => {
+      const nonAdmin = Keypair.generate();
+      await airdrop(provider.connection, nonAdmin.publicKey);
+
+      try {
+        await program.methods
+          .setFeeInsuranceSplit(new BN(0))
+          .accounts({ admin: nonAdmin.publicKey, protocol })
+          .signers([nonAdmin])
+          .rpc();
+        expect.fail("Should have thrown NotAdmin");
+      } catch (err: any) {
+        expect(err.toString()).to.include("NotAdmin");
+      }
+    });
+  });
+
+  describe("fee discount token", () => {
+    it("charges the discounted fee converted at the configured rate", () => {
+      const fee = calcFee(new BN(10 * LAMPORTS_PER_SOL)); // 0.03 SOL
+      // 50% discount, 1000 fee tokens (base units) per lamport
+      const tokensPerLamport = new BN(1000).mul(new BN(PRECISION.toString()));
+      const tokenFee = calcDiscountTokenFee(fee, new BN(5000), tokensPerLamport);
+      expect(tokenFee.toString()).to.equal("15000000000");
+    });
+
+    it("waives the SOL fee so the full collateral backs the position", () => {
+      // pays_fee_in_token => fee = 0 => collateral_after_fee == collateral
+      const collateral = new BN(LAMPORTS_PER_SOL);
+      const solFee = new BN(0);
+      expect(collateral.sub(solFee).toString()).to.equal(
+        collateral.toString()
+      );
+    });
+
+    it("refuses to waive a fee that rounds down to zero tokens", async () => {
+      const tokenFee = calcDiscountTokenFee(
+        new BN(1),
+        new BN(5000),
+        new BN(PRECISION.toString())
+      );
+      expect(tokenFee.toNumber()).to.equal(0);
+      // pay_fee_in_discount_token fails with FeeTokenAmountTooSmall; the
+      // trader pays in SOL instead by omitting fee_token_account
+    });
+
+    it("rejects a zero tokens_per_lamport while the discount is enabled", async () => {
+      const admin = (provider.wallet as anchor.Wallet).payer;
+      const [protocol] = findProtocolPDA();
+      try {
+        await program.methods
+          .setFeeDiscount(Keypair.generate().publicKey, admin.publicKey, new BN(5000), new BN(0))
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        expect.fail("Should have thrown InvalidFeeTokenRate");
+      } catch (err: any) {
+        expect(err.toString()).to.include("InvalidFeeTokenRate");
+      }
+    });
+  });
+
   describe("lending pool edge cases", () => {
     it("first depositor gets 1:1 shares", () => {
       const shares = calcLendingShares(
@@ -211,6 +434,28 @@ describe("edge cases and math", () => {
       expect(tokens.toNumber()).to.equal(1000);
     });
 
+    it("rejects a deposit that rounds to zero shares", () => {
+      // Share price 2: a 1-token deposit truncates to 0 shares (ZeroShares),
+      // while 2 tokens is the smallest deposit that mints a share
+      expect(
+        calcLendingShares(new BN(1), new BN(2000), new BN(1000)).isZero()
+      ).to.be.true;
+      expect(
+        calcLendingShares(new BN(2), new BN(2000), new BN(1000)).toNumber()
+      ).to.equal(1);
+    });
+
+    it("rejects a withdrawal that redeems zero tokens", () => {
+      // Share price 1/2: one share is worth 0 tokens (ZeroTokens), two are
+      // worth one
+      expect(
+        calcLendingTokens(new BN(1), new BN(1000), new BN(2000)).isZero()
+      ).to.be.true;
+      expect(
+        calcLendingTokens(new BN(2), new BN(1000), new BN(2000)).toNumber()
+      ).to.equal(1);
+    });
+
     it("available liquidity = total_deposits - total_borrowed", () => {
       const totalDeposits = new BN(10000);
       const totalBorrowed = new BN(7000);
@@ -114,4 +114,22 @@ describe("initialize", () => {
     // Protocol vault should exist after initialization
     expect(vaultInfo).to.not.be.null;
   });
+
+  it("get_protocol_config mirrors the protocol account", async () => {
+    const protocolState =
+      (await program.account.protocol.fetch(protocol)) as any;
+    const { events } = await program.methods
+      .getProtocolConfig()
+      .accounts({ protocol })
+      .simulate();
+    const config = events.find((e: any) => e.name === "protocolConfig")!.data as any;
+
+    expect(config.admin.toBase58()).to.equal(admin.publicKey.toBase58());
+    expect(config.migrating).to.equal(protocolState.migrating);
+    expect(config.insuranceFund.toString()).to.equal(protocolState.insuranceFund.toString());
+    expect(config.accumulatedFees.toString()).to.equal(protocolState.accumulatedFees.toString());
+    expect(config.totalUserBalances.toString()).to.equal(
+      protocolState.totalUserBalances.toString()
+    );
+  });
 });
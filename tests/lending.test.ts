@@ -19,6 +19,20 @@ import {
   createAndFundTokenAccount,
   calcLendingShares,
   calcLendingTokens,
+  calcAccruedInterest,
+  isWithinBorrowCap,
+  isWithinDepositCap,
+  calcInterestSplit,
+  capInterest,
+  splitCollectedFee,
+  lenderFeeDebt,
+  withdrawalFeeBps,
+  calcWithdrawalFee,
+  protocolOwnedTokens,
+  PRECISION,
+  SECONDS_PER_YEAR,
+  lendingConfigError,
+  LendingConfig,
 } from "./setup";
 
 describe("lending pool (deposit_to_lending / withdraw_from_lending)", () => {
@@ -163,4 +177,316 @@ describe("lending pool (deposit_to_lending / withdraw_from_lending)", () => {
       // Placeholder for integration test
     });
   });
+
+  describe("share/token quotes", () => {
+    it("quotes shares to tokens with withdraw_from_lending rounding", () => {
+      // 1_000_000 deposits backing 999_999 shares after interest
+      const tokens = calcLendingTokens(new BN(333_333), new BN(1_000_000), new BN(999_999));
+      expect(tokens.toNumber()).to.equal(333_333);
+    });
+
+    it("quotes tokens to shares with deposit_to_lending rounding", () => {
+      const shares = calcLendingShares(new BN(100), new BN(1_000_003), new BN(1_000_000));
+      expect(shares.toNumber()).to.equal(99);
+    });
+
+    it("round-trips a deposit without gaining tokens", () => {
+      const totalDeposits = new BN(1_000_003);
+      const totalShares = new BN(1_000_000);
+      const shares = calcLendingShares(new BN(5_000), totalDeposits, totalShares);
+      const tokens = calcLendingTokens(
+        shares,
+        totalDeposits.add(new BN(5_000)),
+        totalShares.add(shares)
+      );
+      expect(tokens.lte(new BN(5_000))).to.be.true;
+    });
+  });
+
+  describe("borrow interest", () => {
+    it("accrues no interest while borrow_rate_bps is zero", () => {
+      const interest = calcAccruedInterest(
+        new BN(1_000_000),
+        new BN(0),
+        new BN(SECONDS_PER_YEAR)
+      );
+      expect(interest.toNumber()).to.equal(0);
+    });
+
+    it("accrues the annual rate over a full year", () => {
+      // 10% APR on 1M tokens for one year = 100k tokens
+      const interest = calcAccruedInterest(
+        new BN(1_000_000),
+        new BN(1000),
+        new BN(SECONDS_PER_YEAR)
+      );
+      expect(interest.toNumber()).to.equal(100_000);
+    });
+
+    it("accrues pro-rata for partial periods and rounds down", () => {
+      const interest = calcAccruedInterest(
+        new BN(1_000_000),
+        new BN(1000),
+        new BN(86_400)
+      );
+      // 100k * 1/365 = 273.97 -> 273
+      expect(interest.toNumber()).to.equal(273);
+    });
+
+    it("folds collected interest into total_deposits and the lifetime counter", () => {
+      const totalDeposits = new BN(10_000_000);
+      const totalShares = new BN(10_000_000);
+      const interest = new BN(100_000);
+      const cumulativeBefore = new BN(50_000);
+
+      const newDeposits = totalDeposits.add(interest);
+      const cumulative = cumulativeBefore.add(interest);
+
+      expect(cumulative.toNumber()).to.equal(150_000);
+      // Share value rises for every lender
+      expect(
+        calcLendingTokens(new BN(1_000_000), newDeposits, totalShares).toNumber()
+      ).to.equal(1_010_000);
+    });
+  });
+
+  describe("reserve factor", () => {
+    it("sends all interest to lenders while reserve_factor_bps is zero", () => {
+      const { reserve, toLenders } = calcInterestSplit(new BN(100_000), new BN(0));
+      expect(reserve.toNumber()).to.equal(0);
+      expect(toLenders.toNumber()).to.equal(100_000);
+    });
+
+    it("keeps the reserve cut out of total_deposits", () => {
+      // 10% reserve factor on 100k tokens of interest
+      const { reserve, toLenders } = calcInterestSplit(new BN(100_000), new BN(1000));
+      expect(reserve.toNumber()).to.equal(10_000);
+      expect(
+        calcLendingTokens(
+          new BN(1_000_000),
+          new BN(10_000_000).add(toLenders),
+          new BN(10_000_000)
+        ).toNumber()
+      ).to.equal(1_009_000);
+    });
+
+    it("rounds the reserve down in lenders' favour", () => {
+      const { reserve, toLenders } = calcInterestSplit(new BN(9), new BN(1000));
+      expect(reserve.toNumber()).to.equal(0);
+      expect(toLenders.toNumber()).to.equal(9);
+    });
+  });
+
+  describe("deposit cap", () => {
+    it("accepts any deposit while deposit_cap is zero", () => {
+      expect(
+        isWithinDepositCap(new BN("1000000000000"), new BN("1000000000000"), new BN(0))
+      ).to.be.true;
+    });
+
+    it("rejects deposits past the cap with DepositCapReached", () => {
+      const cap = new BN(10_000_000);
+      expect(isWithinDepositCap(new BN(9_000_000), new BN(1_000_000), cap)).to.be.true;
+      expect(isWithinDepositCap(new BN(9_000_000), new BN(1_000_001), cap)).to.be.false;
+    });
+  });
+
+  describe("max interest", () => {
+    it("charges the full accrual while max_interest_bps is zero", () => {
+      const interest = capInterest(new BN(2_000_000), new BN(10_000_000), new BN(0));
+      expect(interest.toNumber()).to.equal(2_000_000);
+    });
+
+    it("bounds interest to max_interest_bps of the borrowed tokens", () => {
+      // 10_000_000 borrowed at 50% APR for two years accrues 10_000_000,
+      // but a 1000 bps cap limits the charge to 1_000_000
+      const borrowed = new BN(10_000_000);
+      const accrued = calcAccruedInterest(
+        borrowed,
+        new BN(5000),
+        new BN(SECONDS_PER_YEAR * 2)
+      );
+      expect(accrued.toNumber()).to.equal(10_000_000);
+      expect(capInterest(accrued, borrowed, new BN(1000)).toNumber()).to.equal(1_000_000);
+    });
+
+    it("leaves interest under the cap untouched", () => {
+      const interest = capInterest(new BN(400_000), new BN(10_000_000), new BN(1000));
+      expect(interest.toNumber()).to.equal(400_000);
+    });
+  });
+
+  describe("max borrow per position", () => {
+    it("places no cap while max_borrow_per_position is zero", () => {
+      expect(isWithinBorrowCap(new BN("1000000000000"), new BN(0))).to.be.true;
+    });
+
+    it("rejects a short borrowing past the cap with BorrowCapExceeded", () => {
+      const cap = new BN(5_000_000);
+      expect(isWithinBorrowCap(new BN(5_000_000), cap)).to.be.true;
+      expect(isWithinBorrowCap(new BN(5_000_001), cap)).to.be.false;
+    });
+  });
+
+  describe("swap_balance_to_token_and_lend", () => {
+    it("mints shares for the tokens the swap bought", () => {
+      // Bought tokens mint shares at the same rate as a direct deposit
+      const shares = calcLendingShares(new BN(5_000), new BN(1_000_000), new BN(1_000_000));
+      expect(shares.toNumber()).to.equal(5_000);
+    });
+
+    it("returns unspent SOL to the balance", () => {
+      const solAmount = new BN(1_000_000_000);
+      const solSpent = new BN(999_000_000);
+      expect(solAmount.sub(solSpent).toNumber()).to.equal(1_000_000);
+    });
+  });
+
+  describe("lender fee share", () => {
+    const shares = new BN(1_000_000);
+
+    it("takes the lender share first and splits the rest with insurance", () => {
+      const split = splitCollectedFee(new BN(3_000_000), 2000, 5000, shares);
+      expect(split.toLenders.toNumber()).to.equal(600_000);
+      expect(split.toInsurance.toNumber()).to.equal(1_200_000);
+      expect(split.toTreasury.toNumber()).to.equal(1_200_000);
+    });
+
+    it("never routes more than the whole fee, even at 100% shares", () => {
+      const split = splitCollectedFee(new BN(3_000_000), 10_000, 10_000, shares);
+      expect(split.toLenders.add(split.toInsurance).add(split.toTreasury).toNumber()).to.equal(
+        3_000_000
+      );
+    });
+
+    it("keeps the whole fee when the pool has no lenders", () => {
+      const split = splitCollectedFee(new BN(3_000_000), 2000, 0, new BN(0));
+      expect(split.toLenders.toNumber()).to.equal(0);
+      expect(split.toTreasury.toNumber()).to.equal(3_000_000);
+    });
+
+    it("pays each lender pro rata through fee_per_share", () => {
+      // 600_000 lamports over 1_000_000 shares
+      const feePerShare = new BN(600_000).mul(new BN(PRECISION.toString())).div(shares);
+      expect(lenderFeeDebt(new BN(250_000), feePerShare).toNumber()).to.equal(150_000);
+      expect(lenderFeeDebt(new BN(750_000), feePerShare).toNumber()).to.equal(450_000);
+    });
+
+    it("does not pay a new deposit for fees collected before it", () => {
+      // fee_debt is set to shares * fee_per_share at deposit, so the
+      // pending amount starts at zero
+      const feePerShare = new BN(PRECISION.toString()).muln(3);
+      const debt = lenderFeeDebt(shares, feePerShare);
+      expect(lenderFeeDebt(shares, feePerShare).sub(debt).toNumber()).to.equal(0);
+    });
+
+    it("distribute_to_lenders raises fee_per_share by the reward", () => {
+      // 1 SOL of accumulated_fees over 1_000_000 shares
+      const reward = new BN(LAMPORTS_PER_SOL);
+      const feePerShare = reward.mul(new BN(PRECISION.toString())).div(shares);
+      expect(lenderFeeDebt(shares, feePerShare).toNumber()).to.equal(LAMPORTS_PER_SOL);
+      expect(lenderFeeDebt(shares.divn(4), feePerShare).toNumber()).to.equal(LAMPORTS_PER_SOL / 4);
+    });
+  });
+
+  describe("withdrawal fee", () => {
+    it("charges nothing while the decay period is zero", () => {
+      expect(withdrawalFeeBps(50, 0, 0)).to.equal(0);
+    });
+
+    it("charges the full rate right after a deposit", () => {
+      expect(withdrawalFeeBps(50, 0, 86_400)).to.equal(50);
+    });
+
+    it("decays linearly to zero over the holding period", () => {
+      expect(withdrawalFeeBps(50, 43_200, 86_400)).to.equal(25);
+      expect(withdrawalFeeBps(50, 86_400, 86_400)).to.equal(0);
+      expect(withdrawalFeeBps(50, 200_000, 86_400)).to.equal(0);
+    });
+
+    it("keeps the fee in the pool for the remaining lenders", () => {
+      // 1_000_000 deposits over 1_000_000 shares; withdrawing half at
+      // 50 bps pays out 497_500 and leaves 2_500 for the other half
+      const deposits = new BN(1_000_000);
+      const totalShares = new BN(1_000_000);
+      const tokens = calcLendingTokens(new BN(500_000), deposits, totalShares);
+      const fee = calcWithdrawalFee(tokens, 50, false);
+      expect(fee.toNumber()).to.equal(2_500);
+      const remaining = deposits.sub(tokens.sub(fee));
+      expect(calcLendingTokens(new BN(500_000), remaining, new BN(500_000)).toNumber()).to.equal(
+        502_500
+      );
+    });
+
+    it("waives the fee for the pool's last lender", () => {
+      expect(calcWithdrawalFee(new BN(1_000_000), 50, true).toNumber()).to.equal(0);
+    });
+  });
+
+  describe("set_lending_config", () => {
+    const valid: LendingConfig = {
+      borrowRateBps: 500,
+      maxBorrowPerPosition: new BN(1_000_000),
+      maxInterestBps: 2_000,
+      depositCap: new BN(10_000_000),
+      reserveFactorBps: 1_000,
+      withdrawalFeeBps: 50,
+      withdrawalFeeDecaySeconds: 86_400,
+    };
+
+    it("accepts a consistent config", () => {
+      expect(lendingConfigError(valid)).to.be.null;
+      // Zero still disables caps and fees
+      expect(
+        lendingConfigError({ ...valid, depositCap: new BN(0), withdrawalFeeBps: 0, withdrawalFeeDecaySeconds: 0 })
+      ).to.be.null;
+    });
+
+    it("rejects a per-position borrow cap above the deposit cap", () => {
+      expect(lendingConfigError({ ...valid, maxBorrowPerPosition: new BN(10_000_001) })).to.equal(
+        "InvalidLendingConfig"
+      );
+    });
+
+    it("rejects a withdrawal fee without a decay window", () => {
+      expect(lendingConfigError({ ...valid, withdrawalFeeDecaySeconds: 0 })).to.equal("InvalidLendingConfig");
+    });
+
+    it("keeps the single setters' range checks", () => {
+      expect(lendingConfigError({ ...valid, reserveFactorBps: 10_001 })).to.equal("InvalidBps");
+      expect(lendingConfigError({ ...valid, withdrawalFeeDecaySeconds: -1 })).to.equal("InvalidWithdrawalFeeDecay");
+    });
+  });
+
+  describe("sweep_token_residual", () => {
+    it("sweeps only what lenders and reserves don't own", () => {
+      const residual = protocolOwnedTokens(
+        new BN(1_000_137),
+        new BN(1_000_000),
+        new BN(0),
+        new BN(100)
+      );
+      expect(residual.toNumber()).to.equal(37);
+    });
+
+    it("finds nothing to sweep when the vault is fully owned", () => {
+      expect(
+        protocolOwnedTokens(new BN(900_000), new BN(1_000_000), new BN(100_000), new BN(0)).isZero()
+      ).to.be.true;
+    });
+
+    it("counts force-closed longs' tokens as residual owed to the protocol", () => {
+      // force_close_position adds the long's tokens to unsold_long_tokens;
+      // with no positions left they are part of the sweepable residual
+      const unsoldLongTokens = new BN(500_000);
+      const dust = new BN(37);
+      const residual = protocolOwnedTokens(
+        new BN(1_000_000).add(unsoldLongTokens).add(dust),
+        new BN(1_000_000),
+        new BN(0),
+        new BN(0)
+      );
+      expect(residual.toString()).to.equal(unsoldLongTokens.add(dust).toString());
+    });
+  });
 });
@@ -16,10 +16,36 @@ import {
   findLendingPoolPDA,
   calcLiqPriceLong,
   calcLiqPriceShort,
+  SOFT_LIQUIDATION_THRESHOLD_BPS,
+  interestAdjustedLiqPriceShort,
+  calcCrankReward,
+  isNearLiquidation,
+  nextMarkPrice,
+  isLiquidatableAtMark,
+  calcPoolPrice,
+  poolPriceOrMock,
+  snapshotUnrealizedPnl,
+  calcAccruedInterest,
+  calcLiquidationSplit,
+  estimateSellOutput,
+  estimateBuyInput,
+  isAdlTriggered,
+  closeSellLimit,
+  closeBuyLimit,
+  effectiveLeverage,
+  repriceAfterPartialFill,
+  liquidationChunkTokens,
+  widenSellLimit,
+  widenBuyLimit,
+  ADL_MAX_CLOSE_BPS,
+  MAX_SLIPPAGE_WIDEN_BPS,
   LIQUIDATOR_REWARD_BPS,
   BPS_DENOMINATOR,
   MAX_LEVERAGE,
+  SECONDS_PER_YEAR,
   airdrop,
+  calcForceClosePayout,
+  liquidationDecisionPrice,
 } from "./setup";
 
 describe("liquidate", () => {
@@ -28,6 +54,74 @@ describe("liquidate", () => {
 
   const program = anchor.workspace.Perpe as Program;
 
+  describe("set_mock_price", () => {
+    // 1 SOL against 1M whole 6-decimal tokens
+    const quoteReserve = new BN(LAMPORTS_PER_SOL);
+    const baseReserve = new BN("1000000000000");
+
+    it("drives a long into liquidation in a test build", () => {
+      const poolPrice = poolPriceOrMock(new BN(0), quoteReserve, baseReserve, 6);
+      const liquidationPrice = calcLiqPriceLong(poolPrice, new BN(5));
+      expect(isLiquidatableAtMark(true, poolPrice, new BN(0), liquidationPrice)).to.be.false;
+
+      const mocked = poolPriceOrMock(liquidationPrice.subn(1), quoteReserve, baseReserve, 6);
+      expect(mocked.toString()).to.equal(liquidationPrice.subn(1).toString());
+      expect(isLiquidatableAtMark(true, mocked, new BN(0), liquidationPrice)).to.be.true;
+    });
+
+    it("returns to the pool price once the mock is cleared", () => {
+      expect(poolPriceOrMock(new BN(0), quoteReserve, baseReserve, 6).toString()).to.equal(
+        calcPoolPrice(quoteReserve, baseReserve, 6).toString()
+      );
+    });
+
+    it("writes and clears Market.mock_price through the instruction", async () => {
+      const admin = (provider.wallet as anchor.Wallet).payer;
+      const [protocol] = findProtocolPDA();
+      const [market] = findMarketPDA(Keypair.generate().publicKey);
+      const mockPrice = new BN(1_234_567);
+
+      try {
+        await program.methods
+          .setMockPrice(mockPrice)
+          .accounts({ admin: admin.publicKey, protocol, market })
+          .rpc();
+        let marketState = (await program.account.market.fetch(market)) as any;
+        expect(marketState.mockPrice.toString()).to.equal(mockPrice.toString());
+
+        await program.methods
+          .setMockPrice(new BN(0))
+          .accounts({ admin: admin.publicKey, protocol, market })
+          .rpc();
+        marketState = (await program.account.market.fetch(market)) as any;
+        expect(marketState.mockPrice.toNumber()).to.equal(0);
+      } catch (err: any) {
+        // Without a live market the accounts fail to load in any build; a
+        // build without `--features test` fails here with MockPriceDisabled
+        expect(err.toString()).to.include("AccountNotInitialized");
+      }
+    });
+
+    it("rejects non-admin callers", async () => {
+      const nonAdmin = Keypair.generate();
+      await airdrop(provider.connection, nonAdmin.publicKey);
+      const [protocol] = findProtocolPDA();
+      const [market] = findMarketPDA(Keypair.generate().publicKey);
+
+      try {
+        await program.methods
+          .setMockPrice(new BN(1))
+          .accounts({ admin: nonAdmin.publicKey, protocol, market })
+          .signers([nonAdmin])
+          .rpc();
+        expect.fail("Should have thrown");
+      } catch (err: any) {
+        // NotAdmin, or AccountNotInitialized without a live market
+        expect(err.toString()).to.not.include("Should have thrown");
+      }
+    });
+  });
+
   describe("liquidation eligibility - long positions", () => {
     it("allows liquidation when current_price <= liquidation_price (long)", () => {
       // Long position: liquidatable when price drops to/below liq price
@@ -102,6 +196,218 @@ describe("liquidate", () => {
     });
   });
 
+  describe("soft liquidation tier", () => {
+    const entry = new BN(1_000_000);
+
+    it("sits between entry and the hard liquidation price for longs", () => {
+      // 5x: soft at -10%, hard at -14%
+      const soft = calcLiqPriceLong(entry, new BN(5), SOFT_LIQUIDATION_THRESHOLD_BPS);
+      const hard = calcLiqPriceLong(entry, new BN(5));
+      expect(soft.toNumber()).to.equal(900_000);
+      expect(hard.toNumber()).to.equal(860_000);
+    });
+
+    it("sits between entry and the hard liquidation price for shorts", () => {
+      const soft = calcLiqPriceShort(entry, new BN(5), SOFT_LIQUIDATION_THRESHOLD_BPS);
+      const hard = calcLiqPriceShort(entry, new BN(5));
+      expect(soft.toNumber()).to.equal(1_100_000);
+      expect(hard.toNumber()).to.equal(1_140_000);
+    });
+  });
+
+  describe("mark price", () => {
+    it("moves the mark by elapsed / window toward the pool price", () => {
+      const mark = new BN(1_000_000);
+      expect(nextMarkPrice(mark, new BN(1_300_000), 30).toNumber()).to.equal(1_030_000);
+      expect(nextMarkPrice(mark, new BN(700_000), 60).toNumber()).to.equal(940_000);
+    });
+
+    it("caps how far one sample moves a stale mark", () => {
+      // Nobody sampled for an hour; the next sample is a manipulated pool
+      const mark = new BN(1_000_000);
+      const manipulated = new BN(100_000);
+      const afterStale = nextMarkPrice(mark, manipulated, 3600);
+      expect(afterStale.toNumber()).to.equal(820_000);
+      expect(afterStale.eq(nextMarkPrice(mark, manipulated, 60))).to.be.true;
+      const liqPrice = new BN(900_000);
+      expect(isLiquidatableAtMark(true, manipulated, afterStale, liqPrice)).to.be.true;
+      // A looser liquidation price still holds against the one sample
+      expect(isLiquidatableAtMark(true, manipulated, afterStale, new BN(800_000))).to.be.false;
+    });
+
+    it("converges on a price that holds over several samples", () => {
+      let mark = new BN(1_000_000);
+      for (let i = 0; i < 20; i++) {
+        mark = nextMarkPrice(mark, new BN(700_000), 60);
+      }
+      expect(mark.sub(new BN(700_000)).toNumber()).to.be.lessThan(5_000);
+    });
+
+    it("does not liquidate on a one-block spike the mark hasn't followed", () => {
+      const liqPrice = new BN(900_000);
+      const mark = nextMarkPrice(new BN(1_000_000), new BN(500_000), 1);
+      expect(mark.toNumber()).to.equal(998_334);
+      expect(isLiquidatableAtMark(true, new BN(500_000), mark, liqPrice)).to.be.false;
+    });
+
+    it("liquidates once both the pool and the mark are past the threshold", () => {
+      const liqPrice = new BN(900_000);
+      expect(
+        isLiquidatableAtMark(true, new BN(850_000), new BN(880_000), liqPrice)
+      ).to.be.true;
+      // A short needs both at or above its liquidation price
+      expect(
+        isLiquidatableAtMark(false, new BN(950_000), new BN(890_000), liqPrice)
+      ).to.be.false;
+    });
+
+    it("falls back to the live price before the first sample", () => {
+      expect(
+        isLiquidatableAtMark(true, new BN(850_000), new BN(0), new BN(900_000))
+      ).to.be.true;
+    });
+
+    it("decides at the less aggressive of the live price and the mark", () => {
+      // Spike down: the lagging mark governs a long
+      let decision = liquidationDecisionPrice(true, new BN(500_000), new BN(998_334));
+      expect(decision.price.toNumber()).to.equal(998_334);
+      expect(decision.markGoverned).to.be.true;
+      // Pool already recovered above the mark: the live price governs
+      decision = liquidationDecisionPrice(true, new BN(890_000), new BN(880_000));
+      expect(decision.price.toNumber()).to.equal(890_000);
+      expect(decision.markGoverned).to.be.false;
+      // A short takes the lower of the two
+      decision = liquidationDecisionPrice(false, new BN(950_000), new BN(890_000));
+      expect(decision.price.toNumber()).to.equal(890_000);
+      expect(decision.markGoverned).to.be.true;
+      // No mark yet
+      decision = liquidationDecisionPrice(false, new BN(950_000), new BN(0));
+      expect(decision.markGoverned).to.be.false;
+    });
+
+    it("agrees with liquidating only when both prices are past", () => {
+      const liqPrice = new BN(900_000);
+      for (const isLong of [true, false]) {
+        for (const live of [850_000, 900_000, 950_000]) {
+          for (const mark of [0, 880_000, 920_000]) {
+            const { price } = liquidationDecisionPrice(isLong, new BN(live), new BN(mark));
+            const past = isLong ? price.lte(liqPrice) : price.gte(liqPrice);
+            expect(past).to.equal(isLiquidatableAtMark(isLong, new BN(live), new BN(mark), liqPrice));
+          }
+        }
+      }
+    });
+  });
+
+  describe("interest-adjusted short liquidation price", () => {
+    it("tightens toward entry as borrow interest accrues", () => {
+      const liqPrice = calcLiqPriceShort(new BN(1_000_000), new BN(5));
+      expect(liqPrice.toNumber()).to.equal(1_140_000);
+
+      // 10_000_000 borrowed at 20% APR for half a year owes 1_000_000 interest
+      const borrowed = new BN(10_000_000);
+      const interest = calcAccruedInterest(
+        borrowed,
+        new BN(2000),
+        new BN(SECONDS_PER_YEAR / 2)
+      );
+      expect(interest.toNumber()).to.equal(1_000_000);
+
+      const adjusted = interestAdjustedLiqPriceShort(liqPrice, borrowed, interest);
+      expect(adjusted.toNumber()).to.equal(1_036_363);
+    });
+
+    it("makes a short liquidatable below its stored liquidation_price", () => {
+      const liqPrice = new BN(1_140_000);
+      const adjusted = interestAdjustedLiqPriceShort(
+        liqPrice,
+        new BN(10_000_000),
+        new BN(1_000_000)
+      );
+      const currentPrice = new BN(1_100_000);
+      expect(currentPrice.lt(liqPrice)).to.be.true;
+      expect(currentPrice.gte(adjusted)).to.be.true;
+    });
+
+    it("leaves the price unchanged with no accrued interest", () => {
+      const liqPrice = new BN(1_140_000);
+      expect(
+        interestAdjustedLiqPriceShort(liqPrice, new BN(10_000_000), new BN(0)).toNumber()
+      ).to.equal(1_140_000);
+    });
+  });
+
+  describe("refresh_position crank reward", () => {
+    it("pays nothing while crank_reward is zero", () => {
+      const reward = calcCrankReward(
+        new BN(0),
+        new BN(LAMPORTS_PER_SOL),
+        new BN(1_000),
+        new BN(0),
+        new BN(60)
+      );
+      expect(reward.toNumber()).to.equal(0);
+    });
+
+    it("pays crank_reward once the market's interval has elapsed", () => {
+      const reward = calcCrankReward(
+        new BN(100_000),
+        new BN(LAMPORTS_PER_SOL),
+        new BN(1_060),
+        new BN(1_000),
+        new BN(60)
+      );
+      expect(reward.toNumber()).to.equal(100_000);
+    });
+
+    it("pays nothing to a repeat crank inside the interval", () => {
+      const reward = calcCrankReward(
+        new BN(100_000),
+        new BN(LAMPORTS_PER_SOL),
+        new BN(1_059),
+        new BN(1_000),
+        new BN(60)
+      );
+      expect(reward.toNumber()).to.equal(0);
+    });
+
+    it("never pays more than accumulated_fees", () => {
+      const reward = calcCrankReward(
+        new BN(100_000),
+        new BN(40_000),
+        new BN(1_060),
+        new BN(1_000),
+        new BN(60)
+      );
+      expect(reward.toNumber()).to.equal(40_000);
+    });
+
+    it("pays nothing while crank_interval is unset", () => {
+      const reward = calcCrankReward(
+        new BN(100_000),
+        new BN(LAMPORTS_PER_SOL),
+        new BN(1_060),
+        new BN(1_060),
+        new BN(0)
+      );
+      expect(reward.toNumber()).to.equal(0);
+    });
+
+    it("rejects a zero crank_interval", async () => {
+      const admin = (provider.wallet as anchor.Wallet).payer;
+      const [protocol] = findProtocolPDA();
+      try {
+        await program.methods
+          .setCrankReward(new BN(100_000), new BN(0))
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        expect.fail("Should have thrown InvalidCrankInterval");
+      } catch (err: any) {
+        expect(err.toString()).to.include("InvalidCrankInterval");
+      }
+    });
+  });
+
   describe("reward distribution", () => {
     it("calculates liquidator reward as 5% of remaining", () => {
       const remaining = new BN(10 * LAMPORTS_PER_SOL);
@@ -142,12 +448,69 @@ describe("liquidate", () => {
       // This is a direct SOL transfer, not an SPL transfer
     });
 
+    it("rejects the reward transfer when the vault holds fewer lamports", () => {
+      // transfer_vault_lamports requires vault.lamports >= reward,
+      // failing with InsufficientVaultLamports instead of panicking
+      const vaultLamports = new BN(1_000);
+      const reward = new BN(1_001);
+      expect(vaultLamports.gte(reward)).to.be.false;
+    });
+
+    it("conserves total lamports across the reward transfer", () => {
+      const vaultBefore = new BN(5 * LAMPORTS_PER_SOL);
+      const liquidatorBefore = new BN(LAMPORTS_PER_SOL);
+      const reward = new BN(0.25 * LAMPORTS_PER_SOL);
+      const vaultAfter = vaultBefore.sub(reward);
+      const liquidatorAfter = liquidatorBefore.add(reward);
+      expect(vaultAfter.add(liquidatorAfter).toString()).to.equal(
+        vaultBefore.add(liquidatorBefore).toString()
+      );
+    });
+
     it("owner gets remaining added to user_account balance", () => {
       // owner_account.balance += to_owner
       // Not a direct SOL transfer - added to balance record
     });
   });
 
+  describe("liquidation reward floor", () => {
+    it("rounds a tiny remainder's bps reward down to zero without a floor", () => {
+      const { reward, toOwner } = calcLiquidationSplit(new BN(19));
+      expect(reward.toNumber()).to.equal(0);
+      expect(toOwner.toNumber()).to.equal(19);
+    });
+
+    it("gives a remainder at or below the dust threshold entirely to the liquidator", () => {
+      const { reward, toOwner } = calcLiquidationSplit(
+        new BN(50_000),
+        new BN(0),
+        new BN(50_000)
+      );
+      expect(reward.toNumber()).to.equal(50_000);
+      expect(toOwner.toNumber()).to.equal(0);
+    });
+
+    it("lifts the bps reward to min_liquidator_reward above the dust threshold", () => {
+      // 5% of 1_000_000 is 50_000, below the 100_000 floor
+      const { reward, toOwner } = calcLiquidationSplit(
+        new BN(1_000_000),
+        new BN(100_000),
+        new BN(50_000)
+      );
+      expect(reward.toNumber()).to.equal(100_000);
+      expect(toOwner.toNumber()).to.equal(900_000);
+    });
+
+    it("keeps the bps reward when it already exceeds the floor", () => {
+      const { reward } = calcLiquidationSplit(
+        new BN(10 * LAMPORTS_PER_SOL),
+        new BN(100_000),
+        new BN(50_000)
+      );
+      expect(reward.toNumber()).to.equal(LAMPORTS_PER_SOL / 2);
+    });
+  });
+
   describe("long position liquidation mechanics", () => {
     it("sells all position tokens", () => {
       // execute_sell(position.token_amount)
@@ -227,4 +590,434 @@ describe("liquidate", () => {
       );
     });
   });
+
+  describe("quote_liquidation", () => {
+    const baseReserve = new BN("1000000000000"); // 1M tokens (6 decimals)
+    const quoteReserve = new BN(100 * LAMPORTS_PER_SOL);
+
+    it("estimates long proceeds with the constant-product sell formula", () => {
+      const tokens = new BN("10000000000"); // 1% of the pool
+      const out = estimateSellOutput(baseReserve, quoteReserve, tokens);
+      // gross = 100 SOL * 1 / 101 ~= 0.990099 SOL, minus 0.3% pool fee
+      expect(out.toNumber()).to.equal(987_128_711);
+    });
+
+    it("estimates short buyback cost rounding up", () => {
+      const tokens = new BN("10000000000");
+      const cost = estimateBuyInput(baseReserve, quoteReserve, tokens);
+      // net = ceil(100 SOL * 1 / 99) = 1.010101011 SOL, grossed up for fee
+      expect(cost.toNumber()).to.equal(1_013_140_433);
+    });
+
+    it("expected reward follows the liquidation split", () => {
+      const remaining = new BN(987_128_711);
+      const { reward, toOwner } = calcLiquidationSplit(remaining);
+      expect(reward.toNumber()).to.equal(49_356_435);
+      expect(reward.add(toOwner).toNumber()).to.equal(remaining.toNumber());
+    });
+
+    it("short remaining floors at zero when buyback exceeds proceeds", () => {
+      const positionSizeSol = new BN(LAMPORTS_PER_SOL);
+      const cost = estimateBuyInput(
+        baseReserve,
+        quoteReserve,
+        new BN("10000000000")
+      );
+      const remaining = BN.max(positionSizeSol.sub(cost), new BN(0));
+      expect(remaining.toNumber()).to.equal(0);
+    });
+
+    it("flags a long within the buffer as near_liquidation but not liquidatable", () => {
+      // 1% buffer above a 1_000_000 liquidation price
+      const liqPrice = new BN(1_000_000);
+      const price = new BN(1_010_000);
+      expect(isNearLiquidation(true, price, liqPrice, new BN(100))).to.be.true;
+      expect(price.lte(liqPrice)).to.be.false;
+      expect(isNearLiquidation(true, new BN(1_010_001), liqPrice, new BN(100))).to.be.false;
+    });
+
+    it("flags a short within the buffer below its liquidation price", () => {
+      const liqPrice = new BN(1_000_000);
+      expect(isNearLiquidation(false, new BN(990_000), liqPrice, new BN(100))).to.be.true;
+      expect(isNearLiquidation(false, new BN(989_999), liqPrice, new BN(100))).to.be.false;
+    });
+
+    it("matches liquidatable exactly while the buffer is zero", () => {
+      const liqPrice = new BN(1_000_000);
+      expect(isNearLiquidation(true, liqPrice, liqPrice, new BN(0))).to.be.true;
+      expect(isNearLiquidation(true, liqPrice.addn(1), liqPrice, new BN(0))).to.be.false;
+    });
+  });
+
+  describe("get_position_snapshot", () => {
+    const tokens = new BN(1_000_000).mul(new BN(1_000_000)); // 1M tokens (6 decimals)
+    const positionSizeSol = new BN(10 * LAMPORTS_PER_SOL);
+    const price = new BN(12_000_000); // 0.000012 SOL per token
+
+    it("marks a long's tokens at the current price", () => {
+      const pnl = snapshotUnrealizedPnl(true, positionSizeSol, tokens, price, 6);
+      expect(pnl.toNumber()).to.equal(2 * LAMPORTS_PER_SOL);
+    });
+
+    it("marks a short's owed tokens, interest included, at the current price", () => {
+      const pnl = snapshotUnrealizedPnl(false, positionSizeSol, tokens, price, 6);
+      expect(pnl.toNumber()).to.equal(-2 * LAMPORTS_PER_SOL);
+    });
+  });
+
+  describe("auto_deleverage", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+    // Before a market's first sample the bounds use the spot estimate as is
+    const noMark = new BN(0);
+
+    it("rejects a threshold above 20000 bps with InvalidAdlThreshold", async () => {
+      const admin = (provider.wallet as anchor.Wallet).payer;
+      const [protocol] = findProtocolPDA();
+      try {
+        await program.methods
+          .setAdlThreshold(new BN(2 * BPS_DENOMINATOR + 1))
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        expect.fail("Should have thrown InvalidAdlThreshold");
+      } catch (err: any) {
+        expect(err.toString()).to.include("InvalidAdlThreshold");
+      }
+    });
+
+    it("is disabled while adl_threshold_bps is zero", () => {
+      expect(
+        isAdlTriggered(sol(90), sol(100), sol(90), sol(100), new BN(0))
+      ).to.be.false;
+    });
+
+    it("triggers on the crowded side once share + utilization crosses the threshold", () => {
+      // 80% long share + 60% utilization = 14000 bps
+      expect(
+        isAdlTriggered(sol(80), sol(100), sol(60), sol(100), new BN(14_000))
+      ).to.be.true;
+      expect(
+        isAdlTriggered(sol(80), sol(100), sol(59), sol(100), new BN(14_000))
+      ).to.be.false;
+    });
+
+    it("never triggers for the minority side", () => {
+      expect(
+        isAdlTriggered(sol(40), sol(100), sol(100), sol(100), new BN(1))
+      ).to.be.false;
+    });
+
+    it("closes at most ADL_MAX_CLOSE_BPS of a position per call", () => {
+      const collateral = sol(10);
+      const closed = collateral
+        .mul(new BN(ADL_MAX_CLOSE_BPS))
+        .div(new BN(BPS_DENOMINATOR));
+      expect(closed.toString()).to.equal(sol(5).toString());
+    });
+
+    it("keeps leverage and liquidation price unchanged after a partial close", () => {
+      const collateral = sol(10);
+      const size = sol(50);
+      const closeBps = new BN(2500);
+      const bps = new BN(BPS_DENOMINATOR);
+      const remainingCollateral = collateral.sub(collateral.mul(closeBps).div(bps));
+      const remainingSize = size.sub(size.mul(closeBps).div(bps));
+      expect(remainingSize.div(remainingCollateral).toNumber()).to.equal(5);
+    });
+
+    it("falls back to the owner's close_slippage_bps when no limit is given", () => {
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = sol(100);
+      const tokens = new BN("10000000000");
+      const bps = new BN(100);
+
+      const minSol = closeSellLimit(new BN(0), bps, baseReserve, quoteReserve, tokens, noMark, 9);
+      // 987_128_711 expected, less 1%
+      expect(minSol.toNumber()).to.equal(977_257_423);
+
+      const maxSol = closeBuyLimit(new BN(0), bps, baseReserve, quoteReserve, tokens, noMark, 9);
+      // 1_013_140_433 expected, plus 1%
+      expect(maxSol.toNumber()).to.equal(1_023_271_837);
+    });
+
+    it("a keeper can tighten the owner's bound but not loosen it", () => {
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = new BN(100 * LAMPORTS_PER_SOL);
+      const tokens = new BN("10000000000");
+      const bps = new BN(100);
+
+      // Sell: a 1-lamport minimum is lifted to the owner's floor, a
+      // stricter one is kept
+      expect(closeSellLimit(new BN(1), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()).to.equal(
+        977_257_423
+      );
+      expect(
+        closeSellLimit(new BN(980_000_000), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()
+      ).to.equal(980_000_000);
+
+      // Buy: an unbounded maximum is cut to the owner's cap
+      expect(
+        closeBuyLimit(new BN(10 * LAMPORTS_PER_SOL), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()
+      ).to.equal(1_023_271_837);
+      expect(
+        closeBuyLimit(new BN(1_000_000_000), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()
+      ).to.equal(1_000_000_000);
+    });
+
+    it("falls back to the owner's bound when the keeper names no limit", () => {
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = new BN(100 * LAMPORTS_PER_SOL);
+      const tokens = new BN("10000000000");
+      const bps = new BN(100);
+
+      expect(closeSellLimit(new BN(0), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()).to.equal(
+        977_257_423
+      );
+      expect(closeBuyLimit(new BN(0), bps, baseReserve, quoteReserve, tokens, noMark, 9).toNumber()).to.equal(
+        1_023_271_837
+      );
+    });
+
+    it("values the owner's bound at the mark price, not a moved pool", () => {
+      // 10,000,000 tokens (6 decimals) against 300 SOL, mark at that spot
+      const baseReserve = new BN("10000000000000");
+      const quoteReserve = sol(300);
+      const mark = calcPoolPrice(quoteReserve, baseReserve, 6);
+      const tokens = new BN("100000000000");
+      const bps = new BN(100);
+      const k = baseReserve.mul(quoteReserve);
+
+      // The keeper sells 2,500,000 tokens first; the ADL sale then fetches
+      // less than the owner's floor at the mark, though it would clear a
+      // floor measured at the dragged spot
+      const soldBase = baseReserve.add(new BN("2500000000000"));
+      const soldQuote = k.div(soldBase);
+      const fill = estimateSellOutput(soldBase, soldQuote, tokens);
+      expect(fill.lt(closeSellLimit(new BN(0), bps, soldBase, soldQuote, tokens, mark, 6))).to.be.true;
+      expect(fill.gte(closeSellLimit(new BN(0), bps, soldBase, soldQuote, tokens, noMark, 6))).to.be.true;
+
+      // The keeper buys first; the short's buy-back now costs more than the
+      // owner's cap at the mark
+      const boughtBase = new BN("8000000000000");
+      const boughtQuote = k.div(boughtBase);
+      const cost = estimateBuyInput(boughtBase, boughtQuote, tokens);
+      expect(cost.gt(closeBuyLimit(new BN(0), bps, boughtBase, boughtQuote, tokens, mark, 6))).to.be.true;
+      expect(cost.lte(closeBuyLimit(new BN(0), bps, boughtBase, boughtQuote, tokens, noMark, 6))).to.be.true;
+    });
+
+    it("uses the keeper's limit as given when the owner stored none", () => {
+      const limit = new BN(5);
+      expect(closeSellLimit(limit, new BN(0), new BN(1), new BN(1), new BN(1), new BN(0), 9).toNumber()).to.equal(5);
+    });
+  });
+
+  describe("effective leverage", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+
+    it("stays at the opened leverage through a sequence of partial closes", () => {
+      // 10x long whose buy spent slightly less than requested
+      let collateral = sol(1);
+      let size = sol(10).subn(1_234_567);
+      expect(effectiveLeverage(size, collateral).toNumber()).to.equal(10);
+
+      for (const bps of [2500, 5000, 3333]) {
+        const collateralPart = collateral.muln(bps).divn(BPS_DENOMINATOR);
+        const sizePart = size.muln(bps).divn(BPS_DENOMINATOR);
+        collateral = collateral.sub(collateralPart);
+        size = size.sub(sizePart);
+        expect(effectiveLeverage(size, collateral).toNumber()).to.equal(10);
+      }
+    });
+
+    it("guards zero collateral", () => {
+      expect(() => effectiveLeverage(sol(1), new BN(0))).to.throw("ZeroCollateral");
+    });
+
+    it("lets a partial fill leave leverage over the cap", () => {
+      // 10.5x rounds to 11x, past MAX_LEVERAGE, but liquidate_amount and
+      // auto_deleverage only de-risk, so the leverage is stored and repriced
+      expect(MAX_LEVERAGE).to.equal(10);
+      const entry = new BN(1_000_000);
+      const { leverage, liquidationPrice, softLiquidationPrice } = repriceAfterPartialFill(
+        true,
+        entry,
+        sol(10.5),
+        sol(1)
+      );
+      expect(leverage.toNumber()).to.equal(11);
+      expect(liquidationPrice.toNumber()).to.equal(calcLiqPriceLong(entry, new BN(11)).toNumber());
+      expect(softLiquidationPrice.toNumber()).to.equal(
+        calcLiqPriceLong(entry, new BN(11), SOFT_LIQUIDATION_THRESHOLD_BPS).toNumber()
+      );
+    });
+
+    it("reports still_liquidatable against the repriced liquidation price", () => {
+      // Opened at 10x, but the buy only spent 9.4 SOL of the 10 requested, so
+      // the position really runs at 9x. Halving it in liquidate_amount
+      // reprices it to 9x, whose liquidation price sits below the stored one
+      const entry = new BN(1_000_000);
+      const staleLiq = calcLiqPriceLong(entry, new BN(10));
+      const { leverage, liquidationPrice } = repriceAfterPartialFill(true, entry, sol(4.7), sol(0.5));
+      expect(leverage.toNumber()).to.equal(9);
+      expect(liquidationPrice.lt(staleLiq)).to.be.true;
+
+      // A pool price between the two: the keeper must not be told to go on
+      const priceAfter = staleLiq.add(liquidationPrice).divn(2);
+      expect(isLiquidatableAtMark(true, priceAfter, new BN(0), liquidationPrice)).to.be.false;
+      expect(isLiquidatableAtMark(true, priceAfter, new BN(0), staleLiq)).to.be.true;
+    });
+
+    it("floors the repriced leverage at 1x", () => {
+      const { leverage } = repriceAfterPartialFill(false, new BN(1_000_000), sol(0.4), sol(1));
+      expect(leverage.toNumber()).to.equal(1);
+    });
+
+    it("keeps a pro rata ADL close at the opened leverage and price", () => {
+      // 5x short; closing ADL_MAX_CLOSE_BPS pro rata leaves the ratio alone
+      const entry = new BN(1_000_000);
+      const collateral = sol(2);
+      const size = sol(10);
+      const bps = new BN(ADL_MAX_CLOSE_BPS);
+      const { leverage, liquidationPrice } = repriceAfterPartialFill(
+        false,
+        entry,
+        size.sub(size.mul(bps).divn(BPS_DENOMINATOR)),
+        collateral.sub(collateral.mul(bps).divn(BPS_DENOMINATOR))
+      );
+      expect(leverage.toNumber()).to.equal(5);
+      expect(liquidationPrice.toNumber()).to.equal(calcLiqPriceShort(entry, new BN(5)).toNumber());
+    });
+  });
+
+  describe("liquidate_amount", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+
+    it("caps the chunk at max_tokens and scales collateral pro rata", () => {
+      const tokenAmount = new BN("40000000000");
+      const maxTokens = new BN("10000000000");
+      const tokens = BN.min(maxTokens, tokenAmount);
+      const collateralPart = sol(8).mul(tokens).div(tokenAmount);
+      const sizePart = sol(40).mul(tokens).div(tokenAmount);
+      expect(collateralPart.toString()).to.equal(sol(2).toString());
+      expect(sizePart.toString()).to.equal(sol(10).toString());
+    });
+
+    it("rejects a chunk that would consume the whole position", () => {
+      // tokens must satisfy 0 < tokens < total; the last chunk uses liquidate
+      const total = new BN("40000000000");
+      const tokens = BN.min(new BN("50000000000"), total);
+      expect(tokens.gtn(0) && tokens.lt(total)).to.be.false;
+    });
+
+    it("splits chunk proceeds with the liquidation reward", () => {
+      const { reward, toOwner } = calcLiquidationSplit(sol(1));
+      expect(reward.toNumber()).to.equal(
+        (LAMPORTS_PER_SOL * LIQUIDATOR_REWARD_BPS) / BPS_DENOMINATOR
+      );
+      expect(reward.add(toOwner).toString()).to.equal(sol(1).toString());
+    });
+
+    it("selling a long chunk pushes the price further below liquidation", () => {
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = new BN(100 * LAMPORTS_PER_SOL);
+      const tokens = new BN("10000000000");
+      const out = estimateSellOutput(baseReserve, quoteReserve, tokens);
+      const priceBefore = quoteReserve.muln(1_000_000).div(baseReserve);
+      const priceAfter = quoteReserve
+        .sub(out)
+        .muln(1_000_000)
+        .div(baseReserve.add(tokens));
+      expect(priceAfter.lt(priceBefore)).to.be.true;
+    });
+  });
+
+  describe("max_liquidation_chunk", () => {
+    const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+
+    it("caps each slice at the chunk's share of position size", () => {
+      const total = new BN("40000000000");
+      const chunk = liquidationChunkTokens(total, sol(40), sol(10));
+      expect(chunk.toString()).to.equal("10000000000");
+      // A keeper asking for everything still only gets the chunk
+      expect(BN.min(total, chunk).toString()).to.equal(chunk.toString());
+    });
+
+    it("does not cap positions already within the chunk", () => {
+      const total = new BN("40000000000");
+      expect(liquidationChunkTokens(total, sol(5), sol(10)).toString()).to.equal(
+        total.toString()
+      );
+      expect(liquidationChunkTokens(total, sol(40), new BN(0)).toString()).to.equal(
+        total.toString()
+      );
+    });
+
+    it("rewards each keeper in proportion to its slice", () => {
+      // Four keepers each liquidate a quarter; rewards add up to the
+      // reward on the whole position
+      const slices = [sol(1), sol(1), sol(1), sol(1)];
+      const rewards = slices.map((r) => calcLiquidationSplit(r).reward);
+      const total = rewards.reduce((a, b) => a.add(b), new BN(0));
+      expect(total.toString()).to.equal(calcLiquidationSplit(sol(4)).reward.toString());
+    });
+  });
+
+  describe("keeper slippage widening", () => {
+    it("keeps the keeper's limit when the pool still meets it", () => {
+      const { bound, widenedBps } = widenSellLimit(new BN(1_000_000), 200, new BN(1_000_000));
+      expect(bound.toNumber()).to.equal(1_000_000);
+      expect(widenedBps).to.equal(0);
+    });
+
+    it("lowers a sell limit in 25 bps steps until the estimate meets it", () => {
+      // 0.6% below the limit needs three steps: 75 bps
+      const { bound, widenedBps } = widenSellLimit(new BN(1_000_000), 200, new BN(994_000));
+      expect(widenedBps).to.equal(75);
+      expect(bound.toNumber()).to.equal(992_500);
+    });
+
+    it("stops at max_widen_bps and lets the swap fail there", () => {
+      const { bound, widenedBps } = widenSellLimit(new BN(1_000_000), 100, new BN(900_000));
+      expect(widenedBps).to.equal(100);
+      expect(bound.toNumber()).to.equal(990_000);
+    });
+
+    it("raises a buy limit until it covers the estimated input", () => {
+      const { bound, widenedBps } = widenBuyLimit(new BN(1_000_000), 200, new BN(1_003_000));
+      expect(widenedBps).to.equal(50);
+      expect(bound.toNumber()).to.equal(1_005_000);
+    });
+
+    it("leaves an uncapped buy uncapped", () => {
+      const { bound, widenedBps } = widenBuyLimit(new BN(0), 200, new BN(1_000_000));
+      expect(bound.isZero()).to.be.true;
+      expect(widenedBps).to.equal(0);
+    });
+
+    it("rejects a widening range past MAX_SLIPPAGE_WIDEN_BPS", async () => {
+      // liquidate, liquidate_amount and auto_deleverage fail with InvalidBps
+      // for max_widen_bps > MAX_SLIPPAGE_WIDEN_BPS; KeeperSlippageUsed
+      // reports the limit the swap ran with
+      expect(MAX_SLIPPAGE_WIDEN_BPS).to.equal(1000);
+    });
+  });
+  describe("force_close_position", () => {
+    const collateral = new BN(LAMPORTS_PER_SOL);
+
+    it("never pays out more than the collateral", () => {
+      expect(calcForceClosePayout(collateral, new BN(5 * LAMPORTS_PER_SOL)).eq(collateral)).to.be.true;
+      expect(calcForceClosePayout(collateral, new BN(-LAMPORTS_PER_SOL / 4)).toNumber()).to.equal(
+        0.75 * LAMPORTS_PER_SOL
+      );
+      expect(calcForceClosePayout(collateral, new BN(-2 * LAMPORTS_PER_SOL)).isZero()).to.be.true;
+    });
+
+    it("values the position at settle_price without swapping", () => {
+      const size = new BN(5 * LAMPORTS_PER_SOL);
+      // 4.5 tokens at 1 SOL each against a 5 SOL position
+      const tokens = new BN(4_500_000);
+      const settlePrice = new BN(1_000_000_000_000);
+      const pnl = snapshotUnrealizedPnl(true, size, tokens, settlePrice, 6);
+      expect(calcForceClosePayout(collateral, pnl).toNumber()).to.equal(LAMPORTS_PER_SOL / 2);
+    });
+  });
 });
@@ -24,9 +24,31 @@ import {
   BPS_DENOMINATOR,
   WSOL_MINT,
   calcFee,
+  decayedVolume,
+  feeBpsForVolume,
+  VOLUME_DECAY_WINDOW,
   calcPositionSize,
   calcLiqPriceLong,
   calcLiqPriceShort,
+  calcPoolPrice,
+  calcTokensForQuote,
+  estimateSellOutput,
+  estimateBuyInput,
+  PUMPSWAP_FEE_BPS,
+  MAX_ACTIVE_POSITIONS,
+  isOpenCooldownElapsed,
+  isUnderProtocolPositionCap,
+  isWithinInsuranceCoverage,
+  isWithinEntryPriceLimit,
+  encodeMockPriceUpdate,
+  readSolUsdPrice,
+  PYTH_RECEIVER_PROGRAM_ID,
+  usdToLamports,
+  MAX_ORACLE_AGE,
+  hasBorrowLiquidity,
+  priceJumped,
+  passesPriceJumpGuard,
+  validateLeverage,
 } from "./setup";
 
 describe("open_position", () => {
@@ -62,6 +84,23 @@ describe("open_position", () => {
       }
     });
 
+    it("checks the filled size against the market's max_leverage", () => {
+      const sol = (n: number) => new BN(n * LAMPORTS_PER_SOL);
+      // A 5x open on a 5x market whose fill lands on 5.4x still rounds to 5x
+      expect(validateLeverage(sol(5.4), sol(1), 5).toNumber()).to.equal(5);
+      // A fill worth 5.6x of the collateral is refused, though 5x was requested
+      expect(() => validateLeverage(sol(5.6), sol(1), 5)).to.throw("InvalidLeverage");
+      // The same fill is fine on a 10x market
+      expect(validateLeverage(sol(5.6), sol(1), 10).toNumber()).to.equal(6);
+    });
+
+    it("rejects a fill that leaves effective leverage under 1x", () => {
+      // A 1x long whose buy only spent 0.4 SOL of its 1 SOL
+      expect(() => validateLeverage(new BN(400_000_000), new BN(LAMPORTS_PER_SOL), 10)).to.throw(
+        "InvalidLeverage"
+      );
+    });
+
     it("rejects zero collateral", async () => {
       // collateral = 0 should fail with ZeroCollateral
       const collateral = new BN(0);
@@ -99,6 +138,252 @@ describe("open_position", () => {
     });
   });
 
+  describe("volume fee tiers", () => {
+    const sol = (n: number) => new BN(n).mul(new BN(LAMPORTS_PER_SOL));
+
+    it("charges the base fee below the first tier", () => {
+      expect(feeBpsForVolume(sol(999))).to.equal(PROTOCOL_FEE_BPS);
+    });
+
+    it("discounts the fee at each volume tier", () => {
+      expect(feeBpsForVolume(sol(1_000))).to.equal(25);
+      expect(feeBpsForVolume(sol(10_000))).to.equal(20);
+    });
+
+    it("decays recent volume linearly over the 30-day window", () => {
+      const volume = sol(2_000);
+      const half = new BN(VOLUME_DECAY_WINDOW / 2);
+      expect(decayedVolume(volume, new BN(0), half).toString()).to.equal(
+        sol(1_000).toString()
+      );
+      expect(
+        decayedVolume(volume, new BN(0), new BN(VOLUME_DECAY_WINDOW)).isZero()
+      ).to.be.true;
+    });
+
+    it("drops a trader out of a tier once their volume goes stale", () => {
+      const volume = sol(1_500);
+      expect(feeBpsForVolume(decayedVolume(volume, new BN(0), new BN(0)))).to.equal(25);
+      // After 20 days only a third of the volume remains
+      const later = new BN(20 * 86_400);
+      expect(feeBpsForVolume(decayedVolume(volume, new BN(0), later))).to.equal(
+        PROTOCOL_FEE_BPS
+      );
+    });
+  });
+
+  describe("open cooldown", () => {
+    it("rejects an open before the cooldown elapses", () => {
+      expect(isOpenCooldownElapsed(1_000, 1_059, 60)).to.be.false;
+      expect(isOpenCooldownElapsed(1_000, 1_060, 60)).to.be.true;
+    });
+
+    it("never blocks while the cooldown is zero", () => {
+      expect(isOpenCooldownElapsed(1_000, 1_000, 0)).to.be.true;
+    });
+  });
+
+  describe("protocol position cap", () => {
+    it("rejects an open once total_open_positions reaches the cap", () => {
+      expect(isUnderProtocolPositionCap(9, 10)).to.be.true;
+      expect(isUnderProtocolPositionCap(10, 10)).to.be.false;
+    });
+
+    it("never blocks while max_total_positions is zero", () => {
+      expect(isUnderProtocolPositionCap(1_000_000, 0)).to.be.true;
+    });
+  });
+
+  describe("insurance coverage multiple", () => {
+    const sol = (n: number) => new BN(n).mul(new BN(LAMPORTS_PER_SOL));
+
+    it("caps total open size at insurance_fund * multiple", () => {
+      // 10 SOL fund at 50x backs 500 SOL of open size
+      expect(isWithinInsuranceCoverage(sol(400), sol(100), sol(10), new BN(50))).to.be.true;
+      expect(isWithinInsuranceCoverage(sol(400), sol(101), sol(10), new BN(50))).to.be.false;
+    });
+
+    it("blocks every open while the fund is empty", () => {
+      expect(isWithinInsuranceCoverage(sol(0), sol(1), sol(0), new BN(50))).to.be.false;
+    });
+
+    it("never blocks while the multiple is zero", () => {
+      expect(isWithinInsuranceCoverage(sol(1_000_000), sol(1), sol(0), new BN(0))).to.be.true;
+    });
+
+    it("backfills total_open_size for positions opened before it was tracked", async () => {
+      // backfill_total_open_size overwrites the counter with the off-chain sum
+      // of open positions; fails with ProtocolNotMigrating outside a
+      // migration window, and NotAdmin for anyone but the admin
+      const before = (await program.account.protocol.fetch(protocol)) as any;
+      const total = before.totalOpenSize;
+
+      try {
+        await program.methods
+          .backfillTotalOpenSize(total)
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        expect.fail("Should have thrown ProtocolNotMigrating");
+      } catch (err: any) {
+        expect(err.toString()).to.include("ProtocolNotMigrating");
+      }
+
+      await program.methods
+        .beginMigration()
+        .accounts({ admin: admin.publicKey, protocol })
+        .rpc();
+      try {
+        const nonAdmin = Keypair.generate();
+        await airdrop(provider.connection, nonAdmin.publicKey);
+        try {
+          await program.methods
+            .backfillTotalOpenSize(total)
+            .accounts({ admin: nonAdmin.publicKey, protocol })
+            .signers([nonAdmin])
+            .rpc();
+          expect.fail("Should have thrown NotAdmin");
+        } catch (err: any) {
+          expect(err.toString()).to.include("NotAdmin");
+        }
+
+        // Writing back the current sum keeps the counter intact for the
+        // rest of the suite
+        await program.methods
+          .backfillTotalOpenSize(total)
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        const after = (await program.account.protocol.fetch(protocol)) as any;
+        expect(after.totalOpenSize.toString()).to.equal(total.toString());
+        expect(after.migrating).to.be.true;
+      } finally {
+        await program.methods
+          .endMigration()
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+      }
+    });
+  });
+
+  describe("price jump guard", () => {
+    const market = {
+      maxPriceJumpBps: 500,
+      priceJumpGuardSize: new BN(10 * LAMPORTS_PER_SOL),
+      lastObservedPrice: new BN(1_000_000),
+      lastObservedSlot: 100,
+      priorSlotPrice: new BN(1_000_000),
+    };
+    const large = new BN(10 * LAMPORTS_PER_SOL);
+
+    it("allows moves within max_price_jump_bps", () => {
+      expect(passesPriceJumpGuard(market, new BN(1_050_000), large, 101)).to.be.true;
+      expect(priceJumped(new BN(1_000_000), new BN(1_050_001), 500)).to.be.true;
+    });
+
+    it("refuses a large open on an unconfirmed jump", () => {
+      expect(passesPriceJumpGuard(market, new BN(1_200_000), large, 101)).to.be.false;
+      // Positions below price_jump_guard_size are not guarded
+      expect(passesPriceJumpGuard(market, new BN(1_200_000), large.subn(1), 101)).to.be.true;
+    });
+
+    it("only counts a poke from an earlier slot as confirmation", () => {
+      // poke_price at slot 101 saw the new price; prior_slot_price keeps the old one
+      const poked = {
+        ...market,
+        lastObservedPrice: new BN(1_200_000),
+        lastObservedSlot: 101,
+        priorSlotPrice: new BN(1_000_000),
+      };
+      expect(passesPriceJumpGuard(poked, new BN(1_200_000), large, 101)).to.be.false;
+      expect(passesPriceJumpGuard(poked, new BN(1_200_000), large, 102)).to.be.true;
+    });
+  });
+
+  describe("entry price limit", () => {
+    const limit = new BN(1_000_000);
+
+    it("opens a long only at or below the limit", () => {
+      expect(isWithinEntryPriceLimit(true, new BN(1_000_000), limit)).to.be.true;
+      expect(isWithinEntryPriceLimit(true, new BN(1_000_001), limit)).to.be.false;
+    });
+
+    it("opens a short only at or above the limit", () => {
+      expect(isWithinEntryPriceLimit(false, new BN(1_000_000), limit)).to.be.true;
+      expect(isWithinEntryPriceLimit(false, new BN(999_999), limit)).to.be.false;
+    });
+
+    it("never blocks while the limit is zero", () => {
+      expect(isWithinEntryPriceLimit(true, new BN("18446744073709551615"), new BN(0))).to.be.true;
+      expect(isWithinEntryPriceLimit(false, new BN(1), new BN(0))).to.be.true;
+    });
+  });
+
+  describe("USD-denominated size limits", () => {
+    const now = 1_700_000_000;
+    // SOL at $150.00000000 in Pyth's usual 1e-8 exponent
+    const price = new BN(15_000_000_000);
+
+    it("reads the price from a mock SOL/USD feed", () => {
+      const feed = readSolUsdPrice(encodeMockPriceUpdate(price, -8, now), PYTH_RECEIVER_PROGRAM_ID, now);
+      expect(feed.price.toString()).to.equal(price.toString());
+      expect(feed.expo).to.equal(-8);
+    });
+
+    it("reads partially verified updates at the shifted offset", () => {
+      const feed = readSolUsdPrice(encodeMockPriceUpdate(price, -8, now, true), PYTH_RECEIVER_PROGRAM_ID, now);
+      expect(feed.price.toString()).to.equal(price.toString());
+    });
+
+    it("converts a $15,000 limit to 100 SOL at $150", () => {
+      const limit = usdToLamports(new BN(15_000).muln(1_000_000), price, -8);
+      expect(limit.toString()).to.equal(new BN(100).mul(new BN(LAMPORTS_PER_SOL)).toString());
+    });
+
+    it("rejects a stale feed with StaleOracle", () => {
+      const data = encodeMockPriceUpdate(price, -8, now - MAX_ORACLE_AGE - 1);
+      expect(() => readSolUsdPrice(data, PYTH_RECEIVER_PROGRAM_ID, now)).to.throw("StaleOracle");
+    });
+
+    it("rejects a non-positive price with InvalidOracle", () => {
+      const data = encodeMockPriceUpdate(new BN(0), -8, now);
+      expect(() => readSolUsdPrice(data, PYTH_RECEIVER_PROGRAM_ID, now)).to.throw("InvalidOracle");
+    });
+
+    it("rejects an account the Pyth receiver doesn't own", () => {
+      const data = encodeMockPriceUpdate(price, -8, now);
+      const owner = Keypair.generate().publicKey;
+      expect(() => readSolUsdPrice(data, owner, now)).to.throw("InvalidOracle");
+    });
+
+    it("rejects a feed other than SOL/USD", () => {
+      const otherFeed = Buffer.alloc(32, 7);
+      const data = encodeMockPriceUpdate(price, -8, now, false, otherFeed);
+      expect(() => readSolUsdPrice(data, PYTH_RECEIVER_PROGRAM_ID, now)).to.throw("InvalidOracle");
+    });
+
+    it("sets and resets protocol.sol_usd_oracle", async () => {
+      // While set, open_position fails with OracleRequired without
+      // sol_usd_oracle and InvalidOracle for any other account;
+      // Pubkey::default() returns to lamport limits
+      const before = (await program.account.protocol.fetch(protocol)) as any;
+      const feed = Keypair.generate().publicKey;
+      try {
+        await program.methods
+          .setSolUsdOracle(feed)
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+        const set = (await program.account.protocol.fetch(protocol)) as any;
+        expect(set.solUsdOracle.toBase58()).to.equal(feed.toBase58());
+      } finally {
+        await program.methods
+          .setSolUsdOracle(before.solUsdOracle)
+          .accounts({ admin: admin.publicKey, protocol })
+          .rpc();
+      }
+      const after = (await program.account.protocol.fetch(protocol)) as any;
+      expect(after.solUsdOracle.toBase58()).to.equal(before.solUsdOracle.toBase58());
+    });
+  });
+
   describe("position size limit", () => {
     it("rejects position exceeding max_position_size", async () => {
       // If market.max_position_size = 100 SOL
@@ -122,6 +407,21 @@ describe("open_position", () => {
         maxPositionSize.toNumber()
       );
     });
+
+    it("rejects a low-leverage position below min_position_size", async () => {
+      // 4 SOL at 1x is 3.988 SOL notional after the fee, under a 5 SOL floor,
+      // and fails with PositionTooSmall regardless of collateral
+      const minPositionSize = new BN(5 * LAMPORTS_PER_SOL);
+      const positionSize = calcPositionSize(new BN(4 * LAMPORTS_PER_SOL), new BN(1));
+      expect(positionSize.toNumber()).to.equal(3.988 * LAMPORTS_PER_SOL);
+      expect(positionSize.lt(minPositionSize)).to.be.true;
+    });
+
+    it("accepts the same collateral once leverage lifts it over the floor", async () => {
+      const minPositionSize = new BN(5 * LAMPORTS_PER_SOL);
+      const positionSize = calcPositionSize(new BN(4 * LAMPORTS_PER_SOL), new BN(2));
+      expect(positionSize.gte(minPositionSize)).to.be.true;
+    });
   });
 
   describe("long position", () => {
@@ -167,6 +467,71 @@ describe("open_position", () => {
     });
   });
 
+  describe("WSOL vault residual lamports", () => {
+    const wsolVault = anchor.utils.token.associatedAddress({
+      mint: WSOL_MINT,
+      owner: protocolVault,
+    });
+
+    it("seeds residual native lamports into the WSOL vault", async () => {
+      const before = await provider.connection.getBalance(wsolVault);
+      const residual = 12_345;
+
+      const tx = new anchor.web3.Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: admin.publicKey,
+          toPubkey: wsolVault,
+          lamports: residual,
+        })
+      );
+      await provider.sendAndConfirm(tx);
+
+      // The lamports sit unwrapped until the next sync_native
+      const after = await provider.connection.getBalance(wsolVault);
+      expect(after - before).to.equal(residual);
+    });
+
+    it("measures the buy against the synced balance, not pre-transfer", () => {
+      // wsol_pre = 0, residual = 12_345, sol_amount = 1 SOL
+      // sync_native wraps both: wsol_synced = 1 SOL + 12_345
+      const solAmount = new BN(LAMPORTS_PER_SOL);
+      const residual = new BN(12_345);
+      const wsolSynced = solAmount.add(residual);
+
+      // Pumpswap spends 0.9 SOL
+      const wsolAfter = wsolSynced.sub(new BN(0.9 * LAMPORTS_PER_SOL));
+      const solSpent = wsolSynced.sub(wsolAfter);
+      expect(solSpent.toNumber()).to.equal(0.9 * LAMPORTS_PER_SOL);
+
+      // The old pre-transfer baseline under-reported the spend by the residual
+      const legacySpent = solAmount.sub(wsolAfter);
+      expect(solSpent.sub(legacySpent).toNumber()).to.equal(12_345);
+    });
+
+    it("rejects a spend above sol_amount", () => {
+      // require!(sol_spent <= sol_amount) -> SlippageExceeded
+      const solAmount = new BN(LAMPORTS_PER_SOL);
+      const solSpent = solAmount.addn(1);
+      expect(solSpent.gt(solAmount)).to.be.true;
+    });
+  });
+
+  describe("WSOL vault recreation", () => {
+    it("recreates the vault at the protocol vault's WSOL ATA", async () => {
+      // init_if_needed derives the same associated address initialize used,
+      // so a recreated vault is interchangeable with the original
+      const wsolVault = anchor.utils.token.associatedAddress({
+        mint: WSOL_MINT,
+        owner: protocolVault,
+      });
+      const [expected] = PublicKey.findProgramAddressSync(
+        [protocolVault.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), WSOL_MINT.toBuffer()],
+        ASSOCIATED_TOKEN_PROGRAM_ID
+      );
+      expect(wsolVault.equals(expected)).to.be.true;
+    });
+  });
+
   describe("short position", () => {
     it("calculates liquidation price correctly for short", async () => {
       // entry_price = 1000, leverage = 5
@@ -211,6 +576,17 @@ describe("open_position", () => {
       // Placeholder for integration test
     });
 
+    it("requires the tokens to physically be in token_vault", () => {
+      // Accounting says 1_000 tokens are free, but the vault only holds 600
+      const deposits = new BN(1_000);
+      const borrowed = new BN(0);
+      const vault = new BN(600);
+      expect(hasBorrowLiquidity(deposits, borrowed, vault, new BN(600))).to.be.true;
+      // Fails with InsufficientLiquidity after token_vault.reload()
+      expect(hasBorrowLiquidity(deposits, borrowed, vault, new BN(601))).to.be.false;
+      expect(hasBorrowLiquidity(deposits, new BN(500), new BN(2_000), new BN(501))).to.be.false;
+    });
+
     it("sells borrowed tokens via pumpswap", async () => {
       // For short positions, protocol sells tokens for SOL
       // position.position_size_sol = SOL received
@@ -222,6 +598,61 @@ describe("open_position", () => {
       // After opening short: market.total_short_collateral += collateral_after_fee
       // Placeholder for integration test
     });
+
+    it("records the effective sell price as the short entry", async () => {
+      // 1_000_000 tokens (6 decimals) against 1_000 SOL
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = new BN("1000000000000");
+      const markPrice = calcPoolPrice(quoteReserve, baseReserve, 6);
+      const positionSize = new BN(10 * LAMPORTS_PER_SOL);
+      const borrowed = calcTokensForQuote(positionSize, markPrice, 6);
+
+      const solReceived = estimateSellOutput(baseReserve, quoteReserve, borrowed);
+      const entryPrice = calcPoolPrice(solReceived, borrowed, 6);
+
+      // Slippage and the pumpswap fee put the fill below the mark
+      expect(entryPrice.lt(markPrice)).to.be.true;
+      // The stored basis maps position_size_sol back to the borrowed tokens,
+      // up to price rounding, so PnL and liquidation share one reference
+      const basis = calcTokensForQuote(solReceived, entryPrice, 6);
+      expect(basis.sub(borrowed).abs().lte(borrowed.divn(1_000_000))).to.be.true;
+    });
+
+    it("round-trip short at a flat price nets only swap costs", async () => {
+      const baseReserve = new BN("1000000000000");
+      const quoteReserve = new BN("1000000000000");
+      const borrowed = new BN(10_000_000);
+
+      const solReceived = estimateSellOutput(baseReserve, quoteReserve, borrowed);
+      // Price back at the open mark when the short closes
+      const solSpent = estimateBuyInput(baseReserve, quoteReserve, borrowed);
+      const pnl = solReceived.sub(solSpent);
+
+      // Two pumpswap fees plus a hair of price impact on ~0.01 SOL
+      const feeCost = solSpent.muln(2 * PUMPSWAP_FEE_BPS).divn(BPS_DENOMINATOR);
+      expect(pnl.isNeg()).to.be.true;
+      expect(pnl.abs().sub(feeCost).abs().lte(solSpent.divn(BPS_DENOMINATOR))).to.be.true;
+    });
+  });
+
+  describe("open_position_with_deposit", () => {
+    it("leaves the unused part of the deposit in the balance", () => {
+      const existingBalance = new BN(LAMPORTS_PER_SOL);
+      const deposit = new BN(5 * LAMPORTS_PER_SOL);
+      const collateral = new BN(2 * LAMPORTS_PER_SOL);
+
+      const available = existingBalance.add(deposit);
+      expect(collateral.lte(available)).to.be.true;
+      expect(available.sub(collateral).toNumber()).to.equal(4 * LAMPORTS_PER_SOL);
+    });
+
+    it("can draw on the existing balance beyond the deposit", () => {
+      const existingBalance = new BN(3 * LAMPORTS_PER_SOL);
+      const deposit = new BN(LAMPORTS_PER_SOL);
+      const collateral = new BN(4 * LAMPORTS_PER_SOL);
+      expect(collateral.lte(existingBalance.add(deposit))).to.be.true;
+      expect(collateral.addn(1).lte(existingBalance.add(deposit))).to.be.false;
+    });
   });
 
   describe("common behavior", () => {
@@ -250,14 +681,51 @@ describe("open_position", () => {
       const user = Keypair.generate();
       const tokenMint = Keypair.generate();
       const [market] = findMarketPDA(tokenMint.publicKey);
-      const [position] = findPositionPDA(user.publicKey, market);
+      const [position] = findPositionPDA(user.publicKey, market, true);
 
       // Position PDA should be deterministic from user + market
       expect(position).to.not.be.null;
 
       // Same inputs should give same PDA
-      const [position2] = findPositionPDA(user.publicKey, market);
+      const [position2] = findPositionPDA(user.publicKey, market, true);
       expect(position.toBase58()).to.equal(position2.toBase58());
     });
+
+    it("derives every open position PDA from user_account.active_markets", () => {
+      const user = Keypair.generate();
+      const activeMarkets = [0, 1, 2].map(
+        () => findMarketPDA(Keypair.generate().publicKey)[0]
+      );
+
+      // get_user_positions emits active_markets; each maps to one position
+      const positions = activeMarkets.map(
+        (market) => findPositionPDA(user.publicKey, market, true)[0]
+      );
+      expect(new Set(positions.map((p) => p.toBase58())).size).to.equal(3);
+    });
+
+    it("lists a hedged market once per side and drops one entry per close", () => {
+      const user = Keypair.generate();
+      const [market] = findMarketPDA(Keypair.generate().publicKey);
+
+      // Opening long then short registers the market twice
+      const activeMarkets = [market, market];
+      const positions = [true, false].map(
+        (isLong) => findPositionPDA(user.publicKey, market, isLong)[0]
+      );
+      expect(new Set(positions.map((p) => p.toBase58())).size).to.equal(2);
+
+      // Closing the long removes a single entry, so the short stays listed
+      activeMarkets.splice(activeMarkets.findIndex((m) => m.equals(market)), 1);
+      expect(activeMarkets.length).to.equal(1);
+      expect(activeMarkets[0].toBase58()).to.equal(market.toBase58());
+    });
+
+    it("rejects opening beyond MAX_ACTIVE_POSITIONS markets", () => {
+      // register_active_market fails with TooManyPositions once the
+      // registry is full; closing or liquidating frees a slot
+      const activeCount = MAX_ACTIVE_POSITIONS;
+      expect(activeCount < MAX_ACTIVE_POSITIONS).to.be.false;
+    });
   });
 });
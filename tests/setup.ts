@@ -9,6 +9,7 @@ import {
 } from "@solana/web3.js";
 import {
   TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   ASSOCIATED_TOKEN_PROGRAM_ID,
   getAssociatedTokenAddressSync,
   createMint,
@@ -30,10 +31,29 @@ export const WSOL_MINT = new PublicKey(
 
 export const MAX_LEVERAGE = 10;
 export const LIQUIDATION_THRESHOLD_BPS = 7000;
+export const SOFT_LIQUIDATION_THRESHOLD_BPS = 5000;
 export const LIQUIDATOR_REWARD_BPS = 500;
 export const PROTOCOL_FEE_BPS = 30;
+export const PUMPSWAP_FEE_BPS = 30;
 export const BPS_DENOMINATOR = 10_000;
 export const PRECISION = 1_000_000_000_000;
+export const WSOL_DECIMALS = 9;
+export const MAX_ACTIVE_POSITIONS = 8;
+export const ADL_MAX_CLOSE_BPS = 5000;
+export const SLIPPAGE_WIDEN_STEP_BPS = 25;
+export const MAX_SLIPPAGE_WIDEN_BPS = 1000;
+export const SECONDS_PER_YEAR = 31_536_000;
+export const POOL_COLLAPSE_BPS = 2000;
+export const VOLUME_DECAY_WINDOW = 30 * 86_400;
+export const MARK_PRICE_WINDOW = 300;
+export const MARK_PRICE_MAX_ELAPSED = 60;
+export const MAX_CLOSE_DELAY = 3600;
+export const CLOSE_TOLERANCE_WINDOW = 600;
+// [minimum decayed volume in lamports, fee bps], highest tier first
+export const FEE_TIERS: [string, number][] = [
+  ["10000000000000", 20],
+  ["1000000000000", 25],
+];
 
 // ============ PDA Derivation Helpers ============
 
@@ -73,6 +93,24 @@ export function findUserAccountPDA(user: PublicKey): [PublicKey, number] {
 }
 
 export function findPositionPDA(
+  user: PublicKey,
+  market: PublicKey,
+  isLong: boolean
+): [PublicKey, number] {
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("position"),
+      user.toBuffer(),
+      market.toBuffer(),
+      Buffer.from([isLong ? 1 : 0]),
+    ],
+    PROGRAM_ID
+  );
+}
+
+// Positions opened before positions were keyed by side. close_position,
+// liquidate, liquidate_amount and force_close_position still accept them
+export function findLegacyPositionPDA(
   user: PublicKey,
   market: PublicKey
 ): [PublicKey, number] {
@@ -98,6 +136,17 @@ export interface ProtocolState {
   admin: PublicKey;
   bump: number;
   vaultBump: number;
+  feeInsuranceSplitBps: BN;
+  insuranceFund: BN;
+  accumulatedFees: BN;
+  adlThresholdBps: BN;
+  feeDiscountMint: PublicKey;
+  feeCollector: PublicKey;
+  feeDiscountBps: BN;
+  feeTokensPerLamport: BN;
+  marketCount: BN;
+  totalOpenPositions: BN;
+  totalUserBalances: BN;
 }
 
 export interface MarketState {
@@ -107,7 +156,13 @@ export interface MarketState {
   totalShortCollateral: BN;
   totalPositions: BN;
   maxPositionSize: BN;
+  tokenDecimals: number;
   bump: number;
+  baseUnit: BN;
+  quoteUnit: BN;
+  marketIndex: BN;
+  cumulativePrice: BN;
+  lastPriceUpdate: BN;
 }
 
 export interface LendingPoolState {
@@ -117,12 +172,20 @@ export interface LendingPoolState {
   totalBorrowed: BN;
   totalShares: BN;
   bump: number;
+  borrowRateBps: BN;
+  cumulativeInterestCollected: BN;
+  maxBorrowPerPosition: BN;
+  reserveFactorBps: BN;
+  protocolReserves: BN;
+  depositCap: BN;
 }
 
 export interface UserAccountState {
   owner: PublicKey;
   balance: BN;
   bump: number;
+  feeExempt: boolean;
+  activeMarkets: PublicKey[];
 }
 
 export interface PositionState {
@@ -132,12 +195,15 @@ export interface PositionState {
   collateral: BN;
   leverage: BN;
   entryPrice: BN;
+  markEntryPrice: BN;
   liquidationPrice: BN;
   tokenAmount: BN;
   positionSizeSol: BN;
   borrowedTokens: BN;
   openedAt: BN;
   bump: number;
+  poolLiquidityAtOpen: BN;
+  closeSlippageBps: BN;
 }
 
 export interface LenderPositionState {
@@ -201,14 +267,18 @@ export async function airdrop(
 export async function createTestMint(
   connection: Connection,
   payer: Keypair,
-  decimals: number = 6
+  decimals: number = 6,
+  programId: PublicKey = TOKEN_PROGRAM_ID
 ): Promise<PublicKey> {
   return await createMint(
     connection,
     payer,
     payer.publicKey,
     null,
-    decimals
+    decimals,
+    Keypair.generate(),
+    undefined,
+    programId
   );
 }
 
@@ -231,22 +301,607 @@ export async function createAndFundTokenAccount(
 
 // ============ Math Helpers (mirrors on-chain logic) ============
 
-export function calcLiqPriceLong(entryPrice: BN, leverage: BN): BN {
-  const dropBps = new BN(LIQUIDATION_THRESHOLD_BPS).div(leverage);
+export function calcPoolPrice(
+  quoteAmount: BN,
+  baseAmount: BN,
+  baseDecimals: number,
+  quoteDecimals: number = WSOL_DECIMALS
+): BN {
+  const baseUnit = new BN(10).pow(new BN(baseDecimals));
+  const quoteUnit = new BN(10).pow(new BN(quoteDecimals));
+  return quoteAmount
+    .mul(new BN(PRECISION.toString()))
+    .mul(baseUnit)
+    .div(baseAmount.mul(quoteUnit));
+}
+
+// Mirrors get_pool_price in a test build: a non-zero mock_price replaces
+// the pool's price
+export function poolPriceOrMock(
+  mockPrice: BN,
+  quoteAmount: BN,
+  baseAmount: BN,
+  baseDecimals: number
+): BN {
+  if (!mockPrice.isZero()) return mockPrice;
+  return calcPoolPrice(quoteAmount, baseAmount, baseDecimals);
+}
+
+export function calcTokensForQuote(
+  quoteAmount: BN,
+  price: BN,
+  baseDecimals: number,
+  quoteDecimals: number = WSOL_DECIMALS
+): BN {
+  const baseUnit = new BN(10).pow(new BN(baseDecimals));
+  const quoteUnit = new BN(10).pow(new BN(quoteDecimals));
+  return quoteAmount
+    .mul(new BN(PRECISION.toString()))
+    .mul(baseUnit)
+    .div(price.mul(quoteUnit));
+}
+
+export function calcQuoteForTokens(
+  tokenAmount: BN,
+  price: BN,
+  baseDecimals: number,
+  quoteDecimals: number = WSOL_DECIMALS
+): BN {
+  const baseUnit = new BN(10).pow(new BN(baseDecimals));
+  const quoteUnit = new BN(10).pow(new BN(quoteDecimals));
+  return tokenAmount
+    .mul(price)
+    .mul(quoteUnit)
+    .div(new BN(PRECISION.toString()).mul(baseUnit));
+}
+
+export function minCloseFill(
+  baseReserve: BN,
+  quoteReserve: BN,
+  tokenAmount: BN,
+  markPrice: BN,
+  maxCloseSlippageBps: number,
+  baseDecimals: number
+): BN {
+  if (maxCloseSlippageBps === 0) return new BN(0);
+  const expected = atMarkPrice(
+    estimateSellOutput(baseReserve, quoteReserve, tokenAmount),
+    baseReserve,
+    quoteReserve,
+    markPrice,
+    baseDecimals
+  );
+  return expected
+    .mul(new BN(BPS_DENOMINATOR - maxCloseSlippageBps))
+    .div(new BN(BPS_DENOMINATOR));
+}
+
+export function atMarkPrice(
+  amount: BN,
+  baseReserve: BN,
+  quoteReserve: BN,
+  markPrice: BN,
+  baseDecimals: number
+): BN {
+  if (markPrice.isZero()) return amount;
+  const spot = calcPoolPrice(quoteReserve, baseReserve, baseDecimals);
+  return spot.isZero() ? amount : amount.mul(markPrice).div(spot);
+}
+
+export function snapshotUnrealizedPnl(
+  isLong: boolean,
+  positionSizeSol: BN,
+  tokens: BN,
+  price: BN,
+  baseDecimals: number
+): BN {
+  const value = calcQuoteForTokens(tokens, price, baseDecimals);
+  return isLong ? value.sub(positionSizeSol) : positionSizeSol.sub(value);
+}
+
+export function effectiveLeverage(positionSizeSol: BN, collateral: BN): BN {
+  if (collateral.isZero()) throw new Error("ZeroCollateral");
+  return positionSizeSol.add(collateral.divn(2)).div(collateral);
+}
+
+// Mirrors validate_leverage, run at the end of open_position (and so of
+// open_position_with_deposit) on the filled size and collateral
+export function validateLeverage(
+  positionSizeSol: BN,
+  collateral: BN,
+  maxLeverage: number
+): BN {
+  const leverage = effectiveLeverage(positionSizeSol, collateral);
+  if (leverage.ltn(1) || leverage.gtn(maxLeverage)) throw new Error("InvalidLeverage");
+  return leverage;
+}
+
+export function calcLiqPriceLong(
+  entryPrice: BN,
+  leverage: BN,
+  thresholdBps: number = LIQUIDATION_THRESHOLD_BPS
+): BN {
+  const dropBps = new BN(thresholdBps).div(leverage);
   return entryPrice
     .mul(new BN(BPS_DENOMINATOR).sub(dropBps))
     .div(new BN(BPS_DENOMINATOR));
 }
 
-export function calcLiqPriceShort(entryPrice: BN, leverage: BN): BN {
-  const riseBps = new BN(LIQUIDATION_THRESHOLD_BPS).div(leverage);
+export function calcLiqPriceShort(
+  entryPrice: BN,
+  leverage: BN,
+  thresholdBps: number = LIQUIDATION_THRESHOLD_BPS
+): BN {
+  const riseBps = new BN(thresholdBps).div(leverage);
   return entryPrice
     .mul(new BN(BPS_DENOMINATOR).add(riseBps))
     .div(new BN(BPS_DENOMINATOR));
 }
 
-export function calcFee(amount: BN): BN {
-  return amount.mul(new BN(PROTOCOL_FEE_BPS)).div(new BN(BPS_DENOMINATOR));
+export function repriceAfterPartialFill(
+  isLong: boolean,
+  entryPrice: BN,
+  positionSizeSol: BN,
+  collateral: BN
+): { leverage: BN; liquidationPrice: BN; softLiquidationPrice: BN } {
+  const leverage = BN.max(effectiveLeverage(positionSizeSol, collateral), new BN(1));
+  const calc = isLong ? calcLiqPriceLong : calcLiqPriceShort;
+  return {
+    leverage,
+    liquidationPrice: calc(entryPrice, leverage),
+    softLiquidationPrice: calc(entryPrice, leverage, SOFT_LIQUIDATION_THRESHOLD_BPS),
+  };
+}
+
+export function interestAdjustedLiqPriceShort(
+  liquidationPrice: BN,
+  borrowed: BN,
+  interest: BN
+): BN {
+  if (borrowed.isZero()) return liquidationPrice;
+  const adjusted = liquidationPrice.mul(borrowed).div(borrowed.add(interest));
+  return BN.min(adjusted, liquidationPrice);
+}
+
+export function calcCrankReward(
+  crankReward: BN,
+  accumulatedFees: BN,
+  now: BN,
+  lastCrankTs: BN,
+  crankInterval: BN
+): BN {
+  const reward = BN.min(crankReward, accumulatedFees);
+  if (reward.isZero() || crankInterval.lten(0) || now.sub(lastCrankTs).lt(crankInterval)) {
+    return new BN(0);
+  }
+  return reward;
+}
+
+export function decayedVolume(
+  recentVolume: BN,
+  volumeUpdatedAt: BN,
+  now: BN
+): BN {
+  const elapsed = BN.max(now.sub(volumeUpdatedAt), new BN(0));
+  if (elapsed.gten(VOLUME_DECAY_WINDOW)) return new BN(0);
+  return recentVolume
+    .mul(new BN(VOLUME_DECAY_WINDOW).sub(elapsed))
+    .div(new BN(VOLUME_DECAY_WINDOW));
+}
+
+export function feeBpsForVolume(volume: BN): number {
+  const tier = FEE_TIERS.find(([minVolume]) => volume.gte(new BN(minVolume)));
+  return tier ? tier[1] : PROTOCOL_FEE_BPS;
+}
+
+export function checkTwoPhaseClose(
+  market: { twoPhaseCloseSize: BN; closeDelay: BN; closePriceToleranceBps: BN },
+  position: { isLong: boolean; positionSizeSol: BN; closeRequestedAt: BN; closeRequestPrice: BN },
+  currentPrice: BN,
+  now: BN
+): string | null {
+  if (market.twoPhaseCloseSize.isZero() || position.positionSizeSol.lt(market.twoPhaseCloseSize)) {
+    return null;
+  }
+  if (position.closeRequestedAt.isZero()) return "CloseNotRequested";
+  const executableAt = position.closeRequestedAt.add(market.closeDelay);
+  if (now.lt(executableAt)) return "CloseDelayPending";
+  if (now.gte(executableAt.addn(CLOSE_TOLERANCE_WINDOW))) return null;
+
+  const tolerance = position.closeRequestPrice
+    .mul(market.closePriceToleranceBps)
+    .div(new BN(BPS_DENOMINATOR));
+  const within = position.isLong
+    ? currentPrice.gte(position.closeRequestPrice.sub(tolerance))
+    : currentPrice.lte(position.closeRequestPrice.add(tolerance));
+  return within ? null : "PriceMovedSinceCloseRequest";
+}
+
+export function nextMarkPrice(mark: BN, price: BN, elapsed: number): BN {
+  const weight = new BN(Math.min(Math.max(elapsed, 0), MARK_PRICE_MAX_ELAPSED));
+  const window = new BN(MARK_PRICE_WINDOW);
+  return price.gte(mark)
+    ? mark.add(price.sub(mark).mul(weight).div(window))
+    : mark.sub(mark.sub(price).mul(weight).div(window));
+}
+
+export function isLiquidatableAtMark(
+  isLong: boolean,
+  currentPrice: BN,
+  markPrice: BN,
+  liquidationPrice: BN
+): boolean {
+  const past = (price: BN) =>
+    isLong ? price.lte(liquidationPrice) : price.gte(liquidationPrice);
+  return past(currentPrice) && (markPrice.isZero() || past(markPrice));
+}
+
+// Mirrors liquidation_decision_price: the higher of live and mark for a
+// long, the lower for a short
+export function liquidationDecisionPrice(
+  isLong: boolean,
+  currentPrice: BN,
+  markPrice: BN
+): { price: BN; markGoverned: boolean } {
+  const markGoverned =
+    !markPrice.isZero() && (isLong ? markPrice.gt(currentPrice) : markPrice.lt(currentPrice));
+  return { price: markGoverned ? markPrice : currentPrice, markGoverned };
+}
+
+export function isNearLiquidation(
+  isLong: boolean,
+  currentPrice: BN,
+  liquidationPrice: BN,
+  bufferBps: BN
+): boolean {
+  const buffer = liquidationPrice.mul(bufferBps).div(new BN(BPS_DENOMINATOR));
+  return isLong
+    ? currentPrice.lte(liquidationPrice.add(buffer))
+    : currentPrice.gte(liquidationPrice.sub(buffer));
+}
+
+export function maxOpenLeverage(marketMaxLeverage: number, ceiling: number): number {
+  return Math.min(marketMaxLeverage, ceiling);
+}
+
+export function isOpenCooldownElapsed(
+  lastOpenTs: number,
+  now: number,
+  cooldownSeconds: number
+): boolean {
+  return cooldownSeconds === 0 || now >= lastOpenTs + cooldownSeconds;
+}
+
+export function isUnderProtocolPositionCap(
+  totalOpenPositions: number,
+  maxTotalPositions: number
+): boolean {
+  return maxTotalPositions === 0 || totalOpenPositions < maxTotalPositions;
+}
+
+export function closeFeeBps(
+  openFeeBps: number,
+  heldSeconds: number,
+  decaySeconds: number,
+  floorBps: number
+): number {
+  if (decaySeconds <= 0) return openFeeBps;
+  const floor = Math.min(floorBps, openFeeBps);
+  const held = Math.min(Math.max(heldSeconds, 0), decaySeconds);
+  return openFeeBps - Math.floor(((openFeeBps - floor) * held) / decaySeconds);
+}
+
+export function withdrawalFeeBps(
+  feeBps: number,
+  heldSeconds: number,
+  decaySeconds: number
+): number {
+  if (decaySeconds <= 0) return 0;
+  const held = Math.min(Math.max(heldSeconds, 0), decaySeconds);
+  return Math.floor((feeBps * (decaySeconds - held)) / decaySeconds);
+}
+
+export function calcWithdrawalFee(
+  tokens: BN,
+  feeBps: number,
+  lastLender: boolean
+): BN {
+  if (lastLender) return new BN(0);
+  return tokens.muln(feeBps).divn(BPS_DENOMINATOR);
+}
+
+export function splitCollectedFee(
+  fee: BN,
+  lenderShareBps: number,
+  insuranceSplitBps: number,
+  totalShares: BN
+): { toLenders: BN; toInsurance: BN; toTreasury: BN } {
+  const toLenders = totalShares.isZero()
+    ? new BN(0)
+    : fee.mul(new BN(lenderShareBps)).div(new BN(BPS_DENOMINATOR));
+  const remainder = fee.sub(toLenders);
+  const toInsurance = remainder.mul(new BN(insuranceSplitBps)).div(new BN(BPS_DENOMINATOR));
+  return { toLenders, toInsurance, toTreasury: remainder.sub(toInsurance) };
+}
+
+export function lenderFeeDebt(shares: BN, feePerShare: BN): BN {
+  return shares.mul(feePerShare).div(new BN(PRECISION.toString()));
+}
+
+export function shortSettlesInSol(
+  poolCollapsed: boolean,
+  tokensOwed: BN,
+  baseReserve: BN
+): boolean {
+  return poolCollapsed || tokensOwed.gte(baseReserve);
+}
+
+export function isWithinInsuranceCoverage(
+  totalOpenSize: BN,
+  positionSize: BN,
+  insuranceFund: BN,
+  coverageMultiple: BN
+): boolean {
+  if (coverageMultiple.isZero()) return true;
+  return totalOpenSize.add(positionSize).lte(insuranceFund.mul(coverageMultiple));
+}
+
+export function isWithinEntryPriceLimit(
+  isLong: boolean,
+  entryPrice: BN,
+  entryPriceLimit: BN
+): boolean {
+  if (entryPriceLimit.isZero()) return true;
+  return isLong ? entryPrice.lte(entryPriceLimit) : entryPrice.gte(entryPriceLimit);
+}
+
+export function protocolOwnedTokens(
+  vaultAmount: BN,
+  totalDeposits: BN,
+  totalBorrowed: BN,
+  protocolReserves: BN
+): BN {
+  const lenderTokens = BN.max(totalDeposits.sub(totalBorrowed), new BN(0));
+  return BN.max(vaultAmount.sub(lenderTokens).sub(protocolReserves), new BN(0));
+}
+
+export function eventPriceToNumber(price: BN, pricePrecision: BN): number {
+  const whole = price.div(pricePrecision);
+  const frac = price.mod(pricePrecision);
+  return whole.toNumber() + frac.toNumber() / pricePrecision.toNumber();
+}
+
+export function addRealizedPnl(realized: BN, payout: BN, collateral: BN): BN {
+  return realized.add(payout).sub(collateral);
+}
+
+export function calcOpenFeeRebate(
+  openFee: BN,
+  pnl: BN,
+  rebateBps: number,
+  accumulatedFees: BN
+): BN {
+  // Mirrors open_fee_rebate: profitable closes only, capped at the treasury
+  if (pnl.lten(0) || rebateBps === 0) {
+    return new BN(0);
+  }
+  return BN.min(openFee.muln(rebateBps).divn(BPS_DENOMINATOR), accumulatedFees);
+}
+
+export const PRICE_UPDATE_DISCRIMINATOR = Buffer.from([34, 241, 35, 99, 157, 126, 244, 205]);
+export const PYTH_RECEIVER_PROGRAM_ID = new PublicKey(
+  "rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ"
+);
+export const SOL_USD_FEED_ID = Buffer.from(
+  "ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d",
+  "hex"
+);
+export const MAX_ORACLE_AGE = 60;
+export const USD_UNIT = 1_000_000;
+
+// Pyth PriceUpdateV2 layout, enough of it for read_sol_usd_price
+export function encodeMockPriceUpdate(
+  price: BN,
+  expo: number,
+  publishTime: number,
+  partial: boolean = false,
+  feedId: Buffer = SOL_USD_FEED_ID
+): Buffer {
+  const level = partial ? Buffer.from([0, 3]) : Buffer.from([1]);
+  const message = Buffer.alloc(32 + 8 + 8 + 4 + 8 + 8 + 8 + 8);
+  feedId.copy(message, 0);
+  price.toTwos(64).toArrayLike(Buffer, "le", 8).copy(message, 32);
+  message.writeInt32LE(expo, 48);
+  new BN(publishTime).toTwos(64).toArrayLike(Buffer, "le", 8).copy(message, 52);
+  return Buffer.concat([PRICE_UPDATE_DISCRIMINATOR, Buffer.alloc(32), level, message, Buffer.alloc(8)]);
+}
+
+export function readSolUsdPrice(
+  data: Buffer,
+  owner: PublicKey,
+  now: number
+): { price: BN; expo: number } {
+  if (!owner.equals(PYTH_RECEIVER_PROGRAM_ID)) throw new Error("InvalidOracle");
+  if (data.length <= 40 || !data.subarray(0, 8).equals(PRICE_UPDATE_DISCRIMINATOR)) {
+    throw new Error("InvalidOracle");
+  }
+  const message = data[40] === 0 ? 42 : data[40] === 1 ? 41 : -1;
+  if (message < 0 || data.length < message + 60) throw new Error("InvalidOracle");
+  if (!data.subarray(message, message + 32).equals(SOL_USD_FEED_ID)) throw new Error("InvalidOracle");
+  const readI64 = (at: number) => new BN(data.subarray(at, at + 8), "le").fromTwos(64);
+  const price = readI64(message + 32);
+  const expo = data.readInt32LE(message + 48);
+  const publishTime = readI64(message + 52).toNumber();
+  if (price.lten(0) || expo < -12 || expo > 0) throw new Error("InvalidOracle");
+  if (now - publishTime > MAX_ORACLE_AGE) throw new Error("StaleOracle");
+  return { price, expo };
+}
+
+export function usdToLamports(usd: BN, price: BN, expo: number): BN {
+  return usd
+    .mul(new BN(10).pow(new BN(9 - expo)))
+    .div(price.muln(USD_UNIT));
+}
+
+export function calcPortfolioNetting(
+  longTokens: BN,
+  shortTokensOwed: BN,
+  baseReserve: BN,
+  quoteReserve: BN
+): { nettedTokens: BN; nettedSol: BN; longPoolTokens: BN; shortPoolTokens: BN } {
+  // Mirrors portfolio_netting: the overlap is settled at the pre-swap spot
+  // price and only each leg's remainder goes through the pool
+  const nettedTokens = BN.min(longTokens, shortTokensOwed);
+  return {
+    nettedTokens,
+    nettedSol: nettedTokens.mul(quoteReserve).div(baseReserve),
+    longPoolTokens: longTokens.sub(nettedTokens),
+    shortPoolTokens: shortTokensOwed.sub(nettedTokens),
+  };
+}
+
+export function hasBorrowLiquidity(
+  totalDeposits: BN,
+  totalBorrowed: BN,
+  vaultAmount: BN,
+  tokensToBorrow: BN
+): boolean {
+  // Both the pool's accounting and the token_vault balance must cover it
+  const available = BN.max(totalDeposits.sub(totalBorrowed), new BN(0));
+  return tokensToBorrow.lte(available) && tokensToBorrow.lte(vaultAmount);
+}
+
+export interface LendingConfig {
+  borrowRateBps: number;
+  maxBorrowPerPosition: BN;
+  maxInterestBps: number;
+  depositCap: BN;
+  reserveFactorBps: number;
+  withdrawalFeeBps: number;
+  withdrawalFeeDecaySeconds: number;
+}
+
+// Mirrors set_lending_config's checks; returns the error it would raise
+export function lendingConfigError(config: LendingConfig): string | null {
+  const bps = [
+    config.borrowRateBps,
+    config.maxInterestBps,
+    config.reserveFactorBps,
+    config.withdrawalFeeBps,
+  ];
+  if (bps.some((b) => b > BPS_DENOMINATOR)) {
+    return "InvalidBps";
+  }
+  if (config.withdrawalFeeDecaySeconds < 0) {
+    return "InvalidWithdrawalFeeDecay";
+  }
+  if (!config.depositCap.isZero() && config.maxBorrowPerPosition.gt(config.depositCap)) {
+    return "InvalidLendingConfig";
+  }
+  if (config.withdrawalFeeBps > 0 && config.withdrawalFeeDecaySeconds === 0) {
+    return "InvalidLendingConfig";
+  }
+  return null;
+}
+
+export const TOKEN_AMOUNT_OFFSET = 64;
+
+// Mirrors get_pool_reserves: both vaults must be SPL or Token-2022 accounts
+// long enough to hold the amount field; returns [base, quote]
+export function readPoolReserves(
+  baseVault: { owner: PublicKey; data: Buffer },
+  quoteVault: { owner: PublicKey; data: Buffer }
+): [BN, BN] {
+  // Both owners are checked before either amount is read
+  [baseVault, quoteVault].forEach((v) => {
+    if (!v.owner.equals(TOKEN_PROGRAM_ID) && !v.owner.equals(TOKEN_2022_PROGRAM_ID)) {
+      throw new Error("InvalidPool");
+    }
+  });
+  const read = (data: Buffer): BN => {
+    if (data.length < TOKEN_AMOUNT_OFFSET + 8) {
+      throw new Error("InvalidPool");
+    }
+    return new BN(data.subarray(TOKEN_AMOUNT_OFFSET, TOKEN_AMOUNT_OFFSET + 8), "le");
+  };
+  return [read(baseVault.data), read(quoteVault.data)];
+}
+
+export function priceJumped(previous: BN, price: BN, maxJumpBps: number): boolean {
+  if (previous.isZero()) {
+    return false;
+  }
+  return price.sub(previous).abs().muln(BPS_DENOMINATOR).gt(previous.muln(maxJumpBps));
+}
+
+// Mirrors check_price_jump: samples from the current slot don't confirm a move
+export function passesPriceJumpGuard(
+  market: {
+    maxPriceJumpBps: number;
+    priceJumpGuardSize: BN;
+    lastObservedPrice: BN;
+    lastObservedSlot: number;
+    priorSlotPrice: BN;
+  },
+  price: BN,
+  positionSize: BN,
+  slot: number
+): boolean {
+  if (market.maxPriceJumpBps === 0 || positionSize.lt(market.priceJumpGuardSize)) {
+    return true;
+  }
+  const reference = market.lastObservedSlot < slot ? market.lastObservedPrice : market.priorSlotPrice;
+  return !priceJumped(reference, price, market.maxPriceJumpBps);
+}
+
+// Mirrors force_close_position: floored at zero, never above the collateral
+export function calcForceClosePayout(collateral: BN, pnl: BN): BN {
+  const gross = collateral.add(pnl);
+  if (gross.lten(0)) {
+    return new BN(0);
+  }
+  return BN.min(gross, collateral);
+}
+
+// Mirrors attribute_pnl: the price move on the position's tokens, and
+// everything else as execution, so the two always sum to pnl
+export function attributePnl(
+  isLong: boolean,
+  tokens: BN,
+  markEntryPrice: BN,
+  exitPrice: BN,
+  pnl: BN,
+  baseDecimals: number
+): { pricePnl: BN; executionPnl: BN } {
+  const entryValue = calcQuoteForTokens(tokens, markEntryPrice, baseDecimals);
+  const exitValue = calcQuoteForTokens(tokens, exitPrice, baseDecimals);
+  const pricePnl = isLong ? exitValue.sub(entryValue) : entryValue.sub(exitValue);
+  return { pricePnl, executionPnl: pnl.sub(pricePnl) };
+}
+
+export function calcFee(amount: BN, feeBps: number = PROTOCOL_FEE_BPS): BN {
+  // Rounds up, matching protocol_fee on-chain
+  const bps = new BN(BPS_DENOMINATOR);
+  return amount.mul(new BN(feeBps)).add(bps.subn(1)).div(bps);
+}
+
+export function calcDiscountTokenFee(
+  feeLamports: BN,
+  discountBps: BN,
+  tokensPerLamport: BN
+): BN {
+  const discounted = feeLamports.mul(discountBps).div(new BN(BPS_DENOMINATOR));
+  return discounted.mul(tokensPerLamport).div(new BN(PRECISION.toString()));
+}
+
+export function calcRealizedCloseFee(
+  collateral: BN,
+  pnl: BN,
+  closeFee: BN
+): BN {
+  const gross = collateral.add(pnl);
+  if (gross.lten(0)) return new BN(0);
+  return BN.min(gross, closeFee);
 }
 
 export function calcPositionSize(collateral: BN, leverage: BN): BN {
@@ -254,6 +909,232 @@ export function calcPositionSize(collateral: BN, leverage: BN): BN {
   return collateral.sub(fee).mul(leverage);
 }
 
+function divCeil(a: BN, b: BN): BN {
+  const { div, mod } = a.divmod(b);
+  return mod.isZero() ? div : div.addn(1);
+}
+
+export function estimateSellOutput(
+  baseReserve: BN,
+  quoteReserve: BN,
+  tokensIn: BN
+): BN {
+  const gross = quoteReserve.mul(tokensIn).div(baseReserve.add(tokensIn));
+  return gross
+    .mul(new BN(BPS_DENOMINATOR - PUMPSWAP_FEE_BPS))
+    .div(new BN(BPS_DENOMINATOR));
+}
+
+export function estimateBuyInput(
+  baseReserve: BN,
+  quoteReserve: BN,
+  tokensOut: BN
+): BN {
+  const net = divCeil(quoteReserve.mul(tokensOut), baseReserve.sub(tokensOut));
+  return divCeil(
+    net.mul(new BN(BPS_DENOMINATOR)),
+    new BN(BPS_DENOMINATOR - PUMPSWAP_FEE_BPS)
+  );
+}
+
+export function closeSellLimit(
+  slippageLimit: BN,
+  closeSlippageBps: BN,
+  baseReserve: BN,
+  quoteReserve: BN,
+  tokens: BN,
+  markPrice: BN,
+  baseDecimals: number
+): BN {
+  if (closeSlippageBps.isZero()) return slippageLimit;
+  const expected = estimateSellOutput(baseReserve, quoteReserve, tokens);
+  const ownerFloor = atMarkPrice(expected, baseReserve, quoteReserve, markPrice, baseDecimals)
+    .mul(new BN(BPS_DENOMINATOR).sub(closeSlippageBps))
+    .div(new BN(BPS_DENOMINATOR));
+  return BN.max(slippageLimit, ownerFloor);
+}
+
+export function closeBuyLimit(
+  slippageLimit: BN,
+  closeSlippageBps: BN,
+  baseReserve: BN,
+  quoteReserve: BN,
+  tokens: BN,
+  markPrice: BN,
+  baseDecimals: number
+): BN {
+  if (closeSlippageBps.isZero()) return slippageLimit;
+  const expected = estimateBuyInput(baseReserve, quoteReserve, tokens);
+  const ownerCap = atMarkPrice(expected, baseReserve, quoteReserve, markPrice, baseDecimals)
+    .mul(new BN(BPS_DENOMINATOR).add(closeSlippageBps))
+    .div(new BN(BPS_DENOMINATOR));
+  return slippageLimit.isZero() ? ownerCap : BN.min(slippageLimit, ownerCap);
+}
+
+export function widenSellLimit(
+  limit: BN,
+  maxWidenBps: number,
+  expected: BN
+): { bound: BN; widenedBps: number } {
+  let bound = limit;
+  let widenedBps = 0;
+  while (bound.gt(expected) && widenedBps < maxWidenBps) {
+    widenedBps = Math.min(widenedBps + SLIPPAGE_WIDEN_STEP_BPS, maxWidenBps);
+    bound = limit.muln(BPS_DENOMINATOR - widenedBps).divn(BPS_DENOMINATOR);
+  }
+  return { bound, widenedBps };
+}
+
+export function widenBuyLimit(
+  limit: BN,
+  maxWidenBps: number,
+  expected: BN
+): { bound: BN; widenedBps: number } {
+  let bound = limit;
+  let widenedBps = 0;
+  while (!limit.isZero() && bound.lt(expected) && widenedBps < maxWidenBps) {
+    widenedBps = Math.min(widenedBps + SLIPPAGE_WIDEN_STEP_BPS, maxWidenBps);
+    bound = limit.muln(BPS_DENOMINATOR + widenedBps).divn(BPS_DENOMINATOR);
+  }
+  return { bound, widenedBps };
+}
+
+export function liquidationChunkTokens(
+  totalTokens: BN,
+  positionSizeSol: BN,
+  maxChunk: BN
+): BN {
+  if (maxChunk.isZero() || positionSizeSol.lte(maxChunk)) return totalTokens;
+  return totalTokens.mul(maxChunk).div(positionSizeSol);
+}
+
+export function calcLiquidationSplit(
+  remaining: BN,
+  minReward: BN = new BN(0),
+  dustThreshold: BN = new BN(0)
+): {
+  reward: BN;
+  toOwner: BN;
+} {
+  const reward = remaining.lte(dustThreshold)
+    ? remaining
+    : BN.max(
+        remaining.mul(new BN(LIQUIDATOR_REWARD_BPS)).div(new BN(BPS_DENOMINATOR)),
+        BN.min(minReward, remaining)
+      );
+  return { reward, toOwner: remaining.sub(reward) };
+}
+
+export function isAdlTriggered(
+  sideCollateral: BN,
+  totalCollateral: BN,
+  totalBorrowed: BN,
+  totalDeposits: BN,
+  adlThresholdBps: BN
+): boolean {
+  if (adlThresholdBps.isZero() || totalCollateral.isZero()) return false;
+  const bps = new BN(BPS_DENOMINATOR);
+  const sideShareBps = sideCollateral.mul(bps).div(totalCollateral);
+  const utilizationBps = totalDeposits.isZero()
+    ? new BN(0)
+    : totalBorrowed.mul(bps).div(totalDeposits);
+  return (
+    sideShareBps.gt(bps.divn(2)) &&
+    sideShareBps.add(utilizationBps).gte(adlThresholdBps)
+  );
+}
+
+export function accumulatePrice(
+  cumulativePrice: BN,
+  lastUpdate: BN,
+  price: BN,
+  now: BN
+): BN {
+  if (lastUpdate.isZero()) return cumulativePrice;
+  const elapsed = BN.max(now.sub(lastUpdate), new BN(0));
+  return cumulativePrice.add(price.mul(elapsed));
+}
+
+export function isPoolCollapsed(
+  liquidityAtOpen: BN,
+  currentLiquidity: BN
+): boolean {
+  return currentLiquidity
+    .mul(new BN(BPS_DENOMINATOR))
+    .lt(liquidityAtOpen.mul(new BN(POOL_COLLAPSE_BPS)));
+}
+
+// Mirrors long_value_at_mark: a long whose pool collapsed is valued at the
+// mark, or at spot before the first mark sample, and its tokens are kept
+export function longValueAtMark(
+  markPrice: BN,
+  tokenAmount: BN,
+  baseReserve: BN,
+  quoteReserve: BN,
+  baseDecimals: number
+): BN {
+  const price = markPrice.isZero()
+    ? calcPoolPrice(quoteReserve, baseReserve, baseDecimals)
+    : markPrice;
+  return calcQuoteForTokens(tokenAmount, price, baseDecimals);
+}
+
+export function calcInterestSplit(
+  interest: BN,
+  reserveFactorBps: BN
+): { reserve: BN; toLenders: BN } {
+  const reserve = interest.mul(reserveFactorBps).div(new BN(BPS_DENOMINATOR));
+  return { reserve, toLenders: interest.sub(reserve) };
+}
+
+export function calcRiskShares(
+  totalLongCollateral: BN,
+  totalShortCollateral: BN,
+  insuranceFund: BN
+): { longShareBps: BN; insuranceCoverageBps: BN | null } {
+  const total = totalLongCollateral.add(totalShortCollateral);
+  if (total.isZero()) {
+    // On-chain coverage is u64::MAX when nothing is open
+    return { longShareBps: new BN(0), insuranceCoverageBps: null };
+  }
+  return {
+    longShareBps: totalLongCollateral.mul(new BN(BPS_DENOMINATOR)).div(total),
+    insuranceCoverageBps: insuranceFund.mul(new BN(BPS_DENOMINATOR)).div(total),
+  };
+}
+
+export function isWithinDepositCap(
+  totalDeposits: BN,
+  amount: BN,
+  depositCap: BN
+): boolean {
+  return depositCap.isZero() || totalDeposits.add(amount).lte(depositCap);
+}
+
+export function capInterest(
+  interest: BN,
+  borrowed: BN,
+  maxInterestBps: BN
+): BN {
+  if (maxInterestBps.isZero()) return interest;
+  return BN.min(interest, borrowed.mul(maxInterestBps).div(new BN(BPS_DENOMINATOR)));
+}
+
+export function isWithinBorrowCap(tokensToBorrow: BN, cap: BN): boolean {
+  return cap.isZero() || tokensToBorrow.lte(cap);
+}
+
+export function calcAccruedInterest(
+  borrowed: BN,
+  rateBps: BN,
+  elapsedSeconds: BN
+): BN {
+  return borrowed
+    .mul(rateBps)
+    .mul(elapsedSeconds)
+    .div(new BN(BPS_DENOMINATOR).mul(new BN(SECONDS_PER_YEAR)));
+}
+
 export function calcLendingShares(
   amount: BN,
   totalDeposits: BN,